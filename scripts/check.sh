@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+# Builds and tests the workspace with the default features as well as the minimal
+# `sev_step_lib` configuration without default features. Run from anywhere inside the repo.
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+
+echo "### workspace, default features"
+cargo build --workspace --all-targets
+cargo test --workspace
+
+echo "### sev_step_lib, no default features"
+cargo build -p sev_step_lib --no-default-features
+cargo test -p sev_step_lib --no-default-features --lib
@@ -23,10 +23,47 @@ enum DetectMemArgHandlerState {
     AfterTargetInstruction,
 }
 
+/// Information from an event that is relevant for the state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Observation {
+    PageFault { gpa: u64 },
+    Step { retired_instructions: u32 },
+}
+
+impl From<&Event> for Observation {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::PageFaultEvent(v) => Observation::PageFault { gpa: v.faulted_gpa },
+            Event::StepEvent(v) => Observation::Step {
+                retired_instructions: v.retired_instructions,
+            },
+        }
+    }
+}
+
+/// Action that [`DetectMemArgHandler::process`] has to perform after advancing the state machine.
+/// Keeps the state machine free of API calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transition {
+    /// Track all pages, start single stepping and wait for the next event
+    StartStepping,
+    /// Ack the event and wait for the next one
+    AwaitNextEvent,
+    /// The target instruction has been executed. Stop stepping, untrack all pages and return the event
+    FinishInstruction,
+    /// We are already done. Return the event without touching it
+    PassOn,
+    /// The step event retired more than one instruction
+    MultiStep,
+}
+
 pub struct DetectMemArgHandler {
     state: DetectMemArgHandlerState,
-    ///page faults encountered during the execution of the instruction
-    recorded_page_faults: Vec<u64>,
+    ///page faults encountered during the execution of the instruction together with the
+    ///amount of zero steps that preceded them
+    recorded_accesses: Vec<(usize, u64)>,
+    ///zero steps observed while executing the target instruction
+    zero_steps: usize,
     single_step_time: u32,
 }
 
@@ -36,13 +73,52 @@ impl DetectMemArgHandler {
     pub fn new(apic_timer: u32) -> DetectMemArgHandler {
         DetectMemArgHandler {
             state: DetectMemArgHandlerState::BeforeTargetInstruction,
-            recorded_page_faults: Vec::new(),
+            recorded_accesses: Vec::new(),
+            zero_steps: 0,
             single_step_time: apic_timer,
         }
     }
 
-    pub fn get_observed_faults(&self) -> &Vec<u64> {
-        &self.recorded_page_faults
+    /// GPAs of the page faults observed during the execution of the instruction, in order
+    pub fn get_observed_faults(&self) -> Vec<u64> {
+        self.recorded_accesses.iter().map(|(_, gpa)| *gpa).collect()
+    }
+
+    /// Page faults observed during the execution of the instruction, in order.
+    /// Each entry is `(step_index, faulted_gpa)`, where `step_index` is the amount of zero steps
+    /// that were observed before the page fault
+    pub fn get_observed_accesses(&self) -> &[(usize, u64)] {
+        &self.recorded_accesses
+    }
+
+    /// Advance the state machine for the current event
+    fn advance(&mut self, observation: Observation) -> Transition {
+        match self.state {
+            DetectMemArgHandlerState::BeforeTargetInstruction => {
+                self.state = DetectMemArgHandlerState::ExecutingTargetInstruction;
+                Transition::StartStepping
+            }
+            DetectMemArgHandlerState::ExecutingTargetInstruction => match observation {
+                Observation::PageFault { gpa } => {
+                    self.recorded_accesses.push((self.zero_steps, gpa));
+                    Transition::AwaitNextEvent
+                }
+                Observation::Step {
+                    retired_instructions: 0,
+                } => {
+                    self.zero_steps += 1;
+                    Transition::AwaitNextEvent
+                }
+                Observation::Step {
+                    retired_instructions: 1,
+                } => {
+                    self.state = DetectMemArgHandlerState::AfterTargetInstruction;
+                    Transition::FinishInstruction
+                }
+                Observation::Step { .. } => Transition::MultiStep,
+            },
+            DetectMemArgHandlerState::AfterTargetInstruction => Transition::PassOn,
+        }
     }
 }
 
@@ -61,37 +137,38 @@ impl ComposableEventHandler for DetectMemArgHandler {
         );
         loop {
             debug!("{}: at state {}", DetectMemArgHandler::NAME, self.state);
-            match self.state {
-                DetectMemArgHandlerState::BeforeTargetInstruction => {
+            match self.advance(Observation::from(&event)) {
+                Transition::StartStepping => {
                     api.track_all_pages(kvm_page_track_mode::KVM_PAGE_TRACK_WRITE)?;
                     api.start_stepping(self.single_step_time, &mut [], true)?;
+                }
+                Transition::AwaitNextEvent => (),
+                Transition::FinishInstruction => {
+                    debug!(
+                        "{}: finished target instruction. Pending event is {:x?}",
+                        DetectMemArgHandler::NAME,
+                        event
+                    );
+                    api.stop_stepping()?;
+                    api.untrack_all_pages(kvm_page_track_mode::KVM_PAGE_TRACK_WRITE)?;
 
-                    self.state = DetectMemArgHandlerState::ExecutingTargetInstruction;
+                    //N.B. that the event is not yet acked at this point (as requested by our "contract")
+                    return Ok(EventHandlerOutcome {
+                        pending_event: event,
+                        next_action: StateMachineNextAction::NEXT,
+                    });
                 }
-                DetectMemArgHandlerState::ExecutingTargetInstruction => match &event {
-                    Event::PageFaultEvent(v) => self.recorded_page_faults.push(v.faulted_gpa),
-                    Event::StepEvent(v) => match v.retired_instructions {
-                        0 => (),
-                        1 => {
-                            debug!(
-                                "{}: finished target instruction. Pending event is {:x?}",
-                                DetectMemArgHandler::NAME,
-                                event
-                            );
-                            self.state = DetectMemArgHandlerState::AfterTargetInstruction;
-                            api.stop_stepping()?;
-                            api.untrack_all_pages(kvm_page_track_mode::KVM_PAGE_TRACK_WRITE)?;
-
-                            //N.B. that the event is not yet acked at this point (as requested by our "contract")
-                            return Ok(EventHandlerOutcome {
-                                pending_event: event,
-                                next_action: StateMachineNextAction::NEXT,
-                            });
-                        }
-                        _ => return Err(SevStepError::MultiStep { event: v.clone() }),
-                    },
+                Transition::PassOn => {
+                    //the instruction has already been analyzed, leave the event to the next handler
+                    return Ok(EventHandlerOutcome {
+                        pending_event: event,
+                        next_action: StateMachineNextAction::NEXT,
+                    });
+                }
+                Transition::MultiStep => match event {
+                    Event::StepEvent(v) => return Err(SevStepError::MultiStep { event: v }),
+                    Event::PageFaultEvent(_) => unreachable!("only step events can multi step"),
                 },
-                DetectMemArgHandlerState::AfterTargetInstruction => todo!(),
             }
             api.ack_event();
             event = api.block_untill_event(|| Ok(()), None)?;
@@ -102,3 +179,95 @@ impl ComposableEventHandler for DetectMemArgHandler {
         DetectMemArgHandler::NAME
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_ordered_accesses_until_instruction_finishes() {
+        let mut handler = DetectMemArgHandler::new(0);
+        let observations = [
+            (
+                Observation::Step {
+                    retired_instructions: 0,
+                },
+                Transition::StartStepping,
+            ),
+            (
+                Observation::PageFault { gpa: 0x2000 },
+                Transition::AwaitNextEvent,
+            ),
+            (
+                Observation::Step {
+                    retired_instructions: 0,
+                },
+                Transition::AwaitNextEvent,
+            ),
+            (
+                Observation::PageFault { gpa: 0x5000 },
+                Transition::AwaitNextEvent,
+            ),
+            (
+                Observation::PageFault { gpa: 0x3000 },
+                Transition::AwaitNextEvent,
+            ),
+            (
+                Observation::Step {
+                    retired_instructions: 1,
+                },
+                Transition::FinishInstruction,
+            ),
+        ];
+        for (observation, want) in observations {
+            assert_eq!(handler.advance(observation), want, "at {:?}", observation);
+        }
+        assert_eq!(
+            handler.get_observed_accesses(),
+            &[(0, 0x2000), (1, 0x5000), (1, 0x3000)]
+        );
+        assert_eq!(handler.get_observed_faults(), vec![0x2000, 0x5000, 0x3000]);
+    }
+
+    #[test]
+    fn events_after_the_instruction_are_passed_on() {
+        let mut handler = DetectMemArgHandler::new(0);
+        handler.advance(Observation::Step {
+            retired_instructions: 0,
+        });
+        handler.advance(Observation::PageFault { gpa: 0x2000 });
+        assert_eq!(
+            handler.advance(Observation::Step {
+                retired_instructions: 1
+            }),
+            Transition::FinishInstruction
+        );
+
+        for observation in [
+            Observation::PageFault { gpa: 0x4000 },
+            Observation::Step {
+                retired_instructions: 1,
+            },
+            Observation::Step {
+                retired_instructions: 3,
+            },
+        ] {
+            assert_eq!(handler.advance(observation), Transition::PassOn);
+        }
+        assert_eq!(handler.get_observed_accesses(), &[(0, 0x2000)]);
+    }
+
+    #[test]
+    fn multi_step_is_reported() {
+        let mut handler = DetectMemArgHandler::new(0);
+        handler.advance(Observation::Step {
+            retired_instructions: 0,
+        });
+        assert_eq!(
+            handler.advance(Observation::Step {
+                retired_instructions: 2
+            }),
+            Transition::MultiStep
+        );
+    }
+}
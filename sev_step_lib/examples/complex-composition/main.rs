@@ -63,14 +63,7 @@ fn main() -> Result<()> {
         .context("failed to get VCPU thread id")?;
     debug!("vcpu_thread_id is {}", vcpu_thread_id);
 
-    vm_setup_helpers::pin_pid_to_cpu(vcpu_thread_id, vm_config.vm_cpu_core).context(format!(
-        "failed to pin vcpu (tid {}) to core {}",
-        vcpu_thread_id, vm_config.vm_cpu_core,
-    ))?;
-    debug!(
-        "Pinned vcpu_thread (tid {}) to core {}",
-        vcpu_thread_id, vm_config.vm_cpu_core
-    );
+    vm_setup_helpers::pin_vcpu_to_vm_core(vcpu_thread_id, &vm_config)?;
 
     let external_victim_req = InitCustomTargetReq {
         folder_path: args.path_victim_prog,
@@ -178,8 +171,8 @@ fn main() -> Result<()> {
 
     let _res = executor.run()?;
     println!(
-        "Detected memory accesses: {:x?}",
-        leak_mem_arg.get_observed_faults()
+        "Detected memory accesses (zero steps before access, gpa): {:x?}",
+        leak_mem_arg.get_observed_accesses()
     );
 
     //Check the result of the attack
@@ -14,7 +14,9 @@ use sev_step_lib::single_stepper::{
 };
 use sev_step_lib::{
     api::SevStep,
-    config, vm_setup_helpers,
+    config,
+    types::TrackMode,
+    vm_setup_helpers,
     vmserver_client::{self},
 };
 use std::time::Duration;
@@ -41,6 +43,7 @@ fn build_cf_victim(guess: u64) -> Result<InitAssemblyTargetReq> {
     Ok(InitAssemblyTargetReq {
         code: a.take_instructions(),
         required_mem_bytes: 0,
+        initial_data: Vec::new(),
     })
 }
 ///This program demonstrates how to use the SEV-Step API to infer secret dependent control flow.
@@ -60,6 +63,9 @@ struct CliArgs {
     /// Input to victim program. See Program documentation
     #[arg(long)]
     guess_for_secret_input: u64,
+    /// Tracking mode used to detect when the victim program is executed
+    #[arg(long, value_enum, default_value_t = TrackMode::Exec)]
+    track_mode: TrackMode,
 }
 
 fn main() -> Result<()> {
@@ -78,14 +84,7 @@ fn main() -> Result<()> {
         .context("failed to get VCPU thread id")?;
     debug!("vcpu_thread_id is {}", vcpu_thread_id);
 
-    vm_setup_helpers::pin_pid_to_cpu(vcpu_thread_id, vm_config.vm_cpu_core).context(format!(
-        "failed to pin vcpu (tid {}) to core {}",
-        vcpu_thread_id, vm_config.vm_cpu_core,
-    ))?;
-    debug!(
-        "Pinned vcpu_thread (tid {}) to core {}",
-        vcpu_thread_id, vm_config.vm_cpu_core
-    );
+    vm_setup_helpers::pin_vcpu_to_vm_core(vcpu_thread_id, &vm_config)?;
 
     //In this example we use the VM server that comes with SEV-Step. This component
     //is intended to quickly test attack ideas/scenarios. It allows us to first JIT assemble a
@@ -118,8 +117,8 @@ fn main() -> Result<()> {
 
     //only single step if we are executing pages belonging to our victim program
     let mut single_step_target_gpa_only = SkipIfNotOnTargetGPAs::new(
-        &[victim_program.code_paddr as u64],
-        sev_step_lib::types::kvm_page_track_mode::KVM_PAGE_TRACK_EXEC,
+        &victim_program.code_gpas(),
+        args.track_mode.into(),
         args.apic_timer_value.unwrap(),
     );
 
@@ -151,8 +150,8 @@ fn main() -> Result<()> {
     let stepper = TargetedStepper::new(
         sev_step,
         handler_chain,
-        sev_step_lib::types::kvm_page_track_mode::KVM_PAGE_TRACK_EXEC,
-        vec![victim_program.code_paddr as u64],
+        args.track_mode.into(),
+        victim_program.code_gpas(),
         move || {
             vmserver_client::run_target_program(&vm_config.vm_server_address)
                 .context("failed to start victim_wrong_guess")
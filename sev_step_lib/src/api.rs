@@ -7,11 +7,12 @@
 //!
 //!
 use crate::{
-    ioctls, raw_spinlock,
+    ioctls::{self, SevStepIoctls},
+    raw_spinlock::{self, SpinLock, SpinPolicy},
     types::{
         kvm_page_track_mode, sev_step_event_t, sev_step_param_t, sev_step_partial_vmcb_save_area_t,
         shared_mem_region_t, track_all_pages_t, track_page_param_t, usp_event_type_t,
-        usp_init_poll_api_t, usp_page_fault_event_t, vmsa_register_name_t,
+        usp_init_poll_api_t, usp_page_fault_event_t, vmsa_register_name_t, TrackMode,
         SEV_STEP_SHARED_MEM_BYTES,
     },
 };
@@ -19,12 +20,29 @@ use anyhow::{anyhow, Context, Result as AhwResult};
 use core::slice;
 use crossbeam::channel::{bounded, Receiver, TryRecvError};
 use log::{debug, error, warn};
-use std::{fs::File, os::fd::AsRawFd, time::Instant};
-use std::{mem, process};
+#[cfg(feature = "full")]
+use serde::Serialize;
+use std::{
+    any::Any,
+    cell::UnsafeCell,
+    collections::{HashMap, HashSet},
+    fs::File,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::Instant,
+};
+use std::{mem, process, ptr};
 use std::{thread, time::Duration};
 use thiserror::Error;
 use SevStepError::MultiStep;
 
+#[cfg(test)]
+pub(crate) mod mock_kernel;
+
+/// log2 of the page size of the VM. Converts between guest frame numbers and guest physical addresses
+pub const PAGE_SHIFT: u64 = 12;
+
 #[derive(Error, Debug)]
 pub enum SevStepError {
     #[error("failed to execute trigger function : {source}")]
@@ -34,6 +52,12 @@ pub enum SevStepError {
     },
     #[error("operation timed out")]
     Timeout,
+    #[error("received abort signal")]
+    Aborted,
+    #[error("trigger function panicked : {message}")]
+    TriggerPanicked { message: String },
+    #[error("stepping requested without target GPAs while no pages are tracked")]
+    NoSteppingTargets,
     #[error(
         "page tracking error, gpa=0x{:x}, mode={:?}, message={} : {}",
         gpa,
@@ -50,25 +74,173 @@ pub enum SevStepError {
     },
     #[error("multi step")]
     MultiStep { event: SevStepEvent },
+    #[error("kernel sent event with unknown type {0}")]
+    UnknownEventType(u32),
+    #[error("operation not supported by the kernel : {0}")]
+    Unsupported(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+impl SevStepError {
+    /// Owned summary of the error that can be serialized, e.g. to record why a run ended in a report
+    pub fn to_report(&self) -> ErrorReport {
+        let kind = match self {
+            SevStepError::TriggerFailed { .. } => "TriggerFailed",
+            SevStepError::Timeout => "Timeout",
+            SevStepError::Aborted => "Aborted",
+            SevStepError::TriggerPanicked { .. } => "TriggerPanicked",
+            SevStepError::NoSteppingTargets => "NoSteppingTargets",
+            SevStepError::PageTracking { .. } => "PageTracking",
+            SevStepError::MultiStep { .. } => "MultiStep",
+            SevStepError::UnknownEventType(_) => "UnknownEventType",
+            SevStepError::Unsupported(_) => "Unsupported",
+            SevStepError::Other(_) => "Other",
+        };
+        let message = match self {
+            //transparent, thus also include the context of the wrapped error
+            SevStepError::Other(e) => format!("{:#}", e),
+            _ => self.to_string(),
+        };
+        let (retired_instructions, rip) = match self {
+            SevStepError::MultiStep { event } => (
+                Some(event.retired_instructions),
+                event.get_register(vmsa_register_name_t::VRN_RIP),
+            ),
+            _ => (None, None),
+        };
+        ErrorReport {
+            kind: kind.to_string(),
+            message,
+            retired_instructions,
+            rip,
+        }
+    }
+}
+
+/// Serializable summary of a [`SevStepError`], see [`SevStepError::to_report`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "full", derive(Serialize))]
+pub struct ErrorReport {
+    /// Name of the [`SevStepError`] variant
+    pub kind: String,
+    /// Error message, including the underlying errors
+    pub message: String,
+    /// Instructions retired by the step event of [`SevStepError::MultiStep`]
+    pub retired_instructions: Option<u32>,
+    /// RIP after the step event of [`SevStepError::MultiStep`]. Only available in debug mode
+    pub rip: Option<u64>,
+}
+
+/// Return this error (wrapped in an [`anyhow::Error`]) from a target trigger, to signal that the
+/// trigger failed but may succeed when called again, e.g. because the victim server is briefly busy.
+/// See [`TargetedStepper::with_trigger_retries`](crate::single_stepper::TargetedStepper::with_trigger_retries)
+#[derive(Error, Debug)]
+#[error("retryable trigger error : {0}")]
+pub struct RetryableTriggerError(pub anyhow::Error);
+
 #[repr(C, align(4096))]
 ///Page aligned array of size `SEV_STEP_SHARED_MEM_BYTES`. This is only
 /// a custom type so that we can use repr C to achieve the alignment
-struct AlignedSevStepBuf([u8; SEV_STEP_SHARED_MEM_BYTES as usize]);
+struct AlignedSevStepBuf(UnsafeCell<[u8; SEV_STEP_SHARED_MEM_BYTES as usize]>);
+
+//concurrent accesses to the buffer are synchronized by the spinlock inside of it
+unsafe impl Sync for AlignedSevStepBuf {}
+
+impl AlignedSevStepBuf {
+    fn new() -> Self {
+        AlignedSevStepBuf(UnsafeCell::new([0; SEV_STEP_SHARED_MEM_BYTES as usize]))
+    }
+
+    fn as_mut_ptr(&self) -> *mut u8 {
+        self.0.get().cast()
+    }
+}
 
 ///Main context struct for interacting with the SEV STEP API.
 ///Will automatically close the connection to kernel space when dropped
 pub struct SevStep<'a> {
-    _raw_shared_mem: AlignedSevStepBuf,
+    ///On the heap, as the kernel keeps using the address that we pass in during initialization.
+    /// Shared, as the mock kernel writes events to it from another thread
+    _raw_shared_mem: Arc<AlignedSevStepBuf>,
     shared_mem_region: &'a mut shared_mem_region_t,
-    kvm: File,
+    kvm: Box<dyn SevStepIoctls>,
     ///if we receive something on this channel, abort any blocking operations
     abort: Receiver<()>,
     ///If true, Abort with [`MultiStep`] if a multi step is encountered
     error_on_multi_step: bool,
+    ///Our view on the pages that are currently tracked in the kernel
+    tracked_pages: TrackedPages,
+    ///Tracking state saved by [`SevStep::pause_tracking`]
+    paused_tracking: Option<TrackedPages>,
+    ///If true, [`SevStep::start_stepping`] fails with [`SevStepError::NoSteppingTargets`] instead of only warning
+    strict_stepping_checks: bool,
+    ///Parameters of the last [`SevStep::start_stepping`] call, if stepping is active
+    stepping_session: Option<SteppingSession>,
+    ///Number of [`SevStep::ack_event`] calls on this instance
+    ack_count: u64,
+    ///Time of the most recent [`SevStep::ack_event`] call
+    last_ack: Option<Instant>,
+    ///If true, the event buffer is copied to `last_raw_event` whenever an event is decoded
+    capture_raw_events: bool,
+    ///Content of the event buffer for the most recently decoded event, see [`SevStep::set_capture_raw_events`]
+    last_raw_event: Option<Vec<u8>>,
+    ///How to wait for the spinlock of the shared memory region
+    spin_policy: SpinPolicy,
+}
+
+///Parameters of an active single stepping session
+#[derive(Clone)]
+struct SteppingSession {
+    target_gpas: Vec<u64>,
+    flush_tlb: bool,
+}
+
+///Bookkeeping of the tracking ioctls issued via [`SevStep`]
+#[derive(Default)]
+struct TrackedPages {
+    ///gpas tracked with [`SevStep::track_page`], by tracking mode
+    pages: HashMap<kvm_page_track_mode, HashSet<u64>>,
+    ///modes for which [`SevStep::track_all_pages`] was called
+    all_pages: HashSet<kvm_page_track_mode>,
+}
+
+impl TrackedPages {
+    fn is_empty(&self) -> bool {
+        self.all_pages.is_empty() && self.pages.values().all(|v| v.is_empty())
+    }
+
+    ///All tracking modes in use, in a stable order
+    fn modes(&self) -> Vec<kvm_page_track_mode> {
+        let mut modes: Vec<_> = self
+            .pages
+            .iter()
+            .filter(|(_, gpas)| !gpas.is_empty())
+            .map(|(mode, _)| *mode)
+            .chain(self.all_pages.iter().copied())
+            .collect();
+        modes.sort_by_key(|v| *v as i32);
+        modes.dedup();
+        modes
+    }
+
+    ///The kernel untracks a page once it reports a page fault for it
+    fn on_page_fault(&mut self, gpa: u64) {
+        for gpas in self.pages.values_mut() {
+            gpas.remove(&gpa);
+        }
+    }
+}
+
+/// Extracts the message from the payload of a panic, as passed to `panic!`
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(v) = payload.downcast_ref::<&str>() {
+        v.to_string()
+    } else if let Some(v) = payload.downcast_ref::<String>() {
+        v.clone()
+    } else {
+        "<panic payload is not a string>".to_string()
+    }
 }
 
 impl<'a> Drop for SevStep<'a> {
@@ -78,10 +250,11 @@ impl<'a> Drop for SevStep<'a> {
         if let Err(e) = self.stop_stepping() {
             error!("Failed to stop stepping: {}", e)
         }
-        unsafe {
-            if let Err(e) = ioctls::close_api(self.kvm.as_raw_fd()) {
-                error!("Error closing API: {}", e);
-            }
+        if let Err(e) = self.untrack_remaining_pages() {
+            error!("Failed to untrack remaining pages: {}", e)
+        }
+        if let Err(e) = self.kvm.close_api() {
+            error!("Error closing API: {}", e);
         }
     }
 }
@@ -99,31 +272,17 @@ impl<'a> SevStep<'a> {
         error_on_multi_step: bool,
     ) -> Result<Self, SevStepError> {
         //alloc buffer
-        let mut raw_shared_mem: AlignedSevStepBuf =
-            AlignedSevStepBuf([0; SEV_STEP_SHARED_MEM_BYTES as usize]);
-
-        //create shared_mem_region_t "view" into buffer
-        assert!(SEV_STEP_SHARED_MEM_BYTES as usize >= mem::size_of::<shared_mem_region_t>());
-        let shared_mem_ptr = raw_shared_mem.0.as_mut_ptr();
-        let shared_mem_region;
-        unsafe {
-            shared_mem_region = (shared_mem_ptr as *mut shared_mem_region_t)
-                .as_mut()
-                .unwrap();
-        }
-
-        //init shared_mem_region
-        raw_spinlock::init(&mut shared_mem_region.spinlock);
-        shared_mem_region.event_acked = 1;
-        shared_mem_region.have_event = 0;
+        let raw_shared_mem = Arc::new(AlignedSevStepBuf::new());
+        let shared_mem_ptr = raw_shared_mem.as_mut_ptr();
 
-        //call api init ioctl
+        //call api init ioctl. The kernel does not report a protocol version, see [`ioctls::init_api`]
         let mut params = usp_init_poll_api_t {
             pid: process::id() as i32,
             user_vaddr_shared_mem: shared_mem_ptr as u64,
             decrypt_vmsa,
         };
         let kvm = File::open("/dev/kvm").context("failed to open kvm file")?;
+        let shared_mem_region = Self::init_shared_mem_region(&raw_shared_mem);
         unsafe {
             ioctls::init_api(kvm.as_raw_fd(), &mut params).context("init_api ioctl failed")?;
         }
@@ -132,18 +291,77 @@ impl<'a> SevStep<'a> {
         Ok(SevStep {
             _raw_shared_mem: raw_shared_mem,
             shared_mem_region,
-            kvm,
+            kvm: Box::new(kvm),
             abort,
             error_on_multi_step,
+            tracked_pages: TrackedPages::default(),
+            paused_tracking: None,
+            strict_stepping_checks: false,
+            stepping_session: None,
+            ack_count: 0,
+            capture_raw_events: false,
+            last_raw_event: None,
+            spin_policy: SpinPolicy::default(),
+            last_ack: None,
         })
     }
 
+    ///Create a `shared_mem_region_t` "view" into `raw_shared_mem` and initialize it
+    fn init_shared_mem_region(raw_shared_mem: &AlignedSevStepBuf) -> &'a mut shared_mem_region_t {
+        assert!(SEV_STEP_SHARED_MEM_BYTES as usize >= mem::size_of::<shared_mem_region_t>());
+        let shared_mem_region;
+        unsafe {
+            shared_mem_region = (raw_shared_mem.as_mut_ptr() as *mut shared_mem_region_t)
+                .as_mut()
+                .unwrap();
+        }
+
+        raw_spinlock::init(&mut shared_mem_region.spinlock);
+        shared_mem_region.event_acked = 1;
+        shared_mem_region.have_event = 0;
+
+        shared_mem_region
+    }
+
+    /// If `strict` is true, [`Self::start_stepping`] fails if it is called without target GPAs while
+    /// no pages are tracked, instead of only logging a warning
+    pub fn set_strict_stepping_checks(&mut self, strict: bool) {
+        self.strict_stepping_checks = strict;
+    }
+
+    /// Configure how to wait while the kernel holds the spinlock of the shared memory region,
+    /// e.g. to yield the core instead of busy waiting on an oversubscribed host
+    pub fn set_spin_policy(&mut self, policy: SpinPolicy) {
+        self.spin_policy = policy;
+    }
+
+    /// Take the spinlock protecting the shared memory region, waiting according to the spin policy
+    fn lock_shared_mem(&mut self) {
+        unsafe { SpinLock::new(&mut self.shared_mem_region.spinlock, self.spin_policy).lock() }
+    }
+
+    /// If `capture` is true, the raw bytes of the shared memory event buffer are copied for each received event.
+    /// Use [`Self::last_raw_event_bytes`] to compare them against the kernel's struct layout, e.g. to debug
+    /// decoding errors caused by outdated bindings
+    pub fn set_capture_raw_events(&mut self, capture: bool) {
+        self.capture_raw_events = capture;
+        if !capture {
+            self.last_raw_event = None;
+        }
+    }
+
+    /// Raw content of the event buffer for the most recently received event. None, unless
+    /// capturing has been enabled with [`Self::set_capture_raw_events`]
+    pub fn last_raw_event_bytes(&self) -> Option<Vec<u8>> {
+        self.last_raw_event.clone()
+    }
+
     /// Track a single page of the VM with the given mode
     /// # Arguments
     /// * `gpa` - Guest Physical address of the page to track. Must be page aligned
     /// * `track_mode` - Tracking mode
     pub fn track_page(
-        &self,
+        &mut self,
         gpa: u64,
         track_mode: kvm_page_track_mode,
     ) -> Result<(), SevStepError> {
@@ -151,8 +369,15 @@ impl<'a> SevStep<'a> {
             gpa,
             track_mode: track_mode as i32,
         };
-        match unsafe { ioctls::track_page(self.kvm.as_raw_fd(), &mut p) } {
-            Ok(_) => Ok(()),
+        match self.kvm.track_page(&mut p) {
+            Ok(_) => {
+                self.tracked_pages
+                    .pages
+                    .entry(track_mode)
+                    .or_default()
+                    .insert(gpa);
+                Ok(())
+            }
             Err(e) => Err(SevStepError::PageTracking {
                 source: e.into(),
                 gpa,
@@ -166,7 +391,7 @@ impl<'a> SevStep<'a> {
     /// If you already got a page fault event for a page, it is automatically untracked
     /// See [`track_page`](Self::track_page) for parameter description
     pub fn untrack_page(
-        &self,
+        &mut self,
         gpa: u64,
         track_mode: kvm_page_track_mode,
     ) -> Result<(), SevStepError> {
@@ -174,49 +399,244 @@ impl<'a> SevStep<'a> {
             gpa,
             track_mode: track_mode as i32,
         };
-        unsafe {
-            ioctls::untrack_page(self.kvm.as_raw_fd(), &mut p)
-                .context("untrack page ioctl failed")?;
+        self.kvm
+            .untrack_page(&mut p)
+            .context("untrack page ioctl failed")?;
+        if let Some(gpas) = self.tracked_pages.pages.get_mut(&track_mode) {
+            gpas.remove(&gpa);
         }
 
         Ok(())
     }
 
+    /// Track a single page of the VM, identified by its guest frame number (GFN), with the given mode.
+    /// The GFN is the GPA of the page shifted right by the page size of 4096 bytes, i.e. the GPA is `gfn << 12`
+    /// # Arguments
+    /// * `gfn` - Guest frame number of the page to track
+    /// * `track_mode` - Tracking mode
+    pub fn track_gfn(
+        &mut self,
+        gfn: u64,
+        track_mode: kvm_page_track_mode,
+    ) -> Result<(), SevStepError> {
+        self.track_page(gfn << PAGE_SHIFT, track_mode)
+    }
+
+    /// Untrack a single page of the VM, identified by its guest frame number (GFN)
+    /// See [`track_gfn`](Self::track_gfn) for parameter description
+    pub fn untrack_gfn(
+        &mut self,
+        gfn: u64,
+        track_mode: kvm_page_track_mode,
+    ) -> Result<(), SevStepError> {
+        self.untrack_page(gfn << PAGE_SHIFT, track_mode)
+    }
+
     /// Tracks all of the VM's memory pages with the given mode
-    pub fn track_all_pages(&self, track_mode: kvm_page_track_mode) -> Result<(), SevStepError> {
+    pub fn track_all_pages(&mut self, track_mode: kvm_page_track_mode) -> Result<(), SevStepError> {
         let mut p = track_all_pages_t {
             track_mode: track_mode as i32,
         };
 
-        unsafe {
-            ioctls::track_all_pages(self.kvm.as_raw_fd(), &mut p)
-                .context("track all pages ioctl failed")?;
-        }
+        self.kvm
+            .track_all_pages(&mut p)
+            .context("track all pages ioctl failed")?;
+        self.tracked_pages.all_pages.insert(track_mode);
 
         Ok(())
     }
 
     /// Untrack all of the VM's memory pages if they where previously tracked with the given
     /// mode
-    pub fn untrack_all_pages(&self, track_mode: kvm_page_track_mode) -> Result<(), SevStepError> {
+    pub fn untrack_all_pages(
+        &mut self,
+        track_mode: kvm_page_track_mode,
+    ) -> Result<(), SevStepError> {
         let mut p = track_all_pages_t {
             track_mode: track_mode as i32,
         };
 
-        unsafe {
-            ioctls::untrack_all_pages(self.kvm.as_raw_fd(), &mut p)
-                .context("untrack all pages ioctl failed")?;
+        self.kvm
+            .untrack_all_pages(&mut p)
+            .context("untrack all pages ioctl failed")?;
+        self.tracked_pages.all_pages.remove(&track_mode);
+        self.tracked_pages.pages.remove(&track_mode);
+
+        Ok(())
+    }
+
+    /// Untrack all pages that are still tracked according to our bookkeeping, i.e. that were tracked
+    /// but never reported a page fault. Leaves a clean kernel state for the next API connection
+    pub fn untrack_remaining_pages(&mut self) -> Result<(), SevStepError> {
+        for mode in self.tracked_pages.modes() {
+            if self.tracked_pages.all_pages.contains(&mode) {
+                debug!("untracking all pages with {:?}", mode);
+                self.untrack_all_pages(mode)?;
+                continue;
+            }
+            let mut gpas: Vec<_> = self.tracked_pages.pages[&mode].iter().copied().collect();
+            gpas.sort();
+            for gpa in gpas {
+                debug!("untracking remaining page 0x{:x} with {:?}", gpa, mode);
+                self.untrack_page(gpa, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Issue the minimal set of track/untrack calls to go from the currently tracked pages to `desired`.
+    /// Pages that are tracked but not in `desired` are untracked, pages from `desired` that are not tracked yet
+    /// are tracked and pages that are tracked with a different mode are untracked and re-tracked with the desired mode.
+    /// All untrack calls are issued before the track calls, each in the order of ascending GPAs.
+    /// Fails if [`Self::track_all_pages`] is active, as this cannot be reconciled on a per page level
+    /// # Arguments
+    /// * `desired` : maps the GPAs that should be tracked to their tracking mode
+    pub fn reconcile_tracking(
+        &mut self,
+        desired: &HashMap<u64, kvm_page_track_mode>,
+    ) -> Result<(), SevStepError> {
+        if !self.tracked_pages.all_pages.is_empty() {
+            return Err(anyhow!(
+                "cannot reconcile tracking while all pages are tracked with {:?}",
+                self.tracked_pages.all_pages
+            )
+            .into());
+        }
+
+        let mut to_untrack = Vec::new();
+        for mode in self.tracked_pages.modes() {
+            for gpa in &self.tracked_pages.pages[&mode] {
+                if desired.get(gpa) != Some(&mode) {
+                    to_untrack.push((*gpa, mode));
+                }
+            }
         }
+        let mut to_track: Vec<_> = desired
+            .iter()
+            .filter(|(gpa, mode)| {
+                !self
+                    .tracked_pages
+                    .pages
+                    .get(mode)
+                    .is_some_and(|v| v.contains(gpa))
+            })
+            .map(|(gpa, mode)| (*gpa, *mode))
+            .collect();
+        to_untrack.sort_by_key(|(gpa, mode)| (*gpa, *mode as i32));
+        to_track.sort_by_key(|(gpa, _)| *gpa);
 
+        debug!(
+            "reconcile_tracking: untracking {:x?}, tracking {:x?}",
+            to_untrack, to_track
+        );
+        for (gpa, mode) in to_untrack {
+            self.untrack_page(gpa, mode)?;
+        }
+        for (gpa, mode) in to_track {
+            self.track_page(gpa, mode)?;
+        }
         Ok(())
     }
 
+    /// Check which tracking modes are accepted by the kernel, by tracking and immediately untracking `test_gpa`
+    /// with each mode that can be used for tracking pages (see [`TrackMode`]). A mode is reported as supported
+    /// if both calls succeed. `test_gpa` must not be tracked by the caller, as it is untracked afterwards
+    /// # Arguments
+    /// * `test_gpa` : GPA of a page that is used for the probe
+    pub fn probe_track_modes(&mut self, test_gpa: u64) -> Vec<(kvm_page_track_mode, bool)> {
+        [TrackMode::Write, TrackMode::Access, TrackMode::Exec]
+            .into_iter()
+            .map(kvm_page_track_mode::from)
+            .map(|mode| {
+                let supported = match self
+                    .track_page(test_gpa, mode)
+                    .and_then(|_| self.untrack_page(test_gpa, mode))
+                {
+                    Ok(()) => true,
+                    Err(e) => {
+                        debug!("probing {:?} on 0x{:x} failed: {:?}", mode, test_gpa, e);
+                        false
+                    }
+                };
+                (mode, supported)
+            })
+            .collect()
+    }
+
+    /// Untrack all pages but remember the tracking configuration, so that it can be restored with
+    /// [`Self::resume_tracking`]. In between, the VM runs without page fault events. Single stepping is not affected.
+    /// If an untrack ioctl fails, the modes that were already untracked count as paused and can still be
+    /// restored with [`Self::resume_tracking`], while the remaining modes stay tracked
+    pub fn pause_tracking(&mut self) -> Result<(), SevStepError> {
+        if self.paused_tracking.is_some() {
+            return Err(anyhow!("tracking is already paused").into());
+        }
+        let mut snapshot = TrackedPages::default();
+        for mode in self.tracked_pages.modes() {
+            debug!("pause_tracking: untracking all pages with {:?}", mode);
+            let mut p = track_all_pages_t {
+                track_mode: mode as i32,
+            };
+            if let Err(e) = self.kvm.untrack_all_pages(&mut p) {
+                if !snapshot.is_empty() {
+                    self.paused_tracking = Some(snapshot);
+                }
+                return Err(anyhow!(e)
+                    .context(format!("untrack all pages ioctl failed for {:?}", mode))
+                    .into());
+            }
+            if let Some(gpas) = self.tracked_pages.pages.remove(&mode) {
+                snapshot.pages.insert(mode, gpas);
+            }
+            if self.tracked_pages.all_pages.remove(&mode) {
+                snapshot.all_pages.insert(mode);
+            }
+        }
+        self.paused_tracking = Some(snapshot);
+        Ok(())
+    }
+
+    /// Re-track the pages that were tracked when [`Self::pause_tracking`] was called, with the same modes.
+    /// Pages that were tracked in the meantime remain tracked
+    pub fn resume_tracking(&mut self) -> Result<(), SevStepError> {
+        let snapshot = self.paused_tracking.take().ok_or(anyhow!(
+            "resume_tracking requires a prior call to pause_tracking"
+        ))?;
+        for mode in snapshot.modes() {
+            if snapshot.all_pages.contains(&mode) {
+                self.track_all_pages(mode)?;
+                continue;
+            }
+            let mut gpas: Vec<_> = snapshot.pages[&mode].iter().copied().collect();
+            gpas.sort();
+            for gpa in gpas {
+                self.track_page(gpa, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Enable single stepping
+    /// # Arguments
+    /// * `timer_value` - APIC timer value used for single stepping
+    /// * `target_gpa` - if not empty, only single step while executing these pages
+    /// * `flush_tlb` - flush the TLB before each step
+    ///
+    /// If `target_gpa` is empty and no pages are tracked, this is most likely a setup mistake.
+    /// This is logged as a warning or rejected with [`SevStepError::NoSteppingTargets`],
+    /// see [`Self::set_strict_stepping_checks`]
     pub fn start_stepping(
-        &self,
+        &mut self,
         timer_value: u32,
         target_gpa: &mut [u64],
         flush_tlb: bool,
     ) -> Result<(), SevStepError> {
+        if target_gpa.is_empty() && self.tracked_pages.is_empty() {
+            if self.strict_stepping_checks {
+                return Err(SevStepError::NoSteppingTargets);
+            }
+            warn!("start_stepping called without target GPAs while no pages are tracked");
+        }
         let mut p = sev_step_param_t {
             tmict_value: timer_value,
             gpas_target_pages: target_gpa.as_mut_ptr(),
@@ -224,28 +644,80 @@ impl<'a> SevStep<'a> {
             do_tlb_flush_before_each_step: flush_tlb,
         };
 
-        unsafe {
-            ioctls::start_stepping(self.kvm.as_raw_fd(), &mut p)
-                .context("start stepping ioctl failed")?;
-        }
+        self.kvm
+            .start_stepping(&mut p)
+            .context("start stepping ioctl failed")?;
+        self.stepping_session = Some(SteppingSession {
+            target_gpas: target_gpa.to_vec(),
+            flush_tlb,
+        });
 
         Ok(())
     }
 
-    pub fn stop_stepping(&self) -> Result<(), SevStepError> {
-        unsafe {
-            ioctls::stop_stepping(self.kvm.as_raw_fd()).context("stop stepping ioctls failed")?;
-        }
+    pub fn stop_stepping(&mut self) -> Result<(), SevStepError> {
+        self.kvm
+            .stop_stepping()
+            .context("stop stepping ioctls failed")?;
+        self.stepping_session = None;
         Ok(())
     }
 
+    /// Change the APIC timer value of the active single stepping session, keeping its target GPAs and
+    /// TLB flush setting. The kernel has no ioctl to update the timer of a running session. Thus, this stops
+    /// and restarts single stepping. Call this while an event is pending, i.e. before [`Self::ack_event`].
+    /// The VM waits for the ack and cannot run unstepped between the two ioctls.
+    /// If the restart fails, single stepping remains stopped and the error says so
+    /// # Arguments
+    /// * `new_timer` - APIC timer value used for the remaining single steps
+    pub fn retune_timer(&mut self, new_timer: u32) -> Result<(), SevStepError> {
+        let session = self.stepping_session.clone().ok_or(anyhow!(
+            "retune_timer requires an active single stepping session"
+        ))?;
+        self.stop_stepping()?;
+        let mut target_gpas = session.target_gpas;
+        self.start_stepping(new_timer, &mut target_gpas, session.flush_tlb)
+            .map_err(|e| {
+                anyhow::Error::new(e)
+                    .context(format!(
+                        "failed to restart single stepping with timer value 0x{:x}, single stepping is stopped",
+                        new_timer
+                    ))
+                    .into()
+            })
+    }
+
+    /// Read `len` bytes of guest memory, starting at `gpa`, e.g. to confirm the value of an observed write.
+    /// The SEV-Step kernel does not export an ioctl for this yet, thus this always returns
+    /// [`SevStepError::Unsupported`]. Once it does, the memory of SEV VMs is decrypted, which requires the VM to run
+    /// in debug mode
+    pub fn read_guest_mem(&self, gpa: u64, len: usize) -> Result<Vec<u8>, SevStepError> {
+        Err(SevStepError::Unsupported(format!(
+            "kernel lacks an ioctl to read 0x{:x} bytes of guest memory at 0x{:x}",
+            len, gpa
+        )))
+    }
+
+    /// Read the model specific register `msr` of the host CPU core `cpu`, e.g. APERF/MPERF to observe the
+    /// frequency of the core that runs the VM. Uses `/dev/cpu/<cpu>/msr`, which requires the `msr` kernel
+    /// module and root privileges
+    pub fn read_host_msr(&self, cpu: usize, msr: u32) -> Result<u64, SevStepError> {
+        let path = format!("/dev/cpu/{}/msr", cpu);
+        let f = File::open(&path).context(format!(
+            "failed to open {}. Is the msr kernel module loaded?",
+            path
+        ))?;
+        let mut value = [0u8; 8];
+        f.read_exact_at(&mut value, msr as u64)
+            .context(format!("failed to read msr 0x{:x} from {}", msr, path))?;
+        Ok(u64::from_ne_bytes(value))
+    }
+
     /// Check if there is a new event. The Result only indicates whether we were
     /// able to check for an event. The option inside the result indicates if there was an
     /// event
     pub fn poll_event(&mut self) -> Result<Option<Event>, SevStepError> {
-        unsafe {
-            raw_spinlock::lock(&mut self.shared_mem_region.spinlock);
-        }
+        self.lock_shared_mem();
         if 0 == self.shared_mem_region.have_event {
             unsafe {
                 raw_spinlock::unlock(&mut self.shared_mem_region.spinlock);
@@ -254,26 +726,42 @@ impl<'a> SevStep<'a> {
         }
 
         //if we are here, we hold the lock and there was and event
-        let result;
-        match self.shared_mem_region.event_type {
-            usp_event_type_t::PAGE_FAULT_EVENT => {
+        let result = self.decode_event();
+
+        unsafe { raw_spinlock::unlock(&mut self.shared_mem_region.spinlock) }
+        result.map(Some)
+    }
+
+    /// Parse the event from the shared memory region. The caller must hold the lock
+    fn decode_event(&mut self) -> Result<Event, SevStepError> {
+        const PAGE_FAULT_EVENT: u32 = usp_event_type_t::PAGE_FAULT_EVENT as u32;
+        const SEV_STEP_EVENT: u32 = usp_event_type_t::SEV_STEP_EVENT as u32;
+        //the kernel might use values that are not (yet) covered by the bindgen enum. Thus, we read
+        //the raw value instead of matching on the enum
+        let event_type = unsafe {
+            ptr::read_volatile(ptr::addr_of!(self.shared_mem_region.event_type) as *const u32)
+        };
+        if self.capture_raw_events {
+            self.last_raw_event = Some(self.shared_mem_region.event_buffer.to_vec());
+        }
+        match event_type {
+            PAGE_FAULT_EVENT => {
                 let e: *const usp_page_fault_event_t =
                     self.shared_mem_region.event_buffer.as_ptr() as *const usp_page_fault_event_t;
-                result = Event::PageFaultEvent(PageFaultEvent::from_c_struct(e));
-            }
-            usp_event_type_t::SEV_STEP_EVENT => {
-                result = Event::StepEvent(SevStepEvent::from_raw_event_buffer(
-                    &self.shared_mem_region.event_buffer,
-                ));
+                let pf_event = PageFaultEvent::from_c_struct(e);
+                self.tracked_pages.on_page_fault(pf_event.faulted_gpa);
+                Ok(Event::PageFaultEvent(pf_event))
             }
+            SEV_STEP_EVENT => Ok(Event::StepEvent(SevStepEvent::from_raw_event_buffer(
+                &self.shared_mem_region.event_buffer,
+            ))),
+            v => Err(SevStepError::UnknownEventType(v)),
         }
-
-        unsafe { raw_spinlock::unlock(&mut self.shared_mem_region.spinlock) }
-        Ok(Some(result))
     }
 
     ///Execute `target_trigger` (in background) and block until we receive an event
-    /// or the optional `timeout` expires.
+    /// or the optional `timeout` expires. If `target_trigger` fails, [`SevStepError::TriggerFailed`]
+    /// is returned
     pub fn block_untill_event<F>(
         &mut self,
         target_trigger: F,
@@ -284,14 +772,14 @@ impl<'a> SevStep<'a> {
         F: Send + 'static,
     {
         let (s, trigger_result) = bounded(1);
-        thread::spawn(move || s.send(target_trigger()));
+        thread::spawn(move || s.send(panic::catch_unwind(AssertUnwindSafe(target_trigger))));
 
         let start_timestamp = Instant::now();
         let mut trigger_finished = false;
         loop {
             //check if caller requested abort
             match self.abort.try_recv() {
-                Ok(()) => return Err(SevStepError::Other(anyhow!("received abort signal"))),
+                Ok(()) => return Err(SevStepError::Aborted),
                 Err(TryRecvError::Empty) => (),
                 Err(e) => {
                     return Err(SevStepError::Other(anyhow!(
@@ -304,19 +792,23 @@ impl<'a> SevStep<'a> {
             //abort if trigger function failed
             if !trigger_finished {
                 match trigger_result.try_recv() {
-                    Ok(_) => {
+                    Ok(Ok(Ok(()))) => {
                         debug!("trigger finished successfully");
                         trigger_finished = true
                     }
+                    Ok(Ok(Err(e))) => return Err(SevStepError::TriggerFailed { source: e }),
+                    Ok(Err(payload)) => {
+                        return Err(SevStepError::TriggerPanicked {
+                            message: panic_message(payload.as_ref()),
+                        })
+                    }
                     Err(TryRecvError::Empty) => (),
                     Err(e) => return Err(SevStepError::TriggerFailed { source: e.into() }),
                 }
             }
 
             //check for event
-            unsafe {
-                raw_spinlock::lock(&mut self.shared_mem_region.spinlock);
-            }
+            self.lock_shared_mem();
             if 1 == self.shared_mem_region.have_event {
                 break;
             }
@@ -332,42 +824,131 @@ impl<'a> SevStep<'a> {
         }
 
         //if we are here, we hold the lock and there was and event
-        let result;
-        match self.shared_mem_region.event_type {
-            usp_event_type_t::PAGE_FAULT_EVENT => {
-                let e: *const usp_page_fault_event_t =
-                    self.shared_mem_region.event_buffer.as_ptr() as *const usp_page_fault_event_t;
-                result = Event::PageFaultEvent(PageFaultEvent::from_c_struct(e));
+        let result = match self.decode_event() {
+            Ok(Event::StepEvent(step_event))
+                if self.error_on_multi_step && step_event.retired_instructions > 1 =>
+            {
+                Err(MultiStep { event: step_event })
             }
-            usp_event_type_t::SEV_STEP_EVENT => {
-                let step_event =
-                    SevStepEvent::from_raw_event_buffer(&self.shared_mem_region.event_buffer);
+            v => v,
+        };
 
-                if self.error_on_multi_step && step_event.retired_instructions > 1 {
-                    unsafe { raw_spinlock::unlock(&mut self.shared_mem_region.spinlock) }
-                    return Err(MultiStep { event: step_event });
-                }
-                result = Event::StepEvent(step_event);
+        unsafe { raw_spinlock::unlock(&mut self.shared_mem_region.spinlock) }
+        result
+    }
+
+    ///Block until we receive an event for which `predicate` returns true or the optional
+    /// `timeout` expires. Events for which `predicate` returns false are acked and discarded.
+    /// The matching event is not acked
+    /// # Arguments
+    /// * `predicate` : selects the event that we want to wait for
+    /// * `timeout` : timeout for the whole operation, not for the individual events
+    pub fn block_until_event_of<P>(
+        &mut self,
+        predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<Event, SevStepError>
+    where
+        P: Fn(&Event) -> bool,
+    {
+        let start_timestamp = Instant::now();
+        loop {
+            let remaining_timeout = match timeout {
+                Some(v) => match v.checked_sub(start_timestamp.elapsed()) {
+                    Some(v) => Some(v),
+                    None => return Err(SevStepError::Timeout),
+                },
+                None => None,
+            };
+            let event = self.block_untill_event(|| Ok(()), remaining_timeout)?;
+            if predicate(&event) {
+                return Ok(event);
+            }
+            debug!("discarding non matching event {:X?}", event);
+            self.ack_event();
+        }
+    }
+
+    ///Block until the next page fault event, discarding all step events in between.
+    /// See [`Self::block_until_event_of`]
+    pub fn next_fault(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<PageFaultEvent, SevStepError> {
+        match self.block_until_event_of(|e| matches!(e, Event::PageFaultEvent(_)), timeout)? {
+            Event::PageFaultEvent(v) => Ok(v),
+            Event::StepEvent(_) => unreachable!("predicate only matches page fault events"),
+        }
+    }
+
+    ///Block until the next step event, discarding all page fault events in between.
+    /// See [`Self::block_until_event_of`]
+    pub fn next_step(&mut self, timeout: Option<Duration>) -> Result<SevStepEvent, SevStepError> {
+        match self.block_until_event_of(|e| matches!(e, Event::StepEvent(_)), timeout)? {
+            Event::StepEvent(v) => Ok(v),
+            Event::PageFaultEvent(_) => unreachable!("predicate only matches step events"),
+        }
+    }
+
+    /// Minimal event loop for quick experiments that do not warrant a handler chain. Executes `target_trigger`
+    /// and passes each event to `f` until it returns [`LoopControl::Stop`]. Events are acked after `f` returns.
+    /// If `f` fails, the loop is left with the error, without acking the event
+    /// # Arguments
+    /// * `target_trigger` : function that starts the victim, see [`Self::block_untill_event`]
+    /// * `timeout` : timeout while waiting for each event
+    /// * `f` : called once for each event
+    pub fn run_event_loop<T, F>(
+        &mut self,
+        target_trigger: T,
+        timeout: Option<Duration>,
+        mut f: F,
+    ) -> Result<(), SevStepError>
+    where
+        T: FnOnce() -> AhwResult<()>,
+        T: Send + 'static,
+        F: FnMut(&Event, &mut SevStep) -> AhwResult<LoopControl>,
+    {
+        let mut event = self.block_untill_event(target_trigger, timeout)?;
+        loop {
+            let control = f(&event, self)?;
+            self.ack_event();
+            if control == LoopControl::Stop {
+                return Ok(());
             }
+            event = self.block_untill_event(|| Ok(()), timeout)?;
         }
+    }
 
-        unsafe { raw_spinlock::unlock(&mut self.shared_mem_region.spinlock) }
-        Ok(result)
+    /// Number of times [`Self::ack_event`] has been called on this instance. Useful to
+    /// diagnose missing or duplicate acks, e.g. across the handlers of a [`crate::event_handlers::ComposableHandlerChain`]
+    pub fn ack_count(&self) -> u64 {
+        self.ack_count
+    }
+
+    /// Time of the most recent call to [`Self::ack_event`], i.e. when the VM was last allowed to resume.
+    /// None if no event has been acked yet
+    pub fn last_ack_time(&self) -> Option<Instant> {
+        self.last_ack
     }
 
     /// Signal to the kernel space, that we are done with the latest event and that
     /// the VM can resume its execution
     pub fn ack_event(&mut self) {
-        unsafe {
-            raw_spinlock::lock(&mut self.shared_mem_region.spinlock);
-        }
+        self.lock_shared_mem();
 
+        self.ack_count += 1;
+        debug!(
+            "ack #{}, event pending: {}",
+            self.ack_count,
+            self.shared_mem_region.have_event == 1
+        );
         self.shared_mem_region.event_acked = 1;
         self.shared_mem_region.have_event = 0;
 
         unsafe {
             raw_spinlock::unlock(&mut self.shared_mem_region.spinlock);
         }
+        self.last_ack = Some(Instant::now());
     }
 }
 
@@ -385,10 +966,80 @@ pub struct CacheTrace {
     pub timing_probes: Vec<u64>,
     pub perf_counter_probes: Vec<u64>,
 }
+
+impl CacheTrace {
+    /// Classify each entry of `timing_probes` as cache hit (true) or miss (false)
+    /// # Arguments
+    /// * `hit_threshold` : probes with an access time of at most this value are considered hits
+    pub fn classify(&self, hit_threshold: u64) -> Vec<bool> {
+        self.timing_probes
+            .iter()
+            .map(|v| *v <= hit_threshold)
+            .collect()
+    }
+
+    /// Returns the indices of the cache sets for which at least one probe was a hit.
+    /// See [`CacheTrace::classify`]
+    /// # Arguments
+    /// * `ways` : number of consecutive probes that make up one cache set
+    /// * `hit_threshold` : probes with an access time of at most this value are considered hits
+    pub fn hit_set_indices(&self, ways: usize, hit_threshold: u64) -> Vec<usize> {
+        assert!(ways > 0, "ways must be at least 1");
+        self.classify(hit_threshold)
+            .chunks(ways)
+            .enumerate()
+            .filter(|(_, probes)| probes.iter().any(|hit| *hit))
+            .map(|(set_idx, _)| set_idx)
+            .collect()
+    }
+}
+/// Copy of the register file of the VM, taken at the time of an event. Only available if the VM runs in debug mode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    ///indexed by [`vmsa_register_name_t`]
+    register_values: Vec<u64>,
+}
+
+impl RegisterSnapshot {
+    fn from_vmsa_data(data: &sev_step_partial_vmcb_save_area_t) -> RegisterSnapshot {
+        RegisterSnapshot {
+            register_values: data.register_values[..vmsa_register_name_t::VRN_MAX as usize]
+                .to_vec(),
+        }
+    }
+
+    pub fn get(&self, name: vmsa_register_name_t) -> u64 {
+        self.register_values[name as usize]
+    }
+
+    /// All register values, indexed by [`vmsa_register_name_t`]
+    pub fn values(&self) -> &[u64] {
+        &self.register_values
+    }
+
+    /// Returns `(register, value in self, value in other)` for all registers whose value differs
+    pub fn diff(&self, other: &RegisterSnapshot) -> Vec<(vmsa_register_name_t, u64, u64)> {
+        self.register_values
+            .iter()
+            .zip(other.register_values.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(idx, (old, new))| {
+                //the bindgen enum is repr(u32) and its variants are numbered consecutively
+                //from zero up to `VRN_MAX`
+                let name: vmsa_register_name_t = unsafe { mem::transmute(idx as u32) };
+                (name, *old, *new)
+            })
+            .collect()
+    }
+}
+
 /// Events generated by activating single stepping.
 #[derive(Clone, Debug)]
 pub struct SevStepEvent {
     /// Amount of instructions executed by the VM in this step event.
+    /// There is no cumulative counterpart, as the decrypted VMSA data sent by the kernel does not
+    /// contain the guest's retired instruction counter
     pub retired_instructions: u32,
     register_values: Option<sev_step_partial_vmcb_save_area_t>,
     /// If a cache attack was requested prior to this step event, this will hold the resulting
@@ -397,11 +1048,21 @@ pub struct SevStepEvent {
 }
 
 impl SevStepEvent {
-    /// If the VM runs in debug mode, this allows read access to its register file
+    /// If the VM runs in debug mode, this allows read access to its register file.
+    /// Only RFLAGS, RIP and the general purpose registers are available. The kernel does not export
+    /// segment or control registers, e.g. CS or CR3
     pub fn get_register(&self, name: vmsa_register_name_t) -> Option<u64> {
         self.register_values
             .map(|v| v.register_values[name as usize])
     }
+
+    /// If the VM runs in debug mode, returns a copy of its whole register file
+    pub fn get_register_snapshot(&self) -> Option<RegisterSnapshot> {
+        self.register_values
+            .as_ref()
+            .map(RegisterSnapshot::from_vmsa_data)
+    }
+
     pub fn get_cache_trace(&self) -> Option<&CacheTrace> {
         return self.cache_trace.as_ref();
     }
@@ -464,7 +1125,9 @@ impl SevStepEvent {
 }
 
 #[derive(Clone, Debug)]
-/// Events generated by activating page tracking
+/// Events generated by activating page tracking.
+/// The kernel does not report which tracking mode caused the fault. Thus, for a page tracked with
+/// multiple modes, e.g. exec and write, code and data accesses cannot be told apart
 pub struct PageFaultEvent {
     /// GPA at which the page fault occurred
     pub faulted_gpa: u64,
@@ -472,12 +1135,21 @@ pub struct PageFaultEvent {
 }
 
 impl PageFaultEvent {
-    /// If the VM runs in debug mode, this allows read access to its register file
+    /// If the VM runs in debug mode, this allows read access to its register file.
+    /// Only RFLAGS, RIP and the general purpose registers are available. The kernel does not export
+    /// segment or control registers, e.g. CS or CR3
     pub fn get_register(&self, name: vmsa_register_name_t) -> Option<u64> {
         self.register_values
             .map(|v| v.register_values[name as usize])
     }
 
+    /// If the VM runs in debug mode, returns a copy of its whole register file
+    pub fn get_register_snapshot(&self) -> Option<RegisterSnapshot> {
+        self.register_values
+            .as_ref()
+            .map(RegisterSnapshot::from_vmsa_data)
+    }
+
     fn from_c_struct(ptr: *const usp_page_fault_event_t) -> PageFaultEvent {
         let event;
         unsafe {
@@ -497,8 +1169,517 @@ impl PageFaultEvent {
     }
 }
 
+/// Returned by the closure passed to [`SevStep::run_event_loop`] to decide whether the loop continues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopControl {
+    /// Ack the event and wait for the next one
+    Continue,
+    /// Ack the event and leave the loop
+    Stop,
+}
+
 #[derive(Clone, Debug)]
 pub enum Event {
     PageFaultEvent(PageFaultEvent),
     StepEvent(SevStepEvent),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_kernel::{spawn_event_feeder, IoctlCall, MockEvent};
+
+    #[test]
+    fn block_until_event_of_discards_non_matching_events() -> Result<(), SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let timeout = Some(Duration::from_secs(5));
+        let feeder = spawn_event_feeder(
+            &mut api,
+            vec![
+                MockEvent::step(1),
+                MockEvent::step(2),
+                MockEvent::page_fault(0x2000),
+                MockEvent::page_fault(0x3000),
+                MockEvent::step(3),
+            ],
+        );
+
+        assert_eq!(api.next_fault(timeout)?.faulted_gpa, 0x2000);
+        api.ack_event();
+        assert_eq!(api.next_step(timeout)?.retired_instructions, 3);
+        api.ack_event();
+
+        //the feeder only terminates once all events have been delivered
+        feeder.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn run_event_loop_passes_each_event_until_stop() -> Result<(), SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(
+            &mut api,
+            vec![
+                MockEvent::page_fault(0x2000),
+                MockEvent::step(1),
+                MockEvent::step(2),
+                MockEvent::step(3),
+            ],
+        );
+
+        let mut seen = Vec::new();
+        api.run_event_loop(
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+            |event, _api| {
+                seen.push(match event {
+                    Event::PageFaultEvent(v) => v.faulted_gpa,
+                    Event::StepEvent(v) => v.retired_instructions as u64,
+                });
+                match seen.len() {
+                    3 => Ok(LoopControl::Stop),
+                    _ => Ok(LoopControl::Continue),
+                }
+            },
+        )?;
+        assert_eq!(seen, vec![0x2000, 1, 2]);
+        assert_eq!(api.ack_count(), 3);
+
+        //the remaining event is still delivered after the loop was left
+        assert_eq!(
+            api.next_step(Some(Duration::from_secs(5)))?
+                .retired_instructions,
+            3
+        );
+        api.ack_event();
+        feeder.join().unwrap();
+        Ok(())
+    }
+
+    #[cfg(feature = "full")]
+    #[test]
+    fn serialize_error_reports() -> Result<(), SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(
+            &mut api,
+            vec![MockEvent::step(3).with_register(vmsa_register_name_t::VRN_RIP, 0x4005)],
+        );
+        let multi_step_event = api.next_step(Some(Duration::from_secs(5)))?;
+        api.ack_event();
+        feeder.join().unwrap();
+
+        let errors = vec![
+            SevStepError::TriggerFailed {
+                source: anyhow!("victim server unreachable"),
+            },
+            SevStepError::Timeout,
+            SevStepError::Aborted,
+            SevStepError::TriggerPanicked {
+                message: "boom".to_string(),
+            },
+            SevStepError::NoSteppingTargets,
+            SevStepError::PageTracking {
+                source: anyhow!("EINVAL"),
+                gpa: 0x1000,
+                tracking_mode: kvm_page_track_mode::KVM_PAGE_TRACK_EXEC,
+                message: "track_page failed".to_string(),
+            },
+            SevStepError::MultiStep {
+                event: multi_step_event,
+            },
+            SevStepError::UnknownEventType(7),
+            SevStepError::Unsupported("read guest memory".to_string()),
+            SevStepError::Other(anyhow!("inner").context("outer")),
+        ];
+        let reports = errors
+            .iter()
+            .map(|e| serde_json::to_value(e.to_report()).unwrap())
+            .collect::<Vec<_>>();
+
+        let kinds: Vec<_> = reports.iter().map(|v| v["kind"].clone()).collect();
+        assert_eq!(
+            kinds,
+            [
+                "TriggerFailed",
+                "Timeout",
+                "Aborted",
+                "TriggerPanicked",
+                "NoSteppingTargets",
+                "PageTracking",
+                "MultiStep",
+                "UnknownEventType",
+                "Unsupported",
+                "Other",
+            ]
+        );
+        assert_eq!(
+            reports[0]["message"],
+            "failed to execute trigger function : victim server unreachable"
+        );
+        assert_eq!(reports[6]["retired_instructions"], 3);
+        assert_eq!(reports[6]["rip"], 0x4005);
+        assert!(reports[1]["rip"].is_null());
+        assert_eq!(reports[9]["message"], "outer: inner");
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode_rejects_stepping_without_targets() -> Result<(), SevStepError> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let track_mode = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+
+        //only warns by default
+        api.start_stepping(42, &mut [], true)?;
+        api.set_strict_stepping_checks(true);
+        assert!(matches!(
+            api.start_stepping(42, &mut [], true),
+            Err(SevStepError::NoSteppingTargets)
+        ));
+        assert_eq!(
+            kernel
+                .calls()
+                .iter()
+                .filter(|v| matches!(v, IoctlCall::StartStepping { .. }))
+                .count(),
+            1
+        );
+
+        //target GPAs or tracked pages make the request valid
+        api.start_stepping(42, &mut [0x1000], true)?;
+        api.track_page(0x1000, track_mode)?;
+        api.start_stepping(42, &mut [], true)?;
+
+        //tracked pages are removed on untrack and on page faults
+        api.untrack_page(0x1000, track_mode)?;
+        assert!(api.start_stepping(42, &mut [], true).is_err());
+        api.track_page(0x2000, track_mode)?;
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::page_fault(0x2000)]);
+        api.next_fault(Some(Duration::from_secs(5)))?;
+        api.ack_event();
+        feeder.join().unwrap();
+        assert!(api.start_stepping(42, &mut [], true).is_err());
+
+        api.track_all_pages(track_mode)?;
+        api.start_stepping(42, &mut [], true)?;
+        api.untrack_all_pages(track_mode)?;
+        assert!(api.start_stepping(42, &mut [], true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn feeder_stops_when_api_is_dropped() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        //the second event is never acked, thus the feeder keeps waiting
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1), MockEvent::step(1)]);
+        drop(api);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !feeder.is_finished() {
+            assert!(Instant::now() < deadline, "feeder did not stop");
+            thread::sleep(Duration::from_millis(10));
+        }
+        feeder.join().unwrap();
+    }
+
+    #[test]
+    fn unknown_event_type_is_reported() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        //craft an event with a type that is not covered by `usp_event_type_t`
+        unsafe {
+            raw_spinlock::lock(&mut api.shared_mem_region.spinlock);
+            ptr::write_volatile(
+                ptr::addr_of_mut!(api.shared_mem_region.event_type) as *mut u32,
+                42,
+            );
+            api.shared_mem_region.event_acked = 0;
+            api.shared_mem_region.have_event = 1;
+            raw_spinlock::unlock(&mut api.shared_mem_region.spinlock);
+        }
+
+        assert!(matches!(
+            api.poll_event(),
+            Err(SevStepError::UnknownEventType(42))
+        ));
+        //the lock must have been released despite the error
+        assert!(matches!(
+            api.block_untill_event(|| Ok(()), Some(Duration::from_secs(5))),
+            Err(SevStepError::UnknownEventType(42))
+        ));
+    }
+
+    #[test]
+    fn track_gfn_tracks_page_at_gpa() -> Result<(), SevStepError> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let mode = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+        api.track_gfn(0x42, mode)?;
+        api.untrack_gfn(0x42, mode)?;
+
+        assert_eq!(
+            kernel.calls(),
+            vec![
+                IoctlCall::TrackPage {
+                    gpa: 0x42000,
+                    track_mode: mode as i32,
+                },
+                IoctlCall::UntrackPage {
+                    gpa: 0x42000,
+                    track_mode: mode as i32,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retune_timer_restarts_active_session() -> Result<(), SevStepError> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        assert!(api.retune_timer(0x30).is_err());
+        assert!(kernel.calls().is_empty());
+
+        api.start_stepping(0x20, &mut [0x1000, 0x2000], true)?;
+        api.retune_timer(0x30)?;
+
+        assert_eq!(
+            kernel.calls(),
+            vec![
+                IoctlCall::StartStepping {
+                    timer_value: 0x20,
+                    target_gpas: vec![0x1000, 0x2000],
+                    flush_tlb: true,
+                },
+                IoctlCall::StopStepping,
+                IoctlCall::StartStepping {
+                    timer_value: 0x30,
+                    target_gpas: vec![0x1000, 0x2000],
+                    flush_tlb: true,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retune_timer_reports_stopped_session() -> Result<(), SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let exec = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+        api.set_strict_stepping_checks(true);
+        api.track_page(0x1000, exec)?;
+        api.start_stepping(0x20, &mut [], false)?;
+        //without tracked pages, the strict checks reject the restart
+        api.untrack_page(0x1000, exec)?;
+
+        let err = api.retune_timer(0x30).unwrap_err();
+        assert!(format!("{:#}", err).contains("single stepping is stopped"));
+        assert!(api.retune_timer(0x30).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cache_trace_hit_classification() {
+        //4 sets with 2 ways each. Hits take around 40 cycles, misses around 200
+        let trace = CacheTrace {
+            timing_probes: vec![210, 190, 38, 205, 199, 220, 41, 45],
+            perf_counter_probes: vec![0; 8],
+        };
+        assert_eq!(
+            trace.classify(100),
+            vec![false, false, true, false, false, false, true, true]
+        );
+        assert_eq!(trace.hit_set_indices(2, 100), vec![1, 3]);
+        //threshold is inclusive
+        assert_eq!(trace.hit_set_indices(2, 190), vec![0, 1, 3]);
+        assert!(trace.hit_set_indices(4, 10).is_empty());
+    }
+
+    #[test]
+    fn pause_and_resume_tracking() -> Result<(), SevStepError> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let exec = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+        let write = kvm_page_track_mode::KVM_PAGE_TRACK_WRITE;
+        api.track_page(0x3000, exec)?;
+        api.track_page(0x1000, exec)?;
+        api.track_all_pages(write)?;
+        assert!(api.resume_tracking().is_err());
+
+        //modes are processed in the order of their numeric values, pages by ascending GPA
+        let calls_before_pause = kernel.calls().len();
+        api.pause_tracking()?;
+        assert!(api.pause_tracking().is_err());
+        api.resume_tracking()?;
+
+        assert_eq!(
+            kernel.calls()[calls_before_pause..],
+            [
+                IoctlCall::untrack_all_pages(write),
+                IoctlCall::untrack_all_pages(exec),
+                IoctlCall::TrackAllPages {
+                    track_mode: write as i32,
+                },
+                IoctlCall::TrackPage {
+                    gpa: 0x1000,
+                    track_mode: exec as i32,
+                },
+                IoctlCall::TrackPage {
+                    gpa: 0x3000,
+                    track_mode: exec as i32,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failed_pause_keeps_tracking_state() -> Result<(), SevStepError> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let exec = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+        let write = kvm_page_track_mode::KVM_PAGE_TRACK_WRITE;
+        api.track_page(0x1000, exec)?;
+        api.track_all_pages(write)?;
+        kernel.reject_untrack_all_mode(exec);
+
+        //write is untracked before exec fails
+        assert!(api.pause_tracking().is_err());
+        assert!(api.pause_tracking().is_err());
+        let calls_before_resume = kernel.calls().len();
+        api.resume_tracking()?;
+        assert_eq!(
+            kernel.calls()[calls_before_resume..],
+            [IoctlCall::TrackAllPages {
+                track_mode: write as i32,
+            }]
+        );
+        //the exec page is still tracked
+        assert_eq!(api.tracked_pages.modes(), vec![write, exec]);
+        Ok(())
+    }
+
+    #[test]
+    fn trigger_panic_message_surfaces() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let result = api.block_untill_event(
+            || panic!("victim server returned {}", 500),
+            Some(Duration::from_secs(5)),
+        );
+        match result {
+            Err(SevStepError::TriggerPanicked { message }) => {
+                assert_eq!(message, "victim server returned 500")
+            }
+            v => panic!("expected TriggerPanicked, got {:?}", v.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn read_guest_mem_is_unsupported() {
+        let (api, kernel, _abort) = SevStep::new_mock(false);
+        assert!(matches!(
+            api.read_guest_mem(0x1000, 4),
+            Err(SevStepError::Unsupported(_))
+        ));
+        assert!(kernel.calls().is_empty());
+    }
+
+    #[test]
+    fn read_host_msr_reads_tsc() -> Result<(), SevStepError> {
+        const IA32_TIME_STAMP_COUNTER: u32 = 0x10;
+        if !std::path::Path::new("/dev/cpu/0/msr").exists() {
+            eprintln!("skipping, /dev/cpu/0/msr is not available");
+            return Ok(());
+        }
+        let (api, _kernel, _abort) = SevStep::new_mock(false);
+        let first = api.read_host_msr(0, IA32_TIME_STAMP_COUNTER)?;
+        let second = api.read_host_msr(0, IA32_TIME_STAMP_COUNTER)?;
+        assert!(second > first);
+        Ok(())
+    }
+
+    #[test]
+    fn capture_raw_event_bytes() -> Result<(), SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let timeout = Some(Duration::from_secs(5));
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1), MockEvent::step(3)]);
+
+        api.block_untill_event(|| Ok(()), timeout)?;
+        assert_eq!(api.last_raw_event_bytes(), None);
+        api.ack_event();
+
+        api.set_capture_raw_events(true);
+        api.block_untill_event(|| Ok(()), timeout)?;
+        api.ack_event();
+        feeder.join().unwrap();
+
+        let mut injected: sev_step_event_t = unsafe { mem::zeroed() };
+        injected.counted_instructions = 3;
+        let injected = unsafe {
+            std::slice::from_raw_parts(
+                &injected as *const sev_step_event_t as *const u8,
+                mem::size_of::<sev_step_event_t>(),
+            )
+        };
+        let captured = api.last_raw_event_bytes().expect("capturing is enabled");
+        assert_eq!(captured.len(), api.shared_mem_region.event_buffer.len());
+        assert_eq!(&captured[..injected.len()], injected);
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_tracking_issues_minimal_diff() -> Result<(), SevStepError> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let exec = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+        let write = kvm_page_track_mode::KVM_PAGE_TRACK_WRITE;
+        api.track_page(0x1000, exec)?;
+        api.track_page(0x2000, exec)?;
+        api.track_page(0x3000, write)?;
+
+        //keep 0x1000, drop 0x3000, change the mode of 0x2000 and add 0x4000
+        let desired = HashMap::from([(0x1000, exec), (0x2000, write), (0x4000, exec)]);
+        let calls_before = kernel.calls().len();
+        api.reconcile_tracking(&desired)?;
+        assert_eq!(
+            kernel.calls()[calls_before..],
+            [
+                IoctlCall::UntrackPage {
+                    gpa: 0x2000,
+                    track_mode: exec as i32,
+                },
+                IoctlCall::UntrackPage {
+                    gpa: 0x3000,
+                    track_mode: write as i32,
+                },
+                IoctlCall::TrackPage {
+                    gpa: 0x2000,
+                    track_mode: write as i32,
+                },
+                IoctlCall::TrackPage {
+                    gpa: 0x4000,
+                    track_mode: exec as i32,
+                },
+            ]
+        );
+
+        //already reconciled, thus no further calls
+        let calls_before = kernel.calls().len();
+        api.reconcile_tracking(&desired)?;
+        assert_eq!(kernel.calls().len(), calls_before);
+
+        api.track_all_pages(write)?;
+        assert!(api.reconcile_tracking(&desired).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn probe_track_modes_reports_rejected_mode() {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        kernel.reject_track_mode(kvm_page_track_mode::KVM_PAGE_TRACK_EXEC);
+
+        assert_eq!(
+            api.probe_track_modes(0x1000),
+            vec![
+                (kvm_page_track_mode::KVM_PAGE_TRACK_WRITE, true),
+                (kvm_page_track_mode::KVM_PAGE_TRACK_ACCESS, true),
+                (kvm_page_track_mode::KVM_PAGE_TRACK_EXEC, false),
+            ]
+        );
+        //the probe does not leave pages tracked behind
+        assert!(api.tracked_pages.is_empty());
+    }
+}
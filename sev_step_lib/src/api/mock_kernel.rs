@@ -0,0 +1,334 @@
+//! Mock for the kernel side of the SEV STEP API, used to unit test code that uses the [`SevStep`] API
+//! without a SEV-Step kernel or a running VM.
+//!
+//! The ioctls are recorded by [`MockKernel`]. Events are delivered through the shared memory region,
+//! just like the kernel does it, by a feeder thread started with [`spawn_event_feeder`].
+use std::{
+    collections::HashSet,
+    mem, ptr,
+    sync::{Arc, Mutex, Weak},
+    thread::{self, JoinHandle},
+};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use nix::{errno::Errno, libc};
+
+use crate::{
+    ioctls::SevStepIoctls,
+    raw_spinlock,
+    types::{
+        kvm_page_track_mode, sev_step_event_t, sev_step_param_t, shared_mem_region_t,
+        track_all_pages_t, track_page_param_t, usp_event_type_t, usp_page_fault_event_t,
+        vmsa_register_name_t,
+    },
+};
+
+use super::{AlignedSevStepBuf, SevStep};
+
+/// Ioctl call as recorded by [`MockKernel`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum IoctlCall {
+    CloseApi,
+    TrackPage {
+        gpa: u64,
+        track_mode: i32,
+    },
+    UntrackPage {
+        gpa: u64,
+        track_mode: i32,
+    },
+    TrackAllPages {
+        track_mode: i32,
+    },
+    UntrackAllPages {
+        track_mode: i32,
+    },
+    StartStepping {
+        timer_value: u32,
+        target_gpas: Vec<u64>,
+        flush_tlb: bool,
+    },
+    StopStepping,
+}
+
+impl IoctlCall {
+    pub(crate) fn untrack_all_pages(track_mode: kvm_page_track_mode) -> Self {
+        IoctlCall::UntrackAllPages {
+            track_mode: track_mode as i32,
+        }
+    }
+}
+
+/// Records all ioctls issued through the [`SevStep`] instance it was created with. Clones share
+/// the same recording
+#[derive(Clone, Default)]
+pub(crate) struct MockKernel {
+    calls: Arc<Mutex<Vec<IoctlCall>>>,
+    ///Track modes for which the track page ioctl fails
+    rejected_track_modes: Arc<Mutex<HashSet<i32>>>,
+    ///GPAs for which the track page ioctl fails
+    rejected_track_gpas: Arc<Mutex<HashSet<u64>>>,
+    ///Track modes for which the untrack all pages ioctl fails
+    rejected_untrack_all_modes: Arc<Mutex<HashSet<i32>>>,
+}
+
+impl MockKernel {
+    /// All ioctls issued so far, in order
+    pub(crate) fn calls(&self) -> Vec<IoctlCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Fail the track page ioctl for `track_mode`, as if the kernel did not support it
+    pub(crate) fn reject_track_mode(&self, track_mode: kvm_page_track_mode) {
+        self.rejected_track_modes
+            .lock()
+            .unwrap()
+            .insert(track_mode as i32);
+    }
+
+    /// Fail the track page ioctl for `gpa`
+    pub(crate) fn reject_track_gpa(&self, gpa: u64) {
+        self.rejected_track_gpas.lock().unwrap().insert(gpa);
+    }
+
+    /// Fail the untrack all pages ioctl for `track_mode`
+    pub(crate) fn reject_untrack_all_mode(&self, track_mode: kvm_page_track_mode) {
+        self.rejected_untrack_all_modes
+            .lock()
+            .unwrap()
+            .insert(track_mode as i32);
+    }
+
+    fn record(&self, call: IoctlCall) -> nix::Result<libc::c_int> {
+        self.calls.lock().unwrap().push(call);
+        Ok(0)
+    }
+}
+
+impl SevStepIoctls for MockKernel {
+    fn close_api(&self) -> nix::Result<libc::c_int> {
+        self.record(IoctlCall::CloseApi)
+    }
+
+    fn track_page(&self, data: &mut track_page_param_t) -> nix::Result<libc::c_int> {
+        if self
+            .rejected_track_modes
+            .lock()
+            .unwrap()
+            .contains(&data.track_mode)
+            || self.rejected_track_gpas.lock().unwrap().contains(&data.gpa)
+        {
+            return Err(Errno::EINVAL);
+        }
+        self.record(IoctlCall::TrackPage {
+            gpa: data.gpa,
+            track_mode: data.track_mode,
+        })
+    }
+
+    fn track_all_pages(&self, data: &mut track_all_pages_t) -> nix::Result<libc::c_int> {
+        self.record(IoctlCall::TrackAllPages {
+            track_mode: data.track_mode,
+        })
+    }
+
+    fn untrack_all_pages(&self, data: &mut track_all_pages_t) -> nix::Result<libc::c_int> {
+        if self
+            .rejected_untrack_all_modes
+            .lock()
+            .unwrap()
+            .contains(&data.track_mode)
+        {
+            return Err(Errno::EINVAL);
+        }
+        self.record(IoctlCall::UntrackAllPages {
+            track_mode: data.track_mode,
+        })
+    }
+
+    fn untrack_page(&self, data: &mut track_page_param_t) -> nix::Result<libc::c_int> {
+        self.record(IoctlCall::UntrackPage {
+            gpa: data.gpa,
+            track_mode: data.track_mode,
+        })
+    }
+
+    fn start_stepping(&self, data: &mut sev_step_param_t) -> nix::Result<libc::c_int> {
+        let target_gpas = unsafe {
+            std::slice::from_raw_parts(data.gpas_target_pages, data.gpas_target_pages_len as usize)
+        };
+        self.record(IoctlCall::StartStepping {
+            timer_value: data.tmict_value,
+            target_gpas: target_gpas.to_vec(),
+            flush_tlb: data.do_tlb_flush_before_each_step,
+        })
+    }
+
+    fn stop_stepping(&self) -> nix::Result<libc::c_int> {
+        self.record(IoctlCall::StopStepping)
+    }
+}
+
+/// Description of an event that the mock kernel should send
+#[derive(Debug, Clone)]
+pub(crate) enum MockEvent {
+    PageFault {
+        gpa: u64,
+        registers: Vec<(vmsa_register_name_t, u64)>,
+    },
+    Step {
+        retired_instructions: u32,
+        registers: Vec<(vmsa_register_name_t, u64)>,
+    },
+}
+
+impl MockEvent {
+    pub(crate) fn page_fault(gpa: u64) -> Self {
+        MockEvent::PageFault {
+            gpa,
+            registers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn step(retired_instructions: u32) -> Self {
+        MockEvent::Step {
+            retired_instructions,
+            registers: Vec::new(),
+        }
+    }
+
+    /// Add a decrypted register value to the event, as if the VM was running in debug mode
+    pub(crate) fn with_register(mut self, name: vmsa_register_name_t, value: u64) -> Self {
+        match &mut self {
+            MockEvent::PageFault { registers, .. } | MockEvent::Step { registers, .. } => {
+                registers.push((name, value))
+            }
+        }
+        self
+    }
+
+    /// Serialize the event into the shared memory region, like the kernel would do
+    fn write_to(&self, region: &mut shared_mem_region_t) {
+        match self {
+            MockEvent::PageFault { gpa, registers } => {
+                let mut e: usp_page_fault_event_t = unsafe { mem::zeroed() };
+                e.faulted_gpa = *gpa;
+                e.is_decrypted_vmsa_data_valid = !registers.is_empty();
+                for (name, value) in registers {
+                    e.decrypted_vmsa_data.register_values[*name as usize] = *value;
+                }
+                region.event_type = usp_event_type_t::PAGE_FAULT_EVENT;
+                unsafe {
+                    ptr::write_unaligned(
+                        region.event_buffer.as_mut_ptr() as *mut usp_page_fault_event_t,
+                        e,
+                    )
+                };
+            }
+            MockEvent::Step {
+                retired_instructions,
+                registers,
+            } => {
+                let mut e: sev_step_event_t = unsafe { mem::zeroed() };
+                e.counted_instructions = *retired_instructions;
+                e.is_decrypted_vmsa_data_valid = !registers.is_empty();
+                for (name, value) in registers {
+                    e.decrypted_vmsa_data.register_values[*name as usize] = *value;
+                }
+                region.event_type = usp_event_type_t::SEV_STEP_EVENT;
+                unsafe {
+                    ptr::write_unaligned(
+                        region.event_buffer.as_mut_ptr() as *mut sev_step_event_t,
+                        e,
+                    )
+                };
+            }
+        }
+    }
+}
+
+impl<'a> SevStep<'a> {
+    /// Create an API instance that is backed by a [`MockKernel`] instead of the kvm device.
+    /// Sending on the returned channel aborts blocking operations, like for [`SevStep::new`]
+    pub(crate) fn new_mock(error_on_multi_step: bool) -> (SevStep<'a>, MockKernel, Sender<()>) {
+        let raw_shared_mem = Arc::new(AlignedSevStepBuf::new());
+        let shared_mem_region = Self::init_shared_mem_region(&raw_shared_mem);
+        let kernel = MockKernel::default();
+        let (abort_sender, abort) = bounded(1);
+
+        let api = SevStep {
+            _raw_shared_mem: raw_shared_mem,
+            shared_mem_region,
+            kvm: Box::new(kernel.clone()),
+            abort,
+            error_on_multi_step,
+            tracked_pages: Default::default(),
+            paused_tracking: None,
+            strict_stepping_checks: false,
+            stepping_session: None,
+            ack_count: 0,
+            capture_raw_events: false,
+            last_raw_event: None,
+            spin_policy: Default::default(),
+            last_ack: None,
+        };
+        (api, kernel, abort_sender)
+    }
+}
+
+/// Deliver `events` to `api` through the shared memory region. Each event is only sent once
+/// the previous one has been acked. The thread terminates after the last event has been sent
+/// or once `api` has been dropped
+pub(crate) fn spawn_event_feeder(api: &mut SevStep, events: Vec<MockEvent>) -> JoinHandle<()> {
+    feed_events(api, events, None)
+}
+
+/// Like [`spawn_event_feeder`], but the first event is only sent after a message has been
+/// received on `start`. Allows tests to synchronize the events with e.g. the target trigger
+pub(crate) fn spawn_gated_event_feeder(
+    api: &mut SevStep,
+    events: Vec<MockEvent>,
+    start: Receiver<()>,
+) -> JoinHandle<()> {
+    feed_events(api, events, Some(start))
+}
+
+fn feed_events(
+    api: &mut SevStep,
+    events: Vec<MockEvent>,
+    start: Option<Receiver<()>>,
+) -> JoinHandle<()> {
+    //only keep a weak reference, so that we notice when `api` is dropped.
+    //Like for the real kernel, the spinlock protects concurrent accesses
+    let shared_mem: Weak<AlignedSevStepBuf> = Arc::downgrade(&api._raw_shared_mem);
+    thread::spawn(move || {
+        if let Some(start) = start {
+            start
+                .recv()
+                .expect("start channel closed before first event");
+        }
+        for event in events {
+            loop {
+                //keeps the memory alive while we access it
+                let Some(shared_mem) = shared_mem.upgrade() else {
+                    return;
+                };
+                let region = unsafe {
+                    (shared_mem.as_mut_ptr() as *mut shared_mem_region_t)
+                        .as_mut()
+                        .unwrap()
+                };
+                unsafe { raw_spinlock::lock(&mut region.spinlock) };
+                if region.have_event == 0 && region.event_acked == 1 {
+                    event.write_to(region);
+                    region.event_acked = 0;
+                    region.have_event = 1;
+                    unsafe { raw_spinlock::unlock(&mut region.spinlock) };
+                    break;
+                }
+                unsafe { raw_spinlock::unlock(&mut region.spinlock) };
+                thread::yield_now();
+            }
+        }
+    })
+}
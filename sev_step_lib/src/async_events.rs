@@ -0,0 +1,126 @@
+//! Adapter to consume the events of a [`SevStep`] API connection from async code.
+//!
+//! The API is inherently single threaded: there is only one pending event at a time and the kernel
+//! only sends the next event after the current one has been acked. Thus, [`AsyncEventReceiver`] moves
+//! the API connection to a dedicated thread that performs the blocking [`SevStep::block_untill_event`]
+//! calls and forwards the events over a channel. Acks are forwarded back over a second channel.
+//! Async code must ack each event before it can receive the next one. As the API connection is owned
+//! by the worker thread, it cannot be used to e.g. change the page tracking while receiving events this way
+use std::{
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use tokio::sync::mpsc;
+
+use crate::api::{Event, SevStep, SevStepError};
+
+/// Receives events from an API connection that is driven by a dedicated worker thread
+pub struct AsyncEventReceiver {
+    events: mpsc::Receiver<Result<Event, SevStepError>>,
+    acks: mpsc::Sender<()>,
+    worker: JoinHandle<()>,
+}
+
+impl AsyncEventReceiver {
+    /// Moves `api` to a worker thread that waits for events. The first wait calls `target_trigger`,
+    /// just like [`SevStep::block_untill_event`]. The worker terminates after forwarding an error, e.g.
+    /// [`SevStepError::Timeout`], or once the receiver is dropped. The API connection is closed on termination
+    /// # Arguments
+    /// * `api` : API connection that is used to receive the events
+    /// * `target_trigger` : function that starts the victim
+    /// * `timeout` : timeout while waiting for a single event
+    pub fn spawn<F>(
+        mut api: SevStep<'static>,
+        target_trigger: F,
+        timeout: Option<Duration>,
+    ) -> AsyncEventReceiver
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        let (event_sender, events) = mpsc::channel(1);
+        let (acks, mut ack_receiver) = mpsc::channel(1);
+        let worker = thread::spawn(move || {
+            let mut event = api.block_untill_event(target_trigger, timeout);
+            loop {
+                let failed = event.is_err();
+                if event_sender.blocking_send(event).is_err() {
+                    debug!("AsyncEventReceiver was dropped, terminating event worker");
+                    return;
+                }
+                if failed {
+                    return;
+                }
+                match ack_receiver.blocking_recv() {
+                    Some(()) => api.ack_event(),
+                    None => return,
+                }
+                event = api.block_untill_event(|| Ok(()), timeout);
+            }
+        });
+        AsyncEventReceiver {
+            events,
+            acks,
+            worker,
+        }
+    }
+
+    /// Waits for the next event. Returns `None` once the worker has terminated and all
+    /// events have been received
+    pub async fn recv(&mut self) -> Option<Result<Event, SevStepError>> {
+        self.events.recv().await
+    }
+
+    /// Acks the last received event, allowing the VM to continue
+    pub async fn ack(&self) -> Result<()> {
+        self.acks
+            .send(())
+            .await
+            .map_err(|_| anyhow!("event worker terminated before the event was acked"))
+    }
+
+    /// Returns true if the worker thread has terminated
+    pub fn is_finished(&self) -> bool {
+        self.worker.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock_kernel::{spawn_event_feeder, MockEvent};
+
+    #[tokio::test]
+    async fn events_are_forwarded_and_acked() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(
+            &mut api,
+            vec![
+                MockEvent::page_fault(0x1000),
+                MockEvent::step(1),
+                MockEvent::step(2),
+            ],
+        );
+        let mut receiver =
+            AsyncEventReceiver::spawn(api, || Ok(()), Some(Duration::from_millis(200)));
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(receiver.recv().await.unwrap().unwrap());
+            receiver.ack().await.unwrap();
+        }
+        assert!(matches!(&received[0], Event::PageFaultEvent(v) if v.faulted_gpa == 0x1000));
+        assert!(matches!(&received[1], Event::StepEvent(v) if v.retired_instructions == 1));
+        assert!(matches!(&received[2], Event::StepEvent(v) if v.retired_instructions == 2));
+
+        //no more events, the worker forwards the timeout and terminates
+        assert!(matches!(
+            receiver.recv().await,
+            Some(Err(SevStepError::Timeout))
+        ));
+        assert!(receiver.recv().await.is_none());
+        feeder.join().unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Diagnostic for new custom targets: initializes the target, prints the values it reports
+//! during its setup phase together with the GPAs they resolve to and tears it down again.
+//! Does not use the SEV-Step API
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use sev_step_lib::{
+    config,
+    vmserver_client::{self, parse_hex_str},
+};
+use vm_server::req_resp::InitCustomTargetReq;
+
+#[derive(Parser, Debug)]
+struct CliArgs {
+    /// Path to vm config file
+    #[arg(short, long, default_value = "./vm-config.toml")]
+    vm_config_path: String,
+    /// Path to the folder with the custom target, inside the VM
+    #[arg(long)]
+    folder_path: String,
+    /// Command that starts the custom target, relative to `folder_path`
+    #[arg(long, default_value = "./a.out")]
+    execute_cmd: String,
+}
+
+/// Formats each entry of `setup_output` on its own line, sorted by name. Hex values are shown with
+/// the GPA of their page and the offset inside the page
+fn describe_setup_output(setup_output: &HashMap<String, String>) -> Vec<String> {
+    let mut names = setup_output.keys().collect::<Vec<_>>();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let value = &setup_output[name];
+            match parse_hex_str(value) {
+                Ok(v) => format!(
+                    "{} = {} -> GPA 0x{:x} + 0x{:x}",
+                    name,
+                    value,
+                    v & !0xfff,
+                    v & 0xfff
+                ),
+                Err(_) => format!("{} = {} (not a hex value)", name, value),
+            }
+        })
+        .collect()
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let args = CliArgs::parse();
+    let vm_config =
+        config::parse_config(&args.vm_config_path).context("failed to parse vm config")?;
+
+    let req = InitCustomTargetReq {
+        folder_path: args.folder_path,
+        execute_cmd: args.execute_cmd,
+    };
+    let resp = vmserver_client::new_custom_target(&vm_config.vm_server_address, &req)
+        .context(format!("request to create custom target {:?} failed", req))?;
+
+    println!("{} values reported during setup:", resp.setup_output.len());
+    for line in describe_setup_output(&resp.setup_output) {
+        println!("\t{}", line);
+    }
+
+    vmserver_client::teardown_target(&vm_config.vm_server_address)
+        .context("failed to tear down custom target")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe_sorted_setup_output() {
+        let setup_output = HashMap::from([
+            ("victim_fn".to_string(), "0x7f123".to_string()),
+            ("mem_buffer".to_string(), "a000".to_string()),
+            ("mode".to_string(), "fast".to_string()),
+        ]);
+        assert_eq!(
+            describe_setup_output(&setup_output),
+            vec![
+                "mem_buffer = a000 -> GPA 0xa000 + 0x0",
+                "mode = fast (not a hex value)",
+                "victim_fn = 0x7f123 -> GPA 0x7f000 + 0x123",
+            ]
+        );
+    }
+}
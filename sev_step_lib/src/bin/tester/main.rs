@@ -1,14 +1,14 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
 use clap_num;
 use colored::Colorize;
 use crossbeam::channel::bounded;
 use log::debug;
 use sev_step_lib::{api::SevStep, config, vm_setup_helpers};
-use std::process;
+use std::{collections::BTreeMap, fmt::Display, fs::File, process};
 use test::TestGroup;
 
-use crate::test::{Test, TestName};
+use crate::test::{Test, TestName, TestReport};
 
 pub mod test;
 
@@ -25,6 +25,104 @@ struct CliArgs {
     tests: Option<Vec<TestName>>,
     #[arg(short='t',long,value_parser=clap_num::maybe_hex::<u32>)]
     apic_timer_value: Option<u32>,
+    /// Single step while the page track tests access the tracked pages. Requires `apic_timer_value`
+    #[arg(long)]
+    with_stepping: bool,
+    /// Run each selected test this many times, to detect flaky tests
+    #[arg(long, default_value_t = 1)]
+    repeat: usize,
+    /// Write the data gathered by each successful test run, like step histograms, to this file as JSON
+    #[arg(long)]
+    stats_out: Option<String>,
+}
+
+/// Outcome of running a single test `runs` times
+#[derive(Debug, PartialEq)]
+struct RepeatReport {
+    name: String,
+    passed: usize,
+    runs: usize,
+    ///Reports of the successful runs
+    stats: Vec<TestReport>,
+}
+
+impl RepeatReport {
+    fn is_reliable(&self) -> bool {
+        self.passed == self.runs
+    }
+
+    fn is_flaky(&self) -> bool {
+        self.passed > 0 && self.passed < self.runs
+    }
+}
+
+impl Display for RepeatReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}/{}", self.name, self.passed, self.runs)
+    }
+}
+
+/// Number of tests that passed all, some or none of their runs
+#[derive(Debug, PartialEq)]
+struct Summary {
+    reliable: usize,
+    flaky: usize,
+    failed: usize,
+}
+
+fn summarize(reports: &[RepeatReport]) -> Summary {
+    let reliable = reports.iter().filter(|r| r.is_reliable()).count();
+    let flaky = reports.iter().filter(|r| r.is_flaky()).count();
+    Summary {
+        reliable,
+        flaky,
+        failed: reports.len() - reliable - flaky,
+    }
+}
+
+/// Calls `run` `repeat` times and counts the successful runs
+fn run_repeated(
+    name: String,
+    repeat: usize,
+    mut run: impl FnMut() -> Result<TestReport>,
+) -> RepeatReport {
+    let mut passed = 0;
+    let mut stats = Vec::new();
+    for run_idx in 0..repeat {
+        if repeat > 1 {
+            println!("Run [{}/{}]", run_idx + 1, repeat);
+        }
+        match run() {
+            Ok(v) => {
+                passed += 1;
+                stats.push(v);
+                println!("{}", "SUCCESS".green());
+            }
+            Err(e) => {
+                println!("{}:", "FAILED".red());
+                for x in e.chain() {
+                    println!("\t {}", x)
+                }
+            }
+        }
+    }
+    RepeatReport {
+        name,
+        passed,
+        runs: repeat,
+        stats,
+    }
+}
+
+/// Write the stats of all successful runs as JSON object, mapping test names to the list of their reports
+fn write_stats(path: &str, reports: &[RepeatReport]) -> Result<()> {
+    let stats = reports
+        .iter()
+        .map(|r| (r.name.as_str(), &r.stats))
+        .collect::<BTreeMap<_, _>>();
+    let f = File::create(path).context(format!("failed to create {}", path))?;
+    serde_json::to_writer_pretty(f, &stats).context("failed to serialize stats")?;
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -32,6 +130,9 @@ fn main() -> Result<()> {
 
     //parse args
     let args = CliArgs::parse();
+    if args.repeat == 0 {
+        bail!("--repeat must be at least 1");
+    }
     let vm_config =
         config::parse_config(&args.vm_config_path).context("failed to parse vm config")?;
 
@@ -41,14 +142,7 @@ fn main() -> Result<()> {
         .context("failed to get VCPU thread id")?;
     debug!("vcpu_thread_id is {}", vcpu_thread_id);
 
-    vm_setup_helpers::pin_pid_to_cpu(vcpu_thread_id, vm_config.vm_cpu_core).context(format!(
-        "failed to pin vcpu (tid {}) to core {}",
-        vcpu_thread_id, vm_config.vm_cpu_core,
-    ))?;
-    debug!(
-        "Pinned vcpu_thread (tid {}) to core {}",
-        vcpu_thread_id, vm_config.vm_cpu_core
-    );
+    vm_setup_helpers::pin_vcpu_to_vm_core(vcpu_thread_id, &vm_config)?;
 
     let core_ourself = 15;
     debug!("Pinning ourself to {}", core_ourself);
@@ -80,6 +174,7 @@ fn main() -> Result<()> {
                 rx.clone(),
                 vm_config.vm_server_address.clone(),
                 args.apic_timer_value,
+                args.with_stepping,
             )
         })
         .collect::<Result<_>>()
@@ -89,8 +184,8 @@ fn main() -> Result<()> {
         ))?;
 
     //runs tests
-    let mut successful_tests = 0;
     let test_count = tests.len();
+    let mut reports = Vec::new();
     for (idx, t) in tests.into_iter().enumerate() {
         println!(
             "Running test [{}/{}]: {}",
@@ -98,29 +193,93 @@ fn main() -> Result<()> {
             test_count,
             t.get_name()
         );
-        match t.run() {
-            Ok(_) => {
-                successful_tests += 1;
-                println!("{}", "SUCCESS".green());
-            }
-            Err(e) => {
-                println!("{}:", "FAILED".red());
-                for x in e.chain() {
-                    println!("\t {}", x)
-                }
+        reports.push(run_repeated(t.get_name(), args.repeat, || t.run()));
+    }
+
+    if args.repeat > 1 {
+        println!("Pass counts:");
+        for r in &reports {
+            let line = r.to_string();
+            match (r.is_reliable(), r.is_flaky()) {
+                (true, _) => println!("\t{}", line.green()),
+                (false, true) => println!("\t{}", line.yellow()),
+                (false, false) => println!("\t{}", line.red()),
             }
         }
     }
-    if successful_tests == test_count {
+    if let Some(path) = &args.stats_out {
+        write_stats(path, &reports).context(format!("failed to write stats to {}", path))?;
+    }
+    let summary = summarize(&reports);
+    if summary.reliable == test_count {
         println!("{}", "All tests succeeded".green());
     } else {
         println!(
             "{}, {} out of {} tests succeeded",
             "ONLY".yellow(),
-            successful_tests,
+            summary.reliable,
             test_count
         );
+        if summary.flaky > 0 {
+            println!(
+                "{} tests passed only some of their {} runs",
+                summary.flaky, args.repeat
+            );
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// Runs a fake test whose outcomes are taken from `outcomes`
+    fn report_for(name: &str, outcomes: &[bool]) -> RepeatReport {
+        let mut outcomes = outcomes.iter();
+        run_repeated(name.to_string(), outcomes.len(), || {
+            match outcomes.next().expect("run called too often") {
+                true => Ok(TestReport::default()),
+                false => Err(anyhow!("injected failure")),
+            }
+        })
+    }
+
+    #[test]
+    fn repeat_counts_passes_and_summarizes() {
+        let reports = vec![
+            report_for("Reliable", &[true, true, true]),
+            report_for("Flaky", &[true, false, true]),
+            report_for("Broken", &[false, false, false]),
+        ];
+
+        assert_eq!(
+            reports.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec!["Reliable: 3/3", "Flaky: 2/3", "Broken: 0/3"]
+        );
+        assert_eq!(
+            summarize(&reports),
+            Summary {
+                reliable: 1,
+                flaky: 1,
+                failed: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn serialize_test_report() -> Result<()> {
+        let report = TestReport {
+            step_histogram: Some(BTreeMap::from([(0, 2), (1, 40)])),
+            fault_histogram: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&report)?,
+            r#"{"step_histogram":{"0":2,"1":40},"fault_histogram":null}"#
+        );
+        Ok(())
+    }
+}
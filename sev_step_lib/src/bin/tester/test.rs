@@ -1,15 +1,22 @@
-use std::{collections::HashSet, fmt::Display, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+};
 
 use crate::SevStep;
 use anyhow::{anyhow, bail, Context, Result};
 use clap::ValueEnum;
 use core::time::Duration;
 use crossbeam::channel::Receiver;
-use iced_x86::{code_asm::CodeAssembler, Instruction};
+use iced_x86::code_asm::CodeAssembler;
 use log::debug;
+use serde::Serialize;
 use sev_step_lib::{
+    api::{Event, SevStepError},
     single_stepper::{
-        BuildStepHistogram, EventHandler, RetrackGPASet, SkipIfNotOnTargetGPAs,
+        expected_rip_stepper, expected_step_rips, AssertSingleSteppingQuality, BuildStepHistogram,
+        EventHandler, RetrackGPASet, SkipIfNotOnTargetGPAs, StateMachineNextAction,
         StopAfterNSingleStepsHandler, TargetedStepper,
     },
     types::kvm_page_track_mode,
@@ -19,7 +26,58 @@ use vm_server::req_resp::{InitAssemblyTargetReq, InitPagePingPongerReq};
 pub trait Test {
     fn get_name(&self) -> String;
     fn get_description(&self) -> &str;
-    fn run(&self) -> Result<()>;
+    fn run(&self) -> Result<TestReport>;
+}
+
+/// Data gathered by a successful test run, exported with `--stats-out`
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct TestReport {
+    ///Step sizes and their number of occurrences, if the test single steps
+    pub step_histogram: Option<BTreeMap<u64, u64>>,
+    ///Faulted GPAs and their number of faults, if the test tracks pages
+    pub fault_histogram: Option<BTreeMap<u64, u64>>,
+}
+
+impl TestReport {
+    fn with_step_histogram(mut self, step_histogram: &BuildStepHistogram) -> Self {
+        let merged = self.step_histogram.get_or_insert_with(BTreeMap::new);
+        for (size, count) in step_histogram.get_values() {
+            *merged.entry(*size).or_default() += count;
+        }
+        self
+    }
+
+    fn with_fault_histogram(mut self, fault_histogram: &FaultHistogram) -> Self {
+        let merged = self.fault_histogram.get_or_insert_with(BTreeMap::new);
+        for (gpa, count) in &fault_histogram.faults {
+            *merged.entry(*gpa).or_default() += count;
+        }
+        self
+    }
+}
+
+/// Counts the page faults for each GPA
+#[derive(Default)]
+struct FaultHistogram {
+    faults: BTreeMap<u64, u64>,
+}
+
+impl EventHandler for FaultHistogram {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        if let Event::PageFaultEvent(v) = event {
+            *self.faults.entry(v.faulted_gpa).or_default() += 1;
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn get_name(&self) -> &str {
+        "FaultHistogram"
+    }
 }
 
 ///This enum describes all known tests
@@ -30,6 +88,7 @@ pub enum TestName {
     PageTrackWrite,
     PageTrackExec,
     SingleStepNopSlide,
+    SingleStepEnterLeaveReenter,
 }
 
 impl FromStr for TestName {
@@ -42,6 +101,7 @@ impl FromStr for TestName {
             "PageTrackWrite" => Ok(Self::PageTrackWrite),
             "PageTrackExec" => Ok(Self::PageTrackExec),
             "SingleStepNopSlide" => Ok(Self::SingleStepNopSlide),
+            "SingleStepEnterLeaveReenter" => Ok(Self::SingleStepEnterLeaveReenter),
             _ => Err("invalid TestName value"),
         }
     }
@@ -53,23 +113,34 @@ impl TestName {
         abort_chan: Receiver<()>,
         server_addr: String,
         apic_timer_value: Option<u32>,
+        with_stepping: bool,
     ) -> Result<Box<dyn Test>> {
+        //only used by the page track tests
+        let stepping_timer_value = match with_stepping {
+            true => Some(apic_timer_value.ok_or(anyhow!(
+                "--with-stepping requires apic_timer_value but got None"
+            ))?),
+            false => None,
+        };
         match &self {
             TestName::SetupTeardown => Ok(Box::new(SetupTeardownTest::new(abort_chan))),
             TestName::PageTrackPresent => Ok(Box::new(CommonPageTrackTest::new(
                 abort_chan,
                 kvm_page_track_mode::KVM_PAGE_TRACK_ACCESS,
                 server_addr,
+                stepping_timer_value,
             )?)),
             TestName::PageTrackWrite => Ok(Box::new(CommonPageTrackTest::new(
                 abort_chan,
                 kvm_page_track_mode::KVM_PAGE_TRACK_WRITE,
                 server_addr,
+                stepping_timer_value,
             )?)),
             TestName::PageTrackExec => Ok(Box::new(CommonPageTrackTest::new(
                 abort_chan,
                 kvm_page_track_mode::KVM_PAGE_TRACK_EXEC,
                 server_addr,
+                stepping_timer_value,
             )?)),
             TestName::SingleStepNopSlide => {
                 let apic_timer_value = apic_timer_value.ok_or(anyhow!(
@@ -80,6 +151,15 @@ impl TestName {
                         .context("failed to instantiate nop slide test")?,
                 ))
             }
+            TestName::SingleStepEnterLeaveReenter => {
+                let apic_timer_value = apic_timer_value.ok_or(anyhow!(
+                    "SingleStepEnterLeaveReenter requires apic_timer_value but got None"
+                ))?;
+                Ok(Box::new(
+                    SingleStepEnterLeaveReenterTest::new(abort_chan, server_addr, apic_timer_value)
+                        .context("failed to instantiate enter leave reenter test")?,
+                ))
+            }
         }
     }
 }
@@ -92,6 +172,7 @@ impl Display for TestName {
             TestName::PageTrackWrite => write!(f, "PageTrackWrite"),
             TestName::PageTrackExec => write!(f, "PageTrackExec"),
             TestName::SingleStepNopSlide => write!(f, "SingleStepNopSlide"),
+            TestName::SingleStepEnterLeaveReenter => write!(f, "SingleStepEnterLeaveReenter"),
         }
     }
 }
@@ -118,6 +199,7 @@ impl Into<Vec<TestName>> for TestGroup {
                 TestName::PageTrackPresent,
                 TestName::PageTrackExec,
                 TestName::SingleStepNopSlide,
+                TestName::SingleStepEnterLeaveReenter,
             ],
             TestGroup::Basic => vec![TestName::SetupTeardown],
             TestGroup::PageFault => vec![
@@ -125,7 +207,10 @@ impl Into<Vec<TestName>> for TestGroup {
                 TestName::PageTrackPresent,
                 TestName::PageTrackExec,
             ],
-            TestGroup::SingleStepping => vec![TestName::SingleStepNopSlide],
+            TestGroup::SingleStepping => vec![
+                TestName::SingleStepNopSlide,
+                TestName::SingleStepEnterLeaveReenter,
+            ],
         }
     }
 }
@@ -180,13 +265,13 @@ impl Test for SetupTeardownTest {
         &self.description
     }
 
-    fn run(&self) -> Result<()> {
+    fn run(&self) -> Result<TestReport> {
         for _i in 0..10 {
             let mut _sev_step = SevStep::new(false, self.abort_chan.clone(), false)
                 .context("failed to open API connection")?;
             drop(_sev_step);
         }
-        Ok(())
+        Ok(TestReport::default())
     }
 }
 
@@ -197,6 +282,8 @@ pub struct CommonPageTrackTest {
     server_addr: String,
     name: TestName,
     description: String,
+    /// If set, single step with this APIC timer value while executing the tracked pages
+    stepping_timer_value: Option<u32>,
 }
 
 impl CommonPageTrackTest {
@@ -204,6 +291,7 @@ impl CommonPageTrackTest {
         abort_chan: Receiver<()>,
         track_type: kvm_page_track_mode,
         server_addr: String,
+        stepping_timer_value: Option<u32>,
     ) -> Result<Self> {
         let name = match track_type {
             kvm_page_track_mode::KVM_PAGE_TRACK_WRITE => TestName::PageTrackWrite,
@@ -222,8 +310,112 @@ impl CommonPageTrackTest {
             description:
                 "Track read access to two pages that are accessed in an alternating manner"
                     .to_string(),
+            stepping_timer_value,
         })
     }
+
+    /// Single step while the victim accesses the tracked pages, using [`SkipIfNotOnTargetGPAs`].
+    /// Checks that there is at least one single step between two faults on the tracked pages
+    fn run_with_stepping(
+        &self,
+        sev_step: SevStep,
+        page_gpas: Vec<u64>,
+        timer_value: u32,
+        report: TestReport,
+    ) -> Result<TestReport> {
+        let mut fault_histogram = FaultHistogram::default();
+        let mut skip_if_not_on_pages =
+            SkipIfNotOnTargetGPAs::new(&page_gpas, self.track_type, timer_value);
+        let mut step_histogram = BuildStepHistogram::new();
+        let mut steps_between_faults = AssertStepsBetweenFaults::new();
+        //safeguard against zero step loops, the run usually ends with a timeout once the victim is done
+        let mut stop_stepping = StopAfterNSingleStepsHandler::new(1000, None);
+        let handler_chain: Vec<&mut dyn EventHandler> = vec![
+            &mut fault_histogram,
+            &mut skip_if_not_on_pages,
+            &mut step_histogram,
+            &mut steps_between_faults,
+            &mut stop_stepping,
+        ];
+
+        let a = self.server_addr.clone();
+        let handler = TargetedStepper::new(
+            sev_step,
+            handler_chain,
+            self.track_type,
+            page_gpas,
+            move || {
+                debug!("requesting page track victim start");
+                vmserver_client::run_target_program(&a)
+                    .context("failed to start page track victim in trigger fn")
+            },
+            Some(Duration::from_secs(5)),
+        );
+        debug!("Calling handler.run()");
+        match handler.run() {
+            Ok(()) | Err(SevStepError::Timeout) => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        debug!("Step histogram: {}", step_histogram);
+        if step_histogram.get_values().get(&1).copied().unwrap_or(0) == 0 {
+            bail!(
+                "did not observe any single steps on the tracked pages. Step Histogram : {}",
+                step_histogram
+            );
+        }
+        Ok(report
+            .with_step_histogram(&step_histogram)
+            .with_fault_histogram(&fault_histogram))
+    }
+}
+
+/// Requests an error shutdown if there is no single step between two consecutive
+/// page fault events
+struct AssertStepsBetweenFaults {
+    seen_fault: bool,
+    steps_since_fault: usize,
+}
+
+impl AssertStepsBetweenFaults {
+    fn new() -> Self {
+        AssertStepsBetweenFaults {
+            seen_fault: false,
+            steps_since_fault: 0,
+        }
+    }
+}
+
+impl EventHandler for AssertStepsBetweenFaults {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        match event {
+            Event::PageFaultEvent(v) => {
+                if self.seen_fault && self.steps_since_fault == 0 {
+                    return Ok(StateMachineNextAction::ErrorShutdown(format!(
+                        "no single step before fault at 0x{:x}",
+                        v.faulted_gpa
+                    )));
+                }
+                self.seen_fault = true;
+                self.steps_since_fault = 0;
+            }
+            Event::StepEvent(v) => {
+                if v.retired_instructions > 0 {
+                    self.steps_since_fault += 1;
+                }
+            }
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn get_name(&self) -> &str {
+        "AssertStepsBetweenFaults"
+    }
 }
 
 impl Test for CommonPageTrackTest {
@@ -235,13 +427,16 @@ impl Test for CommonPageTrackTest {
         &self.description
     }
 
-    fn run(&self) -> Result<()> {
+    fn run(&self) -> Result<TestReport> {
         let init_args = InitPagePingPongerReq {
             variant: self.track_type.try_into()?,
             rounds: 10,
+            randomized: false,
+            seed: 0,
         };
 
         const REPS: u32 = 5;
+        let mut report = TestReport::default();
         for _i in 0..REPS {
             debug!("iteration {}/{}", _i + 1, REPS);
 
@@ -252,12 +447,24 @@ impl Test for CommonPageTrackTest {
                 .context("failed to init pagetrack victim")?;
             debug!("Received PageTrackVictim description : {:?}", victim_prog);
 
+            if let Some(timer_value) = self.stepping_timer_value {
+                report = self.run_with_stepping(
+                    sev_step,
+                    victim_prog.page_paddrs.iter().map(|v| *v as u64).collect(),
+                    timer_value,
+                    report,
+                )?;
+                continue;
+            }
+
+            let mut fault_histogram = FaultHistogram::default();
             let mut retrack_gpas = RetrackGPASet::new(
                 HashSet::from_iter(victim_prog.page_paddrs.iter().map(|v| *v as u64)),
                 self.track_type,
                 Some(init_args.rounds as usize),
             );
-            let handler_chain: Vec<&mut dyn EventHandler> = vec![&mut retrack_gpas];
+            let handler_chain: Vec<&mut dyn EventHandler> =
+                vec![&mut fault_histogram, &mut retrack_gpas];
 
             let a = self.server_addr.clone();
             let handler = TargetedStepper::new(
@@ -274,9 +481,10 @@ impl Test for CommonPageTrackTest {
             );
             debug!("Calling handler.run()");
             handler.run()?;
+            report = report.with_fault_histogram(&fault_histogram);
         }
 
-        Ok(())
+        Ok(report)
     }
 }
 
@@ -302,6 +510,7 @@ impl SingleStepNopSlideTest {
         let nop_slide_req = InitAssemblyTargetReq {
             code: a.take_instructions(),
             required_mem_bytes: 0,
+            initial_data: Vec::new(),
         };
 
         Ok(SingleStepNopSlideTest {
@@ -324,61 +533,210 @@ impl Test for SingleStepNopSlideTest {
         &self.description
     }
 
-    fn run(&self) -> Result<()> {
+    fn run(&self) -> Result<TestReport> {
         let mut _sev_step = SevStep::new(true, self.abort_chan.clone(), false)?;
 
         let victim_prog = new_assembly_target(&self.server_addr, &self.nop_slide_req)
             .context("failed to init NopSlide victim")?;
 
-        let mut targetter = SkipIfNotOnTargetGPAs::new(
-            &[victim_prog.code_paddr as u64],
-            kvm_page_track_mode::KVM_PAGE_TRACK_EXEC,
-            self.timer_value,
-        );
-        let mut step_histogram = BuildStepHistogram::new();
-
-        //first instruction is not part of single stepping as it is consumed as part of the page fault logic
-        let expected_instructions: Vec<&Instruction> =
-            victim_prog.instructions_with_rip.iter().skip(1).collect();
-
-        let mut stop_after = StopAfterNSingleStepsHandler::new(
-            expected_instructions.len(),
-            Some(expected_instructions.iter().map(|v| v.ip()).collect()),
-        );
-        let handler_chain: Vec<&mut dyn EventHandler> =
-            vec![&mut targetter, &mut step_histogram, &mut stop_after];
+        let expected_instructions = expected_step_rips(&victim_prog);
 
         let server_addr = self.server_addr.clone();
-        let stepper = TargetedStepper::new(
+        let step_histogram = expected_rip_stepper(
             _sev_step,
-            handler_chain,
-            kvm_page_track_mode::KVM_PAGE_TRACK_ACCESS,
-            vec![victim_prog.code_paddr as u64],
+            &victim_prog,
+            self.timer_value,
             move || {
                 vmserver_client::run_target_program(&server_addr)
                     .context("target trigger assembly_target_run failed")
             },
             Some(Duration::from_secs(50)),
+        )?;
+
+        AssertSingleSteppingQuality::new(expected_instructions.len(), true)
+            .check_histogram(step_histogram.get_values())
+            .context("Did not successfully single step target")?;
+        Ok(TestReport::default().with_step_histogram(&step_histogram))
+    }
+}
+
+pub struct SingleStepEnterLeaveReenterTest {
+    abort_chan: Receiver<()>,
+    /// address at which the server inside vm is reachable. format: http://hostname:port
+    server_addr: String,
+    name: TestName,
+    description: String,
+    timer_value: u32,
+    victim_req: InitAssemblyTargetReq,
+}
+
+impl SingleStepEnterLeaveReenterTest {
+    /// Number of nops before and after the call to the helper function on the second code page
+    const NOPS_PER_CLUSTER: usize = 100;
+
+    pub fn new(abort_chan: Receiver<()>, server_addr: String, timer_value: u32) -> Result<Self> {
+        let mut a = CodeAssembler::new(64)?;
+        let mut helper = a.create_label();
+        for _ in 0..Self::NOPS_PER_CLUSTER {
+            a.nop()?;
+        }
+        a.call(helper)?;
+        for _ in 0..Self::NOPS_PER_CLUSTER {
+            a.nop()?;
+        }
+        a.ret()?;
+        //never executed, moves the helper function to the second code page
+        for _ in 0..4096 {
+            a.int3()?;
+        }
+        a.set_label(&mut helper)?;
+        for _ in 0..Self::NOPS_PER_CLUSTER {
+            a.nop()?;
+        }
+        a.ret()?;
+
+        Ok(SingleStepEnterLeaveReenterTest {
+            abort_chan,
+            server_addr,
+            name: TestName::SingleStepEnterLeaveReenter,
+            description: "Single step a victim that calls a function on a non target page and returns to the target page. Stepping has to stop on leave and resume on re-entry".to_string(),
+            timer_value,
+            victim_req: InitAssemblyTargetReq {
+                code: a.take_instructions(),
+                required_mem_bytes: 0,
+                initial_data: Vec::new(),
+            },
+        })
+    }
+}
+
+/// Groups the single steps into clusters, one for each time the target page is entered.
+/// Requests shutdown once the target page has been re-entered after the helper page and
+/// is left for a third page
+struct StepClusters {
+    target_gpa: u64,
+    helper_gpa: u64,
+    ///Number of single steps for each entry of the target page
+    clusters: Vec<u64>,
+    ///Index of the first cluster after the helper page was executed
+    reentered_cluster: Option<usize>,
+    seen_helper: bool,
+}
+
+impl EventHandler for StepClusters {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        match event {
+            Event::PageFaultEvent(v) if v.faulted_gpa == self.target_gpa => {
+                if self.seen_helper && self.reentered_cluster.is_none() {
+                    self.reentered_cluster = Some(self.clusters.len());
+                }
+                self.clusters.push(0);
+            }
+            Event::PageFaultEvent(v) if v.faulted_gpa == self.helper_gpa => {
+                self.seen_helper = true;
+            }
+            Event::PageFaultEvent(_) => {
+                if self.reentered_cluster.is_some() {
+                    return Ok(StateMachineNextAction::SHUTDOWN);
+                }
+            }
+            Event::StepEvent(v) => {
+                if let (1, Some(cluster)) = (v.retired_instructions, self.clusters.last_mut()) {
+                    *cluster += 1;
+                }
+            }
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn get_name(&self) -> &str {
+        "StepClusters"
+    }
+}
+
+impl Test for SingleStepEnterLeaveReenterTest {
+    fn get_name(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn get_description(&self) -> &str {
+        &self.description
+    }
+
+    fn run(&self) -> Result<TestReport> {
+        let sev_step = SevStep::new(false, self.abort_chan.clone(), false)?;
+
+        let victim_prog = new_assembly_target(&self.server_addr, &self.victim_req)
+            .context("failed to init enter leave reenter victim")?;
+        if victim_prog.code_page_paddrs.len() < 2 {
+            bail!(
+                "expected victim code to span two pages, got {:x?}",
+                victim_prog.code_page_paddrs
+            );
+        }
+        let target_gpa = (victim_prog.code_page_paddrs[0] & !0xfff) as u64;
+        let helper_gpa = (victim_prog.code_page_paddrs[1] & !0xfff) as u64;
+        debug!(
+            "target page 0x{:x}, helper page 0x{:x}",
+            target_gpa, helper_gpa
         );
 
-        stepper.run()?;
+        let track_mode = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+        let mut step_clusters = StepClusters {
+            target_gpa,
+            helper_gpa,
+            clusters: Vec::new(),
+            reentered_cluster: None,
+            seen_helper: false,
+        };
+        let mut skip_if_not_on_target =
+            SkipIfNotOnTargetGPAs::new(&[target_gpa], track_mode, self.timer_value);
+        let mut step_histogram = BuildStepHistogram::new();
+        let handler_chain: Vec<&mut dyn EventHandler> = vec![
+            &mut step_clusters,
+            &mut skip_if_not_on_target,
+            &mut step_histogram,
+        ];
 
-        let step_sizes = step_histogram.get_values();
+        let server_addr = self.server_addr.clone();
+        TargetedStepper::new(
+            sev_step,
+            handler_chain,
+            track_mode,
+            vec![target_gpa],
+            move || {
+                vmserver_client::run_target_program(&server_addr)
+                    .context("target trigger assembly_target_run failed")
+            },
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
 
-        if step_sizes.len() == 1 && step_sizes.contains_key(&1) {
-            Ok(())
-        } else if step_sizes.len() == 2
-            && step_sizes.contains_key(&1)
-            && step_sizes.contains_key(&0)
-            && *step_sizes.get(&1).unwrap() >= expected_instructions.len() as u64
-        {
-            Ok(())
-        } else {
+        debug!(
+            "Step clusters: {:?}, Step histogram: {}",
+            step_clusters.clusters, step_histogram
+        );
+        let reentered_cluster = step_clusters
+            .reentered_cluster
+            .ok_or(anyhow!("did not re-enter the target page after the helper"))?;
+        //the first instruction of each cluster is consumed by the page fault
+        let min_steps = Self::NOPS_PER_CLUSTER as u64 - 1;
+        let before_leave: u64 = step_clusters.clusters[..reentered_cluster].iter().sum();
+        let after_reentry: u64 = step_clusters.clusters[reentered_cluster..].iter().sum();
+        if before_leave < min_steps || after_reentry < min_steps {
             bail!(
-                "Did not successfully single step target. Require {} single steps and NO multi steps. Step Histogram : {}",
-                expected_instructions.len(),
+                "expected at least {} single steps before leaving and after re-entering the target page, got {} and {}. Step Histogram : {}",
+                min_steps,
+                before_leave,
+                after_reentry,
                 step_histogram
-            )
+            );
         }
+        Ok(TestReport::default().with_step_histogram(&step_histogram))
     }
 }
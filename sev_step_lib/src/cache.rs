@@ -0,0 +1,67 @@
+//! Address arithmetic for cache attacks, e.g. to check if two physical addresses
+//! map to the same cache set when building eviction sets
+
+/// Geometry of a physically indexed, set associative cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheParams {
+    ///Size of a cache line in bytes
+    pub line_bytes: u64,
+    ///Number of cache sets
+    pub sets: u64,
+    ///Number of cache lines per set
+    pub ways: u64,
+}
+
+impl CacheParams {
+    /// Total size of the cache in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.line_bytes * self.sets * self.ways
+    }
+}
+
+/// Returns the index of the cache set that `paddr` maps to. Does not account for
+/// the slice hash functions used by sliced last level caches
+/// # Arguments
+/// * `paddr` : physical address
+/// * `cache_params` : geometry of the cache
+pub fn set_index(paddr: u64, cache_params: &CacheParams) -> u64 {
+    (paddr / cache_params.line_bytes) % cache_params.sets
+}
+
+/// Returns true if `paddr_a` and `paddr_b` map to the same cache set, i.e. if they are congruent
+/// # Arguments
+/// * `paddr_a` : physical address
+/// * `paddr_b` : physical address
+/// * `cache_params` : geometry of the cache
+pub fn same_cache_set(paddr_a: u64, paddr_b: u64, cache_params: &CacheParams) -> bool {
+    set_index(paddr_a, cache_params) == set_index(paddr_b, cache_params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 16-way LLC with 64 byte lines and 2048 sets (2 MiB)
+    const LLC: CacheParams = CacheParams {
+        line_bytes: 64,
+        sets: 2048,
+        ways: 16,
+    };
+
+    #[test]
+    fn set_index_and_congruence() {
+        assert_eq!(LLC.size_bytes(), 2 * 1024 * 1024);
+
+        assert_eq!(set_index(0x0, &LLC), 0);
+        assert_eq!(set_index(0x3f, &LLC), 0);
+        assert_eq!(set_index(0x40, &LLC), 1);
+        assert_eq!(set_index(0x1234_5678, &LLC), 0x159);
+        //set index bits are 6..17, thus addresses 128 KiB apart are congruent
+        assert_eq!(set_index(0x20000, &LLC), 0);
+
+        assert!(same_cache_set(0x1000, 0x21000, &LLC));
+        assert!(same_cache_set(0x1000, 0x1000 + 0x3f, &LLC));
+        assert!(!same_cache_set(0x1000, 0x1040, &LLC));
+        assert!(!same_cache_set(0x1000, 0x11000, &LLC));
+    }
+}
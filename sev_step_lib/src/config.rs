@@ -25,6 +25,12 @@ pub struct Config {
     pub qemu_qmp_address: String,
     /// method for fixating the cpu frequncy of the vm core
     pub fix_cpu_frequency: FixCpuFrequency,
+    /// abort if `vm_cpu_core` is not isolated. Otherwise, only a warning is logged
+    #[serde(default)]
+    pub require_isolated_core: bool,
+    /// after pinning, wait up to this many milliseconds until the vcpu has been scheduled on `vm_cpu_core`
+    #[serde(default)]
+    pub wait_until_on_core_ms: Option<u64>,
 }
 
 pub fn parse_config(config_file_path: &str) -> Result<Config> {
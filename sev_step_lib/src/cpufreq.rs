@@ -1,10 +1,17 @@
 //!
 //! Thin wrapper around the file based cpufreq interface exposed by the Linux kernel
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{Read, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 /// Return base file path of the cpufreq interface for the given cpu
@@ -89,3 +96,144 @@ pub fn pin_cpu_freq(cpu: usize, governor: &str, freq: &str) -> Result<()> {
     }
     Ok(())
 }
+
+/// Parse the content of a cpufreq frequency file, e.g. `scaling_cur_freq`. Unit is kHz
+fn read_freq_file(path: &Path) -> Result<u64> {
+    let content = fs::read_to_string(path).context(format!("failed to read {:?}", path))?;
+    content.trim().parse().context(format!(
+        "failed to parse \"{}\" from {:?} as frequency",
+        content.trim(),
+        path
+    ))
+}
+
+/// Returns the current frequency of the given cpu in kHz, as reported by `scaling_cur_freq`
+/// # Arguments
+/// * `cpu`: logical cpu id
+pub fn read_current_freq(cpu: usize) -> Result<u64> {
+    read_freq_file(&cpufreq_basepath(cpu).join("scaling_cur_freq"))
+}
+
+/// Sample that deviated from the expected frequency
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrequencyDeviation {
+    /// Time since the monitor was started
+    pub elapsed: Duration,
+    /// Observed frequency in kHz
+    pub freq_khz: u64,
+}
+
+/// Samples the frequency of a cpu in a background thread and records all samples that deviate from the
+/// expected value, e.g. due to thermal throttling or a governor change while the attack is running.
+/// Even with a pinned frequency, these invalidate timing based results
+pub struct FrequencyMonitor {
+    stop: Arc<AtomicBool>,
+    worker: JoinHandle<Result<Vec<FrequencyDeviation>>>,
+}
+
+impl FrequencyMonitor {
+    /// Start sampling `scaling_cur_freq` of `cpu`
+    /// # Arguments
+    /// * `cpu`: logical cpu id, e.g. the core of the vCPU
+    /// * `expected_khz`: pinned frequency
+    /// * `tolerance_khz`: samples that differ by at most this value from `expected_khz` are fine
+    /// * `interval`: time between two samples
+    pub fn start(
+        cpu: usize,
+        expected_khz: u64,
+        tolerance_khz: u64,
+        interval: Duration,
+    ) -> Result<Self> {
+        let path = cpufreq_basepath(cpu).join("scaling_cur_freq");
+        if !path.exists() {
+            bail!("{:?} does not exist. Either cpufreq is not available on this system or logical cpu id {} is out of bounds", path, cpu);
+        }
+        Ok(Self::start_with_path(
+            path,
+            expected_khz,
+            tolerance_khz,
+            interval,
+        ))
+    }
+
+    fn start_with_path(
+        path: PathBuf,
+        expected_khz: u64,
+        tolerance_khz: u64,
+        interval: Duration,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+        let worker = thread::spawn(move || {
+            let start = Instant::now();
+            let mut deviations = Vec::new();
+            while !worker_stop.load(Ordering::SeqCst) {
+                let freq_khz = read_freq_file(&path)?;
+                if freq_khz.abs_diff(expected_khz) > tolerance_khz {
+                    if deviations.is_empty() {
+                        warn!(
+                            "frequency {} kHz deviates from expected {} kHz, timing results may be invalid",
+                            freq_khz, expected_khz
+                        );
+                    }
+                    deviations.push(FrequencyDeviation {
+                        elapsed: start.elapsed(),
+                        freq_khz,
+                    });
+                }
+                thread::sleep(interval);
+            }
+            Ok(deviations)
+        });
+        FrequencyMonitor { stop, worker }
+    }
+
+    /// Stop sampling and return all samples that deviated from the expected frequency
+    pub fn stop(self) -> Result<Vec<FrequencyDeviation>> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.worker
+            .join()
+            .map_err(|_| anyhow!("frequency monitor thread panicked"))?
+            .context("failed to sample frequency")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequency_change_is_reported_as_deviation() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("fake_cpufreq_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let cur_freq = dir.join("scaling_cur_freq");
+        //replace the file atomically, so that the monitor never reads a partial write
+        let set_freq = |v: &str| -> Result<()> {
+            let tmp = dir.join("scaling_cur_freq.tmp");
+            fs::write(&tmp, v)?;
+            fs::rename(&tmp, &cur_freq)?;
+            Ok(())
+        };
+
+        set_freq("2400000\n")?;
+        let monitor = FrequencyMonitor::start_with_path(
+            cur_freq.clone(),
+            2400000,
+            1000,
+            Duration::from_millis(1),
+        );
+        thread::sleep(Duration::from_millis(20));
+        //within tolerance
+        set_freq("2400500\n")?;
+        thread::sleep(Duration::from_millis(20));
+        set_freq("1800000\n")?;
+        thread::sleep(Duration::from_millis(20));
+        let deviations = monitor.stop()?;
+        fs::remove_dir_all(&dir)?;
+
+        assert!(!deviations.is_empty());
+        assert!(deviations.iter().all(|v| v.freq_khz == 1800000));
+        assert!(deviations.windows(2).all(|v| v[0].elapsed <= v[1].elapsed));
+        Ok(())
+    }
+}
@@ -46,6 +46,8 @@ where
 pub struct ComposableHandlerChainOutcome {
     pub pending_event: Event,
     pub produced_ctx: HashMap<String, Vec<u8>>,
+    ///Total number of events acked during the run, see [`SevStep::ack_count`]
+    pub ack_count: u64,
 }
 
 pub struct InitialTrackingRequest {
@@ -118,6 +120,7 @@ where
                     return Ok(ComposableHandlerChainOutcome {
                         pending_event: event,
                         produced_ctx: ctx,
+                        ack_count: self.api.ack_count(),
                     });
                 }
                 StateMachineNextAction::ErrorShutdown(message) => {
@@ -135,6 +138,7 @@ where
         Ok(ComposableHandlerChainOutcome {
             pending_event: event,
             produced_ctx: ctx,
+            ack_count: self.api.ack_count(),
         })
     }
 }
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use log::{debug, warn};
 
 use crate::{
@@ -226,3 +226,313 @@ impl ComposableEventHandler for SkipUntilNSingleSteps {
         SkipUntilNSingleSteps::NAME
     }
 }
+
+pub struct MatchRipSequence {
+    idx_next_rip: usize,
+    rip_sequence: Vec<u64>,
+    matching: SequenceMatchingStrategy,
+    shutdown_on_match: bool,
+}
+
+impl MatchRipSequence {
+    const NAME: &'static str = "MatchRipSequence";
+    /// Handler that consumes single step events until their RIP values contain the requested sequence.
+    /// Page fault events and zero steps are ignored. Requires debug mode.
+    /// Expects single stepping to be configured. Does not disable single stepping
+    /// # Arguments
+    /// - `rip_sequence`: sequence of RIP values that we want to observe before returning
+    /// - `matching`: configures if it is ok for `rip_sequence` to be interrupted by steps at other RIPs
+    /// - `shutdown_on_match`: If true, return [`StateMachineNextAction::SHUTDOWN`] instead of [`StateMachineNextAction::NEXT`]
+    ///   once the sequence has been observed
+    ///
+    /// Returns an error if `rip_sequence` is empty
+    pub fn new(
+        rip_sequence: Vec<u64>,
+        matching: SequenceMatchingStrategy,
+        shutdown_on_match: bool,
+    ) -> anyhow::Result<MatchRipSequence> {
+        if rip_sequence.is_empty() {
+            bail!("{}: rip_sequence must not be empty", MatchRipSequence::NAME);
+        }
+        Ok(MatchRipSequence {
+            idx_next_rip: 0,
+            rip_sequence,
+            matching,
+            shutdown_on_match,
+        })
+    }
+}
+
+impl ComposableEventHandler for MatchRipSequence {
+    fn process(
+        &mut self,
+        event: &Event,
+        api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<EventHandlerOutcome, SevStepError> {
+        let mut event = event.clone();
+        let mut first_iteration = true;
+        loop {
+            if !first_iteration {
+                api.ack_event();
+                event = api.block_untill_event(|| Ok(()), None)?;
+            } else {
+                first_iteration = false;
+            }
+
+            let step_event = match &event {
+                Event::PageFaultEvent(v) => {
+                    debug!("{}: got page fault event {:x?}", MatchRipSequence::NAME, v);
+                    continue;
+                }
+                Event::StepEvent(v) => v,
+            };
+            if step_event.retired_instructions == 0 {
+                debug!("{}: got zero step", MatchRipSequence::NAME);
+                continue;
+            }
+
+            let got_rip = step_event
+                .get_register(vmsa_register_name_t::VRN_RIP)
+                .ok_or(anyhow!(
+                    "{}: failed to get RIP, is the VM running in debug mode?",
+                    MatchRipSequence::NAME
+                ))?;
+            let want_rip = self.rip_sequence[self.idx_next_rip];
+            if got_rip == want_rip {
+                self.idx_next_rip += 1;
+            } else {
+                match self.matching {
+                    SequenceMatchingStrategy::StrictWithReset => {
+                        debug!(
+                            "{}: got RIP 0x{:x} instead of 0x{:x}, resetting progress",
+                            MatchRipSequence::NAME,
+                            got_rip,
+                            want_rip
+                        );
+                        //the unexpected RIP might already be the start of the next attempt
+                        self.idx_next_rip = match got_rip == self.rip_sequence[0] {
+                            true => 1,
+                            false => 0,
+                        };
+                    }
+                    SequenceMatchingStrategy::StrictWithAbort => {
+                        return Ok(EventHandlerOutcome {
+                            pending_event: event.clone(),
+                            next_action: StateMachineNextAction::ErrorShutdown(format!(
+                                "requested StrictWithAbort matching and at idx {} we got RIP 0x{:x} instead of expected 0x{:x}",
+                                self.idx_next_rip, got_rip, want_rip
+                            )),
+                        });
+                    }
+                    SequenceMatchingStrategy::Scattered => (),
+                }
+            }
+
+            if self.idx_next_rip == self.rip_sequence.len() {
+                debug!("{}: observed requested sequence", MatchRipSequence::NAME);
+                return Ok(EventHandlerOutcome {
+                    pending_event: event.clone(),
+                    next_action: match self.shutdown_on_match {
+                        true => StateMachineNextAction::SHUTDOWN,
+                        false => StateMachineNextAction::NEXT,
+                    },
+                });
+            }
+        }
+    }
+
+    fn get_name(&self) -> &str {
+        MatchRipSequence::NAME
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, thread, time::Duration};
+
+    use super::*;
+    use crate::{
+        api::mock_kernel::{spawn_event_feeder, MockEvent},
+        event_handlers::ComposableHandlerChain,
+    };
+    use vm_server::assembly_target::page_ping_ponger::access_order;
+
+    #[test]
+    fn abort_while_handler_is_blocked() {
+        let (mut api, _kernel, abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::page_fault(0x1000)]);
+
+        //after consuming the first event, the handler blocks without timeout, waiting for a fault at 0x2000
+        let (result_sender, result_receiver) = channel();
+        let chain_thread = thread::spawn(move || {
+            let mut handler =
+                SkipUntilPageFaultSequence::new(vec![0x2000], SequenceMatchingStrategy::Scattered);
+            let result = ComposableHandlerChain::new(
+                api,
+                vec![&mut handler],
+                None,
+                None::<fn() -> anyhow::Result<()>>,
+                None,
+            )
+            .run();
+            result_sender.send(result.map(|_| ())).unwrap();
+        });
+
+        feeder.join().unwrap();
+        //give the handler time to consume the first event and to block on the next one
+        thread::sleep(Duration::from_millis(100));
+        abort.send(()).unwrap();
+        let result = result_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("handler chain did not return after abort");
+        assert!(matches!(result, Err(SevStepError::Aborted)));
+        chain_thread.join().unwrap();
+    }
+
+    fn step_to(rip: u64) -> MockEvent {
+        MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, rip)
+    }
+
+    /// Run `handler` on `events`. The handler must consume all of them
+    fn run_rip_matcher(
+        handler: &mut MatchRipSequence,
+        events: Vec<MockEvent>,
+    ) -> Result<EventHandlerOutcome, SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, events);
+        let event = api.block_untill_event(|| Ok(()), Some(Duration::from_secs(5)))?;
+        let outcome = handler.process(&event, &mut api, &mut HashMap::new());
+        feeder.join().unwrap();
+        outcome
+    }
+
+    fn pending_rip(outcome: &EventHandlerOutcome) -> Option<u64> {
+        match &outcome.pending_event {
+            Event::StepEvent(v) => v.get_register(vmsa_register_name_t::VRN_RIP),
+            Event::PageFaultEvent(_) => None,
+        }
+    }
+
+    #[test]
+    fn match_rip_sequence_contiguous() -> Result<(), SevStepError> {
+        let mut handler = MatchRipSequence::new(
+            vec![0x10, 0x14, 0x18],
+            SequenceMatchingStrategy::StrictWithReset,
+            false,
+        )?;
+        //interrupted attempt, then a restart right at an unexpected RIP
+        let events = vec![
+            step_to(0x10),
+            step_to(0x14),
+            step_to(0x30),
+            step_to(0x10),
+            step_to(0x10),
+            MockEvent::page_fault(0x1000),
+            step_to(0x14),
+            step_to(0x18),
+        ];
+        let outcome = run_rip_matcher(&mut handler, events)?;
+        assert!(matches!(outcome.next_action, StateMachineNextAction::NEXT));
+        assert_eq!(pending_rip(&outcome), Some(0x18));
+
+        let mut handler = MatchRipSequence::new(
+            vec![0x10, 0x14],
+            SequenceMatchingStrategy::StrictWithAbort,
+            false,
+        )?;
+        let outcome = run_rip_matcher(&mut handler, vec![step_to(0x10), step_to(0x30)])?;
+        assert!(matches!(
+            outcome.next_action,
+            StateMachineNextAction::ErrorShutdown(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn match_rip_sequence_rejects_empty_sequence() {
+        assert!(MatchRipSequence::new(vec![], SequenceMatchingStrategy::Scattered, false).is_err());
+    }
+
+    #[test]
+    fn match_rip_sequence_scattered() -> Result<(), SevStepError> {
+        let mut handler =
+            MatchRipSequence::new(vec![0x10, 0x18], SequenceMatchingStrategy::Scattered, true)?;
+        let events = vec![
+            step_to(0x10),
+            step_to(0x14),
+            MockEvent::step(0),
+            step_to(0x30),
+            step_to(0x18),
+        ];
+        let outcome = run_rip_matcher(&mut handler, events)?;
+        assert!(matches!(
+            outcome.next_action,
+            StateMachineNextAction::SHUTDOWN
+        ));
+        assert_eq!(pending_rip(&outcome), Some(0x18));
+        Ok(())
+    }
+
+    #[test]
+    fn scattered_matching_on_randomized_ping_pong() -> Result<(), SevStepError> {
+        let page_gpas = [0x1000, 0x2000];
+        //the regular ping pong pattern is not contiguous in the randomized order, but each
+        //pair of rounds contains one (first page, second page) subsequence
+        let events = access_order(8, Some(42))
+            .into_iter()
+            .map(|page| MockEvent::page_fault(page_gpas[page]))
+            .collect();
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut handler = SkipUntilPageFaultSequence::new(
+            vec![0x1000, 0x2000, 0x1000, 0x2000, 0x1000, 0x2000],
+            SequenceMatchingStrategy::Scattered,
+        );
+        let event = api.block_untill_event(|| Ok(()), Some(Duration::from_secs(5)))?;
+        let outcome = handler.process(&event, &mut api, &mut HashMap::new())?;
+        assert!(matches!(outcome.next_action, StateMachineNextAction::NEXT));
+
+        //drain the remaining events, so that the feeder terminates
+        loop {
+            api.ack_event();
+            match api.block_untill_event(|| Ok(()), Some(Duration::from_millis(500))) {
+                Ok(_) => (),
+                Err(SevStepError::Timeout) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        feeder.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn composable_chain_acks_all_but_pending_event() -> Result<(), SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let events = vec![
+            MockEvent::step(1),
+            MockEvent::page_fault(0x1000),
+            MockEvent::step(1),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        //the first handler acks the first two events and returns the third one as pending,
+        //the second handler consumes the pending event without acking it
+        let mut skip_two = SkipUntilNSingleSteps::new(2, None);
+        let mut skip_one = SkipUntilNSingleSteps::new(1, None);
+        let outcome = ComposableHandlerChain::new(
+            api,
+            vec![&mut skip_two, &mut skip_one],
+            None,
+            None::<fn() -> anyhow::Result<()>>,
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(outcome.ack_count, 2);
+        assert!(matches!(outcome.pending_event, Event::StepEvent(_)));
+        Ok(())
+    }
+}
@@ -1,10 +1,19 @@
 pub mod api;
+#[cfg(feature = "async")]
+pub mod async_events;
+pub mod cache;
+#[cfg(feature = "full")]
 pub mod config;
 pub mod cpufreq;
+#[cfg(feature = "full")]
 pub mod event_handlers;
-mod ioctls;
-mod raw_spinlock;
+pub mod sev_step_core;
+#[cfg(feature = "full")]
 pub mod single_stepper;
-pub mod types;
+#[cfg(feature = "full")]
 pub mod vm_setup_helpers;
+#[cfg(feature = "full")]
 pub mod vmserver_client;
+
+use sev_step_core::{ioctls, raw_spinlock};
+pub use sev_step_core::types;
@@ -0,0 +1,8 @@
+//! Minimal part of the library that only wraps the kernel interface: the types from the
+//! "linux/sev-step/sev-step.h" header, the ioctls and the spinlock protecting the shared memory.
+//!
+//! Does not depend on any of the heavy dependencies (`reqwest`, `vm_server`, `iced-x86`, ...)
+//! and is thus also available if the `full` feature is disabled.
+pub mod ioctls;
+pub mod raw_spinlock;
+pub mod types;
@@ -0,0 +1,169 @@
+//! Rust wrappers for the SEV STEP IOCTLs in the "include/uapi/linux/kvm.h" header of the Linux kernel
+//! The behavior of the IOCTLs is documented in the kernel header.
+//! Likewise, the argument structs are documented in "include/uapi/linux/sev-step/sev-step.h"
+//! See `environment.sh` script to look up the path to the currently used kernel headers
+use std::{fs::File, os::fd::AsRawFd};
+
+use crate::sev_step_core::types::{
+    sev_step_param_t, track_all_pages_t, track_page_param_t, usp_init_poll_api_t,
+};
+use nix::{self, errno::Errno, libc};
+
+/// Convert all status codes but `0` to an error value
+/// The `nix` crate only treats `-1` as an error which does not
+/// reflect the semantics of our ioctls
+fn map_result(r: nix::Result<nix::libc::c_int>) -> nix::Result<nix::libc::c_int> {
+    match r {
+        Ok(0) => Ok(0),
+        Ok(v) => Err(Errno::from_i32(v)),
+        Err(e) => Err(e),
+    }
+}
+mod internal {
+    use crate::sev_step_core::types::{
+        sev_step_param_t, track_all_pages_t, track_page_param_t, usp_init_poll_api_t,
+    };
+
+    const KVMIO: u8 = 0xAE;
+    // API Management
+
+    nix::ioctl_readwrite!(init_api, KVMIO, 0xf, usp_init_poll_api_t);
+    nix::ioctl_none!(close_api, KVMIO, 0x10);
+
+    // Page Tracking
+
+    nix::ioctl_readwrite!(track_page, KVMIO, 0xb, track_page_param_t);
+    nix::ioctl_readwrite!(track_all_pages, KVMIO, 0xc, track_all_pages_t);
+
+    nix::ioctl_readwrite!(untrack_all_pages, KVMIO, 0xd, track_all_pages_t);
+    nix::ioctl_readwrite!(untrack_page, KVMIO, 0xe, track_page_param_t);
+
+    // Single Stepping
+
+    nix::ioctl_readwrite!(start_stepping, KVMIO, 0x11, sev_step_param_t);
+    nix::ioctl_none!(stop_stepping, KVMIO, 0x12);
+
+    // Cache Attack
+
+    // Misc
+}
+
+/// Initialize the API connection, registering the shared memory region from `data`.
+/// There is no version handshake: neither `usp_init_poll_api_t` nor `shared_mem_region_t` carries a protocol
+/// version or capability field. Both are generated from the kernel headers, thus a field cannot be added on
+/// the user space side alone, as it would change the layout the kernel expects. The userland must be built
+/// against the headers of the running kernel, otherwise the shared memory may be misinterpreted
+/// # Safety
+/// `fd` must be an open handle to the kvm device and `data` must point to a valid, initialized struct
+pub unsafe fn init_api(
+    fd: libc::c_int,
+    data: *mut usp_init_poll_api_t,
+) -> nix::Result<libc::c_int> {
+    map_result(internal::init_api(fd, data))
+}
+
+/// Close the API connection
+/// # Safety
+/// `fd` must be an open handle to the kvm device
+pub unsafe fn close_api(fd: libc::c_int) -> nix::Result<libc::c_int> {
+    map_result(internal::close_api(fd))
+}
+
+/// Track the page described by `data`
+/// # Safety
+/// `fd` must be an open handle to the kvm device and `data` must point to a valid, initialized struct
+pub unsafe fn track_page(
+    fd: libc::c_int,
+    data: *mut track_page_param_t,
+) -> nix::Result<libc::c_int> {
+    map_result(internal::track_page(fd, data))
+}
+
+/// Track all pages with the mode from `data`
+/// # Safety
+/// `fd` must be an open handle to the kvm device and `data` must point to a valid, initialized struct
+pub unsafe fn track_all_pages(
+    fd: libc::c_int,
+    data: *mut track_all_pages_t,
+) -> nix::Result<libc::c_int> {
+    map_result(internal::track_all_pages(fd, data))
+}
+
+/// Untrack all pages with the mode from `data`
+/// # Safety
+/// `fd` must be an open handle to the kvm device and `data` must point to a valid, initialized struct
+pub unsafe fn untrack_all_pages(
+    fd: libc::c_int,
+    data: *mut track_all_pages_t,
+) -> nix::Result<libc::c_int> {
+    map_result(internal::untrack_all_pages(fd, data))
+}
+
+/// Untrack the page described by `data`
+/// # Safety
+/// `fd` must be an open handle to the kvm device and `data` must point to a valid, initialized struct
+pub unsafe fn untrack_page(
+    fd: libc::c_int,
+    data: *mut track_page_param_t,
+) -> nix::Result<libc::c_int> {
+    map_result(internal::untrack_page(fd, data))
+}
+
+/// Start single stepping with the parameters from `data`
+/// # Safety
+/// `fd` must be an open handle to the kvm device and `data` must point to a valid, initialized struct
+pub unsafe fn start_stepping(
+    fd: libc::c_int,
+    data: *mut sev_step_param_t,
+) -> nix::Result<libc::c_int> {
+    map_result(internal::start_stepping(fd, data))
+}
+
+/// Stop single stepping
+/// # Safety
+/// `fd` must be an open handle to the kvm device
+pub unsafe fn stop_stepping(fd: libc::c_int) -> nix::Result<libc::c_int> {
+    map_result(internal::stop_stepping(fd))
+}
+
+/// Issues the SEV STEP ioctls on an initialized API connection. Implemented by the kvm device file.
+/// Unit tests use a mock kernel instead, to exercise the user space logic without SEV-Step
+pub trait SevStepIoctls: Send {
+    fn close_api(&self) -> nix::Result<libc::c_int>;
+    fn track_page(&self, data: &mut track_page_param_t) -> nix::Result<libc::c_int>;
+    fn track_all_pages(&self, data: &mut track_all_pages_t) -> nix::Result<libc::c_int>;
+    fn untrack_all_pages(&self, data: &mut track_all_pages_t) -> nix::Result<libc::c_int>;
+    fn untrack_page(&self, data: &mut track_page_param_t) -> nix::Result<libc::c_int>;
+    fn start_stepping(&self, data: &mut sev_step_param_t) -> nix::Result<libc::c_int>;
+    fn stop_stepping(&self) -> nix::Result<libc::c_int>;
+}
+
+impl SevStepIoctls for File {
+    fn close_api(&self) -> nix::Result<libc::c_int> {
+        unsafe { close_api(self.as_raw_fd()) }
+    }
+
+    fn track_page(&self, data: &mut track_page_param_t) -> nix::Result<libc::c_int> {
+        unsafe { track_page(self.as_raw_fd(), data) }
+    }
+
+    fn track_all_pages(&self, data: &mut track_all_pages_t) -> nix::Result<libc::c_int> {
+        unsafe { track_all_pages(self.as_raw_fd(), data) }
+    }
+
+    fn untrack_all_pages(&self, data: &mut track_all_pages_t) -> nix::Result<libc::c_int> {
+        unsafe { untrack_all_pages(self.as_raw_fd(), data) }
+    }
+
+    fn untrack_page(&self, data: &mut track_page_param_t) -> nix::Result<libc::c_int> {
+        unsafe { untrack_page(self.as_raw_fd(), data) }
+    }
+
+    fn start_stepping(&self, data: &mut sev_step_param_t) -> nix::Result<libc::c_int> {
+        unsafe { start_stepping(self.as_raw_fd(), data) }
+    }
+
+    fn stop_stepping(&self) -> nix::Result<libc::c_int> {
+        unsafe { stop_stepping(self.as_raw_fd()) }
+    }
+}
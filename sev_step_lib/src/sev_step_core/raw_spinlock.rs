@@ -0,0 +1,145 @@
+//! Implementation of a simple spinlock whose definition is shared between he kernel
+//! and userspace part of the API to allow locking from both sides.
+//!
+//! The lock itself is just a simple integer. It must be initialized with [`init`](fn@init)
+//! before first usage.
+//!
+//! [`lock`](fn@lock) busy waits with a `pause` between attempts. [`SpinLock`] takes the same lock, but
+//! allows to configure how to wait, e.g. to yield the core on an oversubscribed host
+use std::{arch::global_asm, hint, thread};
+
+global_asm!(include_str!("raw_spinlock.s"));
+
+extern "C" {
+    /// Take the lock
+    fn raw_spinlock_lock(lock: &mut i32);
+    /// Try to take the lock once. Returns true on success
+    fn raw_spinlock_trylock(lock: &mut i32) -> bool;
+    /// Release the lock
+    fn raw_spinlock_unlock(lock: &mut i32);
+}
+
+/// Take the lock
+/// # Safety
+/// `lock` must be part of the shared memory region and must have been initialized with [`init`](fn@init)
+pub unsafe fn lock(lock: &mut i32) {
+    raw_spinlock_lock(lock);
+}
+/// Release the lock
+/// # Safety
+/// `lock` must be held by the caller
+pub unsafe fn unlock(lock: &mut i32) {
+    raw_spinlock_unlock(lock)
+}
+
+/// Initialize the lock
+pub fn init(lock: &mut i32) {
+    *lock = 1;
+}
+
+/// Waiting behavior of [`SpinLock`], based on the number of failed attempts to take the lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinPolicy {
+    /// Failed attempts after which [`std::hint::spin_loop`] is called before the next attempt.
+    /// Before, the lock is retried right away
+    pub spin_loop_after: usize,
+    /// Failed attempts after which the core is yielded with [`std::thread::yield_now`] before the next attempt
+    pub yield_after: usize,
+}
+
+impl Default for SpinPolicy {
+    /// Like [`lock`](fn@lock), pause before each retry and never yield
+    fn default() -> Self {
+        SpinPolicy {
+            spin_loop_after: 0,
+            yield_after: usize::MAX,
+        }
+    }
+}
+
+/// Spinlock with a configurable [`SpinPolicy`]. Uses the same atomic operations as [`lock`](fn@lock)
+/// and [`unlock`](fn@unlock), thus it may be used concurrently with them and with the kernel.
+/// Only the waiting behavior differs
+pub struct SpinLock<'a> {
+    lock: &'a mut i32,
+    policy: SpinPolicy,
+}
+
+impl<'a> SpinLock<'a> {
+    /// # Arguments
+    /// * `lock` : the raw lock, it must have been initialized with [`init`](fn@init)
+    /// * `policy` : how to wait while the lock is held by someone else
+    pub fn new(lock: &'a mut i32, policy: SpinPolicy) -> Self {
+        SpinLock { lock, policy }
+    }
+
+    /// Try to take the lock once. Returns true if the lock was taken
+    /// # Safety
+    /// Same as for [`lock`](fn@lock)
+    pub unsafe fn try_lock(&mut self) -> bool {
+        raw_spinlock_trylock(self.lock)
+    }
+
+    /// Take the lock, waiting according to the policy
+    /// # Safety
+    /// Same as for [`lock`](fn@lock)
+    pub unsafe fn lock(&mut self) {
+        let mut failed_attempts: usize = 0;
+        while !self.try_lock() {
+            failed_attempts = failed_attempts.saturating_add(1);
+            if failed_attempts >= self.policy.yield_after {
+                thread::yield_now();
+            } else if failed_attempts >= self.policy.spin_loop_after {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    /// Release the lock
+    /// # Safety
+    /// Same as for [`unlock`](fn@unlock)
+    pub unsafe fn unlock(&mut self) {
+        unlock(self.lock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spinlock_provides_mutual_exclusion() {
+        const THREADS: usize = 4;
+        const INCREMENTS: usize = 1_000;
+        //waiters yield, as the holder yields inside the critical section to provoke contention
+        let policy = SpinPolicy {
+            spin_loop_after: 1,
+            yield_after: 2,
+        };
+        //lock and counter are shared through their address, like the shared memory region
+        let mut shared = Box::new((0_i32, 0_usize));
+        init(&mut shared.0);
+        let addr = &mut *shared as *mut (i32, usize) as usize;
+
+        let workers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                thread::spawn(move || {
+                    let shared = unsafe { (addr as *mut (i32, usize)).as_mut().unwrap() };
+                    for _ in 0..INCREMENTS {
+                        let mut l = SpinLock::new(&mut shared.0, policy);
+                        unsafe { l.lock() };
+                        let v = unsafe { std::ptr::read_volatile(&shared.1) };
+                        thread::yield_now();
+                        unsafe { std::ptr::write_volatile(&mut shared.1, v + 1) };
+                        unsafe { l.unlock() };
+                    }
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().unwrap();
+        }
+        assert_eq!(shared.1, THREADS * INCREMENTS);
+        assert_eq!(shared.0, 1, "lock should be released");
+    }
+}
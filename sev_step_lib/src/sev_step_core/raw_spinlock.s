@@ -41,6 +41,29 @@ raw_spinlock_lock_success:
 	ret
 
 
+# Tries to acquire the given raw spinlock once, using the same atomic operations as raw_spinlock_lock.
+# Parameters:
+#     - Pointer to spinlock value.
+# Returns 1 if the lock was acquired and 0 if it is held by another thread
+.globl raw_spinlock_trylock
+raw_spinlock_trylock:
+
+	# Try to decrement spinlock value (atomic)
+	lock dec byte ptr [rdi]
+
+	# Value >= 0? -> Acquiring was successful
+	jns raw_spinlock_trylock_success
+
+	# Acquiring failed, reset value
+	lock inc byte ptr [rdi]
+	xor eax, eax
+	ret
+
+raw_spinlock_trylock_success:
+	mov eax, 1
+	ret
+
+
 # Releases the given raw spinlock.
 # Parameters:
 #     - Pointer to spinlock value.
@@ -0,0 +1,121 @@
+//! Rust wrappers for the types in the "linux/sev-step/sev-step.h" header.
+//! Autogenerated in build.rs
+#![allow(non_upper_case_globals)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+
+use std::{fmt::Display, mem, str::FromStr};
+
+use anyhow::bail;
+#[cfg(feature = "full")]
+use clap::ValueEnum;
+#[cfg(feature = "full")]
+use vm_server::assembly_target::page_ping_ponger::PagePingPongVariant;
+
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+impl usp_event_type_t {
+    /// Returns the size of the matching event type in bytes
+    pub fn event_bytes(&self) -> usize {
+        match self {
+            usp_event_type_t::PAGE_FAULT_EVENT => mem::size_of::<usp_page_fault_event_t>(),
+            usp_event_type_t::SEV_STEP_EVENT => mem::size_of::<sev_step_event_t>(),
+        }
+    }
+}
+
+#[cfg(feature = "full")]
+impl TryInto<PagePingPongVariant> for kvm_page_track_mode {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<PagePingPongVariant, Self::Error> {
+        match self {
+            kvm_page_track_mode::KVM_PAGE_TRACK_WRITE => Ok(PagePingPongVariant::WRITE),
+            kvm_page_track_mode::KVM_PAGE_TRACK_ACCESS => Ok(PagePingPongVariant::READ),
+            kvm_page_track_mode::KVM_PAGE_TRACK_EXEC => Ok(PagePingPongVariant::EXEC),
+            _ => bail!(format!("Cannot convert {:?} to PagePingPongVariant", &self)),
+        }
+    }
+}
+
+/// The subset of [`kvm_page_track_mode`] that is used for tracking pages. In contrast to the bindgen
+/// type, this can be parsed from and printed to strings, e.g. to pass it as a CLI argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "full", derive(ValueEnum))]
+pub enum TrackMode {
+    /// Fault on write accesses
+    Write,
+    /// Fault on any access
+    Access,
+    /// Fault on instruction fetches
+    Exec,
+}
+
+impl FromStr for TrackMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "write" => Ok(Self::Write),
+            "access" => Ok(Self::Access),
+            "exec" => Ok(Self::Exec),
+            _ => Err("invalid TrackMode value"),
+        }
+    }
+}
+
+impl Display for TrackMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackMode::Write => write!(f, "write"),
+            TrackMode::Access => write!(f, "access"),
+            TrackMode::Exec => write!(f, "exec"),
+        }
+    }
+}
+
+impl From<TrackMode> for kvm_page_track_mode {
+    fn from(value: TrackMode) -> Self {
+        match value {
+            TrackMode::Write => kvm_page_track_mode::KVM_PAGE_TRACK_WRITE,
+            TrackMode::Access => kvm_page_track_mode::KVM_PAGE_TRACK_ACCESS,
+            TrackMode::Exec => kvm_page_track_mode::KVM_PAGE_TRACK_EXEC,
+        }
+    }
+}
+
+impl TryFrom<kvm_page_track_mode> for TrackMode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: kvm_page_track_mode) -> Result<Self, Self::Error> {
+        match value {
+            kvm_page_track_mode::KVM_PAGE_TRACK_WRITE => Ok(TrackMode::Write),
+            kvm_page_track_mode::KVM_PAGE_TRACK_ACCESS => Ok(TrackMode::Access),
+            kvm_page_track_mode::KVM_PAGE_TRACK_EXEC => Ok(TrackMode::Exec),
+            _ => bail!(format!("Cannot convert {:?} to TrackMode", &value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_mode_string_round_trip() {
+        for mode in [TrackMode::Write, TrackMode::Access, TrackMode::Exec] {
+            assert_eq!(mode.to_string().parse::<TrackMode>(), Ok(mode));
+        }
+        assert_eq!("Exec".parse::<TrackMode>(), Ok(TrackMode::Exec));
+        assert!("reset_exec".parse::<TrackMode>().is_err());
+    }
+
+    #[test]
+    fn track_mode_bindgen_conversions() {
+        for mode in [TrackMode::Write, TrackMode::Access, TrackMode::Exec] {
+            let raw: kvm_page_track_mode = mode.into();
+            assert_eq!(TrackMode::try_from(raw).unwrap(), mode);
+        }
+        assert!(TrackMode::try_from(kvm_page_track_mode::KVM_PAGE_TRACK_RESET_EXEC).is_err());
+    }
+}
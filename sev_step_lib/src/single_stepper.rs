@@ -4,17 +4,28 @@
 //! that they can be chained together, to achieve more complex behavior
 //! The [`TargetedStepper`](struct@TargetedStepper) can be used to "execute" a chain of event handlers.
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
-    time::Duration,
+    fs::{self, File},
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    api::{Event, SevStep, SevStepError},
+    api::{Event, RegisterSnapshot, RetryableTriggerError, SevStep, SevStepError},
     types::*,
 };
 use anyhow::{anyhow, bail, Context, Result};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use vm_server::req_resp::InitAssemblyTargetResp;
 
 pub enum StateMachineNextAction {
     ///continue with next handler in chain
@@ -34,6 +45,34 @@ pub trait EventHandler {
         ctx: &mut HashMap<String, Vec<u8>>,
     ) -> Result<StateMachineNextAction>;
     fn get_name(&self) -> &str;
+    /// Called by [`TargetedStepper`] once a handler requested [`StateMachineNextAction::SHUTDOWN`],
+    /// before the run returns. Called for every handler in the chain, in order. Allows handlers to check
+    /// their accumulated state or to flush their output. An error fails the run
+    fn on_finish(&mut self, _api: &mut SevStep, _ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        Ok(())
+    }
+    /// Static checks of the handler configuration, used by [`TargetedStepper::validate`].
+    /// Returns a description of each problem. Must not access the API
+    fn validate(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Clears the state accumulated during one run of the victim. Called by handlers that repeat
+    /// the victim within a single [`TargetedStepper`] run, like [`AutoRetrigger`], after
+    /// [`Self::on_finish`]. Handlers that keep counters or recordings must override it
+    fn reset(&mut self) {}
+}
+
+/// Returns a problem description for each GPA in `gpas` that is not page aligned
+fn unaligned_gpa_problems<'a>(gpas: impl IntoIterator<Item = &'a u64>) -> Vec<String> {
+    let mut unaligned = gpas
+        .into_iter()
+        .filter(|v| *v & 0xfff != 0)
+        .collect::<Vec<_>>();
+    unaligned.sort();
+    unaligned
+        .into_iter()
+        .map(|v| format!("GPA 0x{:x} is not page aligned", v))
+        .collect()
 }
 
 /// Tracks a set of GPAs with the given track mode.
@@ -127,20 +166,43 @@ impl EventHandler for RetrackGPASet {
         }
     }
 
+    fn validate(&self) -> Vec<String> {
+        unaligned_gpa_problems(&self.gpas)
+    }
+
+    fn reset(&mut self) {
+        self.gpa_for_retrack = None;
+        self.iteration_count = 0;
+    }
+
     fn get_name(&self) -> &str {
         &self.name
     }
 }
 
+/// Maps target GPAs to the APIC timer value that should be used when single stepping is started on them
+pub type PerPageTimer = HashMap<u64, u32>;
+
 pub struct SkipIfNotOnTargetGPAs {
     on_victim_pages: bool,
     target_gpas: HashSet<u64>,
     track_mode: kvm_page_track_mode,
     timer_value: u32,
+    per_page_timer: PerPageTimer,
+    ///Number of step events to discard after each start of single stepping
+    warmup_steps: usize,
+    ///Step events that still have to be discarded in the current stepping session
+    remaining_warmup_steps: usize,
+    record_entry_rip: bool,
+    ///RIP of the page fault on each entry of the target pages, if `record_entry_rip` is set
+    entry_rips: Vec<u64>,
     name: String,
 }
 
 impl SkipIfNotOnTargetGPAs {
+    const CK_STEPPING_ACTIVE: &'static str = "SkipIfNotOnTargetGPAs_Stepping_Active";
+    const CK_ENTRY_RIP: &'static str = "SkipIfNotOnTargetGPAs_Entry_RIP";
+
     pub fn new(
         target_gpas: &[u64],
         track_mode: kvm_page_track_mode,
@@ -151,9 +213,90 @@ impl SkipIfNotOnTargetGPAs {
             target_gpas: HashSet::from_iter(target_gpas.iter().cloned()),
             track_mode,
             timer_value,
+            per_page_timer: PerPageTimer::new(),
+            warmup_steps: 0,
+            remaining_warmup_steps: 0,
+            record_entry_rip: false,
+            entry_rips: Vec::new(),
             name: "SkipIfNotOnTargetGPAs".to_string(),
         }
     }
+
+    /// Number of instructions on the target pages that are executed before the first step event with a
+    /// RIP on the target pages.
+    ///
+    /// Entering the target pages is detected by the page fault on the first instruction. The handler
+    /// then starts single stepping and the VM resumes by re-executing the faulting instruction. The
+    /// register values of step events are captured after the step, thus the first step event already
+    /// reports the RIP of the second instruction. The first instruction is "consumed" by the page fault
+    /// logic in the sense that its RIP never shows up in a step event
+    pub const INSTRUCTIONS_CONSUMED_ON_ENTRY: usize = 1;
+
+    /// See [`Self::INSTRUCTIONS_CONSUMED_ON_ENTRY`]
+    pub fn instructions_consumed_on_entry() -> usize {
+        Self::INSTRUCTIONS_CONSUMED_ON_ENTRY
+    }
+
+    /// Use a dedicated APIC timer value when entering the victim pages via one of the GPAs in
+    /// `per_page_timer`. Since all target pages are untracked while single stepping, the timer is
+    /// selected by the page on which the victim pages are entered. Other pages use the default timer value
+    pub fn with_per_page_timer(mut self, per_page_timer: PerPageTimer) -> Self {
+        self.per_page_timer = per_page_timer;
+        self
+    }
+
+    /// Discard the first `warmup_steps` step events after each start of single stepping, i.e. each time the
+    /// victim pages are entered, by returning [`StateMachineNextAction::SKIP`] for them. On some hardware,
+    /// the first steps after arming the APIC timer are unreliable. N.B. that downstream handlers do not see
+    /// the discarded steps. Thus, step budgets and expected RIP values of e.g. [`StopAfterNSingleStepsHandler`]
+    /// only account for the steps after the warmup
+    pub fn with_warmup_steps(mut self, warmup_steps: usize) -> Self {
+        self.warmup_steps = warmup_steps;
+        self
+    }
+
+    /// Record the RIP of the page fault that enters the target pages, i.e. the RIP of the instruction that is
+    /// consumed by the page fault logic (see [`Self::INSTRUCTIONS_CONSUMED_ON_ENTRY`]). Prepending it to the RIPs
+    /// of the following step events gives a complete trace. Requires the VM to run in debug mode
+    pub fn with_entry_rip(mut self, record_entry_rip: bool) -> Self {
+        self.record_entry_rip = record_entry_rip;
+        self
+    }
+
+    /// RIPs of the page faults that entered the target pages, one per entry. Only recorded if enabled with
+    /// [`Self::with_entry_rip`]
+    pub fn get_entry_rips(&self) -> &[u64] {
+        &self.entry_rips
+    }
+
+    ///Returns the RIP of the page fault that entered the target pages most recently, for handlers further down
+    /// the chain. None if no entry has been recorded yet. See [`Self::with_entry_rip`]
+    pub fn entry_rip_from_ctx(ctx: &HashMap<String, Vec<u8>>) -> Result<Option<u64>> {
+        let serialized_data = match ctx.get(Self::CK_ENTRY_RIP) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        bincode::deserialize(serialized_data)
+            .map(Some)
+            .map_err(|e| anyhow!("failed to deserialize : {:?}", e))
+    }
+
+    ///Returns true if the handler has started single stepping on the target pages and not yet stopped it.
+    /// False if the handler has not processed any page fault yet
+    pub fn is_stepping_from_ctx(ctx: &HashMap<String, Vec<u8>>) -> Result<bool> {
+        let serialized_data = match ctx.get(Self::CK_STEPPING_ACTIVE) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        bincode::deserialize(serialized_data)
+            .map_err(|e| anyhow!("failed to deserialize : {:?}", e))
+    }
+
+    fn update_stepping_in_ctx(stepping: bool, ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        let serialized_data = bincode::serialize(&stepping)?;
+        ctx.insert(String::from(Self::CK_STEPPING_ACTIVE), serialized_data);
+        Ok(())
+    }
 }
 
 impl EventHandler for SkipIfNotOnTargetGPAs {
@@ -161,10 +304,18 @@ impl EventHandler for SkipIfNotOnTargetGPAs {
         &mut self,
         event: &Event,
         api: &mut SevStep,
-        _ctx: &mut HashMap<String, Vec<u8>>,
+        ctx: &mut HashMap<String, Vec<u8>>,
     ) -> Result<StateMachineNextAction> {
         let event = match event {
             Event::PageFaultEvent(v) => v,
+            Event::StepEvent(_) if self.remaining_warmup_steps > 0 => {
+                self.remaining_warmup_steps -= 1;
+                debug!(
+                    "discarding warmup step, {} remaining",
+                    self.remaining_warmup_steps
+                );
+                return Ok(StateMachineNextAction::SKIP);
+            }
             Event::StepEvent(_) => return Ok(StateMachineNextAction::NEXT),
         };
 
@@ -196,8 +347,26 @@ impl EventHandler for SkipIfNotOnTargetGPAs {
                         .with_context(|| format!("Failed to un-track GPA 0x:{:x}", x))?;
                 }
 
+                let timer_value = *self
+                    .per_page_timer
+                    .get(&event.faulted_gpa)
+                    .unwrap_or(&self.timer_value);
+                debug!(
+                    "Using timer value 0x{:x} for GPA 0x{:x}",
+                    timer_value, event.faulted_gpa
+                );
                 let mut gpas = self.target_gpas.iter().copied().collect::<Vec<u64>>();
-                api.start_stepping(self.timer_value, &mut gpas, true)?;
+                api.start_stepping(timer_value, &mut gpas, true)?;
+                self.remaining_warmup_steps = self.warmup_steps;
+
+                if self.record_entry_rip {
+                    let rip = event.get_register(vmsa_register_name_t::VRN_RIP).ok_or(anyhow!(
+                        "failed to get RIP of the entering page fault, is the VM running in debug mode?"
+                    ))?;
+                    debug!("Entered victim pages at RIP 0x{:x}", rip);
+                    self.entry_rips.push(rip);
+                    ctx.insert(String::from(Self::CK_ENTRY_RIP), bincode::serialize(&rip)?);
+                }
 
                 self.on_victim_pages = true;
             } else {
@@ -207,6 +376,7 @@ impl EventHandler for SkipIfNotOnTargetGPAs {
                 );
             }
         }
+        Self::update_stepping_in_ctx(self.on_victim_pages, ctx)?;
 
         if self.on_victim_pages {
             Ok(StateMachineNextAction::NEXT)
@@ -215,6 +385,135 @@ impl EventHandler for SkipIfNotOnTargetGPAs {
         }
     }
 
+    fn validate(&self) -> Vec<String> {
+        let mut problems = unaligned_gpa_problems(&self.target_gpas);
+        if self.target_gpas.is_empty() {
+            problems.push("no target GPAs, single stepping is never started".to_string());
+        }
+        problems
+    }
+
+    fn reset(&mut self) {
+        self.on_victim_pages = false;
+        self.remaining_warmup_steps = 0;
+        self.entry_rips.clear();
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Holds back all events until the victim has entered the target GPAs `required_faults` times, e.g. to skip
+/// setup iterations before arming single stepping. Place it in front of the handler that arms single stepping,
+/// like [`SkipIfNotOnTargetGPAs`].
+///
+/// Earlier events return [`StateMachineNextAction::SKIP`]. To observe the next entry, the handler tracks all but
+/// the target GPAs when the victim enters them and re-tracks only the target GPAs once it leaves them again.
+/// The fault of the `required_faults`-th entry and all subsequent events return [`StateMachineNextAction::NEXT`].
+/// At that point, only the target GPAs are tracked, as expected by [`SkipIfNotOnTargetGPAs`].
+/// Assumes the target GPAs are initially tracked
+pub struct WaitForNFaultsThenAllow {
+    target_gpas: HashSet<u64>,
+    track_mode: kvm_page_track_mode,
+    required_faults: usize,
+    observed_faults: usize,
+    on_target_pages: bool,
+    name: String,
+}
+
+impl WaitForNFaultsThenAllow {
+    /// # Arguments
+    /// * `target_gpas` : GPAs whose page faults are counted
+    /// * `track_mode` : tracking mode used to detect when the victim enters and leaves the target GPAs
+    /// * `required_faults` : number of page faults on the target GPAs until events are forwarded
+    pub fn new(
+        target_gpas: &[u64],
+        track_mode: kvm_page_track_mode,
+        required_faults: usize,
+    ) -> Self {
+        WaitForNFaultsThenAllow {
+            target_gpas: HashSet::from_iter(target_gpas.iter().cloned()),
+            track_mode,
+            required_faults,
+            observed_faults: 0,
+            on_target_pages: false,
+            name: "WaitForNFaultsThenAllow".to_string(),
+        }
+    }
+
+    /// Number of page faults on the target GPAs observed so far
+    pub fn observed_faults(&self) -> usize {
+        self.observed_faults
+    }
+
+    fn is_allowed(&self) -> bool {
+        self.observed_faults >= self.required_faults
+    }
+}
+
+impl EventHandler for WaitForNFaultsThenAllow {
+    fn process(
+        &mut self,
+        event: &Event,
+        api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        if self.is_allowed() {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+        let event = match event {
+            Event::PageFaultEvent(v) => v,
+            Event::StepEvent(_) => return Ok(StateMachineNextAction::SKIP),
+        };
+
+        if self.target_gpas.contains(&event.faulted_gpa) {
+            self.observed_faults += 1;
+            if self.is_allowed() {
+                debug!(
+                    "Observed fault {} on target GPA 0x{:x}, forwarding events",
+                    self.observed_faults, event.faulted_gpa
+                );
+                return Ok(StateMachineNextAction::NEXT);
+            }
+            debug!(
+                "Skipping fault {}/{} on target GPA 0x{:x}",
+                self.observed_faults, self.required_faults, event.faulted_gpa
+            );
+            api.track_all_pages(self.track_mode)?;
+            for x in &self.target_gpas {
+                api.untrack_page(*x, self.track_mode)
+                    .with_context(|| format!("Failed to un-track GPA 0x{:x}", x))?;
+            }
+            self.on_target_pages = true;
+        } else if self.on_target_pages {
+            debug!(
+                "Left target GPAs with fault at GPA 0x{:x}. Re-tracking target GPAs",
+                event.faulted_gpa
+            );
+            api.untrack_all_pages(self.track_mode)?;
+            for x in &self.target_gpas {
+                api.track_page(*x, self.track_mode)
+                    .with_context(|| format!("Failed to re-track target GPA 0x{:x}", x))?;
+            }
+            self.on_target_pages = false;
+        }
+        Ok(StateMachineNextAction::SKIP)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = unaligned_gpa_problems(&self.target_gpas);
+        if self.target_gpas.is_empty() && self.required_faults > 0 {
+            problems.push("no target GPAs, events are never forwarded".to_string());
+        }
+        problems
+    }
+
+    fn reset(&mut self) {
+        self.observed_faults = 0;
+        self.on_target_pages = false;
+    }
+
     fn get_name(&self) -> &str {
         &self.name
     }
@@ -223,21 +522,62 @@ impl EventHandler for SkipIfNotOnTargetGPAs {
 pub struct BuildStepHistogram {
     step_histogram: HashMap<u64, u64>,
     event_counter: usize,
+    snapshots: Option<HistogramSnapshots>,
     name: String,
 }
 
+/// Configuration for [`BuildStepHistogram::with_snapshots`]
+struct HistogramSnapshots {
+    path: PathBuf,
+    every_n_events: usize,
+}
+
 impl BuildStepHistogram {
     pub fn new() -> Self {
         BuildStepHistogram {
             step_histogram: HashMap::new(),
             event_counter: 0,
+            snapshots: None,
             name: "BuildStepHistogram".to_string(),
         }
     }
+
+    /// Every `every_n_events` step events, and once the run finishes, write the current histogram as JSON to `path`.
+    /// The file is replaced atomically, thus it can be watched while the run is in progress.
+    /// Failing to write a snapshot only logs a warning
+    pub fn with_snapshots(mut self, path: impl Into<PathBuf>, every_n_events: usize) -> Self {
+        self.snapshots = Some(HistogramSnapshots {
+            path: path.into(),
+            every_n_events,
+        });
+        self
+    }
+
     ///Returns HapMap, that maps encountered step sizes to their occurrence count
     pub fn get_values(&self) -> &HashMap<u64, u64> {
         &self.step_histogram
     }
+
+    /// Write the current histogram to a temporary file next to `path` and rename it to `path`
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let sorted: BTreeMap<_, _> = self.step_histogram.iter().collect();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let f = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        serde_json::to_writer(f, &sorted).context("failed to serialize histogram")?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to rename snapshot to {}", path.display()))
+    }
+
+    fn try_write_snapshot(&self) {
+        if let Some(snapshots) = &self.snapshots {
+            if let Err(e) = self.write_snapshot(&snapshots.path) {
+                warn!("failed to write histogram snapshot: {:?}", e);
+            }
+        }
+    }
 }
 
 impl Display for BuildStepHistogram {
@@ -265,9 +605,34 @@ impl EventHandler for BuildStepHistogram {
             .or_insert(0)) += 1;
         self.event_counter += 1;
 
+        if let Some(snapshots) = &self.snapshots {
+            if self.event_counter.is_multiple_of(snapshots.every_n_events) {
+                self.try_write_snapshot();
+            }
+        }
+
         Ok(StateMachineNextAction::NEXT)
     }
 
+    fn on_finish(&mut self, _api: &mut SevStep, _ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        self.try_write_snapshot();
+        Ok(())
+    }
+
+    fn validate(&self) -> Vec<String> {
+        match &self.snapshots {
+            Some(v) if v.every_n_events == 0 => {
+                vec!["snapshot interval must be at least one event".to_string()]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.step_histogram.clear();
+        self.event_counter = 0;
+    }
+
     fn get_name(&self) -> &str {
         &self.name
     }
@@ -327,16 +692,32 @@ where
         Ok(StateMachineNextAction::NEXT)
     }
 
+    fn reset(&mut self) {
+        self.step_counter = 0;
+    }
+
     fn get_name(&self) -> &str {
         &self.name
     }
 }
 
+/// Deviation from the expected RIP values of [`StopAfterNSingleStepsHandler`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RipMismatch {
+    ///One based index of the single step
+    pub step: usize,
+    pub expected: u64,
+    pub got: u64,
+}
+
 pub struct StopAfterNSingleStepsHandler {
     step_counter: usize,
     abort_thresh: usize,
     name: String,
     expected_rip_values: Option<Vec<u64>>,
+    ///If true, RIP mismatches are recorded in `rip_mismatches` instead of aborting the run
+    record_all_mismatches: bool,
+    rip_mismatches: Vec<RipMismatch>,
 }
 
 impl StopAfterNSingleStepsHandler {
@@ -353,9 +734,25 @@ impl StopAfterNSingleStepsHandler {
             abort_thresh: n,
             name: "StopAfterNStepsHandler".to_string(),
             expected_rip_values,
+            record_all_mismatches: false,
+            rip_mismatches: Vec::new(),
         }
     }
 
+    /// If `record_all_mismatches` is true, a RIP that does not match the expected RIP values does not abort
+    /// the run. Instead, all mismatches are recorded and the run only fails in [`EventHandler::on_finish`].
+    /// Use this to see how far the execution diverged from the expected one
+    pub fn with_record_all_mismatches(mut self, record_all_mismatches: bool) -> Self {
+        self.record_all_mismatches = record_all_mismatches;
+        self
+    }
+
+    /// Mismatches between the observed and the expected RIP values, in the order in which they occurred.
+    /// Only populated if [`Self::with_record_all_mismatches`] is enabled
+    pub fn get_rip_mismatches(&self) -> &[RipMismatch] {
+        &self.rip_mismatches
+    }
+
     pub fn get_step_counter_from_ctx(ctx: &HashMap<String, Vec<u8>>) -> Result<usize> {
         let serialized_data = match ctx.get(Self::CK_STEPS) {
             Some(v) => v,
@@ -407,7 +804,19 @@ impl EventHandler for StopAfterNSingleStepsHandler {
                         "failed to get RIP to compare against expected rip values"
                     ))?;
                 let want_rip = exepcted_rip_values[self.step_counter];
-                if want_rip != got_rip {
+                if want_rip != got_rip && self.record_all_mismatches {
+                    warn!(
+                        "at step {}, expected RIP 0x{:x} got 0x{:x}",
+                        self.step_counter + 1,
+                        want_rip,
+                        got_rip,
+                    );
+                    self.rip_mismatches.push(RipMismatch {
+                        step: self.step_counter + 1,
+                        expected: want_rip,
+                        got: got_rip,
+                    });
+                } else if want_rip != got_rip {
                     bail!(
                         "at step {}, expected RIP 0x{:x} got 0x{:x}",
                         self.step_counter + 1,
@@ -430,97 +839,3672 @@ impl EventHandler for StopAfterNSingleStepsHandler {
         Ok(StateMachineNextAction::NEXT)
     }
 
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.abort_thresh == 0 {
+            problems.push("step budget is 0, the run stops at the first step".to_string());
+        }
+        if let Some(v) = &self.expected_rip_values {
+            if v.is_empty() {
+                problems.push("expected RIP values are set but empty".to_string());
+            }
+        }
+        problems
+    }
+
+    fn reset(&mut self) {
+        self.step_counter = 0;
+        self.rip_mismatches.clear();
+    }
+
+    fn on_finish(&mut self, _api: &mut SevStep, _ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        if !self.rip_mismatches.is_empty() {
+            let report: Vec<String> = self
+                .rip_mismatches
+                .iter()
+                .map(|v| {
+                    format!(
+                        "step {}: expected 0x{:x} got 0x{:x}",
+                        v.step, v.expected, v.got
+                    )
+                })
+                .collect();
+            bail!(
+                "{} RIP mismatches: {}",
+                self.rip_mismatches.len(),
+                report.join(", ")
+            );
+        }
+        Ok(())
+    }
+
     fn get_name(&self) -> &str {
         &self.name
     }
 }
 
-pub struct TargetedStepper<'a, F>
-where
-    F: FnOnce() -> Result<()>,
-    F: Send + 'static,
-{
-    api: SevStep<'a>,
-    handler_chain: Vec<&'a mut dyn EventHandler>,
-    track_mode: kvm_page_track_mode,
-    initially_tracked_gpas: Vec<u64>,
-    target_trigger: F,
-    timeout: Option<Duration>,
+/// Requests shutdown once RIP leaves the address range `[start, end)`, e.g. when the victim
+/// returns past its final `ret`. Requires the VM to run in debug mode
+pub struct StopWhenRipLeavesRange {
+    start: u64,
+    end: u64,
+    step_counter: usize,
+    name: String,
 }
 
-impl<'a, F> TargetedStepper<'a, F>
-where
-    F: FnOnce() -> Result<()>,
-    F: Send + 'static,
-{
-    pub fn new(
-        api: SevStep<'a>,
-        handler_chain: Vec<&'a mut dyn EventHandler>,
-        initial_track_mode: kvm_page_track_mode,
-        initially_tracked_gpas: Vec<u64>,
-        target_trigger: F,
-        timeout: Option<Duration>,
-    ) -> TargetedStepper<'a, F> {
-        TargetedStepper {
-            api,
-            handler_chain,
-            track_mode: initial_track_mode,
-            initially_tracked_gpas,
-            target_trigger,
-            timeout,
+impl StopWhenRipLeavesRange {
+    /// # Arguments
+    /// * `start` : first address of the range, e.g. the vaddr of the victim code
+    /// * `end` : first address after the range
+    pub fn new(start: u64, end: u64) -> Self {
+        StopWhenRipLeavesRange {
+            start,
+            end,
+            step_counter: 0,
+            name: "StopWhenRipLeavesRange".to_string(),
         }
     }
 
-    pub fn run(mut self) -> Result<(), SevStepError> {
-        debug!("Performing initial tracking");
-        for x in self.initially_tracked_gpas {
-            self.api
-                .track_page(x, self.track_mode)
-                .context(format!("failed to track 0x{:x}", x))?;
-            debug!("Tracking 0x{:x} with {:?}", x, self.track_mode);
-        }
+    /// Number of non-zero steps observed, including the step that left the range
+    pub fn get_step_counter(&self) -> usize {
+        self.step_counter
+    }
+}
 
-        let mut ctx = HashMap::new();
-        info!("entering main event loop");
+impl EventHandler for StopWhenRipLeavesRange {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let event = match event {
+            Event::PageFaultEvent(_) => return Ok(StateMachineNextAction::NEXT),
+            Event::StepEvent(v) => v,
+        };
 
-        //for the first event, trigger the target
-        let mut event = self
-            .api
-            .block_untill_event(self.target_trigger, self.timeout)?;
-        loop {
-            debug!("Got Event {:X?}", event);
-            for handler in &mut self.handler_chain {
-                debug!("Running handler {}", handler.get_name());
-                match handler.process(&event, &mut self.api, &mut ctx)? {
-                    StateMachineNextAction::NEXT => {
-                        debug!("NEXT");
-                    }
-                    StateMachineNextAction::SKIP => {
-                        debug!("SKIP");
-                        self.api.ack_event();
-                        break;
-                    }
-                    StateMachineNextAction::SHUTDOWN => {
-                        debug!("SHUTDOWN");
-                        self.api.ack_event();
-                        info!("Left main event loop");
-                        return Ok(());
-                    }
-                    StateMachineNextAction::ErrorShutdown(message) => {
-                        error!("ERROR_SHUTDOWN with message={}", message);
-                        return Err(anyhow!(
-                            "logic error in handler {} : {}",
-                            handler.get_name(),
-                            message
-                        )
-                        .into());
-                    }
-                }
-            }
-            self.api.ack_event();
+        //zero steps do not change RIP
+        if event.retired_instructions == 0 {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+        self.step_counter += 1;
 
-            //N.B. that we use an empty/NOP trigger now
-            event = self.api.block_untill_event(|| Ok(()), self.timeout)?;
+        let rip = event
+            .get_register(vmsa_register_name_t::VRN_RIP)
+            .ok_or(anyhow!(
+                "failed to get RIP, is the VM running in debug mode?"
+            ))?;
+        if rip < self.start || rip >= self.end {
+            debug!(
+                "RIP 0x{:x} left [0x{:x},0x{:x}) at step {}",
+                rip, self.start, self.end, self.step_counter
+            );
+            return Ok(StateMachineNextAction::SHUTDOWN);
         }
+
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.step_counter = 0;
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Registers modified by a single instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterDelta {
+    /// RIP of the instruction that was executed
+    pub rip: u64,
+    /// `(register, old value, new value)` for all registers besides RIP, whose value changed
+    pub changes: Vec<(vmsa_register_name_t, u64, u64)>,
+}
+
+/// Records which registers got modified by each single stepped instruction, by diffing the register
+/// file of consecutive events. Requires the VM to run in debug mode
+pub struct RegisterDeltaRecorder {
+    previous: Option<RegisterSnapshot>,
+    deltas: Vec<RegisterDelta>,
+    name: String,
+}
+
+impl RegisterDeltaRecorder {
+    pub fn new() -> Self {
+        RegisterDeltaRecorder {
+            previous: None,
+            deltas: Vec::new(),
+            name: "RegisterDeltaRecorder".to_string(),
+        }
+    }
+
+    /// One entry for each single step, in execution order. The first observed step only serves
+    /// as a baseline, unless it was preceded by a page fault event
+    pub fn get_deltas(&self) -> &Vec<RegisterDelta> {
+        &self.deltas
+    }
+}
+
+impl Default for RegisterDeltaRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHandler for RegisterDeltaRecorder {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let event = match event {
+            Event::PageFaultEvent(v) => {
+                //instructions outside of the stepped pages were not observed, thus start a new baseline
+                self.previous = v.get_register_snapshot();
+                return Ok(StateMachineNextAction::NEXT);
+            }
+            Event::StepEvent(v) => v,
+        };
+
+        if event.retired_instructions == 0 {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+        if event.retired_instructions > 1 {
+            bail!(
+                "cannot attribute register changes to a single instruction for step size {}",
+                event.retired_instructions
+            );
+        }
+
+        let current = event.get_register_snapshot().ok_or(anyhow!(
+            "failed to get register snapshot, is the VM running in debug mode?"
+        ))?;
+        if let Some(previous) = &self.previous {
+            let changes = previous
+                .diff(&current)
+                .into_iter()
+                .filter(|(name, _, _)| *name != vmsa_register_name_t::VRN_RIP)
+                .collect();
+            self.deltas.push(RegisterDelta {
+                rip: previous.get(vmsa_register_name_t::VRN_RIP),
+                changes,
+            });
+        }
+        self.previous = Some(current);
+
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.previous = None;
+        self.deltas.clear();
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Records RSP after each single step, e.g. to infer the call depth or stack usage of the victim.
+/// Requires the VM to run in debug mode
+pub struct RecordRspTrace {
+    trace: Vec<u64>,
+    name: String,
+}
+
+impl RecordRspTrace {
+    pub fn new() -> Self {
+        RecordRspTrace {
+            trace: Vec::new(),
+            name: "RecordRspTrace".to_string(),
+        }
+    }
+
+    /// RSP values in execution order. Zero steps are not recorded
+    pub fn get_trace(&self) -> &Vec<u64> {
+        &self.trace
+    }
+
+    /// Lowest recorded RSP, i.e. the deepest point of the stack
+    pub fn get_min(&self) -> Option<u64> {
+        self.trace.iter().copied().min()
+    }
+
+    /// Highest recorded RSP
+    pub fn get_max(&self) -> Option<u64> {
+        self.trace.iter().copied().max()
+    }
+}
+
+impl Default for RecordRspTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHandler for RecordRspTrace {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let event = match event {
+            Event::PageFaultEvent(_) => return Ok(StateMachineNextAction::NEXT),
+            Event::StepEvent(v) => v,
+        };
+
+        if event.retired_instructions == 0 {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+
+        let rsp = event
+            .get_register(vmsa_register_name_t::VRN_RSP)
+            .ok_or(anyhow!(
+                "failed to get RSP, is the VM running in debug mode?"
+            ))?;
+        self.trace.push(rsp);
+
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.trace.clear();
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Maps the RIP after each single step to the basic block of the victim that contains it and records
+/// the sequence of visited blocks. Consecutive steps inside the same block are collapsed into a single
+/// entry, which gives a much more compact trace than raw RIP values. Requires the VM to run in debug mode
+pub struct RecordBasicBlockTrace {
+    ///maps the start of each block to its end (exclusive) and its id
+    blocks: BTreeMap<u64, (u64, usize)>,
+    trace: Vec<usize>,
+    unmapped_steps: usize,
+    name: String,
+}
+
+impl RecordBasicBlockTrace {
+    /// # Arguments
+    /// * `blocks` : RIP ranges of the victim's basic blocks together with their ids. Ranges must not overlap
+    pub fn new(blocks: Vec<(Range<u64>, usize)>) -> Result<Self> {
+        let mut block_map: BTreeMap<u64, (u64, usize)> = BTreeMap::new();
+        for (range, id) in blocks {
+            if range.is_empty() {
+                bail!(
+                    "range 0x{:x}..0x{:x} of block {} is empty",
+                    range.start,
+                    range.end,
+                    id
+                );
+            }
+            let overlaps_prev = block_map
+                .range(..range.end)
+                .next_back()
+                .is_some_and(|(_, (end, _))| *end > range.start);
+            if overlaps_prev {
+                bail!(
+                    "range 0x{:x}..0x{:x} of block {} overlaps another block",
+                    range.start,
+                    range.end,
+                    id
+                );
+            }
+            block_map.insert(range.start, (range.end, id));
+        }
+        Ok(RecordBasicBlockTrace {
+            blocks: block_map,
+            trace: Vec::new(),
+            unmapped_steps: 0,
+            name: "RecordBasicBlockTrace".to_string(),
+        })
+    }
+
+    /// Id of the block containing `rip`, if any
+    fn lookup(&self, rip: u64) -> Option<usize> {
+        self.blocks
+            .range(..=rip)
+            .next_back()
+            .filter(|(_, (end, _))| rip < *end)
+            .map(|(_, (_, id))| *id)
+    }
+
+    /// Ids of the visited blocks in execution order, with consecutive identical ids collapsed
+    pub fn get_trace(&self) -> &Vec<usize> {
+        &self.trace
+    }
+
+    /// Number of non-zero steps whose RIP was not inside any of the blocks
+    pub fn get_unmapped_steps(&self) -> usize {
+        self.unmapped_steps
+    }
+}
+
+impl EventHandler for RecordBasicBlockTrace {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let event = match event {
+            Event::PageFaultEvent(_) => return Ok(StateMachineNextAction::NEXT),
+            Event::StepEvent(v) => v,
+        };
+
+        //zero steps do not change RIP
+        if event.retired_instructions == 0 {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+
+        let rip = event
+            .get_register(vmsa_register_name_t::VRN_RIP)
+            .ok_or(anyhow!(
+                "failed to get RIP, is the VM running in debug mode?"
+            ))?;
+        match self.lookup(rip) {
+            Some(id) => {
+                if self.trace.last() != Some(&id) {
+                    self.trace.push(id);
+                }
+            }
+            None => {
+                debug!("RIP 0x{:x} is not inside any basic block", rip);
+                self.unmapped_steps += 1;
+            }
+        }
+
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.trace.clear();
+        self.unmapped_steps = 0;
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Attributes page faults to the instruction that caused them. After each step event, RIP points to
+/// the instruction that is executed next. Thus, all faults until the next step event are attributed to
+/// this RIP. Faults before the first step event are ignored. Requires the VM to run in debug mode
+pub struct AttributeFaultsToRip {
+    last_rip: Option<u64>,
+    attributed_faults: Vec<(u64, u64)>,
+    name: String,
+}
+
+impl AttributeFaultsToRip {
+    pub fn new() -> Self {
+        AttributeFaultsToRip {
+            last_rip: None,
+            attributed_faults: Vec::new(),
+            name: "AttributeFaultsToRip".to_string(),
+        }
+    }
+
+    /// `(rip, faulted_gpa)` pairs in the order in which the faults occurred
+    pub fn get_attributed_faults(&self) -> &Vec<(u64, u64)> {
+        &self.attributed_faults
+    }
+}
+
+impl Default for AttributeFaultsToRip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHandler for AttributeFaultsToRip {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        match event {
+            Event::PageFaultEvent(v) => match self.last_rip {
+                Some(rip) => self.attributed_faults.push((rip, v.faulted_gpa)),
+                None => debug!(
+                    "ignoring fault at 0x{:x} before first step event",
+                    v.faulted_gpa
+                ),
+            },
+            Event::StepEvent(v) => {
+                let rip = v
+                    .get_register(vmsa_register_name_t::VRN_RIP)
+                    .ok_or(anyhow!(
+                        "failed to get RIP, is the VM running in debug mode?"
+                    ))?;
+                self.last_rip = Some(rip);
+            }
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.last_rip = None;
+        self.attributed_faults.clear();
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Compares the single stepping quality with and without TLB flushes before each step. Records a step
+/// histogram for the first half of `budget` step events, then re-arms single stepping without TLB flushes
+/// and records a second histogram for the remaining step events. Requests shutdown once `budget` step
+/// events have been observed.
+///
+/// Expects single stepping to already be active with TLB flushes enabled, e.g. as started by
+/// [`SkipIfNotOnTargetGPAs`]. As the latter re-arms single stepping with TLB flushes whenever the
+/// target pages are entered, the whole budget should be spent within a single visit of the target pages
+pub struct CompareFlushModes {
+    timer_value: u32,
+    target_gpas: Vec<u64>,
+    budget: usize,
+    step_counter: usize,
+    with_flush: BuildStepHistogram,
+    without_flush: BuildStepHistogram,
+    name: String,
+}
+
+impl CompareFlushModes {
+    /// # Arguments
+    /// * `timer_value` : APIC timer value used when re-arming single stepping
+    /// * `target_gpas` : target pages passed to [`SevStep::start_stepping`] when re-arming single stepping
+    /// * `budget` : total amount of step events, including zero steps. The first `budget/2` events are
+    ///   recorded with TLB flushes. Smaller budgets than 2 are reported by [`EventHandler::validate`]
+    pub fn new(timer_value: u32, target_gpas: Vec<u64>, budget: usize) -> Self {
+        CompareFlushModes {
+            timer_value,
+            target_gpas,
+            budget,
+            step_counter: 0,
+            with_flush: BuildStepHistogram::new(),
+            without_flush: BuildStepHistogram::new(),
+            name: "CompareFlushModes".to_string(),
+        }
+    }
+
+    /// Step histogram for the first half of the budget, with TLB flushes before each step
+    pub fn get_with_flush(&self) -> &BuildStepHistogram {
+        &self.with_flush
+    }
+
+    /// Step histogram for the second half of the budget, without TLB flushes
+    pub fn get_without_flush(&self) -> &BuildStepHistogram {
+        &self.without_flush
+    }
+}
+
+impl EventHandler for CompareFlushModes {
+    fn process(
+        &mut self,
+        event: &Event,
+        api: &mut SevStep,
+        ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        if let Event::PageFaultEvent(_) = event {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+
+        let half = self.budget / 2;
+        if self.step_counter < half {
+            self.with_flush.process(event, api, ctx)?;
+        } else {
+            self.without_flush.process(event, api, ctx)?;
+        }
+        self.step_counter += 1;
+
+        if self.step_counter >= self.budget {
+            debug!(
+                "{} done. With TLB flush: {}, without TLB flush: {}",
+                self.name, self.with_flush, self.without_flush
+            );
+            return Ok(StateMachineNextAction::SHUTDOWN);
+        }
+        if self.step_counter == half {
+            debug!("{}: re-arming single stepping without TLB flush", self.name);
+            api.stop_stepping()?;
+            api.start_stepping(self.timer_value, &mut self.target_gpas, false)?;
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = unaligned_gpa_problems(&self.target_gpas);
+        if self.budget < 2 {
+            problems.push(format!(
+                "step budget is {}, at least 2 are required to record both flush modes",
+                self.budget
+            ));
+        }
+        problems
+    }
+
+    fn reset(&mut self) {
+        self.step_counter = 0;
+        self.with_flush.reset();
+        self.without_flush.reset();
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Counts the non-zero steps and, once the run is finished, checks that the total is one of the `expected`
+/// values. Useful to detect whether a secret dependent branch was taken. Each non-zero step counts once, even
+/// if it retired more than one instruction
+pub struct AssertTotalSteps {
+    expected: HashSet<usize>,
+    step_counter: usize,
+    name: String,
+}
+
+impl AssertTotalSteps {
+    /// # Arguments
+    /// * `expected` : allowed values for the total amount of non-zero steps
+    pub fn new(expected: HashSet<usize>) -> Self {
+        AssertTotalSteps {
+            expected,
+            step_counter: 0,
+            name: "AssertTotalSteps".to_string(),
+        }
+    }
+
+    /// Returns the amount of non-zero steps observed so far
+    pub fn get_total_steps(&self) -> usize {
+        self.step_counter
+    }
+}
+
+impl EventHandler for AssertTotalSteps {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        if let Event::StepEvent(v) = event {
+            if v.retired_instructions != 0 {
+                self.step_counter += 1;
+            }
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.step_counter = 0;
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_finish(&mut self, _api: &mut SevStep, _ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        if !self.expected.contains(&self.step_counter) {
+            let mut expected: Vec<_> = self.expected.iter().collect();
+            expected.sort();
+            bail!(
+                "observed {} non-zero steps, expected one of {:?}",
+                self.step_counter,
+                expected
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Checks the quality of single stepping once the run is finished: no step may retire more than one instruction
+/// and at least `min_single_steps` steps must retire exactly one instruction. Zero steps are only accepted if
+/// `allow_zero_steps` is set
+pub struct AssertSingleSteppingQuality {
+    min_single_steps: usize,
+    allow_zero_steps: bool,
+    step_histogram: HashMap<u64, u64>,
+    name: String,
+}
+
+impl AssertSingleSteppingQuality {
+    /// # Arguments
+    /// * `min_single_steps` : minimal number of steps that retire exactly one instruction
+    /// * `allow_zero_steps` : if false, steps that retire no instruction fail the check
+    pub fn new(min_single_steps: usize, allow_zero_steps: bool) -> Self {
+        AssertSingleSteppingQuality {
+            min_single_steps,
+            allow_zero_steps,
+            step_histogram: HashMap::new(),
+            name: "AssertSingleSteppingQuality".to_string(),
+        }
+    }
+
+    /// Check `step_histogram`, which maps step sizes to their occurrence count (see [`BuildStepHistogram`]),
+    /// against the criteria. The error describes all violations
+    pub fn check_histogram(&self, step_histogram: &HashMap<u64, u64>) -> Result<()> {
+        let sorted: BTreeMap<_, _> = step_histogram.iter().collect();
+        let mut problems = Vec::new();
+
+        let multi_steps: BTreeMap<_, _> = sorted
+            .iter()
+            .filter(|(size, _)| ***size > 1)
+            .map(|(size, count)| (**size, **count))
+            .collect();
+        if !multi_steps.is_empty() {
+            problems.push(format!(
+                "observed multi steps (size: count) {:?}",
+                multi_steps
+            ));
+        }
+
+        let zero_steps = step_histogram.get(&0).copied().unwrap_or(0);
+        if !self.allow_zero_steps && zero_steps > 0 {
+            problems.push(format!("observed {} zero steps", zero_steps));
+        }
+
+        let single_steps = step_histogram.get(&1).copied().unwrap_or(0);
+        if single_steps < self.min_single_steps as u64 {
+            problems.push(format!(
+                "observed {} single steps, expected at least {}",
+                single_steps, self.min_single_steps
+            ));
+        }
+
+        if !problems.is_empty() {
+            bail!(
+                "single stepping quality check failed: {}. Step histogram : {:?}",
+                problems.join(", "),
+                sorted
+            );
+        }
+        Ok(())
+    }
+}
+
+impl EventHandler for AssertSingleSteppingQuality {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        if let Event::StepEvent(v) = event {
+            *self
+                .step_histogram
+                .entry(v.retired_instructions as u64)
+                .or_insert(0) += 1;
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_finish(&mut self, _api: &mut SevStep, _ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        self.check_histogram(&self.step_histogram)
+    }
+
+    fn reset(&mut self) {
+        self.step_histogram.clear();
+    }
+}
+
+/// Requests shutdown once the register `name` has been equal to `value` for `consecutive` consecutive
+/// non-zero steps. Any non-zero step with a different value resets the streak. Zero steps and page faults are
+/// ignored. Requires the VM to run in debug mode
+pub struct StopWhenRegisterStable {
+    register: vmsa_register_name_t,
+    value: u64,
+    consecutive: usize,
+    streak: usize,
+    name: String,
+}
+
+impl StopWhenRegisterStable {
+    /// # Arguments
+    /// * `register` : register that is compared against `value`
+    /// * `value` : expected register value
+    /// * `consecutive` : number of consecutive non-zero steps for which `register` must equal `value`
+    pub fn new(register: vmsa_register_name_t, value: u64, consecutive: usize) -> Self {
+        StopWhenRegisterStable {
+            register,
+            value,
+            consecutive,
+            streak: 0,
+            name: "StopWhenRegisterStable".to_string(),
+        }
+    }
+}
+
+impl EventHandler for StopWhenRegisterStable {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let event = match event {
+            Event::StepEvent(v) if v.retired_instructions > 0 => v,
+            _ => return Ok(StateMachineNextAction::NEXT),
+        };
+
+        let got = event.get_register(self.register).ok_or(anyhow!(
+            "failed to get {:?}, is the VM running in debug mode?",
+            self.register
+        ))?;
+        if got != self.value {
+            self.streak = 0;
+            return Ok(StateMachineNextAction::NEXT);
+        }
+
+        self.streak += 1;
+        if self.streak >= self.consecutive {
+            debug!(
+                "{:?} was 0x{:x} for {} consecutive steps",
+                self.register, self.value, self.streak
+            );
+            return Ok(StateMachineNextAction::SHUTDOWN);
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.streak = 0;
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Drops page fault events that repeat the GPA of the immediately preceding event by returning
+/// [`StateMachineNextAction::SKIP`], e.g. to clean up the input for fault sequence matching.
+/// Step events are forwarded and reset the duplicate detection
+pub struct DedupConsecutiveFaults {
+    last_faulted_gpa: Option<u64>,
+    dropped: usize,
+    name: String,
+}
+
+impl DedupConsecutiveFaults {
+    pub fn new() -> Self {
+        DedupConsecutiveFaults {
+            last_faulted_gpa: None,
+            dropped: 0,
+            name: "DedupConsecutiveFaults".to_string(),
+        }
+    }
+
+    /// Returns the number of dropped page fault events
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Default for DedupConsecutiveFaults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHandler for DedupConsecutiveFaults {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let event = match event {
+            Event::PageFaultEvent(v) => v,
+            Event::StepEvent(_) => {
+                self.last_faulted_gpa = None;
+                return Ok(StateMachineNextAction::NEXT);
+            }
+        };
+
+        if self.last_faulted_gpa == Some(event.faulted_gpa) {
+            debug!("dropping duplicate fault at 0x{:x}", event.faulted_gpa);
+            self.dropped += 1;
+            return Ok(StateMachineNextAction::SKIP);
+        }
+        self.last_faulted_gpa = Some(event.faulted_gpa);
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.last_faulted_gpa = None;
+        self.dropped = 0;
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Serializable copy of an [`Event`], as stored by [`EventSequenceRecorder`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordedEvent {
+    PageFault {
+        gpa: u64,
+        ///Only available if the VM runs in debug mode, indexed by [`vmsa_register_name_t`]
+        registers: Option<Vec<u64>>,
+    },
+    Step {
+        retired_instructions: u32,
+        ///Only available if the VM runs in debug mode, indexed by [`vmsa_register_name_t`]
+        registers: Option<Vec<u64>>,
+    },
+}
+
+impl RecordedEvent {
+    fn from_event(event: &Event) -> RecordedEvent {
+        match event {
+            Event::PageFaultEvent(v) => RecordedEvent::PageFault {
+                gpa: v.faulted_gpa,
+                registers: v.get_register_snapshot().map(|r| r.values().to_vec()),
+            },
+            Event::StepEvent(v) => RecordedEvent::Step {
+                retired_instructions: v.retired_instructions,
+                registers: v.get_register_snapshot().map(|r| r.values().to_vec()),
+            },
+        }
+    }
+}
+
+/// Entry of the event sequence recorded by [`EventSequenceRecorder`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedEventEntry {
+    pub event: RecordedEvent,
+    ///Time since the first recorded event. Volatile, thus ignored when comparing against a golden file
+    pub elapsed: Duration,
+}
+
+/// Records all events for golden tests with deterministic victims. Store the sequence of a known good
+/// run with [`Self::save_golden`] and compare later runs against it with [`Self::assert_matches_golden`].
+/// Cache attack data is not recorded. Place the handler at the start of the chain, to see all events
+#[derive(Default)]
+pub struct EventSequenceRecorder {
+    entries: Vec<RecordedEventEntry>,
+    first_event: Option<Instant>,
+}
+
+impl EventSequenceRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_entries(&self) -> &[RecordedEventEntry] {
+        &self.entries
+    }
+
+    /// Store the recorded sequence at `path`, overwriting existing files
+    pub fn save_golden<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let f = File::create(path).context(format!("failed to create {}", path.display()))?;
+        bincode::serialize_into(f, &self.entries).context("failed to serialize events")?;
+        Ok(())
+    }
+
+    /// Compare the recorded sequence with the golden file at `path`, ignoring the volatile
+    /// [`RecordedEventEntry::elapsed`]. Returns an error describing the first differing event
+    pub fn assert_matches_golden<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let f = File::open(path).context(format!("failed to open {}", path.display()))?;
+        let golden: Vec<RecordedEventEntry> =
+            bincode::deserialize_from(f).context("failed to deserialize golden events")?;
+
+        for (idx, (want, got)) in golden.iter().zip(self.entries.iter()).enumerate() {
+            if want.event != got.event {
+                bail!(
+                    "event {} differs from golden file {} : expected {:x?} got {:x?}",
+                    idx,
+                    path.display(),
+                    want.event,
+                    got.event
+                );
+            }
+        }
+        if golden.len() != self.entries.len() {
+            bail!(
+                "recorded {} events but golden file {} has {} events",
+                self.entries.len(),
+                path.display(),
+                golden.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl EventHandler for EventSequenceRecorder {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let first_event = *self.first_event.get_or_insert_with(Instant::now);
+        self.entries.push(RecordedEventEntry {
+            event: RecordedEvent::from_event(event),
+            elapsed: first_event.elapsed(),
+        });
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.entries.clear();
+        self.first_event = None;
+    }
+
+    fn get_name(&self) -> &str {
+        "EventSequenceRecorder"
+    }
+}
+
+/// Reads a host MSR at each event and records its delta for each step event, e.g. APERF to observe
+/// the frequency of the core that runs the VM while it is stepped. See [`SevStep::read_host_msr`]
+pub struct SampleHostMsr {
+    cpu: usize,
+    msr: u32,
+    last_value: Option<u64>,
+    step_deltas: Vec<u64>,
+}
+
+impl SampleHostMsr {
+    /// # Arguments
+    /// * `cpu` : host CPU core whose MSR is read, usually the core that runs the VM
+    /// * `msr` : address of the MSR
+    pub fn new(cpu: usize, msr: u32) -> Self {
+        SampleHostMsr {
+            cpu,
+            msr,
+            last_value: None,
+            step_deltas: Vec::new(),
+        }
+    }
+
+    /// Change of the MSR value since the previous event, for each step event. The first event only
+    /// establishes the base value
+    pub fn get_step_deltas(&self) -> &[u64] {
+        &self.step_deltas
+    }
+}
+
+impl EventHandler for SampleHostMsr {
+    fn process(
+        &mut self,
+        event: &Event,
+        api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let value = api.read_host_msr(self.cpu, self.msr)?;
+        if let (Event::StepEvent(_), Some(last_value)) = (event, self.last_value) {
+            self.step_deltas.push(value.wrapping_sub(last_value));
+        }
+        self.last_value = Some(value);
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.last_value = None;
+        self.step_deltas.clear();
+    }
+
+    fn get_name(&self) -> &str {
+        "SampleHostMsr"
+    }
+}
+
+/// Fails the run with [`StateMachineNextAction::ErrorShutdown`] if a page fault occurs while
+/// [`SkipIfNotOnTargetGPAs`] is single stepping. As all non target pages are tracked while stepping,
+/// such a fault means that the victim left the target pages or that tracking is broken.
+/// Must be placed before [`SkipIfNotOnTargetGPAs`] in the chain, as that handler skips the faults
+/// on which it stops stepping. Only suited for victims that never leave the target pages
+pub struct AssertNoFaultsWhileStepping {
+    name: String,
+}
+
+impl AssertNoFaultsWhileStepping {
+    pub fn new() -> Self {
+        AssertNoFaultsWhileStepping {
+            name: "AssertNoFaultsWhileStepping".to_string(),
+        }
+    }
+}
+
+impl Default for AssertNoFaultsWhileStepping {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHandler for AssertNoFaultsWhileStepping {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        match event {
+            Event::PageFaultEvent(v) if SkipIfNotOnTargetGPAs::is_stepping_from_ctx(ctx)? => {
+                Ok(StateMachineNextAction::ErrorShutdown(format!(
+                    "page fault at GPA 0x{:x} while single stepping is active",
+                    v.faulted_gpa
+                )))
+            }
+            _ => Ok(StateMachineNextAction::NEXT),
+        }
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Measures the time from acking an event to the arrival of the next one, i.e. the round trip of the VM for a
+/// single step or page fault, to characterize the performance of the host. Place it at the start of the chain,
+/// as the time that the preceding handlers spend on the event is included in the measurement.
+/// The first event of a run is not measured, as it is not preceded by an ack
+pub struct LatencyProbe {
+    samples: Vec<Duration>,
+    name: String,
+}
+
+/// Percentiles of the latencies measured by [`LatencyProbe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyProbe {
+    pub fn new() -> Self {
+        LatencyProbe {
+            samples: Vec::new(),
+            name: "LatencyProbe".to_string(),
+        }
+    }
+
+    /// Add a latency measurement. Called by [`EventHandler::process`] for each event
+    pub fn add_sample(&mut self, latency: Duration) {
+        self.samples.push(latency);
+    }
+
+    /// All measured latencies, in the order of the events
+    pub fn get_samples(&self) -> &[Duration] {
+        &self.samples
+    }
+
+    /// Nearest rank percentile of the measured latencies. `percentile` is clamped to `[0,100]`.
+    /// None if nothing has been measured yet
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        Self::nearest_rank(&sorted, percentile)
+    }
+
+    /// Summary of the measured latencies. None if nothing has been measured yet
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        Some(LatencySummary {
+            count: sorted.len(),
+            min: *sorted.first()?,
+            p50: Self::nearest_rank(&sorted, 50.0)?,
+            p90: Self::nearest_rank(&sorted, 90.0)?,
+            p99: Self::nearest_rank(&sorted, 99.0)?,
+            max: *sorted.last()?,
+        })
+    }
+
+    fn nearest_rank(sorted: &[Duration], percentile: f64) -> Option<Duration> {
+        if sorted.is_empty() {
+            return None;
+        }
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+}
+
+impl Default for LatencyProbe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHandler for LatencyProbe {
+    fn process(
+        &mut self,
+        _event: &Event,
+        api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        if let Some(acked) = api.last_ack_time() {
+            self.add_sample(acked.elapsed());
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Processes a copy of an event on the background thread of a [`BufferedDispatcher`]
+pub type BufferedProcessor = Box<dyn FnMut(&Event) -> Result<()> + Send + 'static>;
+
+/// What [`BufferedDispatcher`] does with an event if its queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the background thread has made room. The VM is stalled in the meantime,
+    /// like for an unbuffered handler
+    Block,
+    /// Discard the event. The number of discarded events is available via [`BufferedDispatcher::dropped_events`]
+    Drop,
+    /// Fail the run
+    Fail,
+}
+
+/// Decouples slow event processing (e.g. disk or network I/O) from the ack of the event.
+/// Each event is copied into a bounded queue that is drained by a background thread, which passes it to
+/// the `processors` in order. The handler itself returns [`StateMachineNextAction::NEXT`] right away, thus
+/// the event is acked and the VM resumes while the event is still being processed.
+///
+/// N.B. on the timing trade-off: the processors run behind the VM by up to `capacity` events. Thus, they
+/// cannot influence the run, e.g. change tracking or stop it, and they do not have access to the API.
+/// Their results are only complete once [`Self::finish`] has returned, which [`EventHandler::on_finish`]
+/// calls at the end of the run. Errors of the processors are reported on the next event or by [`Self::finish`]
+pub struct BufferedDispatcher {
+    sender: Option<SyncSender<Event>>,
+    worker: Option<thread::JoinHandle<Result<()>>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped_events: usize,
+    name: String,
+}
+
+impl BufferedDispatcher {
+    /// # Arguments
+    /// * `processors` : called for each event, in order, on the background thread
+    /// * `capacity` : maximal number of queued events
+    /// * `policy` : what to do with an event if the queue is full
+    pub fn new(
+        mut processors: Vec<BufferedProcessor>,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Self {
+        let (sender, receiver) = sync_channel::<Event>(capacity);
+        let worker = thread::spawn(move || {
+            //ends once the sender has been dropped and the queue is drained
+            for (idx, event) in receiver.into_iter().enumerate() {
+                for processor in &mut processors {
+                    processor(&event)
+                        .with_context(|| format!("buffered processor failed on event {}", idx))?;
+                }
+            }
+            Ok(())
+        });
+        BufferedDispatcher {
+            sender: Some(sender),
+            worker: Some(worker),
+            capacity,
+            policy,
+            dropped_events: 0,
+            name: "BufferedDispatcher".to_string(),
+        }
+    }
+
+    /// Number of events that were discarded by [`OverflowPolicy::Drop`]
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events
+    }
+
+    /// Wait until all queued events have been processed and stop the background thread.
+    /// Returns the error of a failed processor. Further events are rejected
+    pub fn finish(&mut self) -> Result<()> {
+        //dropping the sender ends the worker loop
+        self.sender.take();
+        match self.worker.take() {
+            Some(worker) => worker
+                .join()
+                .map_err(|_| anyhow!("buffered processing thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl EventHandler for BufferedDispatcher {
+    fn process(
+        &mut self,
+        event: &Event,
+        _api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| anyhow!("buffered processing has already finished"))?;
+        let disconnected = match self.policy {
+            OverflowPolicy::Block => sender.send(event.clone()).is_err(),
+            OverflowPolicy::Drop | OverflowPolicy::Fail => match sender.try_send(event.clone()) {
+                Ok(()) => false,
+                Err(TrySendError::Full(_)) if self.policy == OverflowPolicy::Drop => {
+                    self.dropped_events += 1;
+                    debug!(
+                        "queue full, dropped event ({} dropped so far)",
+                        self.dropped_events
+                    );
+                    false
+                }
+                Err(TrySendError::Full(_)) => {
+                    bail!("event queue with capacity {} is full", self.capacity)
+                }
+                Err(TrySendError::Disconnected(_)) => true,
+            },
+        };
+        //the worker only stops early if a processor failed
+        if disconnected {
+            self.finish()?;
+            bail!("buffered processing stopped unexpectedly");
+        }
+        Ok(StateMachineNextAction::NEXT)
+    }
+
+    fn on_finish(&mut self, _api: &mut SevStep, _ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        self.finish()
+    }
+
+    fn validate(&self) -> Vec<String> {
+        match self.capacity {
+            0 => vec!["capacity is 0, each event blocks until it is processed".to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for BufferedDispatcher {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("buffered processing failed: {:?}", e);
+        }
+    }
+}
+
+/// Repeats the victim up to `max_repeats` times within a single [`TargetedStepper`] run, e.g. for
+/// repeated measurements. Events are forwarded to the per repetition handler chain `handlers`.
+/// A repetition is complete once the victim leaves `victim_gpas`, i.e. on the first page fault outside of
+/// `victim_gpas` after a page fault on them. Thus, the victim must not leave its pages before it returns.
+/// On completion, [`EventHandler::on_finish`] and [`EventHandler::reset`] are called on all handlers and
+/// the trigger is invoked again, once its previous invocation has returned.
+/// The handler requests [`StateMachineNextAction::SHUTDOWN`] after the last repetition.
+/// Pass [`Self::first_trigger`] as trigger to [`TargetedStepper::new`], to start the first repetition
+pub struct AutoRetrigger<'a, F>
+where
+    F: FnMut() -> Result<()> + Send + 'static,
+{
+    trigger: Arc<Mutex<F>>,
+    max_repeats: usize,
+    completed_repeats: usize,
+    victim_gpas: HashSet<u64>,
+    on_victim_pages: bool,
+    handlers: Vec<&'a mut dyn EventHandler>,
+    ///Signaled each time an invocation of the trigger has returned
+    trigger_done: Arc<Mutex<Receiver<()>>>,
+    trigger_done_sender: Sender<()>,
+    ///Error of a failed re-invocation of the trigger, reported on the next event
+    trigger_error: Arc<Mutex<Option<anyhow::Error>>>,
+    name: String,
+}
+
+impl<'a, F> AutoRetrigger<'a, F>
+where
+    F: FnMut() -> Result<()> + Send + 'static,
+{
+    /// # Arguments
+    /// * `trigger` : starts one repetition of the victim
+    /// * `max_repeats` : number of repetitions, including the first one
+    /// * `victim_gpas` : page aligned GPAs of the victim, used to detect its completion
+    /// * `handlers` : handler chain that processes the events of each repetition
+    pub fn new(
+        trigger: F,
+        max_repeats: usize,
+        victim_gpas: &[u64],
+        handlers: Vec<&'a mut dyn EventHandler>,
+    ) -> Self {
+        let (trigger_done_sender, trigger_done) = channel();
+        AutoRetrigger {
+            trigger: Arc::new(Mutex::new(trigger)),
+            max_repeats,
+            completed_repeats: 0,
+            victim_gpas: HashSet::from_iter(victim_gpas.iter().copied()),
+            on_victim_pages: false,
+            handlers,
+            trigger_done: Arc::new(Mutex::new(trigger_done)),
+            trigger_done_sender,
+            trigger_error: Arc::new(Mutex::new(None)),
+            name: "AutoRetrigger".to_string(),
+        }
+    }
+
+    /// Number of repetitions that ran to completion
+    pub fn get_completed_repeats(&self) -> usize {
+        self.completed_repeats
+    }
+
+    /// Trigger for the first repetition, to be passed to [`TargetedStepper::new`]
+    pub fn first_trigger(&self) -> impl FnOnce() -> Result<()> + Send + 'static {
+        let trigger = self.trigger.clone();
+        let done = self.trigger_done_sender.clone();
+        move || {
+            let result = (trigger.lock().unwrap())();
+            let _ = done.send(());
+            result
+        }
+    }
+
+    /// Invokes the trigger in the background, once its previous invocation has returned
+    fn retrigger(&self) {
+        let trigger = self.trigger.clone();
+        let trigger_done = self.trigger_done.clone();
+        let done = self.trigger_done_sender.clone();
+        let trigger_error = self.trigger_error.clone();
+        let repeat = self.completed_repeats + 1;
+        thread::spawn(move || {
+            if trigger_done.lock().unwrap().recv().is_err() {
+                return;
+            }
+            debug!("starting repetition {}", repeat);
+            if let Err(e) = (trigger.lock().unwrap())() {
+                error!("trigger failed for repetition {} : {:?}", repeat, e);
+                *trigger_error.lock().unwrap() =
+                    Some(e.context(format!("trigger failed for repetition {}", repeat)));
+            }
+            let _ = done.send(());
+        });
+    }
+}
+
+impl<'a, F> EventHandler for AutoRetrigger<'a, F>
+where
+    F: FnMut() -> Result<()> + Send + 'static,
+{
+    fn process(
+        &mut self,
+        event: &Event,
+        api: &mut SevStep,
+        ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        if let Some(e) = self.trigger_error.lock().unwrap().take() {
+            return Err(e);
+        }
+
+        let mut completed = false;
+        if let Event::PageFaultEvent(v) = event {
+            let on_victim_pages = self.victim_gpas.contains(&v.faulted_gpa);
+            completed = self.on_victim_pages && !on_victim_pages;
+            self.on_victim_pages = on_victim_pages;
+        }
+
+        for handler in &mut self.handlers {
+            match handler.process(event, api, ctx).with_context(|| {
+                format!(
+                    "handler {} failed in repetition {}",
+                    handler.get_name(),
+                    self.completed_repeats + 1
+                )
+            })? {
+                StateMachineNextAction::NEXT => (),
+                StateMachineNextAction::SKIP => break,
+                v => return Ok(v),
+            }
+        }
+        if !completed {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+
+        for handler in &mut self.handlers {
+            handler
+                .on_finish(api, ctx)
+                .with_context(|| format!("handler {} failed in on_finish", handler.get_name()))?;
+            handler.reset();
+        }
+        self.completed_repeats += 1;
+        debug!(
+            "completed repetition {}/{}",
+            self.completed_repeats, self.max_repeats
+        );
+        if self.completed_repeats >= self.max_repeats {
+            return Ok(StateMachineNextAction::SHUTDOWN);
+        }
+        self.retrigger();
+        Ok(StateMachineNextAction::SKIP)
+    }
+
+    fn on_finish(&mut self, api: &mut SevStep, ctx: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+        //handlers of completed repetitions have already been finished
+        if self.completed_repeats >= self.max_repeats {
+            return Ok(());
+        }
+        for handler in &mut self.handlers {
+            handler
+                .on_finish(api, ctx)
+                .with_context(|| format!("handler {} failed in on_finish", handler.get_name()))?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = unaligned_gpa_problems(&self.victim_gpas);
+        if self.max_repeats == 0 {
+            problems.push("max_repeats is 0".to_string());
+        }
+        for handler in &self.handlers {
+            problems.extend(
+                handler
+                    .validate()
+                    .into_iter()
+                    .map(|v| format!("{}: {}", handler.get_name(), v)),
+            );
+        }
+        problems
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Counts executed instructions and, once `budget` instructions have been single stepped, disables
+/// single stepping and untracks all pages before requesting shutdown. Afterwards, the VM resumes
+/// at full speed.
+pub struct ReleaseAfterNInstructions {
+    instruction_counter: usize,
+    budget: usize,
+    track_mode: kvm_page_track_mode,
+    name: String,
+}
+
+impl ReleaseAfterNInstructions {
+    /// # Arguments
+    /// * `budget` : amount of instructions that should be executed before releasing the VM.
+    ///   Zero steps don't count towards the budget
+    /// * `track_mode` : tracking mode that is removed from all pages when releasing the VM
+    pub fn new(budget: usize, track_mode: kvm_page_track_mode) -> Self {
+        ReleaseAfterNInstructions {
+            instruction_counter: 0,
+            budget,
+            track_mode,
+            name: "ReleaseAfterNInstructions".to_string(),
+        }
+    }
+}
+
+impl EventHandler for ReleaseAfterNInstructions {
+    fn process(
+        &mut self,
+        event: &Event,
+        api: &mut SevStep,
+        _ctx: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<StateMachineNextAction> {
+        let event = match event {
+            Event::PageFaultEvent(_) => return Ok(StateMachineNextAction::NEXT),
+            Event::StepEvent(v) => v,
+        };
+
+        self.instruction_counter += event.retired_instructions as usize;
+        if self.instruction_counter < self.budget {
+            return Ok(StateMachineNextAction::NEXT);
+        }
+
+        debug!(
+            "executed {} instructions, releasing VM",
+            self.instruction_counter
+        );
+        api.stop_stepping()?;
+        api.untrack_all_pages(self.track_mode)?;
+        Ok(StateMachineNextAction::SHUTDOWN)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        match self.budget {
+            0 => vec!["instruction budget is 0, the VM is released at the first step".to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.instruction_counter = 0;
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Single steps `budget` instructions on the `target_gpas` pages and then lets the VM resume
+/// at full speed, by disabling single stepping and untracking all pages.
+/// Returns the histogram of the observed step sizes.
+/// # Arguments
+/// * `api` : API connection that is used for the run
+/// * `target_gpas` : pages that should be single stepped. Initially tracked with `track_mode`
+/// * `track_mode` : tracking mode used to detect when the target pages are executed
+/// * `timer_value` : APIC timer value used for single stepping
+/// * `budget` : amount of instructions that should be single stepped
+/// * `target_trigger` : function that starts the victim
+/// * `timeout` : timeout while waiting for events
+pub fn step_then_release<F>(
+    api: SevStep,
+    target_gpas: &[u64],
+    track_mode: kvm_page_track_mode,
+    timer_value: u32,
+    budget: usize,
+    target_trigger: F,
+    timeout: Option<Duration>,
+) -> Result<BuildStepHistogram, SevStepError>
+where
+    F: FnOnce() -> Result<()>,
+    F: Send + 'static,
+{
+    let mut targetter = SkipIfNotOnTargetGPAs::new(target_gpas, track_mode, timer_value);
+    let mut step_histogram = BuildStepHistogram::new();
+    let mut release = ReleaseAfterNInstructions::new(budget, track_mode);
+
+    let handler_chain: Vec<&mut dyn EventHandler> =
+        vec![&mut targetter, &mut step_histogram, &mut release];
+    TargetedStepper::new(
+        api,
+        handler_chain,
+        track_mode,
+        target_gpas.to_vec(),
+        target_trigger,
+        timeout,
+    )
+    .run()?;
+
+    Ok(step_histogram)
+}
+
+/// RIP values of the single steps through the assembly target described by `resp`.
+/// The first instructions are not part of single stepping, as they are consumed as part of the page fault
+/// logic of [`SkipIfNotOnTargetGPAs`]. See [`SkipIfNotOnTargetGPAs::instructions_consumed_on_entry`]
+pub fn expected_step_rips(resp: &InitAssemblyTargetResp) -> Vec<u64> {
+    resp.instructions_with_rip
+        .iter()
+        .skip(SkipIfNotOnTargetGPAs::instructions_consumed_on_entry())
+        .map(|v| v.ip())
+        .collect()
+}
+
+/// Waits until the assembly target described by `resp` is executed and single steps it, checking
+/// each step against [`expected_step_rips`]. Requires debug mode.
+/// Returns the histogram of the observed step sizes.
+/// # Arguments
+/// * `api` : API connection that is used for the run
+/// * `resp` : response for the assembly target that should be stepped
+/// * `timer_value` : APIC timer value used for single stepping
+/// * `target_trigger` : function that starts the victim
+/// * `timeout` : timeout while waiting for events
+pub fn expected_rip_stepper<F>(
+    api: SevStep,
+    resp: &InitAssemblyTargetResp,
+    timer_value: u32,
+    target_trigger: F,
+    timeout: Option<Duration>,
+) -> Result<BuildStepHistogram, SevStepError>
+where
+    F: FnOnce() -> Result<()>,
+    F: Send + 'static,
+{
+    let mut targetter = SkipIfNotOnTargetGPAs::new(
+        &resp.code_gpas(),
+        kvm_page_track_mode::KVM_PAGE_TRACK_EXEC,
+        timer_value,
+    );
+    let mut step_histogram = BuildStepHistogram::new();
+    let expected_rips = expected_step_rips(resp);
+    let mut stop_after =
+        StopAfterNSingleStepsHandler::new(expected_rips.len(), Some(expected_rips));
+
+    let handler_chain: Vec<&mut dyn EventHandler> =
+        vec![&mut targetter, &mut step_histogram, &mut stop_after];
+    TargetedStepper::new(
+        api,
+        handler_chain,
+        kvm_page_track_mode::KVM_PAGE_TRACK_ACCESS,
+        resp.code_gpas(),
+        target_trigger,
+        timeout,
+    )
+    .run()?;
+
+    Ok(step_histogram)
+}
+
+/// Runs a whole stepping scenario up to `attempts` times, retrying if it fails with
+/// [`SevStepError::Timeout`]. Any other error is returned immediately.
+///
+/// As [`TargetedStepper::run`] consumes the API connection and the stepper, `attempt` has to
+/// set up the whole scenario on each call, i.e. open the API, build the handlers and the
+/// [`TargetedStepper`] and run it.
+/// # Arguments
+/// * `attempts` : maximal number of attempts
+/// * `per_attempt_timeout` : passed to `attempt`, to be used as the timeout of the [`TargetedStepper`]
+/// * `attempt` : called with the zero based index of the attempt and `per_attempt_timeout`
+pub fn run_with_retries<T, A>(
+    attempts: usize,
+    per_attempt_timeout: Duration,
+    mut attempt: A,
+) -> Result<T, SevStepError>
+where
+    A: FnMut(usize, Duration) -> Result<T, SevStepError>,
+{
+    for attempt_idx in 0..attempts {
+        match attempt(attempt_idx, per_attempt_timeout) {
+            Err(SevStepError::Timeout) => {
+                warn!("attempt {}/{} timed out", attempt_idx + 1, attempts);
+            }
+            result => return result,
+        }
+    }
+    Err(SevStepError::Timeout)
+}
+
+/// Single entry of the Chrome trace event format, see
+/// <https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU>
+#[derive(Debug, Clone, Serialize)]
+struct ChromeTraceEvent {
+    name: String,
+    ph: &'static str,
+    ///Microseconds since the start of the run
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: u32,
+    tid: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<HashMap<&'static str, String>>,
+}
+
+/// Records the events of a [`TargetedStepper`] run in the Chrome trace format, which can be loaded
+/// into Perfetto or `chrome://tracing` to visualize the timeline. Page faults are recorded as instant
+/// events, steps as duration events that span from the previous ack to the ack of the step. Each
+/// event is placed on the track of the handler that finished processing it, i.e. the one that
+/// returned SKIP or SHUTDOWN, or the last handler of the chain.
+/// Not a handler itself, as it needs to observe the whole chain. Pass it to
+/// [`TargetedStepper::with_chrome_trace`]
+#[derive(Default)]
+pub struct ChromeTraceRecorder {
+    events: Vec<ChromeTraceEvent>,
+    ///Handler names, indexed by the `tid` of their track
+    tracks: Vec<String>,
+    start: Option<Instant>,
+    last_ack: Option<Instant>,
+}
+
+impl ChromeTraceRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of recorded events, not counting the metadata that names the tracks
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.start = Some(now);
+        self.last_ack = None;
+    }
+
+    fn micros_since_start(&self, t: Instant) -> f64 {
+        let start = self.start.unwrap_or(t);
+        t.saturating_duration_since(start).as_secs_f64() * 1e6
+    }
+
+    /// # Arguments
+    /// * `event` : the processed event
+    /// * `handler` : name of the handler that finished processing the event
+    /// * `received` : time at which the event was received
+    /// * `acked` : time at which the event was acked
+    fn record(&mut self, event: &Event, handler: &str, received: Instant, acked: Instant) {
+        let tid = match self.tracks.iter().position(|v| v == handler) {
+            Some(tid) => tid,
+            None => {
+                self.tracks.push(handler.to_string());
+                self.tracks.len() - 1
+            }
+        };
+        let trace_event = match event {
+            Event::PageFaultEvent(v) => ChromeTraceEvent {
+                name: "page fault".to_string(),
+                ph: "i",
+                ts: self.micros_since_start(received),
+                dur: None,
+                pid: 0,
+                tid,
+                s: Some("t"),
+                args: Some(HashMap::from([("gpa", format!("0x{:x}", v.faulted_gpa))])),
+            },
+            Event::StepEvent(v) => {
+                let from = self.last_ack.or(self.start).unwrap_or(received);
+                ChromeTraceEvent {
+                    name: format!("step ({})", v.retired_instructions),
+                    ph: "X",
+                    ts: self.micros_since_start(from),
+                    dur: Some(acked.saturating_duration_since(from).as_secs_f64() * 1e6),
+                    pid: 0,
+                    tid,
+                    s: None,
+                    args: None,
+                }
+            }
+        };
+        self.events.push(trace_event);
+        self.last_ack = Some(acked);
+    }
+
+    /// Write the recorded events as JSON array to `w`. Starts with metadata events that name the
+    /// track of each handler
+    pub fn write<W: Write>(&self, w: W) -> Result<()> {
+        let metadata = self
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(tid, name)| ChromeTraceEvent {
+                name: "thread_name".to_string(),
+                ph: "M",
+                ts: 0.0,
+                dur: None,
+                pid: 0,
+                tid,
+                s: None,
+                args: Some(HashMap::from([("name", name.clone())])),
+            });
+        let all_events = metadata
+            .chain(self.events.iter().cloned())
+            .collect::<Vec<_>>();
+        serde_json::to_writer(w, &all_events).context("failed to write chrome trace")?;
+        Ok(())
+    }
+}
+
+/// Type erased target trigger, used to pass several triggers to [`TargetedStepper::new_with_triggers`]
+pub type BoxedTrigger = Box<dyn FnOnce() -> Result<()> + Send + 'static>;
+
+/// Combine `triggers` into a single trigger that calls them back-to-back, in order.
+/// Stops at the first trigger that fails
+pub fn sequence_triggers(triggers: Vec<BoxedTrigger>) -> BoxedTrigger {
+    Box::new(move || {
+        let trigger_count = triggers.len();
+        for (idx, trigger) in triggers.into_iter().enumerate() {
+            debug!("Running trigger {}/{}", idx + 1, trigger_count);
+            trigger().with_context(|| format!("trigger {} failed", idx))?;
+        }
+        Ok(())
+    })
+}
+
+pub struct TargetedStepper<'a, F>
+where
+    F: FnOnce() -> Result<()>,
+    F: Send + 'static,
+{
+    api: SevStep<'a>,
+    handler_chain: Vec<&'a mut dyn EventHandler>,
+    track_mode: kvm_page_track_mode,
+    initially_tracked_gpas: Vec<u64>,
+    target_trigger: F,
+    timeouts: StepperTimeouts,
+    trigger_retries: Option<TriggerRetries<F>>,
+    ///Time source for [`StepperTimeouts::total_run`]. Only replaced in tests
+    clock: Box<dyn Fn() -> Instant + 'a>,
+    chrome_trace: Option<&'a mut ChromeTraceRecorder>,
+}
+
+/// Independent timeouts of a [`TargetedStepper`] run. Exceeding any of them aborts the run with
+/// [`SevStepError::Timeout`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StepperTimeouts {
+    ///Bounds the wait for each event
+    pub per_event: Option<Duration>,
+    ///Bounds the duration of the whole run. Checked after each event, thus it also fires if events keep
+    /// arriving within `per_event`. Also bounds the wait for the next event
+    pub total_run: Option<Duration>,
+    ///Bounds the wait for the first event, i.e. the time it takes the target trigger to start the victim.
+    /// If `None`, `per_event` is used
+    pub trigger: Option<Duration>,
+}
+
+impl StepperTimeouts {
+    /// Only bound the wait for each event. Matches the `timeout` argument of [`TargetedStepper::new`]
+    pub fn per_event(per_event: Option<Duration>) -> StepperTimeouts {
+        StepperTimeouts {
+            per_event,
+            ..Default::default()
+        }
+    }
+
+    fn first_event(&self) -> Option<Duration> {
+        self.trigger.or(self.per_event)
+    }
+
+    /// `wait`, shortened to the time that is left until `total_run` passes, `elapsed` after the start of the run
+    fn bounded_wait(&self, wait: Option<Duration>, elapsed: Duration) -> Option<Duration> {
+        let remaining = self.total_run.map(|v| v.saturating_sub(elapsed));
+        match (wait, remaining) {
+            (Some(wait), Some(remaining)) => Some(wait.min(remaining)),
+            (wait, remaining) => wait.or(remaining),
+        }
+    }
+}
+
+/// Configuration for re-invoking a trigger that failed with [`RetryableTriggerError`]
+struct TriggerRetries<F> {
+    max_retries: usize,
+    delay: Duration,
+    ///Creates a fresh trigger for each invocation, as triggers are consumed by [`SevStep::block_untill_event`]
+    clone_trigger: fn(&F) -> F,
+}
+
+impl<'a> TargetedStepper<'a, BoxedTrigger> {
+    /// Like [`Self::new`] but with several triggers. They are called back-to-back on the trigger thread,
+    /// in order, see [`sequence_triggers`]. The first event is awaited after the last trigger has started
+    pub fn new_with_triggers(
+        api: SevStep<'a>,
+        handler_chain: Vec<&'a mut dyn EventHandler>,
+        initial_track_mode: kvm_page_track_mode,
+        initially_tracked_gpas: Vec<u64>,
+        target_triggers: Vec<BoxedTrigger>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self::new(
+            api,
+            handler_chain,
+            initial_track_mode,
+            initially_tracked_gpas,
+            sequence_triggers(target_triggers),
+            timeout,
+        )
+    }
+}
+
+impl<'a, F> TargetedStepper<'a, F>
+where
+    F: FnOnce() -> Result<()>,
+    F: Send + 'static,
+{
+    pub fn new(
+        api: SevStep<'a>,
+        handler_chain: Vec<&'a mut dyn EventHandler>,
+        initial_track_mode: kvm_page_track_mode,
+        initially_tracked_gpas: Vec<u64>,
+        target_trigger: F,
+        timeout: Option<Duration>,
+    ) -> TargetedStepper<'a, F> {
+        TargetedStepper {
+            api,
+            handler_chain,
+            track_mode: initial_track_mode,
+            initially_tracked_gpas,
+            target_trigger,
+            timeouts: StepperTimeouts::per_event(timeout),
+            trigger_retries: None,
+            clock: Box::new(Instant::now),
+            chrome_trace: None,
+        }
+    }
+
+    /// Abort the run with [`SevStepError::Timeout`] once `deadline` has passed since the start of the run,
+    /// even if events keep arriving within the per event timeout. The deadline is checked after each event
+    /// and bounds the wait for the next event. Shorthand for setting [`StepperTimeouts::total_run`]
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.timeouts.total_run = Some(deadline);
+        self
+    }
+
+    /// Replace all timeouts of the run, including the `timeout` passed to [`Self::new`]
+    pub fn with_timeouts(mut self, timeouts: StepperTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Record all events of the run in `recorder`, to visualize them with Perfetto
+    pub fn with_chrome_trace(mut self, recorder: &'a mut ChromeTraceRecorder) -> Self {
+        self.chrome_trace = Some(recorder);
+        self
+    }
+
+    #[cfg(test)]
+    fn with_clock(mut self, clock: impl Fn() -> Instant + 'a) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// If the target trigger fails with [`RetryableTriggerError`], call it again after `delay`, up to
+    /// `max_retries` times. Other trigger errors still abort the run
+    pub fn with_trigger_retries(mut self, max_retries: usize, delay: Duration) -> Self
+    where
+        F: Clone,
+    {
+        self.trigger_retries = Some(TriggerRetries {
+            max_retries,
+            delay,
+            clone_trigger: F::clone,
+        });
+        self
+    }
+
+    /// Statically checks the tracking configuration and the configuration of all handlers, without
+    /// accessing the API. Returns all found problems, prefixed with the name of the affected handler
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = unaligned_gpa_problems(&self.initially_tracked_gpas)
+            .into_iter()
+            .map(|v| format!("initial tracking: {}", v))
+            .collect::<Vec<_>>();
+        for handler in &self.handler_chain {
+            problems.extend(
+                handler
+                    .validate()
+                    .into_iter()
+                    .map(|v| format!("{}: {}", handler.get_name(), v)),
+            );
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems),
+        }
+    }
+
+    pub fn run(mut self) -> Result<(), SevStepError> {
+        let start = (self.clock)();
+        if let Some(recorder) = &mut self.chrome_trace {
+            recorder.start(start);
+        }
+        debug!("Performing initial tracking");
+        for (idx, x) in self.initially_tracked_gpas.iter().enumerate() {
+            if let Err(e) = self
+                .api
+                .track_page(*x, self.track_mode)
+                .context(format!("failed to track 0x{:x}", x))
+            {
+                //don't leave a partial tracking configuration behind
+                for tracked in &self.initially_tracked_gpas[..idx] {
+                    if let Err(untrack_err) = self.api.untrack_page(*tracked, self.track_mode) {
+                        warn!(
+                            "failed to untrack 0x{:x} after initial tracking failed: {:?}",
+                            tracked, untrack_err
+                        );
+                    }
+                }
+                return Err(e.into());
+            }
+            debug!("Tracking 0x{:x} with {:?}", x, self.track_mode);
+        }
+
+        let mut ctx = HashMap::new();
+        info!("entering main event loop");
+
+        //for the first event, trigger the target
+        let mut event = match self.trigger_retries {
+            None => {
+                let timeout = self
+                    .timeouts
+                    .bounded_wait(self.timeouts.first_event(), (self.clock)() - start);
+                self.api.block_untill_event(self.target_trigger, timeout)?
+            }
+            Some(retries) => {
+                let mut retry_idx = 0;
+                loop {
+                    let trigger = (retries.clone_trigger)(&self.target_trigger);
+                    let timeout = self
+                        .timeouts
+                        .bounded_wait(self.timeouts.first_event(), (self.clock)() - start);
+                    match self.api.block_untill_event(trigger, timeout) {
+                        Err(SevStepError::TriggerFailed { source })
+                            if retry_idx < retries.max_retries
+                                && source.is::<RetryableTriggerError>() =>
+                        {
+                            retry_idx += 1;
+                            warn!(
+                                "{}, retrying in {:?} ({}/{})",
+                                source, retries.delay, retry_idx, retries.max_retries
+                            );
+                            thread::sleep(retries.delay);
+                        }
+                        result => break result?,
+                    }
+                }
+            }
+        };
+        let mut event_idx: usize = 0;
+        loop {
+            let received = (self.clock)();
+            debug!("Got Event {:X?}", event);
+            let event_type = match &event {
+                Event::PageFaultEvent(_) => "page fault event",
+                Event::StepEvent(_) => "step event",
+            };
+            let mut processed_by = "";
+            for handler in &mut self.handler_chain {
+                debug!("Running handler {}", handler.get_name());
+                let next_action = handler
+                    .process(&event, &mut self.api, &mut ctx)
+                    .with_context(|| {
+                        format!(
+                            "handler {} failed on event {} ({})",
+                            handler.get_name(),
+                            event_idx,
+                            event_type
+                        )
+                    })?;
+                match next_action {
+                    StateMachineNextAction::NEXT => {
+                        debug!("NEXT");
+                        processed_by = handler.get_name();
+                    }
+                    StateMachineNextAction::SKIP => {
+                        debug!("SKIP");
+                        processed_by = handler.get_name();
+                        break;
+                    }
+                    StateMachineNextAction::SHUTDOWN => {
+                        debug!("SHUTDOWN");
+                        self.api.ack_event();
+                        if let Some(recorder) = &mut self.chrome_trace {
+                            recorder.record(&event, handler.get_name(), received, (self.clock)());
+                        }
+                        info!("Left main event loop");
+                        for handler in &mut self.handler_chain {
+                            handler
+                                .on_finish(&mut self.api, &mut ctx)
+                                .with_context(|| {
+                                    format!("handler {} failed in on_finish", handler.get_name())
+                                })?;
+                        }
+                        self.api
+                            .untrack_remaining_pages()
+                            .context("failed to untrack remaining pages")?;
+                        return Ok(());
+                    }
+                    StateMachineNextAction::ErrorShutdown(message) => {
+                        error!("ERROR_SHUTDOWN with message={}", message);
+                        return Err(anyhow!(
+                            "logic error in handler {} : {}",
+                            handler.get_name(),
+                            message
+                        )
+                        .into());
+                    }
+                }
+            }
+            self.api.ack_event();
+            if let Some(recorder) = &mut self.chrome_trace {
+                recorder.record(&event, processed_by, received, (self.clock)());
+            }
+
+            if let Some(deadline) = self.timeouts.total_run {
+                if (self.clock)().duration_since(start) > deadline {
+                    warn!(
+                        "run exceeded deadline of {:?} after {} events",
+                        deadline,
+                        event_idx + 1
+                    );
+                    return Err(SevStepError::Timeout);
+                }
+            }
+
+            //N.B. that we use an empty/NOP trigger now
+            let timeout = self
+                .timeouts
+                .bounded_wait(self.timeouts.per_event, (self.clock)() - start);
+            event = self.api.block_untill_event(|| Ok(()), timeout)?;
+            event_idx += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use iced_x86::{Code, Instruction};
+
+    use crate::api::mock_kernel::{
+        spawn_event_feeder, spawn_gated_event_feeder, IoctlCall, MockEvent,
+    };
+
+    const TRACK_MODE: kvm_page_track_mode = kvm_page_track_mode::KVM_PAGE_TRACK_EXEC;
+
+    /// Run `handlers` on `events` sent by a mock kernel, without initially tracked pages and with a no-op trigger
+    fn run_with_mock_events(
+        events: Vec<MockEvent>,
+        handlers: Vec<&mut dyn EventHandler>,
+    ) -> Result<(), SevStepError> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, events);
+        let result = TargetedStepper::new(
+            api,
+            handlers,
+            TRACK_MODE,
+            vec![],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run();
+        feeder.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn step_then_release_releases_vm_after_budget() -> Result<()> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let target_gpa = 0x1000;
+        let budget = 5;
+
+        let mut events = vec![MockEvent::page_fault(target_gpa), MockEvent::step(0)];
+        events.extend((0..budget).map(|_| MockEvent::step(1)));
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let histogram = step_then_release(
+            api,
+            &[target_gpa],
+            TRACK_MODE,
+            42,
+            budget,
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )?;
+        feeder.join().unwrap();
+
+        assert_eq!(
+            histogram.get_values(),
+            &HashMap::from([(0, 1), (1, budget as u64)])
+        );
+
+        let calls = kernel.calls();
+        //the last two calls are issued when dropping the api
+        assert_eq!(
+            calls[calls.len() - 4..],
+            [
+                IoctlCall::StopStepping,
+                IoctlCall::untrack_all_pages(TRACK_MODE),
+                IoctlCall::StopStepping,
+                IoctlCall::CloseApi,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stop_when_rip_leaves_range_shuts_down_on_first_step_outside() -> Result<()> {
+        let (start, end) = (0x4000, 0x4010);
+
+        let step_to = |rip| MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, rip);
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            step_to(0x4000),
+            MockEvent::step(0),
+            step_to(0x4008),
+            step_to(0x400f),
+            step_to(0x4010),
+        ];
+
+        let mut stop = StopWhenRipLeavesRange::new(start, end);
+        run_with_mock_events(events, vec![&mut stop])?;
+
+        assert_eq!(stop.get_step_counter(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn register_delta_recorder_records_changed_registers() -> Result<()> {
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            MockEvent::step(1)
+                .with_register(vmsa_register_name_t::VRN_RIP, 0x4000)
+                .with_register(vmsa_register_name_t::VRN_RCX, 1)
+                .with_register(vmsa_register_name_t::VRN_RDX, 7),
+            MockEvent::step(1)
+                .with_register(vmsa_register_name_t::VRN_RIP, 0x4003)
+                .with_register(vmsa_register_name_t::VRN_RCX, 2)
+                .with_register(vmsa_register_name_t::VRN_RDX, 7),
+        ];
+
+        let mut recorder = RegisterDeltaRecorder::new();
+        let mut stop = StopAfterNSingleStepsHandler::new(1, None);
+        run_with_mock_events(events, vec![&mut recorder, &mut stop])?;
+
+        assert_eq!(
+            recorder.get_deltas(),
+            &vec![RegisterDelta {
+                rip: 0x4000,
+                changes: vec![(vmsa_register_name_t::VRN_RCX, 1, 2)],
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn record_rsp_trace_tracks_min_and_max() -> Result<()> {
+        let rsp_values = [0x7000, 0x6ff8, 0x6ff0, 0x6ff8, 0x7000];
+        let mut events = vec![MockEvent::page_fault(0x1000), MockEvent::step(0)];
+        events.extend(
+            rsp_values
+                .iter()
+                .map(|v| MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RSP, *v)),
+        );
+
+        let mut rsp_trace = RecordRspTrace::new();
+        let mut stop = StopAfterNSingleStepsHandler::new(rsp_values.len() - 1, None);
+        run_with_mock_events(events, vec![&mut rsp_trace, &mut stop])?;
+
+        assert_eq!(rsp_trace.get_trace(), &rsp_values.to_vec());
+        assert_eq!(rsp_trace.get_min(), Some(0x6ff0));
+        assert_eq!(rsp_trace.get_max(), Some(0x7000));
+        Ok(())
+    }
+
+    #[test]
+    fn run_with_retries_retries_after_timeout() -> Result<()> {
+        let target_gpa = 0x1000;
+        let mut attempt_count = 0;
+
+        let histogram = run_with_retries(3, Duration::from_millis(200), |attempt_idx, timeout| {
+            attempt_count += 1;
+            let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+            //the first attempt gets no events and thus times out
+            let events = match attempt_idx {
+                0 => vec![],
+                _ => vec![MockEvent::page_fault(target_gpa), MockEvent::step(1)],
+            };
+            let feeder = spawn_event_feeder(&mut api, events);
+            let result = step_then_release(
+                api,
+                &[target_gpa],
+                TRACK_MODE,
+                42,
+                1,
+                || Ok(()),
+                Some(timeout),
+            );
+            feeder.join().unwrap();
+            result
+        })?;
+
+        assert_eq!(attempt_count, 2);
+        assert_eq!(histogram.get_values(), &HashMap::from([(1, 1)]));
+        Ok(())
+    }
+
+    /// Fails on the `fail_at`-th event
+    struct FailingHandler {
+        fail_at: usize,
+        event_count: usize,
+    }
+
+    impl EventHandler for FailingHandler {
+        fn process(
+            &mut self,
+            _event: &Event,
+            _api: &mut SevStep,
+            _ctx: &mut HashMap<String, Vec<u8>>,
+        ) -> Result<StateMachineNextAction> {
+            if self.event_count == self.fail_at {
+                bail!("injected failure");
+            }
+            self.event_count += 1;
+            Ok(StateMachineNextAction::NEXT)
+        }
+
+        fn get_name(&self) -> &str {
+            "FailingHandler"
+        }
+    }
+
+    #[test]
+    fn handler_errors_name_handler_and_event() -> Result<()> {
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            MockEvent::step(1),
+            MockEvent::step(1),
+        ];
+
+        let mut histogram = BuildStepHistogram::new();
+        let mut failing = FailingHandler {
+            fail_at: 2,
+            event_count: 0,
+        };
+        let err = run_with_mock_events(events, vec![&mut histogram, &mut failing])
+            .expect_err("handler error should be propagated");
+
+        let message = err.to_string();
+        assert!(message.contains("FailingHandler"), "{}", message);
+        assert!(message.contains("event 2 (step event)"), "{}", message);
+        Ok(())
+    }
+
+    #[test]
+    fn attribute_faults_to_rip_of_preceding_step() -> Result<()> {
+        let step_to = |rip| MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, rip);
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            step_to(0x4000),
+            MockEvent::page_fault(0x8000),
+            MockEvent::page_fault(0x9000),
+            step_to(0x4004),
+            MockEvent::page_fault(0xa000),
+            step_to(0x4008),
+        ];
+
+        let mut attribute = AttributeFaultsToRip::new();
+        let mut stop = StopAfterNSingleStepsHandler::new(2, None);
+        run_with_mock_events(events, vec![&mut attribute, &mut stop])?;
+
+        assert_eq!(
+            attribute.get_attributed_faults(),
+            &vec![(0x4000, 0x8000), (0x4000, 0x9000), (0x4004, 0xa000)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compare_flush_modes_rearms_once_and_splits_histograms() -> Result<()> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let events = vec![
+            MockEvent::step(1),
+            MockEvent::page_fault(0x2000),
+            MockEvent::step(0),
+            MockEvent::step(1),
+            MockEvent::step(2),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut compare = CompareFlushModes::new(0x42, vec![0x1000], 4);
+        assert!(compare.validate().is_empty());
+        assert_eq!(
+            CompareFlushModes::new(0x42, vec![0x1000], 0).validate(),
+            vec![
+                "step budget is 0, at least 2 are required to record both flush modes".to_string()
+            ]
+        );
+        TargetedStepper::new(
+            api,
+            vec![&mut compare],
+            TRACK_MODE,
+            vec![0x1000],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(
+            compare.get_with_flush().get_values(),
+            &HashMap::from([(1, 1), (0, 1)])
+        );
+        assert_eq!(
+            compare.get_without_flush().get_values(),
+            &HashMap::from([(1, 1), (2, 1)])
+        );
+        let start_calls = kernel
+            .calls()
+            .into_iter()
+            .filter(|v| matches!(v, IoctlCall::StartStepping { .. }))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            start_calls,
+            vec![IoctlCall::StartStepping {
+                timer_value: 0x42,
+                target_gpas: vec![0x1000],
+                flush_tlb: false,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retryable_trigger_error_triggers_again() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let (start_sender, start_receiver) = crossbeam::channel::bounded(1);
+        //the victim only runs once the trigger succeeds
+        let feeder = spawn_gated_event_feeder(&mut api, vec![MockEvent::step(1)], start_receiver);
+
+        let trigger_calls = Arc::new(AtomicUsize::new(0));
+        let trigger = {
+            let trigger_calls = trigger_calls.clone();
+            move || {
+                if trigger_calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(RetryableTriggerError(anyhow!("victim server busy")).into());
+                }
+                start_sender.send(())?;
+                Ok(())
+            }
+        };
+
+        let mut stop = StopAfterNSingleStepsHandler::new(0, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut stop],
+            TRACK_MODE,
+            vec![0x1000],
+            trigger,
+            Some(Duration::from_secs(5)),
+        )
+        .with_trigger_retries(3, Duration::from_millis(10))
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(trigger_calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn triggers_run_in_order_before_first_event() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let (start_sender, start_receiver) = crossbeam::channel::bounded(1);
+        let feeder = spawn_gated_event_feeder(&mut api, vec![MockEvent::step(1)], start_receiver);
+
+        let trigger_log = Arc::new(Mutex::new(Vec::new()));
+        let mut triggers: Vec<BoxedTrigger> = (0..3)
+            .map(|idx| {
+                let trigger_log = trigger_log.clone();
+                Box::new(move || {
+                    trigger_log.lock().unwrap().push(idx);
+                    Ok(())
+                }) as BoxedTrigger
+            })
+            .collect();
+        //the victim only runs once the last trigger has started it
+        triggers.push(Box::new(move || {
+            start_sender.send(())?;
+            Ok(())
+        }));
+
+        let log_at_first_event = Rc::new(RefCell::new(None));
+        let mut record_log = SimpleCallbackAfterNSingleStepsHandler::new(vec![(
+            |step: &usize| *step == 0,
+            |_: &mut SevStep, _: &Event| {
+                *log_at_first_event.borrow_mut() = Some(trigger_log.lock().unwrap().clone());
+                Ok(())
+            },
+        )]);
+        let mut stop = StopAfterNSingleStepsHandler::new(0, None);
+        TargetedStepper::new_with_triggers(
+            api,
+            vec![&mut record_log, &mut stop],
+            TRACK_MODE,
+            vec![0x1000],
+            triggers,
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(*log_at_first_event.borrow(), Some(vec![0, 1, 2]));
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_n_faults_only_forwards_after_nth_fault() -> Result<()> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let target_gpa = 0x1000;
+        let other_gpa = 0x5000;
+        //RIP tags the entries, to tell which fault got forwarded
+        let events = vec![
+            MockEvent::page_fault(target_gpa).with_register(vmsa_register_name_t::VRN_RIP, 1),
+            MockEvent::page_fault(other_gpa),
+            MockEvent::page_fault(target_gpa).with_register(vmsa_register_name_t::VRN_RIP, 2),
+            MockEvent::page_fault(other_gpa),
+            MockEvent::page_fault(target_gpa).with_register(vmsa_register_name_t::VRN_RIP, 3),
+            MockEvent::step(1),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut wait = WaitForNFaultsThenAllow::new(&[target_gpa], TRACK_MODE, 3);
+        let forwarded = RefCell::new(Vec::new());
+        let mut record = SimpleCallbackAfterNSingleStepsHandler::new(vec![(
+            |_: &usize| true,
+            |_: &mut SevStep, event: &Event| {
+                forwarded.borrow_mut().push(match event {
+                    Event::PageFaultEvent(v) => v.get_register(vmsa_register_name_t::VRN_RIP),
+                    Event::StepEvent(_) => None,
+                });
+                Ok(())
+            },
+        )]);
+        let mut stop = StopAfterNSingleStepsHandler::new(0, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut wait, &mut record, &mut stop],
+            TRACK_MODE,
+            vec![target_gpa],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(wait.observed_faults(), 3);
+        assert_eq!(*forwarded.borrow(), vec![Some(3), None]);
+        //both skipped entries switch the tracking to the other pages and back
+        let calls = kernel.calls();
+        let track_all_count = calls
+            .iter()
+            .filter(|v| matches!(v, IoctlCall::TrackAllPages { .. }))
+            .count();
+        assert_eq!(track_all_count, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn histogram_snapshot_is_written_every_n_events() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let path =
+            std::env::temp_dir().join(format!("histogram_snapshot_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let feeder = spawn_event_feeder(
+            &mut api,
+            vec![MockEvent::step(1), MockEvent::step(0), MockEvent::step(1)],
+        );
+
+        let mut histogram = BuildStepHistogram::new().with_snapshots(&path, 2);
+        let mut ctx = HashMap::new();
+        let mut snapshots = Vec::new();
+        for _ in 0..3 {
+            let event = api.block_untill_event(|| Ok(()), Some(Duration::from_secs(5)))?;
+            histogram.process(&event, &mut api, &mut ctx)?;
+            api.ack_event();
+            snapshots.push(path.exists());
+        }
+        feeder.join().unwrap();
+
+        assert_eq!(snapshots, vec![false, true, true]);
+        //the third event is not part of the snapshot
+        let snapshot: BTreeMap<u64, u64> = serde_json::from_reader(File::open(&path)?)?;
+        assert_eq!(snapshot, BTreeMap::from([(0, 1), (1, 1)]));
+
+        histogram.on_finish(&mut api, &mut ctx)?;
+        let snapshot: BTreeMap<u64, u64> = serde_json::from_reader(File::open(&path)?)?;
+        assert_eq!(snapshot, BTreeMap::from([(0, 1), (1, 2)]));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn failed_initial_tracking_untracks_already_tracked_gpas() {
+        let (api, kernel, _abort) = SevStep::new_mock(false);
+        let gpas = vec![0x1000, 0x2000, 0x3000, 0x4000, 0x5000];
+        kernel.reject_track_gpa(0x3000);
+
+        let mut stop = StopAfterNSingleStepsHandler::new(0, None);
+        let result = TargetedStepper::new(
+            api,
+            vec![&mut stop],
+            TRACK_MODE,
+            gpas,
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run();
+        assert!(result.is_err());
+
+        let track_mode = TRACK_MODE as i32;
+        //the last two calls are issued when dropping the api
+        let calls = kernel.calls();
+        assert_eq!(
+            calls[..calls.len() - 2],
+            [
+                IoctlCall::TrackPage {
+                    gpa: 0x1000,
+                    track_mode
+                },
+                IoctlCall::TrackPage {
+                    gpa: 0x2000,
+                    track_mode
+                },
+                IoctlCall::UntrackPage {
+                    gpa: 0x1000,
+                    track_mode
+                },
+                IoctlCall::UntrackPage {
+                    gpa: 0x2000,
+                    track_mode
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn buffered_dispatcher_processes_events_in_order() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let event_count = 10;
+        let events = (0..event_count)
+            .map(|idx| MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, idx))
+            .collect();
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processor: BufferedProcessor = {
+            let processed = processed.clone();
+            Box::new(move |event| {
+                //slow processing, to fill the queue
+                thread::sleep(Duration::from_millis(1));
+                if let Event::StepEvent(v) = event {
+                    processed
+                        .lock()
+                        .unwrap()
+                        .push(v.get_register(vmsa_register_name_t::VRN_RIP).unwrap());
+                }
+                Ok(())
+            })
+        };
+        let mut dispatcher = BufferedDispatcher::new(vec![processor], 2, OverflowPolicy::Block);
+        let mut stop = StopAfterNSingleStepsHandler::new(event_count as usize - 1, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut dispatcher, &mut stop],
+            TRACK_MODE,
+            vec![0x1000],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        //on_finish waits for the queue to drain
+        assert_eq!(
+            *processed.lock().unwrap(),
+            (0..event_count).collect::<Vec<_>>()
+        );
+        assert_eq!(dispatcher.dropped_events(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn entry_rip_is_recorded_on_enter() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let target_gpa = 0x4000;
+        let events = vec![
+            MockEvent::page_fault(target_gpa).with_register(vmsa_register_name_t::VRN_RIP, 0x4000),
+            MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, 0x4001),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut targetter =
+            SkipIfNotOnTargetGPAs::new(&[target_gpa], TRACK_MODE, 42).with_entry_rip(true);
+        let mut stop = StopAfterNSingleStepsHandler::new(0, Some(vec![0x4001]));
+        TargetedStepper::new(
+            api,
+            vec![&mut targetter, &mut stop],
+            TRACK_MODE,
+            vec![target_gpa],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(targetter.get_entry_rips(), &[0x4000]);
+        Ok(())
+    }
+
+    #[test]
+    fn latency_probe_percentiles() {
+        let mut probe = LatencyProbe::new();
+        assert_eq!(probe.summary(), None);
+        //shuffled 1..=100 us
+        for v in (0..100).map(|v| (v * 37) % 100 + 1) {
+            probe.add_sample(Duration::from_micros(v));
+        }
+
+        assert_eq!(
+            probe.summary(),
+            Some(LatencySummary {
+                count: 100,
+                min: Duration::from_micros(1),
+                p50: Duration::from_micros(50),
+                p90: Duration::from_micros(90),
+                p99: Duration::from_micros(99),
+                max: Duration::from_micros(100),
+            })
+        );
+        assert_eq!(probe.percentile(0.0), Some(Duration::from_micros(1)));
+        assert_eq!(probe.percentile(99.5), Some(Duration::from_micros(100)));
+        assert_eq!(probe.percentile(250.0), Some(Duration::from_micros(100)));
+    }
+
+    #[test]
+    fn latency_probe_measures_from_ack() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1); 3]);
+
+        let mut probe = LatencyProbe::new();
+        let mut stop = StopAfterNSingleStepsHandler::new(2, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut probe, &mut stop],
+            TRACK_MODE,
+            vec![0x1000],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        //the first event is not preceded by an ack
+        assert_eq!(probe.get_samples().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn single_stepping_quality_accepts_clean_histograms() {
+        let check = AssertSingleSteppingQuality::new(3, true);
+        assert!(check.check_histogram(&HashMap::from([(1, 3)])).is_ok());
+        assert!(check
+            .check_histogram(&HashMap::from([(0, 5), (1, 4)]))
+            .is_ok());
+    }
+
+    #[test]
+    fn single_stepping_quality_rejects_bad_histograms() {
+        let check = AssertSingleSteppingQuality::new(3, false);
+        let message = |histogram: HashMap<u64, u64>| {
+            format!("{}", check.check_histogram(&histogram).unwrap_err())
+        };
+
+        assert!(message(HashMap::from([(1, 3), (2, 1), (5, 2)]))
+            .contains("observed multi steps (size: count) {2: 1, 5: 2}"));
+        assert!(message(HashMap::from([(0, 1), (1, 3)])).contains("observed 1 zero steps"));
+        assert!(message(HashMap::from([(1, 2)]))
+            .contains("observed 2 single steps, expected at least 3"));
+        assert!(message(HashMap::new()).contains("observed 0 single steps"));
+
+        //all violations are reported
+        let all = message(HashMap::from([(0, 1), (1, 1), (3, 1)]));
+        assert!(all.contains("multi steps"));
+        assert!(all.contains("zero steps"));
+        assert!(all.contains("single steps, expected"));
+    }
+
+    #[test]
+    fn single_stepping_quality_checked_on_finish() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(2), MockEvent::step(1)]);
+
+        let mut quality = AssertSingleSteppingQuality::new(1, true);
+        let mut stop = StopAfterNSingleStepsHandler::new(1, None);
+        let result = TargetedStepper::new(
+            api,
+            vec![&mut quality, &mut stop],
+            TRACK_MODE,
+            vec![0x1000],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run();
+        feeder.join().unwrap();
+
+        let err = format!("{:#}", anyhow::Error::from(result.unwrap_err()));
+        assert!(err.contains("observed multi steps"), "{}", err);
+    }
+
+    /// Assembly target with four instructions starting at 0x4000
+    fn nop_target_resp() -> InitAssemblyTargetResp {
+        let instructions_with_rip = [0x4000, 0x4001, 0x4002, 0x4005]
+            .into_iter()
+            .map(|ip| {
+                let mut i = Instruction::with(Code::Nopd);
+                i.set_ip(ip);
+                i
+            })
+            .collect();
+        InitAssemblyTargetResp {
+            code_vaddr: 0x4000,
+            code_paddr: 0x9000,
+            code_page_paddrs: vec![0x9000],
+            data_buffer_vaddr: 0x5000,
+            data_buffer_paddr: 0xa000,
+            data_page_paddrs: vec![0xa000],
+            data_buffer_bytes: 4096,
+            instructions_with_rip,
+            touched_on_init: true,
+            pinned: true,
+        }
+    }
+
+    #[test]
+    fn expected_step_rips_skip_first_instruction() {
+        let resp = nop_target_resp();
+        assert_eq!(expected_step_rips(&resp), vec![0x4001, 0x4002, 0x4005]);
+    }
+
+    #[test]
+    fn expected_step_rips_use_instructions_consumed_on_entry() {
+        let consumed = SkipIfNotOnTargetGPAs::instructions_consumed_on_entry();
+        assert_eq!(consumed, 1);
+
+        let resp = nop_target_resp();
+        let expected = expected_step_rips(&resp);
+        assert_eq!(expected.len(), resp.instructions_with_rip.len() - consumed);
+        assert_eq!(expected[0], resp.instructions_with_rip[consumed].ip());
+    }
+
+    #[test]
+    fn assert_total_steps_rejects_unexpected_count() {
+        //`stop` shuts down at the 4th non-zero step. The multi step counts once, thus the 5 retired
+        //instructions do not matter
+        let events = vec![
+            MockEvent::step(1),
+            MockEvent::step(0),
+            MockEvent::step(2),
+            MockEvent::step(1),
+            MockEvent::step(1),
+        ];
+
+        let mut assert_steps = AssertTotalSteps::new(HashSet::from([5, 7]));
+        let mut stop = StopAfterNSingleStepsHandler::new(3, None);
+        let err = run_with_mock_events(events, vec![&mut assert_steps, &mut stop]).unwrap_err();
+
+        let message = format!("{:#}", anyhow::Error::from(err));
+        assert!(message.contains("handler AssertTotalSteps failed in on_finish"));
+        assert!(message.contains("observed 4 non-zero steps, expected one of [5, 7]"));
+        assert_eq!(assert_steps.get_total_steps(), 4);
+    }
+
+    /// Counts how often [`EventHandler::on_finish`] is called
+    struct CountFinishCalls {
+        finish_calls: usize,
+    }
+
+    impl EventHandler for CountFinishCalls {
+        fn process(
+            &mut self,
+            _event: &Event,
+            _api: &mut SevStep,
+            _ctx: &mut HashMap<String, Vec<u8>>,
+        ) -> Result<StateMachineNextAction> {
+            Ok(StateMachineNextAction::NEXT)
+        }
+
+        fn get_name(&self) -> &str {
+            "CountFinishCalls"
+        }
+
+        fn on_finish(
+            &mut self,
+            _api: &mut SevStep,
+            _ctx: &mut HashMap<String, Vec<u8>>,
+        ) -> Result<()> {
+            self.finish_calls += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn on_finish_runs_once_after_shutdown() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1); 3]);
+
+        let mut before = CountFinishCalls { finish_calls: 0 };
+        let mut stop = StopAfterNSingleStepsHandler::new(2, None);
+        let mut after = CountFinishCalls { finish_calls: 0 };
+        TargetedStepper::new(
+            api,
+            vec![&mut before, &mut stop, &mut after],
+            TRACK_MODE,
+            vec![],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        //handlers after the one that requested the shutdown are finished as well
+        assert_eq!(before.finish_calls, 1);
+        assert_eq!(after.finish_calls, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn per_page_timer_selects_timer_of_entry_page() -> Result<()> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            MockEvent::step(1),
+            MockEvent::page_fault(0x9000),
+            MockEvent::page_fault(0x2000),
+            MockEvent::step(1),
+            MockEvent::page_fault(0x9000),
+            MockEvent::page_fault(0x3000),
+            MockEvent::step(1),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[0x1000, 0x2000, 0x3000], TRACK_MODE, 0x30)
+            .with_per_page_timer(PerPageTimer::from([(0x1000, 0x10), (0x2000, 0x20)]));
+        let mut stop = StopAfterNSingleStepsHandler::new(2, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut skip, &mut stop],
+            TRACK_MODE,
+            vec![],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        let timer_values = kernel
+            .calls()
+            .into_iter()
+            .filter_map(|v| match v {
+                IoctlCall::StartStepping { timer_value, .. } => Some(timer_value),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        //0x3000 has no dedicated timer and falls back to the default
+        assert_eq!(timer_values, vec![0x10, 0x20, 0x30]);
+        Ok(())
+    }
+
+    /// Advances the shared fake clock by `tick` on each event
+    struct AdvanceClock {
+        now: Rc<Cell<Instant>>,
+        tick: Duration,
+    }
+
+    impl EventHandler for AdvanceClock {
+        fn process(
+            &mut self,
+            _event: &Event,
+            _api: &mut SevStep,
+            _ctx: &mut HashMap<String, Vec<u8>>,
+        ) -> Result<StateMachineNextAction> {
+            self.now.set(self.now.get() + self.tick);
+            Ok(StateMachineNextAction::NEXT)
+        }
+
+        fn get_name(&self) -> &str {
+            "AdvanceClock"
+        }
+    }
+
+    #[test]
+    fn deadline_aborts_run_with_steady_events() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        //the deadline passes while handling the third event
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1); 3]);
+
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut advance_clock = AdvanceClock {
+            now: now.clone(),
+            tick: Duration::from_secs(1),
+        };
+        let mut stop = StopAfterNSingleStepsHandler::new(10, None);
+        let result = TargetedStepper::new(
+            api,
+            vec![&mut advance_clock, &mut stop],
+            TRACK_MODE,
+            vec![],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .with_deadline(Duration::from_millis(2500))
+        .with_clock(move || now.get())
+        .run();
+        feeder.join().unwrap();
+
+        assert!(matches!(result, Err(SevStepError::Timeout)));
+    }
+
+    #[test]
+    fn stop_when_register_stable_requires_streak() -> Result<()> {
+        let rcx = |v| MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RCX, v);
+        //the first streak is interrupted, zero steps don't break the second one
+        let events = vec![
+            rcx(0),
+            rcx(0),
+            rcx(1),
+            rcx(0),
+            MockEvent::step(0),
+            MockEvent::page_fault(0x1000),
+            rcx(0),
+            rcx(0),
+        ];
+
+        let mut stable = StopWhenRegisterStable::new(vmsa_register_name_t::VRN_RCX, 0, 3);
+        let mut histogram = BuildStepHistogram::new();
+        run_with_mock_events(events, vec![&mut stable, &mut histogram])?;
+
+        //the shutdown happens before the histogram sees the last step
+        assert_eq!(histogram.get_values(), &HashMap::from([(1, 5), (0, 1)]));
+        Ok(())
+    }
+
+    #[test]
+    fn validate_reports_all_problems() {
+        let (api, kernel, _abort) = SevStep::new_mock(false);
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[0x1000, 0x2010], TRACK_MODE, 0x20);
+        let mut stop = StopAfterNSingleStepsHandler::new(0, Some(vec![]));
+        let mut release = ReleaseAfterNInstructions::new(10, TRACK_MODE);
+        let stepper = TargetedStepper::new(
+            api,
+            vec![&mut skip, &mut stop, &mut release],
+            TRACK_MODE,
+            vec![0x1000, 0x3001],
+            || Ok(()),
+            None,
+        );
+
+        assert_eq!(
+            stepper.validate(),
+            Err(vec![
+                "initial tracking: GPA 0x3001 is not page aligned".to_string(),
+                "SkipIfNotOnTargetGPAs: GPA 0x2010 is not page aligned".to_string(),
+                "StopAfterNStepsHandler: step budget is 0, the run stops at the first step"
+                    .to_string(),
+                "StopAfterNStepsHandler: expected RIP values are set but empty".to_string(),
+            ])
+        );
+        assert!(kernel.calls().is_empty());
+    }
+
+    #[test]
+    fn validate_accepts_valid_configuration() {
+        let (api, _kernel, _abort) = SevStep::new_mock(false);
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[0x1000], TRACK_MODE, 0x20);
+        let mut stop = StopAfterNSingleStepsHandler::new(10, None);
+        let stepper = TargetedStepper::new(
+            api,
+            vec![&mut skip, &mut stop],
+            TRACK_MODE,
+            vec![0x1000],
+            || Ok(()),
+            None,
+        );
+        assert_eq!(stepper.validate(), Ok(()));
+    }
+
+    /// Records the GPAs of all page fault events
+    struct RecordFaults {
+        gpas: Vec<u64>,
+    }
+
+    impl EventHandler for RecordFaults {
+        fn process(
+            &mut self,
+            event: &Event,
+            _api: &mut SevStep,
+            _ctx: &mut HashMap<String, Vec<u8>>,
+        ) -> Result<StateMachineNextAction> {
+            if let Event::PageFaultEvent(v) = event {
+                self.gpas.push(v.faulted_gpa);
+            }
+            Ok(StateMachineNextAction::NEXT)
+        }
+
+        fn get_name(&self) -> &str {
+            "RecordFaults"
+        }
+    }
+
+    #[test]
+    fn dedup_consecutive_faults_drops_repeats() -> Result<()> {
+        let (a, b) = (0x1000, 0x2000);
+        let events = vec![
+            MockEvent::page_fault(a),
+            MockEvent::page_fault(a),
+            MockEvent::page_fault(b),
+            MockEvent::page_fault(a),
+            MockEvent::page_fault(a),
+            MockEvent::step(1),
+        ];
+
+        let mut dedup = DedupConsecutiveFaults::new();
+        let mut record = RecordFaults { gpas: Vec::new() };
+        let mut stop = StopAfterNSingleStepsHandler::new(0, None);
+        run_with_mock_events(events, vec![&mut dedup, &mut record, &mut stop])?;
+
+        assert_eq!(dedup.get_dropped(), 2);
+        assert_eq!(record.gpas, vec![a, b, a]);
+        Ok(())
+    }
+
+    #[test]
+    fn stop_after_n_records_all_rip_mismatches() {
+        let rips = [0x10, 0x99, 0x18, 0x77, 0x20];
+        let events = rips
+            .iter()
+            .map(|rip| MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, *rip))
+            .collect();
+
+        let mut stop = StopAfterNSingleStepsHandler::new(4, Some(vec![0x10, 0x14, 0x18, 0x1c]))
+            .with_record_all_mismatches(true);
+        let err = run_with_mock_events(events, vec![&mut stop]).unwrap_err();
+
+        assert_eq!(
+            stop.get_rip_mismatches(),
+            &[
+                RipMismatch {
+                    step: 2,
+                    expected: 0x14,
+                    got: 0x99
+                },
+                RipMismatch {
+                    step: 4,
+                    expected: 0x1c,
+                    got: 0x77
+                },
+            ]
+        );
+        let message = format!("{:#}", anyhow::Error::from(err));
+        assert!(message.contains("2 RIP mismatches"));
+    }
+
+    #[test]
+    fn trigger_and_per_event_timeouts_are_independent() {
+        //no events at all, the trigger timeout fires long before the per event timeout
+        let (api, _kernel, _abort) = SevStep::new_mock(false);
+        let mut stop = StopAfterNSingleStepsHandler::new(10, None);
+        let start = Instant::now();
+        let result =
+            TargetedStepper::new(api, vec![&mut stop], TRACK_MODE, vec![], || Ok(()), None)
+                .with_timeouts(StepperTimeouts {
+                    per_event: Some(Duration::from_secs(30)),
+                    total_run: None,
+                    trigger: Some(Duration::from_millis(50)),
+                })
+                .run();
+        assert!(matches!(result, Err(SevStepError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        //the first event arrives, but the second one does not
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1)]);
+        let mut stop = StopAfterNSingleStepsHandler::new(10, None);
+        let result =
+            TargetedStepper::new(api, vec![&mut stop], TRACK_MODE, vec![], || Ok(()), None)
+                .with_timeouts(StepperTimeouts {
+                    per_event: Some(Duration::from_millis(50)),
+                    total_run: None,
+                    trigger: Some(Duration::from_secs(30)),
+                })
+                .run();
+        feeder.join().unwrap();
+        assert!(matches!(result, Err(SevStepError::Timeout)));
+        assert_eq!(stop.step_counter, 1);
+    }
+
+    #[test]
+    fn deadline_bounds_wait_for_next_event() {
+        //the first event arrives, but the second one does not and there is no per event timeout
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1)]);
+        let mut stop = StopAfterNSingleStepsHandler::new(10, None);
+        let start = Instant::now();
+        let result =
+            TargetedStepper::new(api, vec![&mut stop], TRACK_MODE, vec![], || Ok(()), None)
+                .with_deadline(Duration::from_millis(100))
+                .run();
+        feeder.join().unwrap();
+        assert!(matches!(result, Err(SevStepError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(stop.step_counter, 1);
+    }
+
+    #[test]
+    fn total_run_timeout_uses_clock() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(&mut api, vec![MockEvent::step(1); 2]);
+
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut advance_clock = AdvanceClock {
+            now: now.clone(),
+            tick: Duration::from_secs(60),
+        };
+        let mut stop = StopAfterNSingleStepsHandler::new(10, None);
+        let result = TargetedStepper::new(
+            api,
+            vec![&mut advance_clock, &mut stop],
+            TRACK_MODE,
+            vec![],
+            || Ok(()),
+            None,
+        )
+        .with_timeouts(StepperTimeouts {
+            total_run: Some(Duration::from_secs(90)),
+            ..StepperTimeouts::per_event(Some(Duration::from_secs(5)))
+        })
+        .with_clock(move || now.get())
+        .run();
+        feeder.join().unwrap();
+
+        assert!(matches!(result, Err(SevStepError::Timeout)));
+        assert_eq!(stop.step_counter, 2);
+    }
+
+    #[test]
+    fn event_sequence_matches_golden_file() -> Result<()> {
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, 0x10),
+            MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, 0x14),
+        ];
+        let mut recorder = EventSequenceRecorder::new();
+        let mut stop = StopAfterNSingleStepsHandler::new(1, None);
+        run_with_mock_events(events, vec![&mut recorder, &mut stop])?;
+
+        let golden_path = std::env::temp_dir().join(format!("golden_{}.bin", std::process::id()));
+        recorder.save_golden(&golden_path)?;
+        recorder.assert_matches_golden(&golden_path)?;
+
+        let mut perturbed = EventSequenceRecorder::new();
+        perturbed.entries = recorder.get_entries().to_vec();
+        perturbed.entries[0].elapsed += Duration::from_secs(1);
+        perturbed.assert_matches_golden(&golden_path)?;
+        if let RecordedEvent::Step { registers, .. } = &mut perturbed.entries[2].event {
+            registers.as_mut().unwrap()[vmsa_register_name_t::VRN_RIP as usize] = 0x18;
+        }
+        let err = perturbed.assert_matches_golden(&golden_path).unwrap_err();
+        assert!(err.to_string().starts_with("event 2 differs"));
+        perturbed.entries.pop();
+        let err = perturbed.assert_matches_golden(&golden_path).unwrap_err();
+        assert!(err.to_string().starts_with("recorded 2 events"));
+
+        std::fs::remove_file(golden_path)?;
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires the msr kernel module and read access to /dev/cpu/0/msr"]
+    fn sample_host_msr_records_step_deltas() -> Result<()> {
+        const IA32_TIME_STAMP_COUNTER: u32 = 0x10;
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            MockEvent::step(1),
+            MockEvent::step(1),
+        ];
+        let mut sample_tsc = SampleHostMsr::new(0, IA32_TIME_STAMP_COUNTER);
+        let mut stop = StopAfterNSingleStepsHandler::new(1, None);
+        run_with_mock_events(events, vec![&mut sample_tsc, &mut stop])?;
+
+        assert_eq!(sample_tsc.get_step_deltas().len(), 2);
+        assert!(sample_tsc.get_step_deltas().iter().all(|v| *v > 0));
+        Ok(())
+    }
+
+    #[test]
+    fn chrome_trace_has_one_event_per_processed_event() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let events = vec![
+            MockEvent::page_fault(0x1000),
+            MockEvent::step(1),
+            MockEvent::step(1),
+            MockEvent::step(1),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut advance_clock = AdvanceClock {
+            now: now.clone(),
+            tick: Duration::from_millis(1),
+        };
+        let mut stop = StopAfterNSingleStepsHandler::new(2, None);
+        let mut recorder = ChromeTraceRecorder::new();
+        TargetedStepper::new(
+            api,
+            vec![&mut advance_clock, &mut stop],
+            TRACK_MODE,
+            vec![],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .with_clock(move || now.get())
+        .with_chrome_trace(&mut recorder)
+        .run()?;
+        feeder.join().unwrap();
+        assert_eq!(recorder.len(), 4);
+
+        let mut buf = Vec::new();
+        recorder.write(&mut buf)?;
+        let trace: Vec<serde_json::Value> = serde_json::from_slice(&buf)?;
+        let (metadata, events): (Vec<_>, Vec<_>) = trace.iter().partition(|v| v["ph"] == "M");
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0]["args"]["name"], "StopAfterNStepsHandler");
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0]["ph"], "i");
+        assert_eq!(events[0]["args"]["gpa"], "0x1000");
+        //each step spans from the previous ack to its own ack, i.e. one clock tick
+        for step in &events[1..] {
+            assert_eq!(step["ph"], "X");
+            assert_eq!(step["dur"], 1000.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fault_while_stepping_fails_run() {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let target_gpa = 0x1000;
+        let events = vec![
+            MockEvent::page_fault(target_gpa),
+            MockEvent::step(1),
+            MockEvent::page_fault(0x2000),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut assert_no_faults = AssertNoFaultsWhileStepping::new();
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[target_gpa], TRACK_MODE, 42);
+        let result = TargetedStepper::new(
+            api,
+            vec![&mut assert_no_faults, &mut skip],
+            TRACK_MODE,
+            vec![target_gpa],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run();
+        feeder.join().unwrap();
+
+        let err = result.expect_err("fault while stepping should fail the run");
+        assert!(err
+            .to_string()
+            .contains("page fault at GPA 0x2000 while single stepping is active"));
+    }
+
+    #[test]
+    fn faults_before_stepping_are_allowed() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let target_gpa = 0x1000;
+        let events = vec![
+            MockEvent::page_fault(0x2000),
+            MockEvent::page_fault(target_gpa),
+            MockEvent::step(1),
+            MockEvent::step(1),
+        ];
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut assert_no_faults = AssertNoFaultsWhileStepping::new();
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[target_gpa], TRACK_MODE, 42);
+        let mut stop = StopAfterNSingleStepsHandler::new(1, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut assert_no_faults, &mut skip, &mut stop],
+            TRACK_MODE,
+            vec![target_gpa],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn run_untracks_pages_that_never_faulted() -> Result<()> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let feeder = spawn_event_feeder(
+            &mut api,
+            vec![MockEvent::page_fault(0x2000), MockEvent::step(1)],
+        );
+
+        let mut stop = StopAfterNSingleStepsHandler::new(0, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut stop],
+            TRACK_MODE,
+            vec![0x1000, 0x2000, 0x3000],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        let untracked = kernel
+            .calls()
+            .into_iter()
+            .filter(|v| matches!(v, IoctlCall::UntrackPage { .. }))
+            .collect::<Vec<_>>();
+        //0x2000 has been untracked by the kernel, when it reported the fault
+        assert_eq!(
+            untracked,
+            vec![
+                IoctlCall::UntrackPage {
+                    gpa: 0x1000,
+                    track_mode: TRACK_MODE as i32
+                },
+                IoctlCall::UntrackPage {
+                    gpa: 0x3000,
+                    track_mode: TRACK_MODE as i32
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    /// Counts the steps of each repetition in `on_finish`
+    struct StepsPerRepetition {
+        steps: usize,
+        finished: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl EventHandler for StepsPerRepetition {
+        fn process(
+            &mut self,
+            event: &Event,
+            _api: &mut SevStep,
+            _ctx: &mut HashMap<String, Vec<u8>>,
+        ) -> Result<StateMachineNextAction> {
+            if let Event::StepEvent(_) = event {
+                self.steps += 1;
+            }
+            Ok(StateMachineNextAction::NEXT)
+        }
+
+        fn on_finish(
+            &mut self,
+            _api: &mut SevStep,
+            _ctx: &mut HashMap<String, Vec<u8>>,
+        ) -> Result<()> {
+            self.finished.borrow_mut().push(self.steps);
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            self.steps = 0;
+        }
+
+        fn get_name(&self) -> &str {
+            "StepsPerRepetition"
+        }
+    }
+
+    #[test]
+    fn auto_retrigger_repeats_victim() -> Result<()> {
+        let (mut api, kernel, _abort) = SevStep::new_mock(false);
+        let victim_gpa = 0x1000;
+        let repetition = vec![
+            MockEvent::page_fault(victim_gpa),
+            MockEvent::step(1),
+            MockEvent::step(1),
+            MockEvent::page_fault(0x5000),
+        ];
+        let feeder = spawn_event_feeder(&mut api, [repetition.clone(), repetition].concat());
+
+        //the second invocation runs in the background and may return after the run
+        let (trigger_calls, trigger_calls_receiver) = channel();
+        let finished = Rc::new(RefCell::new(Vec::new()));
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[victim_gpa], TRACK_MODE, 42);
+        let mut steps = StepsPerRepetition {
+            steps: 0,
+            finished: finished.clone(),
+        };
+        let mut auto_retrigger = AutoRetrigger::new(
+            move || {
+                trigger_calls.send(())?;
+                Ok(())
+            },
+            2,
+            &[victim_gpa],
+            vec![&mut skip, &mut steps],
+        );
+        let first_trigger = auto_retrigger.first_trigger();
+        TargetedStepper::new(
+            api,
+            vec![&mut auto_retrigger],
+            TRACK_MODE,
+            vec![victim_gpa],
+            first_trigger,
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(auto_retrigger.get_completed_repeats(), 2);
+        for _ in 0..2 {
+            trigger_calls_receiver.recv_timeout(Duration::from_secs(5))?;
+        }
+        assert_eq!(*finished.borrow(), vec![2, 2]);
+        //stepping is started once per repetition
+        let start_stepping_calls = kernel
+            .calls()
+            .iter()
+            .filter(|v| matches!(v, IoctlCall::StartStepping { .. }))
+            .count();
+        assert_eq!(start_stepping_calls, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn auto_retrigger_resets_library_handlers() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let victim_gpa = 0x1000;
+        let repetition = vec![
+            MockEvent::page_fault(victim_gpa),
+            MockEvent::step(1),
+            MockEvent::step(1),
+            MockEvent::page_fault(0x5000),
+        ];
+        let feeder = spawn_event_feeder(&mut api, [repetition.clone(), repetition].concat());
+
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[victim_gpa], TRACK_MODE, 42);
+        //fails in on_finish of the second repetition if the steps of the first one are still counted
+        let mut total_steps = AssertTotalSteps::new(HashSet::from([2]));
+        let mut auto_retrigger = AutoRetrigger::new(
+            || Ok(()),
+            2,
+            &[victim_gpa],
+            vec![&mut skip, &mut total_steps],
+        );
+        let first_trigger = auto_retrigger.first_trigger();
+        TargetedStepper::new(
+            api,
+            vec![&mut auto_retrigger],
+            TRACK_MODE,
+            vec![victim_gpa],
+            first_trigger,
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        assert_eq!(auto_retrigger.get_completed_repeats(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn warmup_steps_are_discarded() -> Result<()> {
+        let (mut api, _kernel, _abort) = SevStep::new_mock(false);
+        let target_gpa = 0x1000;
+        let warmup_steps = 3;
+        let mut events = vec![MockEvent::page_fault(target_gpa)];
+        events.extend((0..warmup_steps).map(|_| MockEvent::step(2)));
+        events.extend([MockEvent::step(1), MockEvent::step(1)]);
+        let feeder = spawn_event_feeder(&mut api, events);
+
+        let mut skip = SkipIfNotOnTargetGPAs::new(&[target_gpa], TRACK_MODE, 42)
+            .with_warmup_steps(warmup_steps);
+        let mut histogram = BuildStepHistogram::new();
+        let mut stop = StopAfterNSingleStepsHandler::new(1, None);
+        TargetedStepper::new(
+            api,
+            vec![&mut skip, &mut histogram, &mut stop],
+            TRACK_MODE,
+            vec![target_gpa],
+            || Ok(()),
+            Some(Duration::from_secs(5)),
+        )
+        .run()?;
+        feeder.join().unwrap();
+
+        //the multi steps during the warmup never reach the histogram
+        assert_eq!(histogram.get_values(), &HashMap::from([(1, 2)]));
+        Ok(())
+    }
+
+    #[test]
+    fn basic_block_trace_collapses_repeated_blocks() -> Result<()> {
+        //block 0 loops twice over block 1, then falls through to block 2. 0x3000 is outside of all blocks
+        let rips = [
+            0x1000, 0x1004, 0x1010, 0x1014, 0x1004, 0x1010, 0x1020, 0x3000, 0x1024,
+        ];
+        let mut events = vec![MockEvent::page_fault(0x1000), MockEvent::step(0)];
+        events.extend(
+            rips.iter()
+                .map(|v| MockEvent::step(1).with_register(vmsa_register_name_t::VRN_RIP, *v)),
+        );
+
+        let mut bb_trace = RecordBasicBlockTrace::new(vec![
+            (0x1000..0x1010, 0),
+            (0x1010..0x1020, 1),
+            (0x1020..0x1030, 2),
+        ])?;
+        let mut stop = StopAfterNSingleStepsHandler::new(rips.len() - 1, None);
+        run_with_mock_events(events, vec![&mut bb_trace, &mut stop])?;
+
+        assert_eq!(bb_trace.get_trace(), &vec![0, 1, 0, 1, 2]);
+        assert_eq!(bb_trace.get_unmapped_steps(), 1);
+        assert!(
+            RecordBasicBlockTrace::new(vec![(0x1000..0x1010, 0), (0x100c..0x1020, 1)]).is_err()
+        );
+        Ok(())
     }
 }
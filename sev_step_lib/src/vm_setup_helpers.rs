@@ -1,7 +1,15 @@
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
 use anyhow::{bail, Context, Result};
+use log::{debug, warn};
 use nix::{sched, sched::CpuSet, unistd::Pid};
 use qapi::{qmp, Qmp};
 
+use crate::config::Config;
+
 /// Returns the thread id of the VM's VCPU. If multiple VPCUs exists an error is returned
 /// # Arguments
 /// - qmp_addr address where QEMU's qmp monitor listens. Format IP:Port
@@ -41,3 +49,229 @@ pub fn pin_pid_to_cpu(thread_id: i64, cpu: usize) -> Result<()> {
 
     Ok(())
 }
+
+/// Extract the `processor` field, i.e. the cpu the task last ran on, from the content of
+/// "/proc/<pid>/stat" or "/proc/<pid>/task/<tid>/stat"
+fn parse_stat_processor(stat: &str) -> Result<usize> {
+    //the command name is enclosed in parenthesis and may itself contain spaces and parenthesis.
+    //Thus, we start counting the fields after the last closing one
+    let (_, fields) = stat
+        .rsplit_once(')')
+        .context(format!("missing command name in stat line \"{}\"", stat))?;
+    //`processor` is field 39. The first field after the command name is field 3
+    let processor = fields
+        .split_whitespace()
+        .nth(39 - 3)
+        .context(format!("stat line \"{}\" has no processor field", stat))?;
+    processor.parse().context(format!(
+        "failed to parse processor field \"{}\" as cpu",
+        processor
+    ))
+}
+
+/// Waits until the given pid/tid has been scheduled on `cpu`, e.g. after it was pinned with [`pin_pid_to_cpu`].
+/// Until then, the task might still run on its previous core and arming single stepping can miss the start of
+/// the victim. Polls the `processor` field in "/proc/<tid>/stat"
+/// # Arguments
+/// * `thread_id` : pid/tid of the task
+/// * `cpu` : core on which the task is expected to run
+/// * `timeout` : return an error if the task has not been scheduled on `cpu` within this duration
+pub fn wait_until_on_core(thread_id: i64, cpu: usize, timeout: Duration) -> Result<()> {
+    let stat_path = format!("/proc/{}/stat", thread_id);
+    let start = Instant::now();
+    loop {
+        let stat =
+            fs::read_to_string(&stat_path).context(format!("failed to read {}", stat_path))?;
+        let current_cpu = parse_stat_processor(&stat)
+            .context(format!("failed to parse {}", stat_path))?;
+        if current_cpu == cpu {
+            debug!(
+                "tid {} is running on core {} after {:?}",
+                thread_id,
+                cpu,
+                start.elapsed()
+            );
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            bail!(
+                "tid {} was not scheduled on core {} within {:?}, last seen on core {}",
+                thread_id,
+                cpu,
+                timeout,
+                current_cpu
+            );
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Parse a list of cpus in the Linux cpu list format, i.e. comma separated cpus and inclusive
+/// ranges like "2-5,9". Surrounding whitespace, e.g. the newline in sysfs files, is ignored.
+/// An empty list is valid and yields no cpus
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let list = list.trim();
+    if list.is_empty() {
+        return Ok(Vec::new());
+    }
+    let parse_cpu = |v: &str| {
+        v.parse::<usize>()
+            .context(format!("invalid cpu \"{}\" in cpu list \"{}\"", v, list))
+    };
+    let mut cpus = Vec::new();
+    for token in list.split(',') {
+        match token.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse_cpu(start)?, parse_cpu(end)?);
+                if start > end {
+                    bail!("range {} in cpu list \"{}\" is reversed", token, list);
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(parse_cpu(token)?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Extract the cpus from the `isolcpus` parameter in the kernel command line, if it is present.
+/// Leading flags like `domain` or `managed_irq` are skipped
+fn parse_isolcpus_param(cmdline: &str) -> Result<Option<Vec<usize>>> {
+    let value = match cmdline
+        .split_whitespace()
+        .find_map(|v| v.strip_prefix("isolcpus="))
+    {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let cpu_list = value
+        .split(',')
+        .skip_while(|v| v.starts_with(|c: char| c.is_ascii_alphabetic()))
+        .collect::<Vec<_>>()
+        .join(",");
+    Ok(Some(parse_cpu_list(&cpu_list)?))
+}
+
+/// Returns an error if `cpu` is not isolated from the rest of the system, i.e. if the kernel may
+/// schedule other tasks on it. Checks "/sys/devices/system/cpu/isolated" and falls back to the
+/// `isolcpus` parameter in "/proc/cmdline"
+pub fn verify_core_isolated(cpu: usize) -> Result<()> {
+    let isolated = match fs::read_to_string("/sys/devices/system/cpu/isolated") {
+        Ok(v) => parse_cpu_list(&v).context("failed to parse /sys/devices/system/cpu/isolated")?,
+        Err(e) => {
+            debug!(
+                "failed to read /sys/devices/system/cpu/isolated : {}, checking /proc/cmdline",
+                e
+            );
+            let cmdline =
+                fs::read_to_string("/proc/cmdline").context("failed to read /proc/cmdline")?;
+            parse_isolcpus_param(&cmdline)
+                .context("failed to parse isolcpus parameter")?
+                .unwrap_or_default()
+        }
+    };
+    if !isolated.contains(&cpu) {
+        bail!(
+            "core {} is not isolated. Isolated cores: {:?}. See the README for how to isolate a core",
+            cpu,
+            isolated
+        );
+    }
+    Ok(())
+}
+
+/// Run [`verify_core_isolated`]. If `required` is false, a failed check is only logged as a warning
+pub fn check_core_isolation(cpu: usize, required: bool) -> Result<()> {
+    match verify_core_isolated(cpu) {
+        Ok(()) => Ok(()),
+        Err(e) if required => Err(e),
+        Err(e) => {
+            warn!("{:#}. Stepping results may be unreliable", e);
+            Ok(())
+        }
+    }
+}
+
+/// Check that `vm_config.vm_cpu_core` is isolated (see [`check_core_isolation`]) and pin the vcpu to it.
+/// If `vm_config.wait_until_on_core_ms` is set, also wait until the vcpu has been scheduled on the core
+/// (see [`wait_until_on_core`])
+/// # Arguments
+/// * `vcpu_thread_id` : tid of the vcpu, see [`get_vcpu_thread_id`]
+/// * `vm_config` : provides the core, whether the isolation is required and the wait timeout
+pub fn pin_vcpu_to_vm_core(vcpu_thread_id: i64, vm_config: &Config) -> Result<()> {
+    check_core_isolation(vm_config.vm_cpu_core, vm_config.require_isolated_core).context(
+        format!(
+            "core {} failed the isolation check",
+            vm_config.vm_cpu_core
+        ),
+    )?;
+
+    pin_pid_to_cpu(vcpu_thread_id, vm_config.vm_cpu_core).context(format!(
+        "failed to pin vcpu (tid {}) to core {}",
+        vcpu_thread_id, vm_config.vm_cpu_core,
+    ))?;
+    debug!(
+        "Pinned vcpu_thread (tid {}) to core {}",
+        vcpu_thread_id, vm_config.vm_cpu_core
+    );
+    if let Some(timeout_ms) = vm_config.wait_until_on_core_ms {
+        wait_until_on_core(
+            vcpu_thread_id,
+            vm_config.vm_cpu_core,
+            Duration::from_millis(timeout_ms),
+        )
+        .context("vcpu did not move to its core")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cpu_list_formats() -> Result<()> {
+        assert_eq!(parse_cpu_list("")?, Vec::<usize>::new());
+        assert_eq!(parse_cpu_list("3")?, vec![3]);
+        assert_eq!(parse_cpu_list("2-5")?, vec![2, 3, 4, 5]);
+        assert_eq!(parse_cpu_list("2-5,9,11-12")?, vec![2, 3, 4, 5, 9, 11, 12]);
+        for malformed in ["a", "2-", "-3", "5-2", "2,,3", "2,", "1-2-3", "2 ,3"] {
+            assert!(
+                parse_cpu_list(malformed).is_err(),
+                "\"{}\" should be rejected",
+                malformed
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_isolated_cpu_strings() -> Result<()> {
+        assert_eq!(parse_cpu_list("2-5,9\n")?, vec![2, 3, 4, 5, 9]);
+        assert_eq!(parse_cpu_list("\n")?, Vec::<usize>::new());
+        assert!(parse_cpu_list("2-x").is_err());
+
+        assert_eq!(parse_isolcpus_param("ro quiet")?, None);
+        assert_eq!(
+            parse_isolcpus_param("BOOT_IMAGE=/vmlinuz isolcpus=2-5,9 nohz_full=2-5,9")?,
+            Some(vec![2, 3, 4, 5, 9])
+        );
+        assert_eq!(
+            parse_isolcpus_param("isolcpus=domain,managed_irq,9 quiet")?,
+            Some(vec![9])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_processor_from_stat_line() -> Result<()> {
+        //command names may contain spaces and parenthesis
+        let stat = "4242 (CPU 0/KVM (x)) S 4200 4200 4200 0 -1 4194624 1127 0 0 0 831 289 0 0 20 0 \
+                    3 0 12345 0 0 18446744073709551615 0 0 0 0 0 0 0 4096 0 0 0 0 -1 9 0 0 0 0 0 \
+                    0 0 0 0 0 0 0 0";
+        assert_eq!(parse_stat_processor(stat)?, 9);
+        assert!(parse_stat_processor("4242 (qemu) S 4200").is_err());
+        assert!(parse_stat_processor("no command name").is_err());
+        Ok(())
+    }
+}
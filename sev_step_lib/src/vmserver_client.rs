@@ -1,21 +1,296 @@
-use std::num::ParseIntError;
+use std::{collections::HashMap, num::ParseIntError};
 
 use std::{env::temp_dir, fs::File};
 
 use anyhow::{bail, Context, Result};
+use iced_x86::{Code, Decoder, DecoderOptions, Instruction, Mnemonic};
 
 use reqwest::{
-    blocking::{multipart::Form, Client},
+    blocking::{multipart::Form, Client, RequestBuilder},
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
     Url,
 };
 use tar::Builder;
 use vm_server::req_resp::*;
 
+use crate::api::PAGE_SHIFT;
+
 /// Helper function to parse a string that might have hex prefix "0x" to u64
 pub fn parse_hex_str(v: &str) -> Result<u64, ParseIntError> {
     u64::from_str_radix(v.strip_prefix("0x").unwrap_or(v), 16)
 }
 
+/// GPAs of all pages that contain at least one byte of the inclusive range between `start_gpa` and `end_gpa`,
+/// in ascending order. Use it to track a span reported by a victim, e.g. the code between two function addresses.
+/// The order of the bounds does not matter
+pub fn pages_between(start_gpa: u64, end_gpa: u64) -> Vec<u64> {
+    let first_page = start_gpa.min(end_gpa) >> PAGE_SHIFT;
+    let last_page = start_gpa.max(end_gpa) >> PAGE_SHIFT;
+    (first_page..=last_page)
+        .map(|page| page << PAGE_SHIFT)
+        .collect()
+}
+
+/// Client for the VM server. Configure it once, e.g. with a path prefix or additional headers
+/// for deployments behind a reverse proxy, and use it for all requests.
+/// The free functions in this module are shorthands for a client without additional headers
+#[derive(Clone)]
+pub struct VmServerClient {
+    ///Always ends with a "/", so that endpoints can be joined relative to the path prefix
+    base_url: Url,
+    default_headers: HeaderMap,
+    client: Client,
+    ///Encoding used for [`InitAssemblyTargetReq`]
+    wire_format: WireFormat,
+}
+
+impl VmServerClient {
+    /// # Arguments
+    /// * `basepath` : URL of the VM server. May contain a path prefix under which all endpoints are located
+    pub fn new(basepath: &str) -> Result<VmServerClient> {
+        let mut base_url =
+            Url::parse(basepath).context(format!("cannot parse {} as url", basepath))?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Ok(VmServerClient {
+            base_url,
+            default_headers: HeaderMap::new(),
+            client: Client::new(),
+            wire_format: WireFormat::default(),
+        })
+    }
+
+    /// Send the given header with every request
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<VmServerClient> {
+        let name = HeaderName::try_from(name).context(format!("invalid header name {}", name))?;
+        let value =
+            HeaderValue::try_from(value).context(format!("invalid value for header {}", name))?;
+        self.default_headers.insert(name, value);
+        self.client = Client::builder()
+            .default_headers(self.default_headers.clone())
+            .build()
+            .context("failed to build http client")?;
+        Ok(self)
+    }
+
+    /// Encode [`InitAssemblyTargetReq`]s as `wire_format` instead of JSON. Use [`WireFormat::Bincode`]
+    /// to speed up uploading large programs
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> VmServerClient {
+        self.wire_format = wire_format;
+        self
+    }
+
+    fn endpoint_url(&self, endpoint: &str) -> Result<Url> {
+        self.base_url.join(endpoint).context(format!(
+            "failed to append {} to base URL {}",
+            endpoint, self.base_url
+        ))
+    }
+
+    /// See [`new_custom_target`]
+    pub fn new_custom_target(&self, args: &InitCustomTargetReq) -> Result<InitCustomTargetResp> {
+        let url = self.endpoint_url("custom-target/new")?;
+
+        //create temporary file for archive, and add all files from `args.folder_path` to it
+        let archive_dir = temp_dir();
+        let archive_file_path = archive_dir.join("vmserver_upload.tar");
+        let archive_file = File::create(archive_dir.join("vmserver_upload.tar"))?;
+        let mut archive = Builder::new(archive_file);
+        archive.append_dir_all("./", &args.folder_path)?;
+        drop(archive.into_inner()?);
+
+        let form = Form::new()
+            .text("execute_cmd", args.execute_cmd.clone())
+            .file("file_archive", archive_file_path)?;
+
+        self.client
+            .post(url)
+            .multipart(form)
+            .send()
+            .context("error sending request")?
+            .error_for_status()
+            .context("server returned error code")?
+            .json()
+            .context("failed to parse body")
+    }
+
+    /// See [`new_page_ping_ponger`]
+    pub fn new_page_ping_ponger(
+        &self,
+        args: &InitPagePingPongerReq,
+    ) -> Result<InitPagePingPongerResp> {
+        let url = self.endpoint_url("page-ping-ponger/new")?;
+
+        self.client
+            .post(url)
+            .json(args)
+            .send()
+            .context("error sending request")?
+            .error_for_status()
+            .context("server returned error code")?
+            .json()
+            .context("failed to parse body")
+    }
+
+    /// See [`new_param_branch_target`]
+    pub fn new_param_branch_target(
+        &self,
+        req: &InitParamBranchReq,
+    ) -> Result<InitAssemblyTargetResp> {
+        let url = self.endpoint_url("param-branch/new")?;
+
+        self.client
+            .post(url)
+            .json(req)
+            .send()
+            .context("error sending request")?
+            .error_for_status()
+            .context("server returned error code")?
+            .json()
+            .context("failed to parse body")
+    }
+
+    /// See [`new_assembly_target`]
+    pub fn new_assembly_target(
+        &self,
+        req: &InitAssemblyTargetReq,
+    ) -> Result<InitAssemblyTargetResp> {
+        self.post_assembly_target("assembly-target/new", req)
+    }
+
+    /// See [`new_named_assembly_target`]
+    pub fn new_named_assembly_target(
+        &self,
+        name: &str,
+        req: &InitAssemblyTargetReq,
+    ) -> Result<InitAssemblyTargetResp> {
+        self.post_assembly_target(&format!("target/{}/new", name), req)
+    }
+
+    fn post_assembly_target(
+        &self,
+        endpoint: &str,
+        req: &InitAssemblyTargetReq,
+    ) -> Result<InitAssemblyTargetResp> {
+        let url = self.endpoint_url(endpoint)?;
+
+        let request = self.client.post(url.clone());
+        let request = match self.wire_format {
+            WireFormat::Json => request.json(&req),
+            WireFormat::Bincode => request
+                .header(CONTENT_TYPE, self.wire_format.content_type())
+                .body(bincode::serialize(req).context("failed to serialize request")?),
+        };
+        request
+            .send()
+            .context(format!("error sending post request to {}", url))?
+            .error_for_status()
+            .context("server returned error code")?
+            .json()
+            .context("failed to parse body")
+    }
+
+    /// See [`new_assembly_target_from_text`]
+    #[cfg(feature = "nasm")]
+    pub fn new_assembly_target_from_text(
+        &self,
+        asm_text: &str,
+        required_mem_bytes: usize,
+    ) -> Result<InitAssemblyTargetResp> {
+        let code = assemble_nasm(asm_text)?;
+        self.new_assembly_target(&InitAssemblyTargetReq {
+            code: decode_flat_binary(&code)?,
+            required_mem_bytes,
+            initial_data: Vec::new(),
+        })
+    }
+
+    /// See [`run_target_program`]
+    pub fn run_target_program(&self) -> Result<()> {
+        self.post_empty("run-target")
+    }
+
+    /// See [`run_named_target`]
+    pub fn run_named_target(&self, name: &str) -> Result<()> {
+        self.post_empty(&format!("target/{}/run", name))
+    }
+
+    ///Send a POST request without body to `endpoint` and check the status code of the response
+    fn post_empty(&self, endpoint: &str) -> Result<()> {
+        self.post_checked(endpoint, |req| req)
+    }
+
+    ///Send a POST request to `endpoint`, using `build` to add e.g. a body, and check the status code of the response
+    fn post_checked(
+        &self,
+        endpoint: &str,
+        build: impl FnOnce(RequestBuilder) -> RequestBuilder,
+    ) -> Result<()> {
+        let url = self.endpoint_url(endpoint)?;
+
+        let resp = build(self.client.post(url.clone()))
+            .send()
+            .context(format!("error sending post request to {}", url))?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => bail!("server returned error {}", resp.text()?),
+        }
+    }
+
+    /// See [`flush_target`]
+    pub fn flush_target(&self) -> Result<()> {
+        self.post_empty("flush-target")
+    }
+
+    /// See [`set_target_affinity`]
+    pub fn set_target_affinity(&self, cpu: usize) -> Result<()> {
+        self.post_checked("set-target-affinity", |req| {
+            req.json(&SetTargetAffinityReq { cpu })
+        })
+    }
+
+    /// See [`teardown_target`]
+    pub fn teardown_target(&self) -> Result<()> {
+        self.post_empty("teardown-target")
+    }
+
+    /// See [`last_return_value`]
+    pub fn last_return_value(&self) -> Result<Option<u64>> {
+        let url = self.endpoint_url("last-return-value")?;
+
+        let resp: LastReturnValueResp = self
+            .client
+            .get(url.clone())
+            .send()
+            .context(format!("error sending get request to {}", url))?
+            .error_for_status()
+            .context("server returned error code")?
+            .json()
+            .context("failed to parse body")?;
+        Ok(resp.return_value)
+    }
+
+    /// See [`translate_offset`]
+    pub fn translate_offset(
+        &self,
+        buffer: TargetBuffer,
+        offset: usize,
+    ) -> Result<TranslateOffsetResp> {
+        let url = self.endpoint_url("translate-offset")?;
+
+        self.client
+            .post(url.clone())
+            .json(&TranslateOffsetReq { buffer, offset })
+            .send()
+            .context(format!("error sending post request to {}", url))?
+            .error_for_status()
+            .context("server returned error code")?
+            .json()
+            .context("failed to parse body")
+    }
+}
+
 /// Prepare the VM server to execute an arbitrary, binary. The binary must adhere
 /// to the communication protocol documented in the `InitCustomTargetReq` struct.
 /// This allows the VM server to provide you with GPA's and other relevant information to quickly
@@ -24,90 +299,347 @@ pub fn new_custom_target(
     basepath: &str,
     args: &InitCustomTargetReq,
 ) -> Result<InitCustomTargetResp> {
-    let url = Url::parse(basepath).context(format!("cannot parse {} as url", basepath))?;
-    const SUB_URL: &'static str = "/custom-target/new";
-    let url = url.join(&SUB_URL).context(format!(
-        "failed to append {} to base URL {}",
-        SUB_URL, basepath
-    ))?;
-
-    //create temporary file for archive, and add all files from `args.folder_path` to it
-    let archive_dir = temp_dir();
-    let archive_file_path = archive_dir.join("vmserver_upload.tar");
-    let archive_file = File::create(archive_dir.join("vmserver_upload.tar"))?;
-    let mut archive = Builder::new(archive_file);
-    archive.append_dir_all("./", &args.folder_path)?;
-    drop(archive.into_inner()?);
-
-    let form = Form::new()
-        .text("execute_cmd", args.execute_cmd.clone())
-        .file("file_archive", archive_file_path)?;
-
-    let client = reqwest::blocking::Client::new();
-    client
-        .post(url)
-        .multipart(form)
-        .send()
-        .context("error sending request")?
-        .error_for_status()
-        .context("server returned error code")?
-        .json()
-        .context("failed to parse body")
+    VmServerClient::new(basepath)?.new_custom_target(args)
 }
 
 pub fn new_page_ping_ponger(
     basepath: &str,
     args: &InitPagePingPongerReq,
 ) -> Result<InitPagePingPongerResp> {
-    let url = Url::parse(basepath).context(format!("cannot parse {} as url", basepath))?;
-    const SUB_URL: &'static str = "/page-ping-ponger/new";
-    let url = url.join(&SUB_URL).context(format!(
-        "failed to append {} to base URL {}",
-        SUB_URL, basepath
-    ))?;
-
-    let client = Client::new();
-    client
-        .post(url)
-        .json(args)
-        .send()
-        .context("error sending request")?
-        .error_for_status()
-        .context("server returned error code")?
-        .json()
-        .context("failed to parse body")
+    VmServerClient::new(basepath)?.new_page_ping_ponger(args)
 }
 
 pub fn new_assembly_target(
     basepath: &str,
     req: &InitAssemblyTargetReq,
 ) -> Result<InitAssemblyTargetResp> {
-    let url = Url::parse(basepath).context(format!("failed to parse {} as url", basepath))?;
-    let url = url.join("/assembly-target/new")?;
+    VmServerClient::new(basepath)?.new_assembly_target(req)
+}
 
-    let client = Client::new();
-    client
-        .post(url.clone())
-        .json(&req)
-        .send()
-        .context(format!("error sending post request to {}", url))?
-        .error_for_status()
-        .context("server returned error code")?
-        .json()
-        .context("failed to parse body")
+/// Victim that branches on whether `req.input` matches `req.secret`. The input is read from the
+/// data buffer, thus sweeping over inputs does not require building new code
+pub fn new_param_branch_target(
+    basepath: &str,
+    req: &InitParamBranchReq,
+) -> Result<InitAssemblyTargetResp> {
+    VmServerClient::new(basepath)?.new_param_branch_target(req)
+}
+
+/// Like [`new_assembly_target`] but the code is given as nasm assembly text,
+/// see [`assemble_nasm`] for the supported syntax
+/// # Arguments
+/// * `asm_text` : assembly code, one instruction per line
+/// * `required_mem_bytes` : size of the data buffer that is passed to the code, see [`InitAssemblyTargetReq`]
+#[cfg(feature = "nasm")]
+pub fn new_assembly_target_from_text(
+    basepath: &str,
+    asm_text: &str,
+    required_mem_bytes: usize,
+) -> Result<InitAssemblyTargetResp> {
+    VmServerClient::new(basepath)?.new_assembly_target_from_text(asm_text, required_mem_bytes)
 }
 
 pub fn run_target_program(basepath: &str) -> Result<()> {
-    let url = Url::parse(basepath).context(format!("failed to parse {} as url", basepath))?;
-    let url = url.join("/run-target")?;
-
-    let client = Client::new();
-    let resp = client
-        .post(url.clone())
-        .send()
-        .context(format!("error sending post request to {}", url))?;
-    match resp.status().is_success() {
-        true => Ok(()),
-        false => bail!("server returned error {}", resp.text()?),
+    VmServerClient::new(basepath)?.run_target_program()
+}
+
+/// Like [`new_assembly_target`] but stores the target under `name`, allowing multiple targets, e.g.
+/// an attacker program next to the victim, to be loaded at the same time. Replaces any previous target
+/// with the same name
+pub fn new_named_assembly_target(
+    basepath: &str,
+    name: &str,
+    req: &InitAssemblyTargetReq,
+) -> Result<InitAssemblyTargetResp> {
+    VmServerClient::new(basepath)?.new_named_assembly_target(name, req)
+}
+
+/// Run the target that was loaded with [`new_named_assembly_target`] under `name`
+pub fn run_named_target(basepath: &str, name: &str) -> Result<()> {
+    VmServerClient::new(basepath)?.run_named_target(name)
+}
+
+/// Flush the code and data of the current target from the cache, so that the next run
+/// starts with a cold cache. Only supported by assembly based targets
+pub fn flush_target(basepath: &str) -> Result<()> {
+    VmServerClient::new(basepath)?.flush_target()
+}
+
+/// Run all subsequent targets on the guest CPU `cpu`, e.g. the vCPU that is single stepped.
+/// Otherwise, the scheduler of the guest may move the victim to another vCPU
+pub fn set_target_affinity(basepath: &str, cpu: usize) -> Result<()> {
+    VmServerClient::new(basepath)?.set_target_affinity(cpu)
+}
+
+/// Stop the current target and release its resources. Succeeds if no target is loaded
+pub fn teardown_target(basepath: &str) -> Result<()> {
+    VmServerClient::new(basepath)?.teardown_target()
+}
+
+/// Value that the current target returned in rax on its most recent run. Allows victims to
+/// communicate a result back to the attacker. Only supported by assembly based targets
+pub fn last_return_value(basepath: &str) -> Result<Option<u64>> {
+    VmServerClient::new(basepath)?.last_return_value()
+}
+
+/// Translate `offset` bytes into `buffer` of the current target to a physical address.
+/// Unlike adding `offset` to the base address from [`InitAssemblyTargetResp`], this is also
+/// correct for offsets beyond the first page of the buffer. Only supported by assembly based targets
+pub fn translate_offset(
+    basepath: &str,
+    buffer: TargetBuffer,
+    offset: usize,
+) -> Result<TranslateOffsetResp> {
+    VmServerClient::new(basepath)?.translate_offset(buffer, offset)
+}
+
+/// IP at which [`decode_flat_binary`] places the first instruction. Far away from the small
+/// label ids used by `iced_x86::code_asm`, so that the assembler on the VM server can relink branches
+/// between the decoded instructions
+const FLAT_BINARY_BASE_IP: u64 = 0x7f00_0000_0000;
+
+/// Decode flat 64 bit machine code into instructions that can be used for [`InitAssemblyTargetReq::code`].
+/// Branches between the decoded instructions are relinked when the VM server assembles the code at
+/// its final address. Branches to absolute addresses outside of the code are not supported
+pub fn decode_flat_binary(code: &[u8]) -> Result<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    for instruction in Decoder::with_ip(64, code, FLAT_BINARY_BASE_IP, DecoderOptions::NONE) {
+        if instruction.code() == Code::INVALID {
+            bail!(
+                "invalid instruction at offset 0x{:x}",
+                instruction.ip() - FLAT_BINARY_BASE_IP
+            );
+        }
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+/// Assemble `asm_text` with the `nasm` binary, which must be in `PATH`. The text uses the regular nasm
+/// syntax and is assembled in the flat binary format (`nasm -f bin`) in 64 bit mode, i.e. there is no need for a
+/// `bits 64` directive. Sections, `extern` symbols and other directives that require a linker are not supported
+#[cfg(feature = "nasm")]
+pub fn assemble_nasm(asm_text: &str) -> Result<Vec<u8>> {
+    use std::{
+        process::{self, Command},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    static INVOCATION: AtomicUsize = AtomicUsize::new(0);
+    let file_stem = temp_dir().join(format!(
+        "vmserver_asm_{}_{}",
+        process::id(),
+        INVOCATION.fetch_add(1, Ordering::Relaxed)
+    ));
+    let src_path = file_stem.with_extension("asm");
+    let bin_path = file_stem.with_extension("bin");
+    std::fs::write(&src_path, format!("bits 64\n{}\n", asm_text))
+        .context(format!("failed to write {}", src_path.display()))?;
+
+    let output = Command::new("nasm")
+        .arg("-f")
+        .arg("bin")
+        .arg("-o")
+        .arg(&bin_path)
+        .arg(&src_path)
+        .output()
+        .context("failed to execute nasm, is it installed?");
+    let code = output.and_then(|output| {
+        if !output.status.success() {
+            bail!(
+                "nasm failed with {} : {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        std::fs::read(&bin_path).context(format!("failed to read {}", bin_path.display()))
+    });
+    let _ = std::fs::remove_file(&src_path);
+    let _ = std::fs::remove_file(&bin_path);
+    code
+}
+
+/// Information about an instruction of an assembly target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionMeta {
+    pub mnemonic: Mnemonic,
+    ///Length of the instruction in bytes
+    pub length: usize,
+    ///Offset of the instruction from the start of the code
+    pub offset: usize,
+}
+
+/// Maps RIP values observed while stepping an assembly target back to the instructions of the target
+pub struct InstructionIndex {
+    instructions: HashMap<u64, InstructionMeta>,
+}
+
+impl InstructionIndex {
+    /// Build the index from the instructions in the response to [`new_assembly_target`]
+    pub fn new(resp: &InitAssemblyTargetResp) -> InstructionIndex {
+        let instructions = resp
+            .instructions_with_rip
+            .iter()
+            .map(|v| {
+                (
+                    v.ip(),
+                    InstructionMeta {
+                        mnemonic: v.mnemonic(),
+                        length: v.len(),
+                        offset: v.ip() as usize - resp.code_vaddr,
+                    },
+                )
+            })
+            .collect();
+        InstructionIndex { instructions }
+    }
+
+    /// Returns the instruction that starts at `rip`, if any
+    pub fn lookup(&self, rip: u64) -> Option<&InstructionMeta> {
+        self.instructions.get(&rip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use iced_x86::code_asm::*;
+
+    use super::*;
+
+    #[test]
+    fn pages_between_covers_inclusive_span() {
+        assert_eq!(pages_between(0x1010, 0x1ff0), vec![0x1000]);
+        assert_eq!(pages_between(0x1fff, 0x1fff), vec![0x1000]);
+        assert_eq!(pages_between(0x1ff0, 0x2000), vec![0x1000, 0x2000]);
+        assert_eq!(
+            pages_between(0x1800, 0x4010),
+            vec![0x1000, 0x2000, 0x3000, 0x4000]
+        );
+        assert_eq!(pages_between(0x4010, 0x1800), pages_between(0x1800, 0x4010));
+    }
+
+    /// Accepts a single request, answers with an empty 200 response and returns the request line
+    /// and the headers of the request
+    fn spawn_mock_server() -> Result<(String, thread::JoinHandle<Vec<String>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                if line.is_empty() {
+                    break;
+                }
+                lines.push(line);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            lines
+        });
+        Ok((format!("http://{}", addr), handle))
+    }
+
+    #[test]
+    fn client_sends_configured_header_below_path_prefix() -> Result<()> {
+        let (server_url, server) = spawn_mock_server()?;
+
+        VmServerClient::new(&format!("{}/vmserver", server_url))?
+            .with_header("X-Auth-Token", "secret")?
+            .run_target_program()?;
+
+        let request = server.join().unwrap();
+        assert_eq!(request[0], "POST /vmserver/run-target HTTP/1.1");
+        assert!(request
+            .iter()
+            .any(|v| v.to_lowercase() == "x-auth-token: secret"));
+        Ok(())
+    }
+
+    #[test]
+    fn instruction_index_lookup() -> Result<()> {
+        let code_vaddr = 0x4000;
+        let mut a = CodeAssembler::new(64)?;
+        a.nop()?;
+        a.mov(rax, 0x42u64)?;
+        a.ret()?;
+        let code = a.assemble(code_vaddr)?;
+        let resp = InitAssemblyTargetResp {
+            code_vaddr: code_vaddr as usize,
+            code_paddr: 0x9000,
+            code_page_paddrs: vec![0x9000],
+            data_buffer_vaddr: 0x5000,
+            data_buffer_paddr: 0xa000,
+            data_page_paddrs: vec![0xa000],
+            data_buffer_bytes: 4096,
+            instructions_with_rip: Decoder::with_ip(64, &code, code_vaddr, DecoderOptions::NONE)
+                .into_iter()
+                .collect(),
+            touched_on_init: true,
+            pinned: true,
+        };
+
+        let index = InstructionIndex::new(&resp);
+        assert_eq!(
+            index.lookup(code_vaddr + 1),
+            Some(&InstructionMeta {
+                mnemonic: Mnemonic::Mov,
+                length: 10,
+                offset: 1,
+            })
+        );
+        assert_eq!(
+            index.lookup(code_vaddr + 11).unwrap().mnemonic,
+            Mnemonic::Ret
+        );
+        //not the start of an instruction
+        assert_eq!(index.lookup(code_vaddr + 2), None);
+        Ok(())
+    }
+
+    #[test]
+    fn decoded_flat_binary_relinks_branches() -> Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        let mut head = a.create_label();
+        a.set_label(&mut head)?;
+        a.dec(rcx)?;
+        a.jnz(head)?;
+        a.ret()?;
+        let flat = a.assemble(0)?;
+
+        let instructions = decode_flat_binary(&flat)?;
+        assert_eq!(instructions.len(), 3);
+        //assemble like the VM server does, at a different address
+        let mut a = CodeAssembler::new(64)?;
+        for x in instructions {
+            a.add_instruction(x)?;
+        }
+        assert_eq!(a.assemble(0x4000)?, flat);
+
+        assert!(decode_flat_binary(&[0x90, 0x0f, 0xff]).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "nasm")]
+    #[test]
+    fn assemble_nop_ret_from_text() -> Result<()> {
+        let code = assemble_nasm("nop\nret")?;
+        assert_eq!(code, vec![0x90, 0xc3]);
+        let mnemonics: Vec<Mnemonic> = decode_flat_binary(&code)?
+            .iter()
+            .map(|v| v.mnemonic())
+            .collect();
+        assert_eq!(mnemonics, vec![Mnemonic::Nop, Mnemonic::Ret]);
+        Ok(())
     }
 }
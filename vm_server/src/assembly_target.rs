@@ -1,17 +1,59 @@
 use anyhow::{bail, Context, Result};
 use iced_x86::{code_asm::CodeAssembler, Decoder, DecoderOptions, Instruction};
-use log::{debug, error};
+use log::{debug, error, warn};
 use nix::{
-    libc::memcpy,
-    sys::mman::{self, munmap, MapFlags, ProtFlags},
+    errno::Errno,
+    libc::{self, memcpy},
+    sys::mman::{self, munmap, MapFlags, MmapAdvise, ProtFlags},
+};
+use std::{
+    arch::{
+        asm,
+        x86_64::{_mm_clflush, _mm_mfence},
+    },
+    collections::BTreeSet,
+    ffi::c_void,
+    num::NonZeroUsize,
+    ptr,
 };
-use std::{arch::asm, ffi::c_void, num::NonZeroUsize};
 
 pub mod page_ping_ponger;
+pub mod param_branch;
+
+use crate::req_resp::TargetBuffer;
+
+const CACHE_LINE_BYTES: usize = 64;
 
 pub trait RunnableTarget {
     unsafe fn run(&mut self) -> Result<()>;
     unsafe fn stop(self) -> Result<()>;
+    /// Returns true while the target is still executing the payload started by [`Self::run`].
+    /// Targets whose `run` only returns once the payload has completed never report
+    /// themselves as running outside of `run`
+    fn is_running(&self) -> bool {
+        false
+    }
+    /// Flush the memory used by the target from the cache, so that the next run starts with a cold cache
+    fn flush(&self) -> Result<()> {
+        bail!("target does not support flushing its memory from the cache")
+    }
+    /// Value returned by the most recent run of the target, if the target reports return values
+    fn last_return_value(&self) -> Result<Option<u64>> {
+        bail!("target does not report return values")
+    }
+    /// Virtual address and length in bytes of the given buffer
+    fn get_buffer(&self, buffer: TargetBuffer) -> Result<(usize, usize)> {
+        bail!("target does not expose its {} buffer", buffer)
+    }
+    /// Id of the process that executes the payload, if the target runs in its own process
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+    /// Release resources that are not freed by dropping the target, e.g. child processes.
+    /// Called when the server shuts down
+    fn teardown(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -22,8 +64,16 @@ pub struct AssemblyTarget {
     ///instructions making up the code with their final rip values
     instructions_with_rip: Vec<Instruction>,
 
+    ///null if the target has no data buffer
     data_buffer: *mut c_void,
+    ///zero if the target has no data buffer
     data_buffer_bytes: usize,
+
+    ///content of rax after the most recent run. None, if the target has not been run yet
+    last_return_value: Option<u64>,
+
+    ///true if all buffers were locked into memory with `mlock`
+    pinned: bool,
 }
 
 unsafe impl Send for AssemblyTarget {}
@@ -33,7 +83,7 @@ impl AssemblyTarget {
     /// # Arguments
     /// * `code` : Gets assembled and loaded into page aligned, executeable memory. Is called with a pointer to page aligned memory of size at least `data_buffer_bytes`. Code is wrapped into assembly stub
     /// to guarantee C calling convections
-    /// * `data_buffer_bytes` size of the data buffer. Is rounded up to be a multiple of page size
+    /// * `data_buffer_bytes` size of the data buffer. Is rounded up to be a multiple of page size. If zero, no data buffer is allocated and the code is called with a null pointer
     pub fn new(code: Vec<Instruction>, data_buffer_bytes: usize) -> Result<AssemblyTarget> {
         let mut assembler = CodeAssembler::new(64)?;
         for x in code {
@@ -57,18 +107,14 @@ impl AssemblyTarget {
             NonZeroUsize::new(required_code_bytes).context("required code bytes are zero")?;
 
         debug!("requested data buffer bytes: 0x{:x}", data_buffer_bytes);
-        let data_buffer_bytes =
-            NonZeroUsize::new(data_buffer_bytes + (4096 - (data_buffer_bytes % 4096)))
-                .context("page aligned data buffer bytes are zero")?;
+        let data_buffer_bytes = NonZeroUsize::new(data_buffer_bytes.next_multiple_of(4096));
         debug!(
             "data_buffer_bytes after rounding: 0x{:x}",
-            data_buffer_bytes.get()
+            data_buffer_bytes.map_or(0, NonZeroUsize::get)
         );
 
-        let code_buffer;
-        let data_buffer;
-        unsafe {
-            code_buffer = mman::mmap(
+        let code_buffer = unsafe {
+            mman::mmap(
                 None,
                 required_code_bytes,
                 ProtFlags::PROT_EXEC | ProtFlags::PROT_WRITE | ProtFlags::PROT_READ,
@@ -76,54 +122,82 @@ impl AssemblyTarget {
                 -1,
                 0,
             )
-            .context("failed to allocate code buffer")?;
+        }
+        .context("failed to allocate code buffer")?;
+        //from here on, dropping the target on an early return unmaps the buffers
+        let mut target = AssemblyTarget {
+            code_buffer,
+            code_buffer_bytes: required_code_bytes.get(),
+            data_buffer: ptr::null_mut(),
+            data_buffer_bytes: 0,
+            instructions_with_rip: Vec::new(),
+            last_return_value: None,
+            pinned: true,
+        };
 
-            data_buffer = mman::mmap(
-                None,
-                data_buffer_bytes,
-                ProtFlags::PROT_WRITE | ProtFlags::PROT_READ,
-                MapFlags::MAP_ANON | MapFlags::MAP_PRIVATE | MapFlags::MAP_POPULATE,
-                -1,
-                0,
-            )
+        if let Some(data_buffer_bytes) = data_buffer_bytes {
+            target.data_buffer = unsafe {
+                mman::mmap(
+                    None,
+                    data_buffer_bytes,
+                    ProtFlags::PROT_WRITE | ProtFlags::PROT_READ,
+                    MapFlags::MAP_ANON | MapFlags::MAP_PRIVATE | MapFlags::MAP_POPULATE,
+                    -1,
+                    0,
+                )
+            }
             .context("failed to allocate data buffer")?;
+            target.data_buffer_bytes = data_buffer_bytes.get();
         }
-        if (code_buffer as u64 % 4096) != 0 {
+        if !(target.code_buffer as u64).is_multiple_of(4096) {
             bail!(
                 "expected code buffer to be page aligned but got {}",
-                code_buffer as u64
+                target.code_buffer as u64
             );
         }
-        if (data_buffer as u64 % 4096) != 0 {
+        if !(target.data_buffer as u64).is_multiple_of(4096) {
             bail!(
                 "expected data buffer to be page aligned but got {}",
-                data_buffer as u64
+                target.data_buffer as u64
             );
         }
 
+        //prevent the kernel from migrating the pages, which would invalidate the physical addresses
+        //that we report to the client. This is best effort, the client learns the outcome via `is_pinned`
+        for (buffer, bytes) in target.mapped_buffers() {
+            if let Err(e) = unsafe { mman::mlock(buffer, bytes) } {
+                warn!(
+                    "failed to mlock buffer at 0x{:x} with len 0x{:x}. Check RLIMIT_MEMLOCK : {}",
+                    buffer as usize, bytes, e
+                );
+                target.pinned = false;
+            }
+            //opting out of KSM is best effort, as the kernel might be built without KSM support
+            if let Err(e) = unsafe { mman::madvise(buffer, bytes, MmapAdvise::MADV_UNMERGEABLE) } {
+                warn!(
+                    "failed to opt buffer at 0x{:x} out of KSM : {}",
+                    buffer as usize, e
+                );
+            }
+        }
+
         //do final code assembly, copy code to target location and cast to c function pointer
-        let code = assembler.assemble(code_buffer as u64)?;
-        if code.len() > required_code_bytes.get() {
+        let code = assembler.assemble(target.code_buffer as u64)?;
+        if code.len() > target.code_buffer_bytes {
             bail!(
                 "final assembly requries {} bytes but code buffer is only {}",
                 code.len(),
-                required_code_bytes
+                target.code_buffer_bytes
             );
         }
         unsafe {
-            memcpy(code_buffer, code.as_ptr().cast(), code.len());
+            memcpy(target.code_buffer, code.as_ptr().cast(), code.len());
         }
 
-        let decoder = Decoder::with_ip(64, &code, code_buffer as u64, DecoderOptions::NONE);
-        let instructions_with_rip = decoder.into_iter().collect();
+        let decoder = Decoder::with_ip(64, &code, target.code_buffer as u64, DecoderOptions::NONE);
+        target.instructions_with_rip = decoder.into_iter().collect();
 
-        Ok(AssemblyTarget {
-            code_buffer,
-            code_buffer_bytes: required_code_bytes.get(),
-            data_buffer,
-            data_buffer_bytes: data_buffer_bytes.get(),
-            instructions_with_rip,
-        })
+        Ok(target)
     }
 
     ///virtual address at which the code is located
@@ -137,15 +211,96 @@ impl AssemblyTarget {
         &self.instructions_with_rip
     }
 
-    ///virtual address of the data buffer
+    ///virtual addresses of all pages that contain at least one byte of the code, in ascending order
+    pub fn get_code_page_vaddrs(&self) -> Vec<usize> {
+        let mut pages = BTreeSet::new();
+        for instr in &self.instructions_with_rip {
+            let first_page = instr.ip() as usize & !0xfff;
+            let last_page = (instr.next_ip() as usize - 1) & !0xfff;
+            pages.extend((first_page..=last_page).step_by(4096));
+        }
+        pages.into_iter().collect()
+    }
+
+    ///Start address and length in bytes of the code buffer and, if allocated, of the data buffer
+    fn mapped_buffers(&self) -> Vec<(*mut c_void, usize)> {
+        let mut buffers = vec![(self.code_buffer, self.code_buffer_bytes)];
+        if self.has_data_buffer() {
+            buffers.push((self.data_buffer, self.data_buffer_bytes));
+        }
+        buffers
+    }
+
+    ///Returns false if the target was created with zero data buffer bytes
+    pub fn has_data_buffer(&self) -> bool {
+        !self.data_buffer.is_null()
+    }
+
+    ///Returns true if code and data buffer are locked into memory with `mlock`, thus their physical addresses
+    /// are stable while the target is alive. Locking fails e.g. if the buffers exceed RLIMIT_MEMLOCK
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    ///Returns true if all pages of the code and the data buffer are resident in memory.
+    /// As both buffers are mapped with `MAP_POPULATE`, this is expected to be true right after [`Self::new`]
+    pub fn all_pages_resident(&self) -> Result<bool> {
+        for (buffer, bytes) in self.mapped_buffers() {
+            //one entry per page, the least significant bit indicates if the page is resident
+            let mut residency = vec![0_u8; bytes.div_ceil(4096)];
+            if unsafe { libc::mincore(buffer, bytes, residency.as_mut_ptr()) } != 0 {
+                bail!(
+                    "mincore failed for buffer at 0x{:x} : {}",
+                    buffer as usize,
+                    Errno::last()
+                );
+            }
+            if residency.iter().any(|v| v & 1 == 0) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    ///virtual address of the data buffer, zero if the target has no data buffer
     pub fn get_data_buffer_vaddr(&self) -> usize {
         self.data_buffer as usize
     }
+
+    ///virtual addresses of all pages of the data buffer, in ascending order
+    pub fn get_data_page_vaddrs(&self) -> Vec<usize> {
+        let start = self.data_buffer as usize;
+        (start..start + self.data_buffer_bytes)
+            .step_by(4096)
+            .collect()
+    }
+
+    ///Copy `data` to the start of the data buffer. If `data` is larger than the data buffer, only
+    /// the first bytes are copied and a warning is logged
+    pub fn load_data(&mut self, data: &[u8]) {
+        let copy_bytes = if data.len() > self.data_buffer_bytes {
+            warn!(
+                "initial data has 0x{:x} bytes but data buffer only 0x{:x}, truncating",
+                data.len(),
+                self.data_buffer_bytes
+            );
+            self.data_buffer_bytes
+        } else {
+            data.len()
+        };
+        if copy_bytes == 0 {
+            return;
+        }
+        unsafe {
+            memcpy(self.data_buffer, data.as_ptr().cast(), copy_bytes);
+        }
+    }
 }
 
 impl RunnableTarget for AssemblyTarget {
-    ///Executes the code
+    ///Executes the code. Stores the return value, i.e. the content of rax after the code returns
     unsafe fn run(&mut self) -> Result<()> {
+        let return_value: u64;
         unsafe {
             asm!(
                 //save registers for arguments, except, rdi and rax, which are handled by the inout in the asm macro
@@ -175,11 +330,12 @@ impl RunnableTarget for AssemblyTarget {
                 "pop rcx",
                 "pop rdx",
                 "pop rsi",
-                inout("rax") (self.code_buffer) as u64 => _,
-                // 1st argument in rdi, which is caller-saved
+                inout("rax") (self.code_buffer) as u64 => return_value,
+                // 1st argument in rdi, which is caller-saved. Null if there is no data buffer
                 inout("rdi") self.data_buffer as u64 => _
             );
         }
+        self.last_return_value = Some(return_value);
 
         Ok(())
     }
@@ -187,6 +343,29 @@ impl RunnableTarget for AssemblyTarget {
     unsafe fn stop(self) -> Result<()> {
         Ok(())
     }
+
+    fn last_return_value(&self) -> Result<Option<u64>> {
+        Ok(self.last_return_value)
+    }
+
+    fn get_buffer(&self, buffer: TargetBuffer) -> Result<(usize, usize)> {
+        Ok(match buffer {
+            TargetBuffer::Code => (self.code_buffer as usize, self.code_buffer_bytes),
+            TargetBuffer::Data => (self.data_buffer as usize, self.data_buffer_bytes),
+        })
+    }
+
+    ///Flushes the code and the data buffer from the cache
+    fn flush(&self) -> Result<()> {
+        for (buffer, bytes) in self.mapped_buffers() {
+            for offset in (0..bytes).step_by(CACHE_LINE_BYTES) {
+                //buffers are mapped for their whole length while self is alive
+                unsafe { _mm_clflush(buffer.cast::<u8>().add(offset)) };
+            }
+        }
+        unsafe { _mm_mfence() };
+        Ok(())
+    }
 }
 
 impl Drop for AssemblyTarget {
@@ -202,6 +381,9 @@ impl Drop for AssemblyTarget {
                     self.code_buffer as u64, self.code_buffer_bytes, e
                 );
             }
+            if !self.has_data_buffer() {
+                return;
+            }
             if let Err(e) = munmap(self.data_buffer, self.data_buffer_bytes) {
                 error!(
                     "failed to munmap data buffer at vaddr 0x{:x} with len=0x{:x} : {}",
@@ -219,6 +401,21 @@ mod tests {
 
     use super::AssemblyTarget;
     use super::RunnableTarget;
+    use crate::req_resp::TargetBuffer;
+    use crate::virt_to_phys::{LinuxPageMap, VirtToPhysResolver};
+
+    #[test]
+    fn capture_return_value() -> Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.mov(rax, 0x1234_5678_9abc_u64)?;
+        a.ret()?;
+
+        let mut target = AssemblyTarget::new(a.take_instructions(), 0)?;
+        assert_eq!(target.last_return_value()?, None);
+        unsafe { target.run()? };
+        assert_eq!(target.last_return_value()?, Some(0x1234_5678_9abc));
+        Ok(())
+    }
     #[test]
     fn catch_caller_preserved_regs() -> Result<()> {
         let mut a = CodeAssembler::new(64)?;
@@ -249,4 +446,131 @@ mod tests {
 
         unsafe { target.run() }
     }
+
+    #[test]
+    fn code_page_vaddrs_cover_multi_page_code() -> Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        //each nop is one byte, thus the code spills into the second page
+        for _ in 0..4100 {
+            a.nop()?;
+        }
+        a.ret()?;
+
+        let target = AssemblyTarget::new(a.take_instructions(), 0)?;
+        let code_vaddr = target.get_code_vaddr();
+        assert_eq!(
+            target.get_code_page_vaddrs(),
+            vec![code_vaddr, code_vaddr + 4096]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn flush_code_and_data_buffer() -> Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.mov(rsi, qword_ptr(rdi))?;
+        a.ret()?;
+
+        let mut target = AssemblyTarget::new(a.take_instructions(), 2 * 4096)?;
+        target.flush()?;
+        //flushing must not affect the target's functionality
+        unsafe { target.run() }
+    }
+
+    #[test]
+    fn pages_stay_resident_after_madvise() -> Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.ret()?;
+
+        let target = AssemblyTarget::new(a.take_instructions(), 4096)?;
+        assert!(target.all_pages_resident()?);
+
+        //hint that the pages are unlikely to be used. The kernel rejects this for locked pages,
+        //thus the result is ignored. In any case, the pages must not be evicted
+        for (buffer, bytes) in [
+            (target.code_buffer, target.code_buffer_bytes),
+            (target.data_buffer, target.data_buffer_bytes),
+        ] {
+            unsafe { nix::libc::madvise(buffer, bytes, nix::libc::MADV_COLD) };
+        }
+        assert!(target.all_pages_resident()?);
+        Ok(())
+    }
+
+    ///Size of the locked part of the mapping starting at `vaddr`, according to `/proc/self/smaps`
+    fn locked_kib(vaddr: usize) -> Result<u64> {
+        let smaps = std::fs::read_to_string("/proc/self/smaps")?;
+        let locked = smaps
+            .split('\n')
+            .skip_while(|line| !line.starts_with(&format!("{:x}-", vaddr)))
+            .skip(1)
+            //stop at the header of the next mapping, which starts with an address range
+            .take_while(|line| {
+                line.split_once(':')
+                    .is_some_and(|(key, _)| !key.contains(' '))
+            })
+            .find_map(|line| line.strip_prefix("Locked:"))
+            .with_context(|| format!("no Locked entry for mapping at 0x{:x}", vaddr))?;
+        Ok(locked.trim().trim_end_matches("kB").trim().parse()?)
+    }
+
+    #[test]
+    fn pinned_pages_keep_their_paddr() -> Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.ret()?;
+
+        let target = AssemblyTarget::new(a.take_instructions(), 4096)?;
+        assert_eq!(
+            target.is_pinned(),
+            locked_kib(target.get_code_vaddr())? > 0
+                && locked_kib(target.get_data_buffer_vaddr())? > 0
+        );
+        if !target.is_pinned() {
+            //without the lock, the kernel is free to move the pages
+            return Ok(());
+        }
+
+        let mut pagemap = LinuxPageMap::new()?;
+        let code_paddr = pagemap.get_phys(target.get_code_vaddr())?;
+        let data_paddr = pagemap.get_phys(target.get_data_buffer_vaddr())?;
+        //try to reclaim the code pages. The kernel rejects this for locked pages, thus the result is ignored
+        unsafe {
+            nix::libc::madvise(
+                target.code_buffer,
+                target.code_buffer_bytes,
+                nix::libc::MADV_PAGEOUT,
+            )
+        };
+        assert_eq!(pagemap.get_phys(target.get_code_vaddr())?, code_paddr);
+        assert_eq!(
+            pagemap.get_phys(target.get_data_buffer_vaddr())?,
+            data_paddr
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn zero_data_buffer_bytes_allocate_no_buffer() -> Result<()> {
+        //control flow only victim that never touches rdi
+        let mut a = CodeAssembler::new(64)?;
+        let mut skip = a.create_label();
+        a.xor(rax, rax)?;
+        a.cmp(rax, 0)?;
+        a.je(skip)?;
+        a.inc(rax)?;
+        a.set_label(&mut skip)?;
+        a.ret()?;
+
+        let mut target = AssemblyTarget::new(a.take_instructions(), 0)?;
+        assert!(!target.has_data_buffer());
+        assert_eq!(target.get_data_buffer_vaddr(), 0);
+        assert!(target.get_data_page_vaddrs().is_empty());
+        assert_eq!(target.get_buffer(TargetBuffer::Data)?, (0, 0));
+        assert!(target.all_pages_resident()?);
+        target.load_data(&[1, 2, 3]);
+        target.flush()?;
+        unsafe { target.run()? };
+        assert_eq!(target.last_return_value()?, Some(0));
+        Ok(())
+    }
 }
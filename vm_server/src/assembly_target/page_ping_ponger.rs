@@ -1,15 +1,17 @@
 use std::arch::asm;
 
 use super::{AssemblyTarget, RunnableTarget};
+use crate::req_resp::TargetBuffer;
 use anyhow::{Context, Result};
 use iced_x86::code_asm::*;
 use log::debug;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumIter};
 
 /// Describes which kind of access a [`PagePingPonger`] performs
 /// to it's two pages
-#[derive(Serialize, Deserialize, Debug, EnumIter, Display)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, EnumIter, Display)]
 pub enum PagePingPongVariant {
     READ,
     WRITE,
@@ -28,8 +30,15 @@ impl PagePingPonger {
     /// # Arguments
     /// * `mode` : specifies the access type
     /// * `rounds` : One rounds consists of reading once from both pages
+    /// * `order_seed` : If set, the order of the two accesses is shuffled for each round, using this seed.
+    ///   Otherwise, the first page is always accessed first. See [`access_order`]
     ///
-    pub fn new(mode: &PagePingPongVariant, rounds: u32) -> Result<PagePingPonger> {
+    pub fn new(
+        mode: &PagePingPongVariant,
+        rounds: u32,
+        order_seed: Option<u64>,
+    ) -> Result<PagePingPonger> {
+        let order = access_order(rounds, order_seed);
         let (code, page_vaddrs) = match mode {
             PagePingPongVariant::READ => {
                 let mut a =
@@ -37,11 +46,9 @@ impl PagePingPonger {
 
                 const DATA_BUFFER_BYTES: usize = 2 * 4096;
 
-                for i in 0..rounds {
-                    a.mov(rsi, qword_ptr(rdi))
-                        .context(format!("failed to add {}th read from first page", i))?;
-                    a.mov(rsi, qword_ptr(rdi + 4096))
-                        .context(format!("failed to add {}th read from second page", i))?;
+                for (i, page) in order.iter().enumerate() {
+                    a.mov(rsi, qword_ptr(rdi + (page * 4096) as i32))
+                        .context(format!("failed to add {}th read from page {}", i, page))?;
                 }
                 a.ret().context("failed to add final add")?;
 
@@ -61,11 +68,9 @@ impl PagePingPonger {
 
                 const DATA_BUFFER_BYTES: usize = 2 * 4096;
 
-                for i in 0..rounds {
-                    a.mov(qword_ptr(rdi), 42)
-                        .context(format!("failed to add {}th write to first page", i))?;
-                    a.mov(qword_ptr(rdi + 4096), 42)
-                        .context(format!("failed to add {}th write to second page", i))?;
+                for (i, page) in order.iter().enumerate() {
+                    a.mov(qword_ptr(rdi + (page * 4096) as i32), 42)
+                        .context(format!("failed to add {}th write to page {}", i, page))?;
                 }
                 a.ret().context("failed to add final add")?;
 
@@ -86,14 +91,11 @@ impl PagePingPonger {
                     "aligned_target_fn1 is at vaddr 0x{:x}, aligned_target_fn2 is at vaddr 0x{:x}",
                     aligned_target_fn1 as u64, aligned_target_fn2 as u64
                 );
-                for i in 0..rounds {
-                    a.call(aligned_target_fn1 as u64).context(format!(
+                let targets = [aligned_target_fn1 as u64, aligned_target_fn2 as u64];
+                for (i, page) in order.iter().enumerate() {
+                    a.call(targets[*page]).context(format!(
                         "failed to add {}th call to 0x{:x}",
-                        i, aligned_target_fn1 as u64
-                    ))?;
-                    a.call(aligned_target_fn2 as u64).context(format!(
-                        "failed to add {}th call to 0x{:x}",
-                        i, aligned_target_fn2 as u64
+                        i, targets[*page]
                     ))?;
                 }
                 a.ret().context("failed to add final add")?;
@@ -124,6 +126,34 @@ impl RunnableTarget for PagePingPonger {
     unsafe fn stop(self) -> Result<()> {
         Ok(())
     }
+
+    fn flush(&self) -> Result<()> {
+        self.code.flush()
+    }
+
+    fn last_return_value(&self) -> Result<Option<u64>> {
+        self.code.last_return_value()
+    }
+
+    fn get_buffer(&self, buffer: TargetBuffer) -> Result<(usize, usize)> {
+        self.code.get_buffer(buffer)
+    }
+}
+
+/// Returns the indices of the pages accessed by a [`PagePingPonger`], in access order.
+/// Each round accesses both pages once. Without `seed`, page 0 is accessed before page 1
+/// in every round. Otherwise, the order within each round is shuffled. The same seed always yields the same order
+pub fn access_order(rounds: u32, seed: Option<u64>) -> Vec<usize> {
+    let mut rng = seed.map(StdRng::seed_from_u64);
+    (0..rounds)
+        .flat_map(|_| {
+            let mut round = [0, 1];
+            if let Some(rng) = rng.as_mut() {
+                round.shuffle(rng);
+            }
+            round
+        })
+        .collect()
 }
 
 /// A page aligned function that is never inlined and does nothing
@@ -146,7 +176,7 @@ fn aligned_target_fn2() {
 
 #[cfg(test)]
 mod tests {
-    use super::{PagePingPongVariant, PagePingPonger};
+    use super::{access_order, PagePingPongVariant, PagePingPonger};
     use crate::assembly_target::RunnableTarget;
     use anyhow::{Context, Result};
     use strum::IntoEnumIterator;
@@ -155,11 +185,26 @@ mod tests {
     /// compiles and does not crash at runtime
     fn run_all_ping_pongers() -> Result<()> {
         for variant in PagePingPongVariant::iter() {
-            let mut p = PagePingPonger::new(&variant, 10)
-                .context(format!("failed to init {} ping ponger", variant))?;
-            unsafe { p.run().unwrap() };
+            for order_seed in [None, Some(42)] {
+                let mut p = PagePingPonger::new(&variant, 10, order_seed)
+                    .context(format!("failed to init {} ping ponger", variant))?;
+                unsafe { p.run().unwrap() };
+            }
         }
 
         Ok(())
     }
+
+    #[test]
+    fn randomized_access_order_is_reproducible() {
+        assert_eq!(access_order(3, None), vec![0, 1, 0, 1, 0, 1]);
+
+        let order = access_order(16, Some(42));
+        assert_eq!(order, access_order(16, Some(42)));
+        assert_ne!(order, access_order(16, None));
+        //each round still accesses both pages once
+        for round in order.chunks(2) {
+            assert!(round == [0, 1] || round == [1, 0]);
+        }
+    }
 }
@@ -0,0 +1,27 @@
+use anyhow::Result;
+use iced_x86::{code_asm::*, Instruction};
+
+/// Number of instructions that are only executed if the input matches the secret, see [`param_branch_code`]
+pub const MATCHING_BRANCH_INSTRUCTIONS: u64 = 2;
+
+/// Builds a victim that compares the `u64` at the start of its data buffer with `secret`. If both
+/// are equal, a branch with [`MATCHING_BRANCH_INSTRUCTIONS`] additional instructions is executed.
+/// Each of them increments `rax`, thus the return value is the number of executed branch instructions.
+/// As the input is read from the data buffer, the same code can be used with different inputs
+pub fn param_branch_code(secret: u64) -> Result<Vec<Instruction>> {
+    let mut a = CodeAssembler::new(64)?;
+
+    let mut no_match = a.create_label();
+    a.xor(rax, rax)?;
+    a.mov(rsi, qword_ptr(rdi))?;
+    a.mov(rdx, secret)?;
+    a.cmp(rsi, rdx)?;
+    a.jne(no_match)?;
+    for _ in 0..MATCHING_BRANCH_INSTRUCTIONS {
+        a.inc(rax)?;
+    }
+    a.set_label(&mut no_match)?;
+    a.ret()?;
+
+    Ok(a.take_instructions())
+}
@@ -99,6 +99,7 @@ fn main() -> Result<()> {
     let req = InitAssemblyTargetReq {
         code: a.take_instructions(),
         required_mem_bytes: 0,
+        initial_data: Vec::new(),
     };
 
     let client = reqwest::blocking::Client::new();
@@ -1,38 +1,26 @@
 use std::sync::{Arc, Mutex};
 
-use axum::{extract::DefaultBodyLimit, routing::post, Router};
 use vm_server::handlers::{self, ServerState};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let shared_state = Arc::new(Mutex::new(ServerState {
-        target_programm: None,
-    }));
-    // build our application with a single route
-    let app = Router::new()
-        .route(
-            "/assembly-target/new",
-            post(handlers::init_assembly_target_handler),
-        )
-        .route("/run-target", post(handlers::run_target_handler))
-        .route(
-            "/page-ping-ponger/new",
-            post(handlers::init_page_ping_ponger_handler),
-        )
-        .route(
-            "/custom-target/new",
-            post(handlers::init_custom_target_program_handler),
-        )
-        //unit is "bytes"
-        .layer(DefaultBodyLimit::max(10 * 1024 * 1024))
-        .with_state(shared_state);
+    let shared_state = Arc::new(Mutex::new(ServerState::default()));
+    let max_upload_bytes = handlers::max_upload_bytes_from_env().unwrap();
+    let app = handlers::router(shared_state.clone(), max_upload_bytes);
 
     let listen_str = "0.0.0.0:8080".to_string();
-    eprintln!("listening on {}", listen_str);
+    eprintln!(
+        "listening on {}, max upload size {} bytes",
+        listen_str, max_upload_bytes
+    );
     axum::Server::bind(&listen_str.parse().unwrap())
         .serve(app.into_make_service())
+        .with_graceful_shutdown(handlers::teardown_on_shutdown(
+            shared_state,
+            handlers::termination_signal(),
+        ))
         .await
         .unwrap();
 }
@@ -4,23 +4,27 @@
 
 use crate::assembly_target::RunnableTarget;
 use anyhow::{anyhow, Context, Result};
-use log::debug;
-use nix::sys::signal;
-use nix::sys::signal::kill;
-use nix::unistd::Pid;
+use log::{debug, warn};
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
-use std::process::{ChildStdin, Command, Stdio};
+use std::process::{Child, ChildStdin, Command, Stdio};
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
 pub struct ExternalTarget {
     key_value_pairs: HashMap<String, String>,
+    ///key value pairs emitted after the setup phase. Filled by the stdout thread
+    runtime_key_value_pairs: Arc<Mutex<HashMap<String, String>>>,
     child_stdin: ChildStdin,
     child_stdout_thread: JoinHandle<()>,
     child_process_id: u32,
+    ///used to query whether the child has exited. Behind a mutex, as [`Child::try_wait`] requires mutable access
+    child_process: Mutex<Child>,
+    ///set once the payload phase has been started
+    started: bool,
 }
 
 impl ExternalTarget {
@@ -40,7 +44,7 @@ impl ExternalTarget {
     pub fn new(working_dir: String, cmd: String, args: Vec<String>) -> Result<ExternalTarget> {
         let cmd_absolute = Path::new(&working_dir).join(cmd);
 
-        let child_process = Command::new(cmd_absolute)
+        let mut child_process = Command::new(cmd_absolute)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
             .current_dir(working_dir)
@@ -50,10 +54,12 @@ impl ExternalTarget {
         debug!("pid of child process: {}", child_process.id());
         let stdin = child_process
             .stdin
+            .take()
             .ok_or(anyhow!("failed to capture stdin"))?;
         let stdout = BufReader::new(
             child_process
                 .stdout
+                .take()
                 .ok_or(anyhow!("failed to capture stdout"))?,
         );
 
@@ -63,6 +69,8 @@ impl ExternalTarget {
         //monitor stdout of child for `ExternalTarget::MAKER_END_SETUP` and `ExternalTarget::PREFIX_KEY_VALUE_PAIR`
 
         let (key_value_sender, key_value_receiver) = channel();
+        let runtime_key_value_pairs = Arc::new(Mutex::new(HashMap::new()));
+        let runtime_key_value_pairs_stdout_thread = runtime_key_value_pairs.clone();
 
         let stdout_thread = thread::spawn(move || {
             println!("starting background reading thread");
@@ -85,16 +93,25 @@ impl ExternalTarget {
                         key_value_sender.send(Ok(key_value_pairs.clone())).unwrap();
                         continue;
                     } else if line.starts_with(ExternalTarget::PREFIX_KEY_VALUE_PAIR) {
-                        let tokens: Vec<_> = line.split(" ").collect();
-                        if tokens.len() != 3 {
-                            panic!("expected 3 tokens, got \"{:?}\"", tokens);
-                        }
-                        key_value_pairs.insert(tokens[1].to_string(), tokens[2].to_string());
+                        let (key, value) = ExternalTarget::parse_key_value_pair(&line)
+                            .unwrap_or_else(|e| panic!("{:?}", e));
+                        key_value_pairs.insert(key, value);
                     }
                 } else {
-                    //past setup phase, simply drain stdout
+                    //past setup phase, only capture key value pairs and drain everything else
                     let line = line.expect("failed to read line");
                     debug!("process send line to stdout: {}", line);
+                    if line.starts_with(ExternalTarget::PREFIX_KEY_VALUE_PAIR) {
+                        match ExternalTarget::parse_key_value_pair(&line) {
+                            Ok((key, value)) => {
+                                runtime_key_value_pairs_stdout_thread
+                                    .lock()
+                                    .unwrap()
+                                    .insert(key, value);
+                            }
+                            Err(e) => warn!("ignoring malformed key value pair : {:?}", e),
+                        }
+                    }
                 }
             }
         });
@@ -108,9 +125,12 @@ impl ExternalTarget {
 
         Ok(ExternalTarget {
             key_value_pairs: setup_phase_values,
+            runtime_key_value_pairs,
             child_stdout_thread: stdout_thread,
             child_stdin: stdin,
             child_process_id: child_id,
+            child_process: Mutex::new(child_process),
+            started: false,
         })
     }
 
@@ -118,25 +138,68 @@ impl ExternalTarget {
     pub fn get_key_value_pairs(&self) -> &HashMap<String, String> {
         &self.key_value_pairs
     }
+
+    ///Name and content of the variables emitted after the setup phase so far, e.g. results reported
+    /// by the payload phase. Separate from [`Self::get_key_value_pairs`].
+    /// Values are captured by a background thread, thus they might show up shortly after the child exited
+    pub fn get_runtime_key_value_pairs(&self) -> HashMap<String, String> {
+        self.runtime_key_value_pairs.lock().unwrap().clone()
+    }
+
+    ///Parses a `ExternalTarget::PREFIX_KEY_VALUE_PAIR <name> <value>` line
+    fn parse_key_value_pair(line: &str) -> Result<(String, String)> {
+        let tokens: Vec<_> = line.split(' ').collect();
+        if tokens.len() != 3 {
+            return Err(anyhow!("expected 3 tokens, got \"{:?}\"", tokens));
+        }
+        Ok((tokens[1].to_string(), tokens[2].to_string()))
+    }
 }
 
 impl RunnableTarget for ExternalTarget {
+    fn process_id(&self) -> Option<u32> {
+        Some(self.child_process_id)
+    }
+
     unsafe fn run(&mut self) -> Result<()> {
         //send start marker to child_process
         debug!("writing start marker on external target's stdin");
         self.child_stdin
             .write_fmt(format_args!("{}\n", ExternalTarget::INPUT_CMD_START))?;
         self.child_stdin.flush()?;
+        self.started = true;
 
         Ok(())
     }
-    unsafe fn stop(self) -> Result<()> {
-        kill(Pid::from_raw(self.child_process_id as i32), signal::SIGKILL)?;
+
+    fn is_running(&self) -> bool {
+        //the child might close or hand off its stdout before exiting, thus ask the OS instead
+        self.started && matches!(self.child_process.lock().unwrap().try_wait(), Ok(None))
+    }
+
+    unsafe fn stop(mut self) -> Result<()> {
+        self.teardown()?;
         self.child_stdout_thread
             .join()
             .expect("failed to join stdout thread. TODO: handle this cleanly");
         Ok(())
     }
+
+    fn teardown(&mut self) -> Result<()> {
+        let child = self.child_process.get_mut().unwrap();
+        //the child might already have exited and been reaped by `is_running`
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        debug!("killing external target with pid {}", self.child_process_id);
+        child.kill()?;
+        //reap the child, so that it does not linger around as a zombie
+        child.wait().context(format!(
+            "failed to wait for child with pid {}",
+            self.child_process_id
+        ))?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -159,4 +222,86 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn capture_key_value_pairs_after_setup() -> Result<()> {
+        let script = [
+            "echo VMSERVER::VAR setup_var 1",
+            "echo VMSERVER::SETUP_DONE",
+            "read line",
+            "echo not a key value pair",
+            "echo VMSERVER::VAR result 42",
+        ]
+        .join("; ");
+        let mut p = ExternalTarget::new(
+            "/bin".to_string(),
+            "sh".to_string(),
+            vec!["-c".to_string(), script],
+        )?;
+        assert!(p.get_runtime_key_value_pairs().is_empty());
+
+        assert!(!p.is_running());
+        unsafe { p.run()? };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while p.is_running() || p.get_runtime_key_value_pairs().is_empty() {
+            assert!(Instant::now() < deadline, "child did not terminate");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            p.get_key_value_pairs(),
+            &HashMap::from([("setup_var".to_string(), "1".to_string())])
+        );
+        assert_eq!(
+            p.get_runtime_key_value_pairs(),
+            HashMap::from([("result".to_string(), "42".to_string())])
+        );
+        p.teardown()
+    }
+
+    #[test]
+    fn running_until_exit_despite_closed_stdout() -> Result<()> {
+        let script = [
+            "echo VMSERVER::SETUP_DONE",
+            "read line",
+            "exec 1>&-",
+            "sleep 0.5",
+        ]
+        .join("; ");
+        let mut p = ExternalTarget::new(
+            "/bin".to_string(),
+            "sh".to_string(),
+            vec!["-c".to_string(), script],
+        )?;
+
+        unsafe { p.run()? };
+        //give the stdout thread time to notice the closed stdout
+        thread::sleep(Duration::from_millis(100));
+        assert!(p.is_running());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while p.is_running() {
+            assert!(Instant::now() < deadline, "child did not terminate");
+            thread::sleep(Duration::from_millis(10));
+        }
+        p.teardown()
+    }
+
+    #[test]
+    fn teardown_kills_running_child() -> Result<()> {
+        let mut p = ExternalTarget::new(
+            "/bin".to_string(),
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "echo VMSERVER::SETUP_DONE; read line; exec sleep 30".to_string(),
+            ],
+        )?;
+        unsafe { p.run()? };
+        assert!(p.is_running());
+
+        p.teardown()?;
+        assert!(!p.is_running());
+        Ok(())
+    }
 }
@@ -1,16 +1,21 @@
 use rand::distributions::{Alphanumeric, DistString};
 use std::{
+    collections::HashMap,
     env::temp_dir,
     fs::create_dir,
+    future::Future,
     io::BufReader,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, TryLockError},
 };
 
 use crate::{
-    assembly_target::{page_ping_ponger::PagePingPonger, AssemblyTarget, RunnableTarget},
+    assembly_target::{
+        page_ping_ponger::PagePingPonger, param_branch, AssemblyTarget, RunnableTarget,
+    },
     req_resp::{
         InitAssemblyTargetReq, InitAssemblyTargetResp, InitCustomTargetResp, InitPagePingPongerReq,
-        InitPagePingPongerResp,
+        InitPagePingPongerResp, InitParamBranchReq, LastReturnValueResp, SetTargetAffinityReq,
+        TranslateOffsetReq, TranslateOffsetResp, WireFormat,
     },
     virt_to_phys::{self, LinuxPageMap, VirtToPhysResolver},
 };
@@ -19,25 +24,45 @@ use crate::external_target::ExternalTarget;
 use anyhow::{anyhow, bail, Context};
 use axum::{
     body::Bytes,
-    extract::{Multipart, State},
-    http::StatusCode,
+    extract::{multipart::MultipartError, DefaultBodyLimit, Multipart, Path, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
-    Json,
+    routing::{get, post},
+    Json, Router,
 };
 use log::{debug, error};
+use nix::{
+    sched::{self, CpuSet},
+    unistd::Pid,
+};
 use tar::Archive;
+use tokio::signal::unix::{signal, SignalKind};
 
 // Make our own error that wraps `anyhow::Error`.
 pub struct AppError(anyhow::Error);
 
+/// Error for requests whose content is invalid. Reported with 400 Bad Request instead of 500
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+
+impl std::fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bad request : {}", self.0)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        //keep the status of multipart errors, e.g. 413 if an upload exceeds the body limit
+        let status = match self.0.downcast_ref::<MultipartError>() {
+            Some(e) => e.status(),
+            None if self.0.is::<BadRequest>() => StatusCode::BAD_REQUEST,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, format!("Something went wrong: {}", self.0)).into_response()
     }
 }
 
@@ -51,9 +76,73 @@ where
         Self(err.into())
     }
 }
-#[derive(Clone)]
+/// Target that can be shared between concurrent requests
+pub type SharedTarget = Arc<Mutex<dyn RunnableTarget + Send>>;
+
+/// Name under which the routes without a target name, e.g. `/run-target`, store and look up their target
+pub const DEFAULT_TARGET_NAME: &str = "default";
+
+#[derive(Clone, Default)]
 pub struct ServerState {
-    pub target_programm: Option<Arc<Mutex<dyn RunnableTarget + Send>>>,
+    ///Loaded targets by name. Multiple targets allow to e.g. run an attacker program alongside the victim
+    pub targets: HashMap<String, SharedTarget>,
+    ///Guest CPU to which targets are pinned when they are run, see [`SetTargetAffinityReq`]
+    pub target_cpu: Option<usize>,
+}
+
+/// Default for the maximal size of request bodies, e.g. of the archive uploaded for a custom target
+pub const DEFAULT_MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+/// Environment variable that overwrites [`DEFAULT_MAX_UPLOAD_BYTES`]
+pub const MAX_UPLOAD_BYTES_ENV: &str = "VMSERVER_MAX_UPLOAD_BYTES";
+
+/// Returns the maximal request body size configured via [`MAX_UPLOAD_BYTES_ENV`], or
+/// [`DEFAULT_MAX_UPLOAD_BYTES`] if the variable is not set
+pub fn max_upload_bytes_from_env() -> Result<usize, anyhow::Error> {
+    match std::env::var(MAX_UPLOAD_BYTES_ENV) {
+        Ok(v) => v.parse().context(format!(
+            "failed to parse {}={} as byte count",
+            MAX_UPLOAD_BYTES_ENV, v
+        )),
+        Err(_) => Ok(DEFAULT_MAX_UPLOAD_BYTES),
+    }
+}
+
+/// Builds the router with all endpoints of the server
+/// # Arguments
+/// * `state` : shared server state
+/// * `max_upload_bytes` : requests with larger bodies are rejected with 413 Payload Too Large
+pub fn router(state: Arc<Mutex<ServerState>>, max_upload_bytes: usize) -> Router {
+    Router::new()
+        .route("/assembly-target/new", post(init_assembly_target_handler))
+        .route("/run-target", post(run_target_handler))
+        .route("/flush-target", post(flush_target_handler))
+        .route("/teardown-target", post(teardown_target_handler))
+        .route("/last-return-value", get(last_return_value_handler))
+        .route("/translate-offset", post(translate_offset_handler))
+        .route("/set-target-affinity", post(set_target_affinity_handler))
+        .route("/page-ping-ponger/new", post(init_page_ping_ponger_handler))
+        .route("/param-branch/new", post(init_param_branch_handler))
+        .route(
+            "/target/:name/new",
+            post(init_named_assembly_target_handler),
+        )
+        .route("/target/:name/run", post(run_named_target_handler))
+        .route("/target/:name/flush", post(flush_named_target_handler))
+        .route(
+            "/target/:name/last-return-value",
+            get(last_return_value_named_handler),
+        )
+        .route(
+            "/target/:name/translate-offset",
+            post(translate_offset_named_handler),
+        )
+        .route(
+            "/custom-target/new",
+            post(init_custom_target_program_handler),
+        )
+        //unit is "bytes"
+        .layer(DefaultBodyLimit::max(max_upload_bytes))
+        .with_state(state)
 }
 
 pub async fn init_custom_target_program_handler(
@@ -147,17 +236,40 @@ fn init_custom_target_program(
     };
 
     debug!("Storing prog in global state");
-    state.target_programm = Some(Arc::new(Mutex::new(p)));
+    state
+        .targets
+        .insert(DEFAULT_TARGET_NAME.to_string(), Arc::new(Mutex::new(p)));
 
     debug!("done");
     Ok(resp)
 }
 
+/// Accepts the [`InitAssemblyTargetReq`] in any of the encodings described by [`WireFormat`]
 pub async fn init_assembly_target_handler(
+    state: State<Arc<Mutex<ServerState>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<InitAssemblyTargetResp>, AppError> {
+    init_named_assembly_target_handler(state, Path(DEFAULT_TARGET_NAME.to_string()), headers, body)
+        .await
+}
+
+/// Like [`init_assembly_target_handler`] but stores the target under the name from the path,
+/// replacing any previous target with the same name
+pub async fn init_named_assembly_target_handler(
     State(state): State<Arc<Mutex<ServerState>>>,
-    Json(req): Json<InitAssemblyTargetReq>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<Json<InitAssemblyTargetResp>, AppError> {
-    match init_assembly_target(state, req) {
+    let req = match decode_assembly_target_req(&headers, &body) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("decode_assembly_target_req failed with: {:?}", e);
+            return Err(AppError::from(e));
+        }
+    };
+    match init_assembly_target(state, &name, req) {
         Ok(v) => Ok(Json(v)),
         Err(e) => {
             error!("init_assembly_target failed with: {:?}", e);
@@ -166,12 +278,42 @@ pub async fn init_assembly_target_handler(
     }
 }
 
+/// Decode `body` according to the `Content-Type` header in `headers`. Without the header,
+/// the body is decoded with [`WireFormat::default`]
+fn decode_assembly_target_req(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<InitAssemblyTargetReq, anyhow::Error> {
+    let format = match headers.get(CONTENT_TYPE) {
+        Some(v) => {
+            let content_type = v
+                .to_str()
+                .context("Content-Type header is not valid ascii")?;
+            WireFormat::from_content_type(content_type)
+                .ok_or(anyhow!("unsupported Content-Type \"{}\"", content_type))?
+        }
+        None => WireFormat::default(),
+    };
+    match format {
+        WireFormat::Json => serde_json::from_slice(body).context("failed to parse body as json"),
+        WireFormat::Bincode => {
+            bincode::deserialize(body).context("failed to parse body as bincode")
+        }
+    }
+}
+
 fn init_assembly_target(
     state: Arc<Mutex<ServerState>>,
+    name: &str,
     req: InitAssemblyTargetReq,
 ) -> Result<InitAssemblyTargetResp, anyhow::Error> {
-    let prog = AssemblyTarget::new(req.code, req.required_mem_bytes)
+    //the target would jump into a code buffer without any instructions
+    if req.code.is_empty() {
+        return Err(BadRequest("code must contain at least one instruction".to_string()).into());
+    }
+    let mut prog = AssemblyTarget::new(req.code, req.required_mem_bytes)
         .context("failed to instantiate supplied program")?;
+    prog.load_data(&req.initial_data);
 
     let mut pagemap_parser = virt_to_phys::LinuxPageMap::new()?;
 
@@ -182,22 +324,55 @@ fn init_assembly_target(
             "failed to translate 0x{:x} to phys addr",
             prog.get_code_vaddr()
         ))?;
+    debug!("translate code page vaddrs to paddrs");
+    let code_page_paddrs = prog
+        .get_code_page_vaddrs()
+        .into_iter()
+        .map(|v| {
+            pagemap_parser
+                .get_phys(v)
+                .context(format!("failed to translate 0x{:x} to phys addr", v))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    debug!("translate data page vaddrs to paddrs");
+    let data_page_paddrs = prog
+        .get_data_page_vaddrs()
+        .into_iter()
+        .map(|v| {
+            pagemap_parser
+                .get_phys(v)
+                .context(format!("failed to translate 0x{:x} to phys addr", v))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
     debug!("translating data_buffer to paddr");
-    let data_buffer_paddr = pagemap_parser
-        .get_phys(prog.get_data_buffer_vaddr())
-        .context(format!(
-            "failed to translate 0x{:x} to phys addr",
-            prog.get_data_buffer_vaddr()
-        ))?;
+    let data_buffer_paddr = if prog.has_data_buffer() {
+        pagemap_parser
+            .get_phys(prog.get_data_buffer_vaddr())
+            .context(format!(
+                "failed to translate 0x{:x} to phys addr",
+                prog.get_data_buffer_vaddr()
+            ))?
+    } else {
+        0
+    };
+
+    //the buffers are mapped with MAP_POPULATE, thus this is only a sanity check
+    let touched_on_init = prog
+        .all_pages_resident()
+        .context("failed to check residency of target pages")?;
 
     debug!("building response");
     let resp = InitAssemblyTargetResp {
         code_vaddr: prog.get_code_vaddr(),
         code_paddr,
+        code_page_paddrs,
         data_buffer_vaddr: prog.get_data_buffer_vaddr(),
         data_buffer_paddr,
+        data_page_paddrs,
         data_buffer_bytes: req.required_mem_bytes,
         instructions_with_rip: prog.get_instr_with_rip().clone(),
+        touched_on_init,
+        pinned: prog.is_pinned(),
     };
 
     debug!("aquiring state lock");
@@ -206,17 +381,55 @@ fn init_assembly_target(
         Err(e) => bail!("failed to aquire state lock {}", e),
     };
 
-    debug!("Storing prog in global state");
-    state.target_programm = Some(Arc::new(Mutex::new(prog)));
+    debug!("Storing prog in global state as {}", name);
+    state
+        .targets
+        .insert(name.to_string(), Arc::new(Mutex::new(prog)));
 
     debug!("Sending response {}", resp);
     Ok(resp)
 }
 
-pub async fn run_target_handler(
+pub async fn init_param_branch_handler(
     State(state): State<Arc<Mutex<ServerState>>>,
+    Json(req): Json<InitParamBranchReq>,
+) -> Result<Json<InitAssemblyTargetResp>, AppError> {
+    match init_param_branch(state, req) {
+        Ok(v) => Ok(Json(v)),
+        Err(e) => {
+            error!("init_param_branch failed with: {:?}", e);
+            Err(AppError::from(e))
+        }
+    }
+}
+
+/// Instantiates the victim from [`param_branch`] as an assembly target whose data buffer holds `req.input`
+fn init_param_branch(
+    state: Arc<Mutex<ServerState>>,
+    req: InitParamBranchReq,
+) -> Result<InitAssemblyTargetResp, anyhow::Error> {
+    let code = param_branch::param_branch_code(req.secret)
+        .context("failed to assemble param branch victim")?;
+    init_assembly_target(
+        state,
+        DEFAULT_TARGET_NAME,
+        InitAssemblyTargetReq {
+            code,
+            required_mem_bytes: 4096,
+            initial_data: req.input.to_le_bytes().to_vec(),
+        },
+    )
+}
+
+pub async fn run_target_handler(state: State<Arc<Mutex<ServerState>>>) -> Result<(), AppError> {
+    run_named_target_handler(state, Path(DEFAULT_TARGET_NAME.to_string())).await
+}
+
+pub async fn run_named_target_handler(
+    State(state): State<Arc<Mutex<ServerState>>>,
+    Path(name): Path<String>,
 ) -> Result<(), AppError> {
-    match run_target(state) {
+    match run_target(state, &name) {
         Ok(_) => Ok(()),
         Err(e) => {
             error!("run_target_handler failed with {:?}", e);
@@ -225,27 +438,296 @@ pub async fn run_target_handler(
     }
 }
 
-fn run_target(state: Arc<Mutex<ServerState>>) -> Result<(), anyhow::Error> {
-    let state = match state.lock() {
+/// Calls `f` with the target program stored under `name`, if it is initialized and not currently running
+fn with_idle_target<R>(
+    state: Arc<Mutex<ServerState>>,
+    name: &str,
+    f: impl FnOnce(&mut (dyn RunnableTarget + Send)) -> Result<R, anyhow::Error>,
+) -> Result<R, anyhow::Error> {
+    //only hold the state lock while fetching the program, to allow concurrent requests to notice
+    //that the target is already running
+    let prog_mutex = {
+        let state = match state.lock() {
+            Ok(v) => v,
+            Err(e) => bail!("failed to aquire state lock {}", e),
+        };
+        match state.targets.get(name) {
+            Some(v) => v.clone(),
+            None => bail!("target program {} not initialized", name),
+        }
+    };
+
+    //if the lock is taken, another request is currently executing the target
+    let mut prog = match prog_mutex.try_lock() {
         Ok(v) => v,
+        Err(TryLockError::WouldBlock) => bail!("target already running"),
+        Err(TryLockError::Poisoned(e)) => bail!("Failed to get target program : {:?}", e),
+    };
+    //targets like the external target continue to execute after `run` returned
+    if prog.is_running() {
+        bail!("target already running");
+    }
+
+    f(&mut *prog)
+}
+
+fn run_target(state: Arc<Mutex<ServerState>>, name: &str) -> Result<(), anyhow::Error> {
+    let target_cpu = match state.lock() {
+        Ok(v) => v.target_cpu,
         Err(e) => bail!("failed to aquire state lock {}", e),
     };
+    with_idle_target(state, name, |prog| {
+        let cpu = match target_cpu {
+            Some(v) => v,
+            None => return run_prog(prog, name),
+        };
+        if let Some(pid) = prog.process_id() {
+            pin_to_cpu(Pid::from_raw(pid as i32), cpu)
+                .context(format!("failed to pin target process {}", pid))?;
+        }
+        //assembly targets are executed by the current thread. It is a worker of the async runtime that
+        //also runs other tasks, thus restore its affinity afterwards
+        let current = Pid::from_raw(0);
+        let prev_affinity =
+            sched::sched_getaffinity(current).context("failed to get server thread affinity")?;
+        pin_to_cpu(current, cpu).context("failed to pin server thread")?;
+        let result = run_prog(prog, name);
+        if let Err(e) = sched::sched_setaffinity(current, &prev_affinity) {
+            error!("failed to restore server thread affinity : {:?}", e);
+        }
+        result
+    })
+}
 
-    match &state.target_programm {
-        Some(prog_mutex) => match &mut prog_mutex.lock() {
-            Ok(prog) => {
-                debug!("Running target program");
-                unsafe { prog.run()? }
-            }
-            Err(e) => bail!("Failed to get target program : {:?}", e),
-        },
-        None => bail!("target program not initialized"),
-    }
+fn run_prog(prog: &mut (dyn RunnableTarget + Send), name: &str) -> Result<(), anyhow::Error> {
+    debug!("Running target program {}", name);
+    unsafe { prog.run()? }
 
     debug!("run_target handler done");
     Ok(())
 }
 
+/// Restrict the thread or process `pid` to `cpu`. Pid 0 selects the calling thread
+pub fn pin_to_cpu(pid: Pid, cpu: usize) -> Result<(), anyhow::Error> {
+    let mut cpu_set = CpuSet::new();
+    cpu_set
+        .set(cpu)
+        .map_err(|_| BadRequest(format!("invalid cpu {}", cpu)))?;
+    sched::sched_setaffinity(pid, &cpu_set).context(format!(
+        "sched_setaffinity for {} to cpu {} failed",
+        pid, cpu
+    ))?;
+    debug!("pinned {} to cpu {}", pid, cpu);
+    Ok(())
+}
+
+pub async fn set_target_affinity_handler(
+    State(state): State<Arc<Mutex<ServerState>>>,
+    Json(req): Json<SetTargetAffinityReq>,
+) -> Result<(), AppError> {
+    match set_target_affinity(state, req) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("set_target_affinity failed with {:?}", e);
+            Err(AppError::from(e))
+        }
+    }
+}
+
+/// Remember `req.cpu` for all subsequent runs, see [`SetTargetAffinityReq`]. Nothing is pinned until the next run
+fn set_target_affinity(
+    state: Arc<Mutex<ServerState>>,
+    req: SetTargetAffinityReq,
+) -> Result<(), anyhow::Error> {
+    if CpuSet::new().set(req.cpu).is_err() {
+        return Err(BadRequest(format!("invalid cpu {}", req.cpu)).into());
+    }
+    let mut state = match state.lock() {
+        Ok(v) => v,
+        Err(e) => bail!("failed to aquire state lock {}", e),
+    };
+    debug!("running targets on cpu {}", req.cpu);
+    state.target_cpu = Some(req.cpu);
+    Ok(())
+}
+
+pub async fn flush_target_handler(state: State<Arc<Mutex<ServerState>>>) -> Result<(), AppError> {
+    flush_named_target_handler(state, Path(DEFAULT_TARGET_NAME.to_string())).await
+}
+
+pub async fn flush_named_target_handler(
+    State(state): State<Arc<Mutex<ServerState>>>,
+    Path(name): Path<String>,
+) -> Result<(), AppError> {
+    match flush_target(state, &name) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("flush_target_handler failed with {:?}", e);
+            Err(AppError::from(e))
+        }
+    }
+}
+
+fn flush_target(state: Arc<Mutex<ServerState>>, name: &str) -> Result<(), anyhow::Error> {
+    with_idle_target(state, name, |prog| {
+        debug!("Flushing target program from cache");
+        prog.flush()
+    })
+}
+
+pub async fn last_return_value_handler(
+    state: State<Arc<Mutex<ServerState>>>,
+) -> Result<Json<LastReturnValueResp>, AppError> {
+    last_return_value_named_handler(state, Path(DEFAULT_TARGET_NAME.to_string())).await
+}
+
+pub async fn last_return_value_named_handler(
+    State(state): State<Arc<Mutex<ServerState>>>,
+    Path(name): Path<String>,
+) -> Result<Json<LastReturnValueResp>, AppError> {
+    match last_return_value(state, &name) {
+        Ok(v) => Ok(Json(v)),
+        Err(e) => {
+            error!("last_return_value failed with {:?}", e);
+            Err(AppError::from(e))
+        }
+    }
+}
+
+fn last_return_value(
+    state: Arc<Mutex<ServerState>>,
+    name: &str,
+) -> Result<LastReturnValueResp, anyhow::Error> {
+    with_idle_target(state, name, |prog| {
+        Ok(LastReturnValueResp {
+            return_value: prog.last_return_value()?,
+        })
+    })
+}
+
+pub async fn translate_offset_handler(
+    state: State<Arc<Mutex<ServerState>>>,
+    req: Json<TranslateOffsetReq>,
+) -> Result<Json<TranslateOffsetResp>, AppError> {
+    translate_offset_named_handler(state, Path(DEFAULT_TARGET_NAME.to_string()), req).await
+}
+
+pub async fn translate_offset_named_handler(
+    State(state): State<Arc<Mutex<ServerState>>>,
+    Path(name): Path<String>,
+    Json(req): Json<TranslateOffsetReq>,
+) -> Result<Json<TranslateOffsetResp>, AppError> {
+    match translate_offset(state, &name, req) {
+        Ok(v) => Ok(Json(v)),
+        Err(e) => {
+            error!("translate_offset failed with {:?}", e);
+            Err(AppError::from(e))
+        }
+    }
+}
+
+fn translate_offset(
+    state: Arc<Mutex<ServerState>>,
+    name: &str,
+    req: TranslateOffsetReq,
+) -> Result<TranslateOffsetResp, anyhow::Error> {
+    with_idle_target(state, name, |prog| {
+        let (buffer_vaddr, buffer_bytes) = prog.get_buffer(req.buffer)?;
+        if req.offset >= buffer_bytes {
+            bail!(
+                "offset 0x{:x} is outside of the {} buffer with 0x{:x} bytes",
+                req.offset,
+                req.buffer,
+                buffer_bytes
+            );
+        }
+        let vaddr = buffer_vaddr + req.offset;
+        let paddr = LinuxPageMap::new()?
+            .get_phys(vaddr)
+            .context(format!("failed to translate 0x{:x} to phys addr", vaddr))?;
+        debug!(
+            "offset 0x{:x} in {} buffer translates to vaddr 0x{:x}, paddr 0x{:x}",
+            req.offset, req.buffer, vaddr, paddr
+        );
+        Ok(TranslateOffsetResp { vaddr, paddr })
+    })
+}
+
+/// Completes once the server process receives SIGTERM or SIGINT
+pub async fn termination_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => debug!("received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => debug!("received SIGINT"),
+    }
+}
+
+/// Waits for `shutdown_signal` and tears down all target programs before completing.
+/// Intended to be passed to `axum::Server::with_graceful_shutdown`, to avoid orphaning
+/// external targets when the server gets killed
+pub async fn teardown_on_shutdown(
+    state: Arc<Mutex<ServerState>>,
+    shutdown_signal: impl Future<Output = ()>,
+) {
+    shutdown_signal.await;
+    debug!("shutting down, tearing down target programs");
+    //teardown waits for ongoing runs, thus keep it off the async runtime threads
+    let teardown = tokio::task::spawn_blocking(move || {
+        let names = match state.lock() {
+            Ok(v) => v.targets.keys().cloned().collect::<Vec<_>>(),
+            Err(e) => {
+                error!("failed to aquire state lock {}", e);
+                return;
+            }
+        };
+        for name in names {
+            if let Err(e) = teardown_target(state.clone(), &name) {
+                error!("teardown_target for {} failed with {:?}", name, e);
+            }
+        }
+    });
+    if let Err(e) = teardown.await {
+        error!("teardown task failed with {:?}", e);
+    }
+}
+
+pub async fn teardown_target_handler(
+    State(state): State<Arc<Mutex<ServerState>>>,
+) -> Result<(), AppError> {
+    match teardown_target(state, DEFAULT_TARGET_NAME) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("teardown_target failed with {:?}", e);
+            Err(AppError::from(e))
+        }
+    }
+}
+
+/// Removes the target program stored under `name` from `state` and releases its resources.
+/// Waits for an ongoing run of the target to complete
+fn teardown_target(state: Arc<Mutex<ServerState>>, name: &str) -> Result<(), anyhow::Error> {
+    let prog_mutex = {
+        let mut state = match state.lock() {
+            Ok(v) => v,
+            Err(e) => bail!("failed to aquire state lock {}", e),
+        };
+        match state.targets.remove(name) {
+            Some(v) => v,
+            None => {
+                debug!("no target program {} loaded, nothing to tear down", name);
+                return Ok(());
+            }
+        }
+    };
+
+    let mut prog = match prog_mutex.lock() {
+        Ok(v) => v,
+        Err(e) => bail!("Failed to get target program : {:?}", e),
+    };
+    prog.teardown()
+    //the target's memory is freed once the last reference to `prog_mutex` is dropped
+}
+
 pub async fn init_page_ping_ponger_handler(
     State(state): State<Arc<Mutex<ServerState>>>,
     Json(req): Json<InitPagePingPongerReq>,
@@ -263,9 +745,10 @@ pub fn init_page_ping_ponger(
     state: Arc<Mutex<ServerState>>,
     req: InitPagePingPongerReq,
 ) -> Result<InitPagePingPongerResp, anyhow::Error> {
-    let p = PagePingPonger::new(&req.variant, req.rounds).context(format!(
-        "failed to instantiate {:?} ping ponger with {} rounds",
-        req.variant, req.rounds
+    let order_seed = req.randomized.then_some(req.seed);
+    let p = PagePingPonger::new(&req.variant, req.rounds, order_seed).context(format!(
+        "failed to instantiate {:?} ping ponger with {} rounds and order seed {:?}",
+        req.variant, req.rounds, order_seed
     ))?;
 
     let mut pagemap = LinuxPageMap::new()?;
@@ -295,7 +778,546 @@ pub fn init_page_ping_ponger(
         Err(e) => bail!("failed to aquire state lock {}", e),
     };
 
-    state.target_programm = Some(Arc::new(Mutex::new(p)));
+    state
+        .targets
+        .insert(DEFAULT_TARGET_NAME.to_string(), Arc::new(Mutex::new(p)));
 
     Ok(resp)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{channel, Receiver, Sender},
+        },
+        thread,
+        time::Duration,
+    };
+
+    use iced_x86::code_asm::*;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::req_resp::TargetBuffer;
+
+    /// State with `target` stored under [`DEFAULT_TARGET_NAME`]
+    fn state_with_default_target(
+        target: impl RunnableTarget + Send + 'static,
+    ) -> Arc<Mutex<ServerState>> {
+        let mut state = ServerState::default();
+        state.targets.insert(
+            DEFAULT_TARGET_NAME.to_string(),
+            Arc::new(Mutex::new(target)),
+        );
+        Arc::new(Mutex::new(state))
+    }
+
+    /// Target whose `run` blocks until it receives a message on `release`
+    struct BlockingTarget {
+        started: Sender<()>,
+        release: Receiver<()>,
+    }
+
+    impl RunnableTarget for BlockingTarget {
+        unsafe fn run(&mut self) -> anyhow::Result<()> {
+            self.started.send(())?;
+            self.release.recv()?;
+            Ok(())
+        }
+
+        unsafe fn stop(self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reject_run_while_target_running() -> anyhow::Result<()> {
+        let (started_sender, started_receiver) = channel();
+        let (release_sender, release_receiver) = channel();
+        let state = state_with_default_target(BlockingTarget {
+            started: started_sender,
+            release: release_receiver,
+        });
+
+        let first_run_state = state.clone();
+        let first_run = thread::spawn(move || run_target(first_run_state, DEFAULT_TARGET_NAME));
+        started_receiver.recv()?;
+
+        let second_run = run_target(state.clone(), DEFAULT_TARGET_NAME);
+        assert!(second_run
+            .expect_err("second run should be rejected")
+            .to_string()
+            .contains("already running"));
+
+        release_sender.send(())?;
+        first_run.join().expect("first run panicked")?;
+        Ok(())
+    }
+
+    /// Target that records whether it has been torn down
+    struct TeardownRecordingTarget {
+        torn_down: Arc<AtomicBool>,
+    }
+
+    impl RunnableTarget for TeardownRecordingTarget {
+        unsafe fn run(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        unsafe fn stop(self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn teardown(&mut self) -> anyhow::Result<()> {
+            self.torn_down.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_tears_down_target() -> anyhow::Result<()> {
+        let torn_down = Arc::new(AtomicBool::new(false));
+        let state = state_with_default_target(TeardownRecordingTarget {
+            torn_down: torn_down.clone(),
+        });
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let server = axum::Server::bind(&"127.0.0.1:0".parse()?)
+            .serve(Router::new().into_make_service())
+            .with_graceful_shutdown(teardown_on_shutdown(state.clone(), async {
+                shutdown_receiver.await.ok();
+            }));
+        let server = tokio::spawn(server);
+
+        assert!(!torn_down.load(Ordering::SeqCst));
+        shutdown_sender
+            .send(())
+            .expect("server dropped shutdown receiver");
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+
+        assert!(torn_down.load(Ordering::SeqCst));
+        assert!(state.lock().unwrap().targets.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_during_run_does_not_block_runtime() -> anyhow::Result<()> {
+        let (started_sender, started_receiver) = channel();
+        let (release_sender, release_receiver) = channel();
+        let state = state_with_default_target(BlockingTarget {
+            started: started_sender,
+            release: release_receiver,
+        });
+        let run_state = state.clone();
+        let run = thread::spawn(move || run_target(run_state, DEFAULT_TARGET_NAME));
+        started_receiver.recv()?;
+
+        let teardown = tokio::spawn(teardown_on_shutdown(state.clone(), async {}));
+        //the test runtime has a single thread, which would be stuck in the teardown otherwise
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!teardown.is_finished());
+
+        release_sender.send(())?;
+        tokio::time::timeout(Duration::from_secs(5), teardown).await??;
+        run.join().expect("run panicked")?;
+        assert!(state.lock().unwrap().targets.is_empty());
+        Ok(())
+    }
+
+    /// Call [`init_assembly_target_handler`] with `req` encoded as `format`
+    async fn init_assembly_target_as(
+        req: &InitAssemblyTargetReq,
+        format: WireFormat,
+    ) -> anyhow::Result<InitAssemblyTargetResp> {
+        let body = match format {
+            WireFormat::Json => serde_json::to_vec(req)?,
+            WireFormat::Bincode => bincode::serialize(req)?,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, format.content_type().parse()?);
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let Json(resp) = init_assembly_target_handler(State(state), headers, Bytes::from(body))
+            .await
+            .map_err(|e| e.0)?;
+        Ok(resp)
+    }
+
+    #[tokio::test]
+    async fn bincode_and_json_assembly_target_reqs_are_equivalent() -> anyhow::Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.mov(rsi, qword_ptr(rdi))?;
+        a.add(rsi, 0x42)?;
+        a.mov(qword_ptr(rdi + 8), rsi)?;
+        a.ret()?;
+        let req = InitAssemblyTargetReq {
+            code: a.take_instructions(),
+            required_mem_bytes: 4096,
+            initial_data: Vec::new(),
+        };
+
+        let json_resp = init_assembly_target_as(&req, WireFormat::Json).await?;
+        let bincode_resp = init_assembly_target_as(&req, WireFormat::Bincode).await?;
+
+        //both targets live at different addresses, thus only compare the address independent parts
+        assert_eq!(
+            json_resp.instructions_with_rip,
+            bincode_resp.instructions_with_rip
+        );
+        let rip_offsets = |resp: &InitAssemblyTargetResp| {
+            resp.instructions_with_rip
+                .iter()
+                .map(|v| v.ip() as usize - resp.code_vaddr)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rip_offsets(&json_resp), rip_offsets(&bincode_resp));
+        assert_eq!(json_resp.data_buffer_bytes, bincode_resp.data_buffer_bytes);
+        assert_eq!(
+            json_resp.code_page_paddrs.len(),
+            bincode_resp.code_page_paddrs.len()
+        );
+        assert_eq!(json_resp.touched_on_init, bincode_resp.touched_on_init);
+        assert_eq!(json_resp.pinned, bincode_resp.pinned);
+        Ok(())
+    }
+
+    #[test]
+    fn assembly_target_req_without_content_type_is_json() -> anyhow::Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.ret()?;
+        let req = InitAssemblyTargetReq {
+            code: a.take_instructions(),
+            required_mem_bytes: 0,
+            initial_data: Vec::new(),
+        };
+        let body = serde_json::to_vec(&req)?;
+
+        let decoded = decode_assembly_target_req(&HeaderMap::new(), &body)?;
+        assert_eq!(decoded.code, req.code);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "text/plain".parse()?);
+        assert!(decode_assembly_target_req(&headers, &body).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn buffer_less_target_reports_no_data_buffer() -> anyhow::Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.nop()?;
+        a.ret()?;
+        let req = InitAssemblyTargetReq {
+            code: a.take_instructions(),
+            required_mem_bytes: 0,
+            initial_data: Vec::new(),
+        };
+
+        let resp = init_assembly_target_as(&req, WireFormat::Json).await?;
+        assert_eq!(resp.data_buffer_vaddr, 0);
+        assert_eq!(resp.data_buffer_paddr, 0);
+        assert!(resp.data_page_paddrs.is_empty());
+        assert!(!resp.data_buffer_shares_code_page());
+        assert_eq!(resp.all_gpas(), resp.code_gpas());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_empty_code_with_bad_request() -> anyhow::Result<()> {
+        let req = InitAssemblyTargetReq {
+            code: Vec::new(),
+            required_mem_bytes: 4096,
+            initial_data: Vec::new(),
+        };
+        let err = init_assembly_target_as(&req, WireFormat::Json)
+            .await
+            .expect_err("empty code should be rejected");
+        assert!(err.to_string().contains("at least one instruction"));
+        assert_eq!(
+            AppError(err).into_response().status(),
+            StatusCode::BAD_REQUEST
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn translate_offset_resolves_page_of_offset() -> anyhow::Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.mov(rsi, qword_ptr(rdi + 4096))?;
+        a.ret()?;
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let resp = init_assembly_target(
+            state.clone(),
+            DEFAULT_TARGET_NAME,
+            InitAssemblyTargetReq {
+                code: a.take_instructions(),
+                required_mem_bytes: 2 * 4096,
+                initial_data: Vec::new(),
+            },
+        )?;
+
+        let translate = |offset| {
+            translate_offset(
+                state.clone(),
+                DEFAULT_TARGET_NAME,
+                TranslateOffsetReq {
+                    buffer: TargetBuffer::Data,
+                    offset,
+                },
+            )
+        };
+        let first_page = translate(0)?;
+        assert_eq!(first_page.vaddr, resp.data_buffer_vaddr);
+        assert_eq!(first_page.paddr, resp.data_buffer_paddr);
+
+        let second_page = translate(4096 + 8)?;
+        assert_eq!(second_page.vaddr, resp.data_buffer_vaddr + 4096 + 8);
+        assert_ne!(second_page.paddr & !0xfff, first_page.paddr & !0xfff);
+        assert_eq!(second_page.paddr & 0xfff, 8);
+
+        assert!(translate(16 * 4096).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn assembly_target_reads_initial_data() -> anyhow::Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.movzx(eax, byte_ptr(rdi))?;
+        a.ret()?;
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        init_assembly_target(
+            state.clone(),
+            DEFAULT_TARGET_NAME,
+            InitAssemblyTargetReq {
+                code: a.take_instructions(),
+                required_mem_bytes: 4096,
+                initial_data: vec![0xa5, 0x01],
+            },
+        )?;
+
+        run_target(state.clone(), DEFAULT_TARGET_NAME)?;
+        assert_eq!(
+            last_return_value(state, DEFAULT_TARGET_NAME)?.return_value,
+            Some(0xa5)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn param_branch_input_selects_branch() -> anyhow::Result<()> {
+        let secret = 42;
+        let executed_branch_instructions = |input| -> anyhow::Result<Option<u64>> {
+            let state = Arc::new(Mutex::new(ServerState::default()));
+            init_param_branch(state.clone(), InitParamBranchReq { secret, input })?;
+            run_target(state.clone(), DEFAULT_TARGET_NAME)?;
+            Ok(last_return_value(state, DEFAULT_TARGET_NAME)?.return_value)
+        };
+
+        assert_eq!(
+            executed_branch_instructions(secret)?,
+            Some(param_branch::MATCHING_BRANCH_INSTRUCTIONS)
+        );
+        assert_eq!(executed_branch_instructions(secret + 1)?, Some(0));
+        Ok(())
+    }
+
+    /// Multipart body for `/custom-target/new` with an archive of `archive_bytes` bytes
+    fn custom_target_form(boundary: &str, archive_bytes: usize) -> Vec<u8> {
+        let mut body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"execute_cmd\"\r\n\r\n./a.out\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"file_archive\"; filename=\"victim.tar\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            b = boundary
+        )
+        .into_bytes();
+        body.extend(vec![0x42; archive_bytes]);
+        body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+        body
+    }
+
+    #[tokio::test]
+    async fn reject_archive_above_upload_limit() -> anyhow::Result<()> {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let server = axum::Server::bind(&"127.0.0.1:0".parse()?)
+            .serve(router(state.clone(), 4096).into_make_service());
+        let url = format!("http://{}/custom-target/new", server.local_addr());
+        let server = tokio::spawn(server);
+
+        let boundary = "vmserver-test-boundary";
+        let client = reqwest::Client::new();
+        let post_archive = |archive_bytes| {
+            client
+                .post(&url)
+                .header(
+                    CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", boundary),
+                )
+                .body(custom_target_form(boundary, archive_bytes))
+                .send()
+        };
+
+        let resp = post_archive(8192).await?;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        //below the limit, the upload is accepted but unpacking the garbage archive fails
+        let resp = post_archive(128).await?;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(state.lock().unwrap().targets.is_empty());
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn named_targets_run_independently() -> anyhow::Result<()> {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let server = axum::Server::bind(&"127.0.0.1:0".parse()?)
+            .serve(router(state.clone(), DEFAULT_MAX_UPLOAD_BYTES).into_make_service());
+        let base_url = format!("http://{}", server.local_addr());
+        let server = tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        for (name, return_value) in [("victim", 0x1111_u64), ("attacker", 0x2222)] {
+            let mut a = CodeAssembler::new(64)?;
+            a.mov(rax, return_value)?;
+            a.ret()?;
+            let req = InitAssemblyTargetReq {
+                code: a.take_instructions(),
+                required_mem_bytes: 0,
+                initial_data: Vec::new(),
+            };
+            client
+                .post(format!("{}/target/{}/new", base_url, name))
+                .json(&req)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        //the targets are only accessible through the named routes
+        let resp = client
+            .post(format!("{}/run-target", base_url))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let return_value_of = |name: &str| {
+            let client = client.clone();
+            let url = format!("{}/target/{}/last-return-value", base_url, name);
+            async move {
+                let resp: LastReturnValueResp = client
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                anyhow::Ok(resp.return_value)
+            }
+        };
+        client
+            .post(format!("{}/target/attacker/run", base_url))
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(return_value_of("attacker").await?, Some(0x2222));
+        assert_eq!(return_value_of("victim").await?, None);
+
+        client
+            .post(format!("{}/target/victim/flush", base_url))
+            .send()
+            .await?
+            .error_for_status()?;
+        client
+            .post(format!("{}/target/victim/run", base_url))
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(return_value_of("victim").await?, Some(0x1111));
+
+        let translated: TranslateOffsetResp = client
+            .post(format!("{}/target/attacker/translate-offset", base_url))
+            .json(&TranslateOffsetReq {
+                buffer: TargetBuffer::Code,
+                offset: 0,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let attacker_code_vaddr = with_idle_target(state.clone(), "attacker", |prog| {
+            Ok(prog.get_buffer(TargetBuffer::Code)?.0)
+        })?;
+        assert_eq!(translated.vaddr, attacker_code_vaddr);
+
+        server.abort();
+        Ok(())
+    }
+
+    /// Target that records the CPUs its `run` is allowed to execute on
+    struct AffinityRecordingTarget {
+        cpus: Vec<usize>,
+    }
+
+    impl RunnableTarget for AffinityRecordingTarget {
+        unsafe fn run(&mut self) -> anyhow::Result<()> {
+            self.cpus = allowed_cpus();
+            Ok(())
+        }
+
+        unsafe fn stop(self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn allowed_cpus() -> Vec<usize> {
+        let allowed = sched::sched_getaffinity(Pid::from_raw(0)).unwrap();
+        (0..CpuSet::count())
+            .filter(|v| allowed.is_set(*v).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn run_restores_server_thread_affinity() {
+        //affinity is per thread, thus use a dedicated one to not affect other tests
+        thread::spawn(|| {
+            let before = allowed_cpus();
+            let target = Arc::new(Mutex::new(AffinityRecordingTarget { cpus: Vec::new() }));
+            let mut state = ServerState::default();
+            state
+                .targets
+                .insert(DEFAULT_TARGET_NAME.to_string(), target.clone());
+            state.target_cpu = Some(*before.last().unwrap());
+
+            run_target(Arc::new(Mutex::new(state)), DEFAULT_TARGET_NAME).unwrap();
+
+            assert_eq!(target.lock().unwrap().cpus, vec![*before.last().unwrap()]);
+            assert_eq!(allowed_cpus(), before);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn pin_current_thread_to_cpu() {
+        //affinity is per thread, thus use a dedicated one to not affect other tests
+        thread::spawn(|| {
+            let current = Pid::from_raw(0);
+            let allowed = sched::sched_getaffinity(current).unwrap();
+            let cpu = (0..CpuSet::count())
+                .find(|v| allowed.is_set(*v).unwrap())
+                .unwrap();
+
+            pin_to_cpu(current, cpu).unwrap();
+
+            let pinned = sched::sched_getaffinity(current).unwrap();
+            let pinned_cpus: Vec<_> = (0..CpuSet::count())
+                .filter(|v| pinned.is_set(*v).unwrap())
+                .collect();
+            assert_eq!(pinned_cpus, vec![cpu]);
+            assert!(pin_to_cpu(current, CpuSet::count()).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -2,4 +2,6 @@ pub mod assembly_target;
 pub mod handlers;
 pub mod req_resp;
 pub mod virt_to_phys;
-pub mod external_target;
\ No newline at end of file
+pub mod external_target;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
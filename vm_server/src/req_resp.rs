@@ -1,4 +1,7 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+};
 
 use iced_x86::Instruction;
 use serde::{Deserialize, Serialize};
@@ -14,7 +17,7 @@ use crate::assembly_target::page_ping_ponger::PagePingPongVariant;
 /// back to the client it may output lines of the format `VMSERVER::VAR <NAME> <VALUE>`. Both `<NAME>` and `<VALUE`> may not
 /// contain any whitespaces. The tuples (<NAME>,<VALUE>) are send back to the calling client as part of [`InitCustomTargetResp`]
 ///
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitCustomTargetReq {
     ///Path to folder containing all files for the custom binary that should get executed
     pub folder_path: String,
@@ -23,21 +26,27 @@ pub struct InitCustomTargetReq {
     pub execute_cmd: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitCustomTargetResp {
     ///Key value pairs recorded during the setup phase. See comment on [`InitCustomTargetReq`] for a desription
     pub setup_output: HashMap<String, String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitPagePingPongerReq {
     ///selects the type of access that should be performed
     pub variant: PagePingPongVariant,
     ///selects the number of rounds. One round consists of one access to each of the two pages accessed by the ping ponger
     pub rounds: u32,
+    ///if true, the order of the two accesses is shuffled for each round. Otherwise, the first page is always accessed first
+    #[serde(default)]
+    pub randomized: bool,
+    ///seed for the shuffling if `randomized` is set. The same seed always results in the same access sequence
+    #[serde(default)]
+    pub seed: u64,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitPagePingPongerResp {
     ///virtual addresses of the two pages accessed by the ping ponger
     pub page_vaddrs: [usize; 2],
@@ -47,36 +56,326 @@ pub struct InitPagePingPongerResp {
     pub variant: PagePingPongVariant,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Request for the victim from [`crate::assembly_target::param_branch`], which branches on whether
+/// the input from its data buffer matches the secret. Answered with [`InitAssemblyTargetResp`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InitParamBranchReq {
+    ///constant compared against the input
+    pub secret: u64,
+    ///stored at the start of the data buffer
+    pub input: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitAssemblyTargetReq {
     pub code: Vec<Instruction>,
     //code requires to be called with ptr to a page aligned buffer
-    //of this size
+    //of this size. If zero, no buffer is allocated and the code is called with a null ptr
     pub required_mem_bytes: usize,
+    ///Copied to the start of the data buffer before the code runs for the first time, e.g. to supply
+    /// a secret. If it is larger than the data buffer, it is truncated. The remaining bytes of the buffer are zero
+    #[serde(default)]
+    pub initial_data: Vec<u8>,
+}
+
+/// Memory buffers of an assembly based target
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetBuffer {
+    Code,
+    Data,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Display for TargetBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetBuffer::Code => write!(f, "code"),
+            TargetBuffer::Data => write!(f, "data"),
+        }
+    }
+}
+
+///Request to translate a byte offset inside one of the target's buffers to a physical address.
+/// Physical addresses are only contiguous within a page, thus offsets beyond the first page
+/// cannot be computed from the buffer's base address
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranslateOffsetReq {
+    pub buffer: TargetBuffer,
+    ///Offset in bytes, relative to the start of `buffer`
+    pub offset: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranslateOffsetResp {
+    ///Virtual address of the requested offset
+    pub vaddr: usize,
+    ///Physical address of the requested offset
+    pub paddr: usize,
+}
+
+/// Request to run the targets on the given guest CPU. The route only stores the CPU, pinning is deferred to
+/// the next run of a target. Each run pins the server thread that runs the target for the duration of the run
+/// and, for targets that run in their own process, that process
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetTargetAffinityReq {
+    ///Index of the guest CPU, e.g. the vCPU that is single stepped
+    pub cpu: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LastReturnValueResp {
+    ///Content of rax after the most recent run of the target. None, if the target has not been run yet
+    pub return_value: Option<u64>,
+}
+
+/// Encoding of the [`InitAssemblyTargetReq`] body, selected via the `Content-Type` header.
+/// JSON is the default. For programs with thousands of instructions, bincode is considerably
+/// smaller and faster to parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Bincode,
+}
+
+impl WireFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "application/json",
+            WireFormat::Bincode => "application/x-bincode",
+        }
+    }
+
+    /// Parses the value of a `Content-Type` header, ignoring parameters like the charset
+    pub fn from_content_type(content_type: &str) -> Option<WireFormat> {
+        let mime_type = content_type.split(';').next().unwrap_or("").trim();
+        [WireFormat::Json, WireFormat::Bincode]
+            .into_iter()
+            .find(|v| v.content_type() == mime_type)
+    }
+}
+
+///Unlike `SingleStepVictimInitResp` in the original SEV-Step userland, this carries no cache attack data
+/// like memory access offsets or the location of a lookup table
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitAssemblyTargetResp {
     ///Virtual address where the code from the request has been placed
     /// Guaranteed to be page aligned
     pub code_vaddr: usize,
     ///Physical address for `code_vaddr`
     pub code_paddr: usize,
-    ///Virtual address of the data buffer supplied to the code in rdi
+    ///Physical addresses of all pages that contain the code, starting with the page at `code_paddr`.
+    /// Consecutive code pages are not guaranteed to be physically contiguous
+    pub code_page_paddrs: Vec<usize>,
+    ///Virtual address of the data buffer supplied to the code in rdi. Zero if `data_buffer_bytes` is zero
     pub data_buffer_vaddr: usize,
-    ///Physical address for `data_buffer_vaddr`
+    ///Physical address for `data_buffer_vaddr`. Zero if `data_buffer_bytes` is zero
     pub data_buffer_paddr: usize,
+    ///Physical addresses of all pages of the data buffer, starting with the page at `data_buffer_paddr`.
+    /// Like for the code pages, they are not guaranteed to be physically contiguous
+    #[serde(default)]
+    pub data_page_paddrs: Vec<usize>,
     ///Same as in the request. Just for convenience
     pub data_buffer_bytes: usize,
     /// Instructions from the request with their final RIP value. Substract
     /// `code_vaddr` to get the expected offsets inside the code page.
     pub instructions_with_rip: Vec<Instruction>,
+    ///True if all code and data pages were resident when the response was sent. In this case, the
+    /// physical addresses are valid right away and do not change while the target is loaded,
+    /// unless the kernel migrates the pages, e.g. for compaction, transparent huge pages or KSM
+    pub touched_on_init: bool,
+    ///True if the code and data pages are locked into memory, preventing the kernel from migrating
+    /// them to other physical addresses
+    pub pinned: bool,
+}
+
+impl InitAssemblyTargetResp {
+    ///Distinct, page aligned physical addresses of all pages occupied by the code
+    pub fn code_page_gpas(&self) -> BTreeSet<usize> {
+        self.code_page_paddrs.iter().map(|v| v & !0xfff).collect()
+    }
+
+    ///Distinct GPAs of all code pages in ascending order, e.g. to track all of them
+    pub fn code_gpas(&self) -> Vec<u64> {
+        self.code_page_gpas()
+            .into_iter()
+            .map(|v| v as u64)
+            .collect()
+    }
+
+    ///Distinct GPAs of all code and data buffer pages in ascending order
+    pub fn all_gpas(&self) -> Vec<u64> {
+        let mut gpas = self.code_page_gpas();
+        if self.data_buffer_bytes != 0 {
+            gpas.insert(self.data_buffer_paddr & !0xfff);
+        }
+        gpas.extend(self.data_page_paddrs.iter().map(|v| v & !0xfff));
+        gpas.into_iter().map(|v| v as u64).collect()
+    }
+
+    ///Returns true if the first page of the data buffer is also occupied by the code
+    pub fn data_buffer_shares_code_page(&self) -> bool {
+        self.data_buffer_bytes != 0
+            && self
+                .code_page_gpas()
+                .contains(&(self.data_buffer_paddr & !0xfff))
+    }
+
+    ///Offsets of all instructions from the start of the code, in program order. These are the
+    /// offsets at which single stepping is expected to observe the instructions
+    pub fn expected_offsets(&self) -> Vec<u64> {
+        self.instructions_with_rip
+            .iter()
+            .map(|v| v.ip() - self.code_vaddr as u64)
+            .collect()
+    }
 }
 
 impl Display for InitAssemblyTargetResp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code_page_gpas: Vec<_> = self
+            .code_page_gpas()
+            .iter()
+            .map(|v| format!("0x{:x}", v))
+            .collect();
         write!(f,
-                "InitAssemblyTargetResp(code_vaddr=0x{:x}, code_paddr=0x{:x}, data_buffer_vaddr=0x{:x}, data_buffer_paddr=0x{:x}, data_buffer_bytes=0x{:x})",self.code_vaddr,self.code_paddr,self.data_buffer_vaddr,self.data_buffer_paddr,self.data_buffer_bytes
+                "InitAssemblyTargetResp(code_vaddr=0x{:x}, code_paddr=0x{:x}, data_buffer_vaddr=0x{:x}, data_buffer_paddr=0x{:x}, data_buffer_bytes=0x{:x}, code_page_gpas=[{}], data_buffer_shares_code_page={})",self.code_vaddr,self.code_paddr,self.data_buffer_vaddr,self.data_buffer_paddr,self.data_buffer_bytes,code_page_gpas.join(", "),self.data_buffer_shares_code_page()
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use iced_x86::code_asm::*;
+    use serde::de::DeserializeOwned;
+
+    /// Serialize `value`, deserialize it again and check that serializing the result yields the same data
+    fn assert_round_trips<T: Serialize + DeserializeOwned>(value: &T) -> Result<()> {
+        let serialized = serde_json::to_string(value)?;
+        let deserialized: T = serde_json::from_str(&serialized)?;
+        assert_eq!(serialized, serde_json::to_string(&deserialized)?);
+        Ok(())
+    }
+
+    #[test]
+    fn req_resp_types_round_trip() -> Result<()> {
+        let mut a = CodeAssembler::new(64)?;
+        a.mov(rsi, qword_ptr(rdi))?;
+        a.ret()?;
+        let instructions = a.take_instructions();
+
+        assert_round_trips(&InitCustomTargetReq {
+            folder_path: "./victims/simple_pf_victim".to_string(),
+            execute_cmd: "./a.out arg1".to_string(),
+        })?;
+        assert_round_trips(&InitCustomTargetResp {
+            setup_output: HashMap::from([("victim_fn".to_string(), "0x1000".to_string())]),
+        })?;
+        assert_round_trips(&InitPagePingPongerReq {
+            variant: PagePingPongVariant::WRITE,
+            rounds: 10,
+            randomized: true,
+            seed: 42,
+        })?;
+        assert_round_trips(&InitPagePingPongerResp {
+            page_vaddrs: [0x1000, 0x2000],
+            page_paddrs: [0x5000, 0x7000],
+            variant: PagePingPongVariant::EXEC,
+        })?;
+        assert_round_trips(&InitAssemblyTargetReq {
+            code: instructions.clone(),
+            required_mem_bytes: 4096,
+            initial_data: vec![0x42; 16],
+        })?;
+        assert_round_trips(&InitAssemblyTargetResp {
+            code_vaddr: 0x1000,
+            code_paddr: 0x5000,
+            code_page_paddrs: vec![0x5000],
+            data_buffer_vaddr: 0x2000,
+            data_buffer_paddr: 0x7000,
+            data_page_paddrs: vec![0x7000],
+            data_buffer_bytes: 4096,
+            instructions_with_rip: instructions,
+            touched_on_init: true,
+            pinned: true,
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn code_page_info_for_multi_page_code() {
+        let mut resp = InitAssemblyTargetResp {
+            code_vaddr: 0x1000,
+            code_paddr: 0x5000,
+            code_page_paddrs: vec![0x5000, 0x9000],
+            data_buffer_vaddr: 0x3000,
+            data_buffer_paddr: 0x7000,
+            data_page_paddrs: vec![0x7000],
+            data_buffer_bytes: 4096,
+            instructions_with_rip: Vec::new(),
+            touched_on_init: true,
+            pinned: true,
+        };
+        assert_eq!(resp.code_page_gpas(), BTreeSet::from([0x5000, 0x9000]));
+        assert!(!resp.data_buffer_shares_code_page());
+        assert!(resp
+            .to_string()
+            .contains("code_page_gpas=[0x5000, 0x9000], data_buffer_shares_code_page=false"));
+
+        resp.data_buffer_paddr = 0x9000;
+        assert!(resp.data_buffer_shares_code_page());
+    }
+
+    #[test]
+    fn tracking_gpas_of_multi_page_target() {
+        let resp = InitAssemblyTargetResp {
+            code_vaddr: 0x1000,
+            code_paddr: 0x5000,
+            code_page_paddrs: vec![0x9000, 0x5000, 0x9000],
+            data_buffer_vaddr: 0x3000,
+            data_buffer_paddr: 0x7000,
+            data_page_paddrs: vec![0x7000, 0x9000, 0xb000],
+            data_buffer_bytes: 3 * 4096,
+            instructions_with_rip: Vec::new(),
+            touched_on_init: true,
+            pinned: true,
+        };
+        assert_eq!(resp.code_gpas(), vec![0x5000, 0x9000]);
+        //0x9000 is shared by code and data
+        assert_eq!(resp.all_gpas(), vec![0x5000, 0x7000, 0x9000, 0xb000]);
+    }
+
+    #[test]
+    fn expected_offsets_follow_instruction_layout() -> Result<()> {
+        let code_vaddr = 0x4000;
+        let mut a = CodeAssembler::new(64)?;
+        a.nop()?;
+        a.mov(rax, 0x42u64)?;
+        a.mov(rsi, qword_ptr(rdi))?;
+        a.ret()?;
+        let code = a.assemble(code_vaddr)?;
+        let resp = InitAssemblyTargetResp {
+            code_vaddr: code_vaddr as usize,
+            code_paddr: 0x9000,
+            code_page_paddrs: vec![0x9000],
+            data_buffer_vaddr: 0x5000,
+            data_buffer_paddr: 0xa000,
+            data_page_paddrs: vec![0xa000],
+            data_buffer_bytes: 4096,
+            instructions_with_rip: iced_x86::Decoder::with_ip(
+                64,
+                &code,
+                code_vaddr,
+                iced_x86::DecoderOptions::NONE,
             )
+            .into_iter()
+            .collect(),
+            touched_on_init: true,
+            pinned: true,
+        };
+        //nop: 1 byte, mov rax, imm64: 10 bytes, mov rsi, [rdi]: 3 bytes
+        assert_eq!(resp.expected_offsets(), vec![0, 1, 11, 14]);
+        Ok(())
     }
 }
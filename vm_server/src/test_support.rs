@@ -0,0 +1,184 @@
+//!Harness for testing the routes of the server in-process. Requests are passed directly to the
+//! [`Router`], without binding a socket. As the targets live in the same process, tests can also
+//! inspect their memory
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{header::CONTENT_TYPE, Method, Request, StatusCode},
+    Router,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tower::ServiceExt;
+
+use crate::handlers::{self, ServerState, DEFAULT_MAX_UPLOAD_BYTES};
+
+/// Router with a fresh [`ServerState`]
+pub struct TestServer {
+    state: Arc<Mutex<ServerState>>,
+    router: Router,
+}
+
+impl TestServer {
+    pub fn new() -> Self {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        TestServer {
+            router: handlers::router(state.clone(), DEFAULT_MAX_UPLOAD_BYTES),
+            state,
+        }
+    }
+
+    /// State shared with the router, e.g. to access the loaded targets directly
+    pub fn state(&self) -> Arc<Mutex<ServerState>> {
+        self.state.clone()
+    }
+
+    /// Send a request to `uri`. If `body` is given, it is sent as JSON.
+    /// Returns the status code and the raw body of the response
+    pub async fn request<Req: Serialize>(
+        &self,
+        method: Method,
+        uri: &str,
+        body: Option<&Req>,
+    ) -> Result<(StatusCode, Bytes)> {
+        let builder = Request::builder().method(method).uri(uri);
+        let req = match body {
+            Some(v) => builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(v).context("failed to serialize request")?,
+                )),
+            None => builder.body(Body::empty()),
+        }
+        .context("failed to build request")?;
+
+        let resp = self
+            .router
+            .clone()
+            .oneshot(req)
+            .await
+            .context(format!("failed to send request to {}", uri))?;
+        let status = resp.status();
+        let mut body = resp.into_body();
+        let mut resp_bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            resp_bytes.extend_from_slice(&chunk.context("failed to read response body")?);
+        }
+        Ok((status, Bytes::from(resp_bytes)))
+    }
+
+    /// Post `req` as JSON to `uri` and parse the JSON response. Fails if the status does not indicate success
+    pub async fn post_json<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        uri: &str,
+        req: &Req,
+    ) -> Result<Resp> {
+        let body = self.request_ok(Method::POST, uri, Some(req)).await?;
+        serde_json::from_slice(&body).context("failed to parse response")
+    }
+
+    /// Post to `uri` without a body, e.g. to run the target. Fails if the status does not indicate success
+    pub async fn post_empty(&self, uri: &str) -> Result<()> {
+        self.request_ok::<()>(Method::POST, uri, None).await?;
+        Ok(())
+    }
+
+    /// Get `uri` and parse the JSON response. Fails if the status does not indicate success
+    pub async fn get_json<Resp: DeserializeOwned>(&self, uri: &str) -> Result<Resp> {
+        let body = self.request_ok::<()>(Method::GET, uri, None).await?;
+        serde_json::from_slice(&body).context("failed to parse response")
+    }
+
+    async fn request_ok<Req: Serialize>(
+        &self,
+        method: Method,
+        uri: &str,
+        body: Option<&Req>,
+    ) -> Result<Bytes> {
+        let (status, body) = self.request(method, uri, body).await?;
+        if !status.is_success() {
+            bail!(
+                "{} returned {} : {}",
+                uri,
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        Ok(body)
+    }
+}
+
+impl Default for TestServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use iced_x86::code_asm::*;
+
+    use super::*;
+    use crate::req_resp::{
+        InitAssemblyTargetReq, InitAssemblyTargetResp, LastReturnValueResp, SetTargetAffinityReq,
+    };
+
+    #[tokio::test]
+    async fn run_assembly_target_modifies_data_buffer() -> Result<()> {
+        let server = TestServer::new();
+        let mut a = CodeAssembler::new(64)?;
+        a.mov(rsi, qword_ptr(rdi))?;
+        a.add(rsi, 0x42)?;
+        a.mov(qword_ptr(rdi + 8), rsi)?;
+        a.mov(rax, rsi)?;
+        a.ret()?;
+        let req = InitAssemblyTargetReq {
+            code: a.take_instructions(),
+            required_mem_bytes: 4096,
+            initial_data: 0x1000_u64.to_le_bytes().to_vec(),
+        };
+
+        let resp: InitAssemblyTargetResp = server.post_json("/assembly-target/new", &req).await?;
+        server.post_empty("/run-target").await?;
+
+        let LastReturnValueResp { return_value } = server.get_json("/last-return-value").await?;
+        assert_eq!(return_value, Some(0x1042));
+        //the target is still loaded and lives in our process, thus its data buffer can be read directly
+        let written = unsafe { ((resp.data_buffer_vaddr + 8) as *const u64).read_volatile() };
+        assert_eq!(written, 0x1042);
+
+        //running without a target fails
+        let (status, _) = TestServer::new()
+            .request::<()>(Method::POST, "/run-target", None)
+            .await?;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_target_affinity_validates_cpu() -> Result<()> {
+        let server = TestServer::new();
+        let (status, _) = server
+            .request(
+                Method::POST,
+                "/set-target-affinity",
+                Some(&SetTargetAffinityReq { cpu: 0 }),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(server.state().lock().unwrap().target_cpu, Some(0));
+
+        let (status, _) = server
+            .request(
+                Method::POST,
+                "/set-target-affinity",
+                Some(&SetTargetAffinityReq { cpu: usize::MAX }),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(server.state().lock().unwrap().target_cpu, Some(0));
+        Ok(())
+    }
+}